@@ -0,0 +1,20 @@
+use crate::{Aviffy, ImageData};
+use rayon::prelude::*;
+use std::io;
+
+/// One file to make with [`Aviffy::to_vec_batch`]
+pub type BatchImage<'a> = ImageData<'a>;
+
+impl Aviffy {
+    /// Makes many files in parallel on the `rayon` thread pool, all with the same settings.
+    ///
+    /// Files are returned in the same order as `images`, with an error for each invalid image (see [`Aviffy::try_to_vec`]).
+    /// Each thread serializes into a reused scratch buffer, so the returned `Vec`s are allocated once, at their exact size.
+    #[must_use]
+    pub fn to_vec_batch(&self, images: &[BatchImage<'_>]) -> Vec<io::Result<Vec<u8>>> {
+        images.par_iter().map_init(Vec::new, |scratch, image| {
+            self.to_vec_in(scratch, image)?;
+            Ok(scratch.as_slice().to_vec())
+        }).collect()
+    }
+}
@@ -0,0 +1,211 @@
+//! Conversion of AV1 data from other formats to the low-overhead bitstream format that AVIF requires.
+
+use crate::obu::{self, leb128, Obus, OBU_FRAME, OBU_FRAME_HEADER, OBU_METADATA, OBU_SEQUENCE_HEADER, OBU_TEMPORAL_DELIMITER};
+use crate::verify::frames_too_large;
+use crate::FrameInfo;
+use std::io::{self, Read};
+
+/// Converts AV1 data in the Annex B format (length-delimited temporal units, frame units and OBUs,
+/// e.g. from `aomenc --annexb=1`) to the low-overhead bitstream format, in which every OBU has an `obu_size` field.
+///
+/// Annex B data written as-is makes files that decoders can't read.
+/// Temporal delimiters are removed, since AVIF doesn't use them.
+///
+/// Returns the converted data, and the size of every temporal unit in it, which are the sizes of frames of an animation.
+pub fn annex_b_to_low_overhead(annex_b: &[u8]) -> io::Result<(Vec<u8>, Vec<usize>)> {
+    let mut out = Vec::with_capacity(annex_b.len());
+    let mut sizes = Vec::new();
+    let mut temporal_units = annex_b;
+    while !temporal_units.is_empty() {
+        let temporal_unit = take_unit(&mut temporal_units)?;
+        let start = out.len();
+        let mut frame_units = temporal_unit;
+        while !frame_units.is_empty() {
+            let mut obus = take_unit(&mut frame_units)?;
+            while !obus.is_empty() {
+                push_obu(&mut out, take_unit(&mut obus)?)?;
+            }
+        }
+        sizes.push(out.len() - start);
+    }
+    Ok((out, sizes))
+}
+
+/// Splits AV1 data of a whole animation, in the low-overhead bitstream format, into temporal units (one for each frame).
+///
+/// A temporal unit ends before a temporal delimiter, or after a frame that is shown when another frame, sequence header or metadata follows.
+/// Temporal delimiters are removed, since AVIF doesn't use them.
+///
+/// Returns the data without temporal delimiters, and the size of every temporal unit in it, which are the sizes of frames of an animation.
+pub fn split_temporal_units(av1_data: &[u8]) -> io::Result<(Vec<u8>, Vec<usize>)> {
+    let mut out = Vec::with_capacity(av1_data.len());
+    let mut sizes = Vec::new();
+    let mut start = 0;
+    let mut shown = false;
+    let mut reduced_still_picture_header = false;
+    for obu in Obus::new(av1_data) {
+        let obu = obu?;
+        let starts_unit = match obu.typ {
+            OBU_TEMPORAL_DELIMITER => true,
+            OBU_SEQUENCE_HEADER | OBU_METADATA | OBU_FRAME_HEADER | OBU_FRAME => shown,
+            _ => false,
+        };
+        if starts_unit && out.len() > start {
+            sizes.push(out.len() - start);
+            start = out.len();
+            shown = false;
+        }
+        match obu.typ {
+            OBU_TEMPORAL_DELIMITER => continue,
+            OBU_SEQUENCE_HEADER => {
+                // seq_profile(3), still_picture(1), reduced_still_picture_header(1)
+                reduced_still_picture_header = obu.payload.first().is_some_and(|&b| b & 0x08 != 0);
+            },
+            OBU_FRAME_HEADER | OBU_FRAME => shown |= obu::shows_frame(obu.payload, reduced_still_picture_header)?,
+            _ => {},
+        }
+        out.extend_from_slice(obu.raw);
+    }
+    if out.len() > start {
+        sizes.push(out.len() - start);
+    }
+    Ok((out, sizes))
+}
+
+/// Sets [`sync`](FrameInfo::sync) of the frames that start with a key frame in `av1_data`, and clears it for the others.
+///
+/// The frames are consecutive parts of the data, of their [`size`](FrameInfo::size). Fails if the sizes add up to more than the data.
+pub fn detect_key_frames(av1_data: &[u8], frames: &mut [FrameInfo]) -> io::Result<()> {
+    let mut rest = av1_data;
+    for frame in frames {
+        let (data, next) = rest.split_at_checked(frame.size).ok_or_else(frames_too_large)?;
+        frame.sync = obu::is_keyframe(data)?;
+        rest = next;
+    }
+    Ok(())
+}
+
+/// Splits off data preceded by its leb128 length
+fn take_unit<'a>(data: &mut &'a [u8]) -> io::Result<&'a [u8]> {
+    let (size, leb_len) = leb128(data)?;
+    let unit = leb_len.checked_add(size).and_then(|end| data.get(leb_len..end)).ok_or_else(obu::invalid)?;
+    *data = &data[leb_len + size..];
+    Ok(unit)
+}
+
+/// Writes the OBU with an `obu_size` field
+fn push_obu(out: &mut Vec<u8>, obu: &[u8]) -> io::Result<()> {
+    let header = *obu.first().ok_or_else(obu::invalid)?;
+    if header & 0x80 != 0 { // obu_forbidden_bit
+        return Err(obu::invalid());
+    }
+    if (header >> 3) & 0x0f == OBU_TEMPORAL_DELIMITER {
+        return Ok(());
+    }
+    let header_len = if header & 0x04 != 0 { 2 } else { 1 };
+    let mut payload = obu.get(header_len..).ok_or_else(obu::invalid)?;
+    if header & 0x02 != 0 { // obu_has_size_field
+        let (size, leb_len) = leb128(payload)?;
+        payload = leb_len.checked_add(size).and_then(|end| payload.get(leb_len..end)).ok_or_else(obu::invalid)?;
+    }
+    out.push(header | 0x02);
+    out.extend_from_slice(&obu[1..header_len]);
+    obu::push_leb128(out, payload.len());
+    out.extend_from_slice(payload);
+    Ok(())
+}
+
+/// Frames of an IVF file (e.g. from `aomenc --ivf`), ready to be passed to [`Aviffy::write`](crate::Aviffy::write)
+/// as `color_av1_data`, `color_frames` and `timescale`.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct IvfFrames {
+    /// All frames, concatenated, without temporal delimiters
+    pub data: Vec<u8>,
+    pub frames: Vec<FrameInfo>,
+    pub timescale: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl IvfFrames {
+    /// Reads a whole IVF file or stream. Key frames are detected from the AV1 data.
+    ///
+    /// Frames last until the next frame's timestamp, and the last one lasts one tick of the IVF timebase.
+    pub fn read(ivf: impl Read) -> io::Result<Self> {
+        let mut reader = IvfReader::new(ivf)?;
+        let mut out = Self { data: Vec::new(), frames: Vec::new(), timescale: reader.timebase_den, width: reader.width, height: reader.height };
+        let mut last_pts = None;
+        while let Some((packet, pts)) = reader.next_packet()? {
+            if let (Some(last_pts), Some(last)) = (last_pts, out.frames.last_mut()) {
+                last.duration_in_timescales = pts.saturating_sub(last_pts).max(1).checked_mul(u64::from(reader.timebase_num))
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "IVF frame duration overflows"))?;
+            }
+            let start = out.data.len();
+            for obu in Obus::new(&packet) {
+                let obu = obu?;
+                if obu.typ != OBU_TEMPORAL_DELIMITER {
+                    out.data.extend_from_slice(obu.raw);
+                }
+            }
+            out.frames.push(FrameInfo {
+                duration_in_timescales: reader.timebase_num.into(),
+                sync: obu::is_keyframe(&packet)?,
+                size: out.data.len() - start,
+            });
+            last_pts = Some(pts);
+        }
+        Ok(out)
+    }
+}
+
+/// Packets of an IVF file, and the fields of its header
+pub(crate) struct IvfReader<R> {
+    reader: R,
+    pub width: u32,
+    pub height: u32,
+    /// Timestamps are in units of `timebase_num`/`timebase_den` seconds
+    pub timebase_num: u32,
+    pub timebase_den: u32,
+}
+
+impl<R: Read> IvfReader<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0; 32];
+        reader.read_exact(&mut header)?;
+        if &header[0..4] != b"DKIF" || &header[8..12] != b"AV01" {
+            return Err(obu::invalid());
+        }
+        let u16le = |b: &[u8]| u16::from_le_bytes([b[0], b[1]]);
+        let u32le = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+        let header_len = u16le(&header[6..]);
+        io::copy(&mut reader.by_ref().take(u64::from(header_len).saturating_sub(32)), &mut io::sink())?;
+        Ok(Self {
+            width: u16le(&header[12..]).into(),
+            height: u16le(&header[14..]).into(),
+            timebase_num: u32le(&header[20..]).max(1),
+            timebase_den: u32le(&header[16..]),
+            reader,
+        })
+    }
+
+    /// Frame data and its timestamp, or `None` at the end of the file
+    pub fn next_packet(&mut self) -> io::Result<Option<(Vec<u8>, u64)>> {
+        let mut header = [0; 12];
+        let mut filled = 0;
+        while filled < header.len() {
+            match self.reader.read(&mut header[filled..])? {
+                0 => return Ok(None), // ignores a truncated frame header
+                n => filled += n,
+            }
+        }
+        let size = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let pts = u64::from_le_bytes([header[4], header[5], header[6], header[7], header[8], header[9], header[10], header[11]]);
+        let mut data = Vec::new();
+        self.reader.by_ref().take(size.into()).read_to_end(&mut data)?;
+        if data.len() != size as usize {
+            return Err(obu::invalid());
+        }
+        Ok(Some((data, pts)))
+    }
+}
@@ -5,16 +5,69 @@ use crate::writer::Writer;
 use crate::writer::WriterBackend;
 use crate::writer::IO;
 use arrayvec::ArrayVec;
+use std::borrow::Cow;
 use std::fmt;
 use std::io;
 use std::io::Write;
+use std::sync::Arc;
 
 pub trait MpegBox {
     fn len(&self) -> usize;
     fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error>;
 }
 
-#[derive(Copy, Clone)]
+/// A box type that isn't built into this crate.
+///
+/// See [`Aviffy::custom_box`](crate::Aviffy::custom_box).
+#[allow(clippy::len_without_is_empty)]
+pub trait CustomBox {
+    /// Size of the whole box in bytes, including its size and type header
+    fn len(&self) -> usize;
+
+    /// Append the whole box, including its size and type header, to `out`.
+    /// Must append exactly `len()` bytes.
+    fn write(&self, out: &mut Vec<u8>);
+}
+
+#[derive(Clone)]
+pub struct ExternalBox(pub Arc<dyn CustomBox + Send + Sync>);
+
+impl ExternalBox {
+    /// A box that writes a different number of bytes than it says would make the whole file invalid
+    pub(crate) fn check_len(&self) -> io::Result<()> {
+        let mut tmp = Vec::with_capacity(self.len());
+        self.0.write(&mut tmp);
+        if tmp.len() != self.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "CustomBox::write doesn't match its len()"));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ExternalBox {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("ExternalBox").field(&self.0.len()).finish()
+    }
+}
+
+impl MpegBox for ExternalBox {
+    #[inline]
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Box header is written by the custom box, so this goes directly into the parent.
+    ///
+    /// The length is checked before writing, but is enforced here too, so that a misbehaving box can't corrupt the rest of the file.
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut tmp = Vec::with_capacity(self.len());
+        self.0.write(&mut tmp);
+        tmp.resize(self.len(), 0);
+        w.push(&tmp)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
 pub struct FourCC(pub [u8; 4]);
 
 impl fmt::Debug for FourCC {
@@ -30,79 +83,167 @@ impl fmt::Debug for FourCC {
 pub struct AvifFile<'data> {
     pub ftyp: FtypBox,
     pub meta: MetaBox,
+    /// `free` box right after `meta`, which editors can turn into more `meta` without moving the data
+    pub padding: Option<BlobBox>,
     pub moov: Option<MoovBox>,
-    pub mdat: MdatBox<'data>,
+    /// Top-level boxes between headers and data
+    pub extra: Vec<BlobBox>,
+    pub mdat: Vec<MdatBox<'data>>,
 }
 
 impl AvifFile<'_> {
-    /// Where the primary data starts inside the `mdat` box, for `iloc`'s offset
-    fn mdat_payload_start_offset(&self) -> u32 {
-        (self.ftyp.len() 
+    /// Where the primary data starts inside the first `mdat` box, for `iloc`'s offset
+    pub fn mdat_payload_start_offset(&self) -> usize {
+        self.ftyp.len()
+
             + self.meta.len()
+            + self.padding.as_ref().map_or(0, |p| p.len())
             + match &self.moov {
                 Some(moov) => moov.len(),
                 _ => 0
             }
-            + BASIC_BOX_SIZE) as u32 // mdat head
+            + self.extra.iter().map(|b| b.len()).sum::<usize>()
+            + BASIC_BOX_SIZE // mdat head
+    }
+
+    /// Size of everything but the payloads: all boxes, `mdat` headers, and Exif's header offset
+    pub fn header_len(&self) -> usize {
+        self.mdat_payload_start_offset() - BASIC_BOX_SIZE
+            + self.mdat.iter().map(|mdat| BASIC_BOX_SIZE + mdat.exif.as_ref().map_or(0, |_| 4)).sum::<usize>()
     }
 
     /// `iloc` is mostly unnecssary, high risk of out-of-buffer accesses in parsers that don't pay attention,
     /// and also awkward to serialize, because its content depends on its own serialized byte size.
-    fn fix_iloc_positions(&mut self) {
+    fn fix_iloc_positions(&mut self) -> io::Result<()> {
         let start_offset = self.mdat_payload_start_offset();
         for iloc_item in self.meta.iloc.items.iter_mut() {
             for ex in iloc_item.extents.iter_mut() {
-                let abs = match ex.offset {
-                    IlocOffset::Relative(n) => n as u32 + start_offset,
-                    IlocOffset::Absolute(_) => continue,
-                };
-                ex.offset = IlocOffset::Absolute(abs);
+                if u32::try_from(ex.len).is_err() {
+                    return Err(too_large());
+                }
+                ex.offset = absolute_offset(&self.mdat, start_offset, ex.offset)?;
             }
         }
+        Ok(())
     }
 
-    fn fix_stco_positions(&mut self) {
-        let mut start_offset = self.mdat_payload_start_offset();
-
-        match self.moov.as_mut() {
-            Some(_moov) => {
-                for i in (0.._moov.tracks.len()).rev() {
-                    _moov.tracks[i].mdia.minf.stbl.stco.chunk_offset = start_offset;
-                    start_offset += _moov.tracks[i].mdia.minf.stbl.stsz.entry_size.clone().into_iter().reduce(|acc, e| acc + e).unwrap();
+    fn fix_stco_positions(&mut self) -> io::Result<()> {
+        let start_offset = self.mdat_payload_start_offset();
+        if let Some(moov) = self.moov.as_mut() {
+            for track in moov.tracks.iter_mut() {
+                if let Some(chunk_offset) = track.mdia.minf.stbl.stco.chunk_offset.as_mut() {
+                    *chunk_offset = absolute_offset(&self.mdat, start_offset, *chunk_offset)?;
                 }
-            },
-            _ => ()
+            }
         }
+        Ok(())
     }
 
-    pub fn write<W: Write>(&mut self, mut out: W) -> io::Result<()> {
-        self.fix_iloc_positions();
-        self.fix_stco_positions();
+    pub fn write(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        // `ipma` has 7 bits for property indices
+        if self.meta.iprp.ipco.props.len() > 0x7F {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "too many item properties"));
+        }
+        self.fix_iloc_positions()?;
+        self.fix_stco_positions()?;
 
-        let mut tmp = Vec::with_capacity(self.ftyp.len() + self.meta.len() + match &self.moov {
-            Some(moov) => moov.len(),
-            _ => 0
-        });
+        let mut tmp = Vec::with_capacity(self.mdat_payload_start_offset());
         let mut w = Writer::new(&mut tmp);
         let _ = self.ftyp.write(&mut w);
         let _ = self.meta.write(&mut w);
+        if let Some(padding) = &self.padding {
+            let _ = padding.write(&mut w);
+        }
         let _ = match &self.moov {
             Some(moov) => moov.write(&mut w),
             _ => Ok(())
         };
+        for extra in &self.extra {
+            let _ = extra.write(&mut w);
+        }
         drop(w);
-        out.write_all(&tmp)?;
+
+        // Only top-level boxes are written to `out`, so that's where IO errors can happen
+        let mut header_boxes = vec![(FourCC(*b"ftyp"), self.ftyp.len()), (FourCC(*b"meta"), self.meta.len())];
+        if let Some(padding) = &self.padding {
+            header_boxes.push((padding.typ, padding.len()));
+        }
+        if let Some(moov) = &self.moov {
+            header_boxes.push((FourCC(*b"moov"), moov.len()));
+        }
+        header_boxes.extend(self.extra.iter().map(|extra| (extra.typ, extra.len())));
+        let mut rest = &tmp[..];
+        for (typ, len) in header_boxes {
+            let (data, after) = rest.split_at(len.min(rest.len()));
+            out.write_all(data).map_err(|e| BoxWriteError::wrap(e, String::from_utf8_lossy(&typ.0).into_owned()))?;
+            rest = after;
+        }
         drop(tmp);
 
         let mut out = IO(out);
         let mut w = Writer::new(&mut out);
-        self.mdat.write(&mut w)?;
+        for (i, mdat) in self.mdat.iter().enumerate() {
+            mdat.write(&mut w).map_err(|e| {
+                BoxWriteError::wrap(e, if self.mdat.len() > 1 { format!("mdat[{i}]") } else { "mdat".into() })
+            })?;
+        }
         Ok(())
     }
 }
 
-const BASIC_BOX_SIZE: usize = 8;
-const FULL_BOX_SIZE: usize = BASIC_BOX_SIZE + 4;
+/// IO error with the path of the box that was being written
+#[derive(Debug)]
+pub(crate) struct BoxWriteError {
+    path: String,
+    source: io::Error,
+}
+
+impl BoxWriteError {
+    pub(crate) fn wrap(source: io::Error, path: String) -> io::Error {
+        io::Error::new(source.kind(), Self { path, source })
+    }
+}
+
+impl fmt::Display for BoxWriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "can't write {}: {}", self.path, self.source)
+    }
+}
+
+impl std::error::Error for BoxWriteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Relative offsets count bytes of all `mdat` payloads as if they were one,
+/// so every subsequent `mdat` shifts the data by the size of its own header.
+fn absolute_offset(mdat: &[MdatBox<'_>], mut start_offset: usize, offset: IlocOffset) -> io::Result<IlocOffset> {
+    let relative = match offset {
+        IlocOffset::Relative(n) => n,
+        IlocOffset::Absolute(_) => return Ok(offset),
+    };
+    let mut mdat_start = 0;
+    for (i, m) in mdat.iter().enumerate() {
+        let mdat_end = mdat_start + m.len() - BASIC_BOX_SIZE;
+        if relative < mdat_end || i + 1 == mdat.len() {
+            break;
+        }
+        mdat_start = mdat_end;
+        start_offset += BASIC_BOX_SIZE;
+    }
+    relative.checked_add(start_offset)
+        .and_then(|n| u32::try_from(n).ok())
+        .map(IlocOffset::Absolute)
+        .ok_or_else(too_large)
+}
+
+fn too_large() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "file too large for 32-bit offsets")
+}
+
+pub(crate) const BASIC_BOX_SIZE: usize = 8;
+pub(crate) const FULL_BOX_SIZE: usize = BASIC_BOX_SIZE + 4;
 
 #[derive(Debug, Clone)]
 pub struct FtypBox {
@@ -141,7 +282,18 @@ pub struct MetaBox {
     pub iinf: IinfBox,
     pub pitm: PitmBox,
     pub iprp: IprpBox,
-    pub iref: ArrayVec<IrefBox, 2>,
+    pub iref: IrefBox,
+    pub grpl: Option<GrplBox>,
+    /// `uuid` boxes from [`BlobLocation::MetaUuid`](crate::BlobLocation::MetaUuid)
+    pub blobs: Vec<BlobBox>,
+    pub custom: Vec<ExternalBox>,
+}
+
+impl MetaBox {
+    /// Where `iloc` starts in the serialized `meta` box
+    pub fn iloc_position(&self) -> usize {
+        FULL_BOX_SIZE + self.hdlr.len() + self.pitm.len()
+    }
 }
 
 impl MpegBox for MetaBox {
@@ -153,7 +305,10 @@ impl MpegBox for MetaBox {
             + self.iloc.len()
             + self.iinf.len()
             + self.iprp.len()
-            + self.iref.iter().map(|b| b.len()).sum::<usize>()
+            + if self.iref.entries.is_empty() { 0 } else { self.iref.len() }
+            + self.grpl.as_ref().map_or(0, |g| g.len())
+            + self.blobs.iter().map(|b| b.len()).sum::<usize>()
+            + self.custom.iter().map(|b| b.len()).sum::<usize>()
     }
 
     fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
@@ -163,17 +318,27 @@ impl MpegBox for MetaBox {
         self.pitm.write(&mut b)?;
         self.iloc.write(&mut b)?;
         self.iinf.write(&mut b)?;
-        for iref in &self.iref {
-            iref.write(&mut b)?;
+        if !self.iref.entries.is_empty() {
+            self.iref.write(&mut b)?;
         }
-        self.iprp.write(&mut b)
+        self.iprp.write(&mut b)?;
+        if let Some(grpl) = &self.grpl {
+            grpl.write(&mut b)?;
+        }
+        for blob in &self.blobs {
+            blob.write(&mut b)?;
+        }
+        for custom in &self.custom {
+            custom.write(&mut b)?;
+        }
+        Ok(())
     }
 }
 
 /// Item Info box
 #[derive(Debug, Clone)]
 pub struct IinfBox {
-    pub items: ArrayVec<InfeBox, 2>,
+    pub items: Vec<InfeBox>,
 }
 
 impl MpegBox for IinfBox {
@@ -196,11 +361,15 @@ impl MpegBox for IinfBox {
 }
 
 /// Item Info Entry box
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct InfeBox {
     pub id: u16,
     pub typ: FourCC,
-    pub name: &'static str,
+    pub name: Cow<'static, str>,
+    /// Only for `mime` items
+    pub content_type: Option<String>,
+    /// Only for `mime` items, e.g. `deflate`. Requires `content_type`.
+    pub content_encoding: Option<&'static str>,
 }
 
 impl MpegBox for InfeBox {
@@ -211,6 +380,8 @@ impl MpegBox for InfeBox {
         + 2 // item_protection_index
         + 4 // type
         + self.name.as_bytes().len() + 1 // nul-terminated
+        + self.content_type.as_ref().map_or(0, |c| c.len() + 1)
+        + self.content_encoding.map_or(0, |c| c.len() + 1)
     }
 
     fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
@@ -220,7 +391,16 @@ impl MpegBox for InfeBox {
         b.u16(0)?;
         b.push(&self.typ.0)?;
         b.push(self.name.as_bytes())?;
-        b.u8(0)
+        b.u8(0)?;
+        if let Some(content_type) = &self.content_type {
+            b.push(content_type.as_bytes())?;
+            b.u8(0)?;
+            if let Some(content_encoding) = self.content_encoding {
+                b.push(content_encoding.as_bytes())?;
+                b.u8(0)?;
+            }
+        }
+        Ok(())
     }
 }
 
@@ -285,6 +465,24 @@ pub enum IpcoProp {
     Ispe(IspeBox),
     AuxC(AuxCBox),
     Colr(ColrBox),
+    ColrIcc(ColrIccBox),
+    Mdcv(MdcvBox),
+    Clli(ClliBox),
+    Amve(AmveBox),
+    Dovi(DoviBox),
+    Udes(UdesBox),
+    Altt(AlttBox),
+    Pasp(PaspBox),
+    Clap(ClapBox),
+    Irot(IrotBox),
+    Imir(ImirBox),
+    A1lx(A1lxBox),
+    Lsel(LselBox),
+    A1op(A1opBox),
+    Av1CObus(Av1CObusBox),
+    Cmpd(CmpdBox),
+    UncC(UncCBox),
+    Custom(ExternalBox),
 }
 
 impl IpcoProp {
@@ -295,6 +493,24 @@ impl IpcoProp {
             Self::Ispe(p) => p.len(),
             Self::AuxC(p) => p.len(),
             Self::Colr(p) => p.len(),
+            Self::ColrIcc(p) => p.len(),
+            Self::Mdcv(p) => p.len(),
+            Self::Clli(p) => p.len(),
+            Self::Amve(p) => p.len(),
+            Self::Dovi(p) => p.len(),
+            Self::Udes(p) => p.len(),
+            Self::Altt(p) => p.len(),
+            Self::Pasp(p) => p.len(),
+            Self::Clap(p) => p.len(),
+            Self::Irot(p) => p.len(),
+            Self::Imir(p) => p.len(),
+            Self::A1lx(p) => p.len(),
+            Self::Lsel(p) => p.len(),
+            Self::A1op(p) => p.len(),
+            Self::Av1CObus(p) => p.len(),
+            Self::Cmpd(p) => p.len(),
+            Self::UncC(p) => p.len(),
+            Self::Custom(p) => p.len(),
         }
     }
 
@@ -305,6 +521,24 @@ impl IpcoProp {
             Self::Ispe(p) => p.write(w),
             Self::AuxC(p) => p.write(w),
             Self::Colr(p) => p.write(w),
+            Self::ColrIcc(p) => p.write(w),
+            Self::Mdcv(p) => p.write(w),
+            Self::Clli(p) => p.write(w),
+            Self::Amve(p) => p.write(w),
+            Self::Dovi(p) => p.write(w),
+            Self::Udes(p) => p.write(w),
+            Self::Altt(p) => p.write(w),
+            Self::Pasp(p) => p.write(w),
+            Self::Clap(p) => p.write(w),
+            Self::Irot(p) => p.write(w),
+            Self::Imir(p) => p.write(w),
+            Self::A1lx(p) => p.write(w),
+            Self::Lsel(p) => p.write(w),
+            Self::A1op(p) => p.write(w),
+            Self::Av1CObus(p) => p.write(w),
+            Self::Cmpd(p) => p.write(w),
+            Self::UncC(p) => p.write(w),
+            Self::Custom(p) => p.write(w),
         }
     }
 }
@@ -312,18 +546,40 @@ impl IpcoProp {
 /// Item Property Container box
 #[derive(Debug, Clone)]
 pub struct IpcoBox {
-    props: ArrayVec<IpcoProp, 7>,
+    props: Vec<IpcoProp>,
 }
 
 impl IpcoBox {
     pub fn new() -> Self {
-        Self { props: ArrayVec::new() }
+        Self { props: Vec::new() }
     }
 
+    /// Indices over 127 don't fit in `ipma`, which is checked when the file is written
     pub fn push(&mut self, prop: IpcoProp) -> u8 {
         self.props.push(prop);
         self.props.len() as u8 // the spec wants them off by one
     }
+
+    /// By the 1-based index used in `ipma`
+    pub fn get(&self, index: u8) -> Option<&IpcoProp> {
+        self.props.get(usize::from(index).checked_sub(1)?)
+    }
+
+    /// Same as `push`, but gives the index of an identical property if there is one
+    pub fn push_or_reuse(&mut self, prop: IpcoProp) -> u8 {
+        let serialized = |p: &IpcoProp| {
+            let mut out = Vec::with_capacity(p.len());
+            let mut w = Writer::new(&mut out);
+            let _ = p.write(&mut w.new_box(p.len()));
+            drop(w);
+            out
+        };
+        let new = serialized(&prop);
+        match self.props.iter().position(|p| serialized(p) == new) {
+            Some(i) => i as u8 + 1,
+            None => self.push(prop),
+        }
+    }
 }
 
 impl MpegBox for IpcoBox {
@@ -343,9 +599,9 @@ impl MpegBox for IpcoBox {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct AuxCBox {
-    pub urn: &'static str,
+    pub urn: Cow<'static, str>,
 }
 
 impl AuxCBox {
@@ -361,6 +617,295 @@ impl AuxCBox {
     }
 }
 
+/// UserDescriptionProperty, nul-terminated UTF-8 strings
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdesBox {
+    /// RFC 5646 language tag, e.g. `en-US`
+    pub lang: String,
+    pub name: String,
+    pub description: String,
+    /// Comma-separated
+    pub tags: String,
+}
+
+impl MpegBox for UdesBox {
+    #[inline]
+    fn len(&self) -> usize {
+        FULL_BOX_SIZE + self.lang.len() + 1 + self.name.len() + 1 + self.description.len() + 1 + self.tags.len() + 1
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.full_box(*b"udes", 0, 0)?;
+        for s in [&self.lang, &self.name, &self.description, &self.tags] {
+            b.push(s.as_bytes())?;
+            b.u8(0)?;
+        }
+        Ok(())
+    }
+}
+
+/// AccessibilityTextProperty, nul-terminated UTF-8 strings
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AlttBox {
+    pub alt_text: String,
+    /// RFC 5646 language tag
+    pub alt_lang: String,
+}
+
+impl UdesBox {
+    pub fn has_nul(&self) -> bool {
+        [&self.lang, &self.name, &self.description, &self.tags].iter().any(|s| s.contains('\0'))
+    }
+}
+
+impl AlttBox {
+    pub fn has_nul(&self) -> bool {
+        self.alt_text.contains('\0') || self.alt_lang.contains('\0')
+    }
+}
+
+impl MpegBox for AlttBox {
+    #[inline]
+    fn len(&self) -> usize {
+        FULL_BOX_SIZE + self.alt_text.len() + 1 + self.alt_lang.len() + 1
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.full_box(*b"altt", 0, 0)?;
+        b.push(self.alt_text.as_bytes())?;
+        b.u8(0)?;
+        b.push(self.alt_lang.as_bytes())?;
+        b.u8(0)
+    }
+}
+
+/// PixelAspectRatioBox, width:height of a pixel
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PaspBox {
+    pub h_spacing: u32,
+    pub v_spacing: u32,
+}
+
+impl MpegBox for PaspBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + 4 + 4
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.basic_box(*b"pasp")?;
+        b.u32(self.h_spacing)?;
+        b.u32(self.v_spacing)
+    }
+}
+
+/// AV1LayeredImageIndexingProperty, byte sizes of the first three layers (the last layer has the rest)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct A1lxBox {
+    pub layer_sizes: [u32; 3],
+}
+
+impl A1lxBox {
+    fn large_size(&self) -> bool {
+        self.layer_sizes.iter().any(|&size| size > 0xFFFF)
+    }
+}
+
+impl MpegBox for A1lxBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + 1 + 3 * if self.large_size() { 4 } else { 2 }
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.basic_box(*b"a1lx")?;
+        let large_size = self.large_size();
+        b.u8(u8::from(large_size))?;
+        for &size in &self.layer_sizes {
+            if large_size {
+                b.u32(size)?;
+            } else {
+                b.u16(size as u16)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// LayerSelectorProperty, the layer to show, or `0xFFFF` for any (progressively)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct LselBox {
+    pub layer_id: u16,
+}
+
+impl MpegBox for LselBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + 2
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.basic_box(*b"lsel")?;
+        b.u16(self.layer_id)
+    }
+}
+
+/// OperatingPointSelectorProperty, the AV1 operating point to decode
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct A1opBox {
+    pub op_index: u8,
+}
+
+impl MpegBox for A1opBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + 1
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.basic_box(*b"a1op")?;
+        b.u8(self.op_index)
+    }
+}
+
+/// ComponentDefinitionBox of uncompressed images, types of the components (e.g. 4 for red)
+#[derive(Debug, Clone, PartialEq)]
+pub struct CmpdBox {
+    pub component_types: Vec<u16>,
+}
+
+impl MpegBox for CmpdBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + 4 + 2 * self.component_types.len()
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.basic_box(*b"cmpd")?;
+        b.u32(self.component_types.len() as u32)?;
+        for &typ in &self.component_types {
+            b.u16(typ)?;
+        }
+        Ok(())
+    }
+}
+
+/// UncompressedFrameConfigBox for unsigned integer components of the same depth, without subsampling, padding or tiles
+#[derive(Debug, Clone, PartialEq)]
+pub struct UncCBox {
+    /// e.g. `rgb3`, or 0 if none applies
+    pub profile: [u8; 4],
+    pub component_count: u16,
+    pub bit_depth: u8,
+    /// 0 for planes one after another, 1 for interleaved pixels
+    pub interleave_type: u8,
+}
+
+impl MpegBox for UncCBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        FULL_BOX_SIZE + 4 + 4 + 5 * usize::from(self.component_count) + 4 + 4 * 5
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.full_box(*b"uncC", 0, 0)?;
+        b.push(&self.profile)?;
+        b.u32(self.component_count.into())?;
+        for index in 0..self.component_count {
+            b.u16(index)?;
+            b.u8(self.bit_depth - 1)?;
+            b.u8(0)?; // unsigned integer
+            b.u8(0)?; // align size
+        }
+        b.u8(0)?; // sampling type: no subsampling
+        b.u8(self.interleave_type)?;
+        b.u8(0)?; // block size
+        b.u8(0)?; // big endian, no blocks
+        b.u32(0)?; // pixel size
+        b.u32(0)?; // row align size
+        b.u32(0)?; // tile align size
+        b.u32(0)?; // tile columns - 1
+        b.u32(0) // tile rows - 1
+    }
+}
+
+/// CleanApertureBox. Fractions are (numerator, denominator), and offsets are from the center of the image.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ClapBox {
+    pub width: (u32, u32),
+    pub height: (u32, u32),
+    pub horiz_off: (i32, u32),
+    pub vert_off: (i32, u32),
+}
+
+impl MpegBox for ClapBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + 4 * 8
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.basic_box(*b"clap")?;
+        b.u32(self.width.0)?;
+        b.u32(self.width.1)?;
+        b.u32(self.height.0)?;
+        b.u32(self.height.1)?;
+        b.u32(self.horiz_off.0 as u32)?;
+        b.u32(self.horiz_off.1)?;
+        b.u32(self.vert_off.0 as u32)?;
+        b.u32(self.vert_off.1)
+    }
+}
+
+/// ImageRotation, counter-clockwise
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct IrotBox {
+    /// In units of 90°, 2 bits
+    pub angle: u8,
+}
+
+impl MpegBox for IrotBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + 1
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.basic_box(*b"irot")?;
+        b.u8(self.angle & 3)
+    }
+}
+
+/// ImageMirror
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ImirBox {
+    /// 0 = vertical axis (left and right swapped), 1 = horizontal axis (top and bottom swapped)
+    pub axis: u8,
+}
+
+impl MpegBox for ImirBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + 1
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.basic_box(*b"imir")?;
+        b.u8(self.axis & 1)
+    }
+}
+
 /// Pixies, I guess.
 #[derive(Debug, Copy, Clone)]
 pub struct PixiBox {
@@ -410,12 +955,12 @@ impl MpegBox for IspeBox {
 #[derive(Debug, Clone)]
 pub struct IpmaEntry {
     pub item_id: u16,
-    pub prop_ids: ArrayVec<u8, 5>,
+    pub prop_ids: Vec<u8>,
 }
 
 #[derive(Debug, Clone)]
 pub struct IpmaBox {
-    pub entries: ArrayVec<IpmaEntry, 2>,
+    pub entries: Vec<IpmaEntry>,
 }
 
 impl MpegBox for IpmaBox {
@@ -441,10 +986,11 @@ impl MpegBox for IpmaBox {
 }
 
 /// Item Reference box
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct IrefEntryBox {
     pub from_id: u16,
-    pub to_id: u16,
+    /// In order, which matters for `dimg`
+    pub to_ids: Vec<u16>,
     pub typ: FourCC,
 }
 
@@ -454,33 +1000,94 @@ impl MpegBox for IrefEntryBox {
         BASIC_BOX_SIZE
             + 2 // from
             + 2 // refcount
-            + 2 // to
+            + 2 * self.to_ids.len() // to
     }
 
     fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
         let mut b = w.new_box(self.len());
         b.basic_box(self.typ.0)?;
         b.u16(self.from_id)?;
-        b.u16(1)?;
-        b.u16(self.to_id)
+        b.u16(self.to_ids.len() as u16)?;
+        for &id in &self.to_ids {
+            b.u16(id)?;
+        }
+        Ok(())
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+/// There can be only one `iref` in `meta`, so it holds references of all types
+#[derive(Debug, Clone, Default)]
 pub struct IrefBox {
-    pub entry: IrefEntryBox,
+    pub entries: Vec<IrefEntryBox>,
 }
 
 impl MpegBox for IrefBox {
     #[inline(always)]
     fn len(&self) -> usize {
-        FULL_BOX_SIZE + self.entry.len()
+        FULL_BOX_SIZE + self.entries.iter().map(|e| e.len()).sum::<usize>()
     }
 
     fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
         let mut b = w.new_box(self.len());
         b.full_box(*b"iref", 0, 0)?;
-        self.entry.write(&mut b)
+        for entry in &self.entries {
+            entry.write(&mut b)?;
+        }
+        Ok(())
+    }
+}
+
+/// Groups list box, for entity groups such as `altr`
+#[derive(Debug, Clone)]
+pub struct GrplBox {
+    pub groups: Vec<EntityGroupBox>,
+}
+
+impl MpegBox for GrplBox {
+    #[inline]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + self.groups.iter().map(|g| g.len()).sum::<usize>()
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.basic_box(*b"grpl")?;
+        for group in &self.groups {
+            group.write(&mut b)?;
+        }
+        Ok(())
+    }
+}
+
+/// `EntityToGroupBox`. Group ids share the namespace with item ids.
+#[derive(Debug, Clone)]
+pub struct EntityGroupBox {
+    pub typ: FourCC,
+    pub group_id: u32,
+    pub entity_ids: Vec<u32>,
+    /// Fields after the entity ids, e.g. `pymd`'s layers
+    pub payload: Vec<u8>,
+}
+
+impl MpegBox for EntityGroupBox {
+    #[inline]
+    fn len(&self) -> usize {
+        FULL_BOX_SIZE
+            + 4 // group_id
+            + 4 // num_entities_in_group
+            + 4 * self.entity_ids.len()
+            + self.payload.len()
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.full_box(self.typ.0, 0, 0)?;
+        b.u32(self.group_id)?;
+        b.u32(self.entity_ids.len() as _)?;
+        for &id in &self.entity_ids {
+            b.u32(id)?;
+        }
+        b.push(&self.payload)
     }
 }
 
@@ -536,9 +1143,148 @@ impl MpegBox for ColrBox {
         b.u8(if self.full_range_flag { 1 << 7 } else { 0 })
     }
 }
-#[derive(Debug, Copy, Clone)]
+
+/// ColourInformationBox with an ICC profile
+#[derive(Debug, Clone)]
+pub struct ColrIccBox {
+    pub icc: Arc<[u8]>,
+}
+
+impl MpegBox for ColrIccBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + 4 + self.icc.len()
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.basic_box(*b"colr")?;
+        b.u32(u32::from_be_bytes(*b"prof"))?;
+        b.push(&self.icc)
+    }
+}
+
+/// MasteringDisplayColourVolumeBox, same as SMPTE ST 2086
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MdcvBox {
+    /// x, y of green, blue, red, in 0.00002 units
+    pub display_primaries: [(u16, u16); 3],
+    pub white_point: (u16, u16),
+    /// In 0.0001 cd/m² units
+    pub max_display_mastering_luminance: u32,
+    pub min_display_mastering_luminance: u32,
+}
+
+impl MpegBox for MdcvBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + 3 * 4 + 4 + 4 + 4
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.basic_box(*b"mdcv")?;
+        for (x, y) in self.display_primaries {
+            b.u16(x)?;
+            b.u16(y)?;
+        }
+        b.u16(self.white_point.0)?;
+        b.u16(self.white_point.1)?;
+        b.u32(self.max_display_mastering_luminance)?;
+        b.u32(self.min_display_mastering_luminance)
+    }
+}
+
+/// ContentLightLevelBox, in cd/m²
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ClliBox {
+    pub max_content_light_level: u16,
+    pub max_pic_average_light_level: u16,
+}
+
+impl MpegBox for ClliBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + 2 + 2
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.basic_box(*b"clli")?;
+        b.u16(self.max_content_light_level)?;
+        b.u16(self.max_pic_average_light_level)
+    }
+}
+
+/// AmbientViewingEnvironmentBox
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AmveBox {
+    /// In 0.0001 lux units
+    pub ambient_illuminance: u32,
+    /// In 0.00002 units
+    pub ambient_light_x: u16,
+    pub ambient_light_y: u16,
+}
+
+impl MpegBox for AmveBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + 4 + 2 + 2
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.basic_box(*b"amve")?;
+        b.u32(self.ambient_illuminance)?;
+        b.u16(self.ambient_light_x)?;
+        b.u16(self.ambient_light_y)
+    }
+}
+
+/// DOVIDecoderConfigurationRecord, in `dvcC`, `dvvC` or `dvwC` depending on the profile
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DoviBox {
+    /// 7 bits
+    pub profile: u8,
+    /// 6 bits
+    pub level: u8,
+    pub rpu_present: bool,
+    pub el_present: bool,
+    pub bl_present: bool,
+    /// 4 bits
+    pub bl_signal_compatibility_id: u8,
+}
+
+impl MpegBox for DoviBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + 24
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.basic_box(match self.profile {
+            0..=7 => *b"dvcC",
+            8..=10 => *b"dvvC",
+            _ => *b"dvwC",
+        })?;
+        b.u8(1)?; // dv_version_major
+        b.u8(0)?; // dv_version_minor
+        b.u16(u16::from(self.profile) << 9 | u16::from(self.level) << 3
+            | u16::from(self.rpu_present) << 2 | u16::from(self.el_present) << 1 | u16::from(self.bl_present))?;
+        b.u8(self.bl_signal_compatibility_id << 4)?;
+        b.push(&[0; 19]) // reserved
+    }
+}
+
+/// AV1 codec configuration (`av1C`). Fields mirror the AV1 sequence header, and must match it.
+///
+/// See [`Aviffy::color_av1c`](crate::Aviffy::color_av1c).
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct Av1CBox {
+    /// 0 = Main, 1 = High, 2 = Professional
     pub seq_profile: u8,
+    /// 5 bits, 31 means no level constraints
     pub seq_level_idx_0: u8,
     pub seq_tier_0: bool,
     pub high_bitdepth: bool,
@@ -546,6 +1292,7 @@ pub struct Av1CBox {
     pub monochrome: bool,
     pub chroma_subsampling_x: bool,
     pub chroma_subsampling_y: bool,
+    /// 2 bits
     pub chroma_sample_position: u8,
 }
 
@@ -556,7 +1303,14 @@ impl MpegBox for Av1CBox {
     }
 
     fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
-        let mut b = w.new_box(self.len());
+        self.write_with_obus(w, &[])
+    }
+}
+
+impl Av1CBox {
+    /// With `config_obus` (e.g. the sequence header OBU) after the fields
+    pub(crate) fn write_with_obus<B: WriterBackend>(&self, w: &mut Writer<B>, config_obus: &[u8]) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len() + config_obus.len());
         b.basic_box(*b"av1C")?;
         let flags1 =
             u8::from(self.seq_tier_0) << 7 |
@@ -572,7 +1326,26 @@ impl MpegBox for Av1CBox {
             (self.seq_profile << 5) | self.seq_level_idx_0, // x2d == 45
             flags1,
             0,
-        ])
+        ])?;
+        b.push(config_obus)
+    }
+}
+
+/// `av1C` with the sequence header OBU in its `configOBUs`, so that decoders can set up before reading `mdat`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Av1CObusBox {
+    pub config: Av1CBox,
+    pub config_obus: Vec<u8>,
+}
+
+impl MpegBox for Av1CObusBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        self.config.len() + self.config_obus.len()
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        self.config.write_with_obus(w, &self.config_obus)
     }
 }
 
@@ -594,7 +1367,7 @@ impl MpegBox for PitmBox {
 
 #[derive(Debug, Clone)]
 pub struct IlocBox {
-    pub items: ArrayVec<IlocItem, 2>,
+    pub items: Vec<IlocItem>,
 }
 
 #[derive(Debug, Clone)]
@@ -636,84 +1409,227 @@ impl MpegBox for IlocBox {
 
     fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
         let mut b = w.new_box(self.len());
-        b.full_box(*b"iloc", 0, 0)?;
-        b.push(&[4 << 4 | 4, 0])?; // offset and length are 4 bytes
-
-        b.u16(self.items.len() as _)?; // num items
-        for item in self.items.iter() {
-            b.u16(item.id)?;
-            b.u16(0)?;
-            b.u16(item.extents.len() as _)?; // num extents
-            for ex in &item.extents {
-                b.u32(match ex.offset {
-                    IlocOffset::Absolute(val) => val,
-                    IlocOffset::Relative(_) => panic!("absolute offset must be set"),
-                })?;
-                b.u32(ex.len as _)?;
-            }
+        b.full_box(*b"iloc", 0, 0)?;
+        b.push(&[4 << 4 | 4, 0])?; // offset and length are 4 bytes
+
+        b.u16(self.items.len() as _)?; // num items
+        for item in self.items.iter() {
+            b.u16(item.id)?;
+            b.u16(0)?;
+            b.u16(item.extents.len() as _)?; // num extents
+            for ex in &item.extents {
+                b.u32(match ex.offset {
+                    IlocOffset::Absolute(val) => val,
+                    IlocOffset::Relative(_) => {
+                        debug_assert!(false, "absolute offset must be set");
+                        0
+                    },
+                })?;
+                b.u32(ex.len as _)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl IlocBox {
+    /// Position of each extent's 4-byte offset (followed by its 4-byte length) in the serialized box, in the order they're written
+    pub fn extent_positions(&self) -> Vec<(usize, &IlocExtent)> {
+        let mut pos = FULL_BOX_SIZE + 1 + 1 + 2; // sizes, num items
+        let mut out = Vec::new();
+        for item in &self.items {
+            pos += 2 + 2 + 2; // id, dat ref idx, num extents
+            for ex in &item.extents {
+                out.push((pos, ex));
+                pos += 4 + 4;
+            }
+        }
+        out
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MdatBox<'data> {
+    /// Borrowed from the input, except data the muxer made, such as compressed metadata
+    pub data_chunks: Vec<Cow<'data, [u8]>>,
+    pub exif: Option<ExifBox>,
+}
+
+impl MpegBox for MdatBox<'_> {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE  + match &self.exif {
+            Some(_exif) => _exif.len(),
+            _ => 0
+        } + self.data_chunks.iter().map(|c| c.len()).sum::<usize>()
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.basic_box(*b"mdat")?;
+        for ch in &self.data_chunks {
+            b.push(ch)?;
+        }
+        if let Some(exif) = &self.exif {
+            exif.write(&mut b)?;
+        }
+        Ok(())
+    }
+}
+
+const UNITY_MATRIX: [u32; 9] = [0x00010000, 0, 0, 0, 0x00010000, 0, 0, 0, 0x40000000];
+
+#[derive(Debug, Clone)]
+pub struct MoovBox {
+    pub mvhd: MvhdBox,
+    pub tracks: Vec<TrakBox>,
+    pub mvex: Option<MvexBox>,
+    pub udta: Option<UdtaBox>,
+}
+
+impl MpegBox for MoovBox {
+    #[inline]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE
+            + self.mvhd.len()
+            + self.tracks.iter().map(|b| b.len()).sum::<usize>()
+            + match &self.mvex {
+                Some(mvex) => mvex.len(),
+                _ => 0,
+            }
+            + match &self.udta {
+                Some(udta) => udta.len(),
+                _ => 0,
+            }
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.basic_box(*b"moov")?;
+        self.mvhd.write(&mut b)?;
+        for track in &self.tracks {
+            track.write(&mut b)?;
+        }
+        if let Some(mvex) = &self.mvex {
+            mvex.write(&mut b)?;
+        }
+        if let Some(udta) = &self.udta {
+            udta.write(&mut b)?;
+        }
+        Ok(())
+    }
+}
+
+/// User Data box
+#[derive(Debug, Clone)]
+pub struct UdtaBox {
+    pub children: Vec<BlobBox>,
+}
+
+impl MpegBox for UdtaBox {
+    #[inline]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + self.children.iter().map(|b| b.len()).sum::<usize>()
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.basic_box(*b"udta")?;
+        for child in &self.children {
+            child.write(&mut b)?;
         }
         Ok(())
     }
 }
 
+/// Box with opaque contents, e.g. `free` or `uuid`
 #[derive(Debug, Clone)]
-pub struct MdatBox<'data> {
-    pub data_chunks: ArrayVec<&'data [u8], 4>,
-    pub exif: Option<ExifBox>,
+pub struct BlobBox {
+    pub typ: FourCC,
+    /// Extended type, only for `uuid` boxes
+    pub uuid: Option<[u8; 16]>,
+    pub data: Vec<u8>,
 }
 
-impl MpegBox for MdatBox<'_> {
-    #[inline(always)]
+impl MpegBox for BlobBox {
+    #[inline]
     fn len(&self) -> usize {
-        BASIC_BOX_SIZE  + match &self.exif {
-            Some(_exif) => _exif.len(),
-            _ => 0
-        } + self.data_chunks.iter().map(|c| c.len()).sum::<usize>()
+        BASIC_BOX_SIZE
+            + if self.uuid.is_some() { 16 } else { 0 }
+            + self.data.len()
     }
 
     fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
         let mut b = w.new_box(self.len());
-        b.basic_box(*b"mdat")?;
-        for ch in &self.data_chunks {
-            b.push(ch)?;
+        b.basic_box(self.typ.0)?;
+        if let Some(uuid) = &self.uuid {
+            b.push(uuid)?;
         }
-        let _ = match &self.exif {
-            Some(exif) => exif.write(&mut b),
-            _ => Ok(())
-        };
-        Ok(())
+        b.push(&self.data)
     }
 }
 
-const UNITY_MATRIX: [u32; 9] = [0x00010000, 0, 0, 0, 0x00010000, 0, 0, 0, 0x40000000];
-
+/// Movie Extends box, announces that samples are in movie fragments
 #[derive(Debug, Clone)]
-pub struct MoovBox {
-    pub mvhd: MvhdBox,
-    pub tracks: Vec<TrakBox>,
+pub struct MvexBox {
+    pub trex: Vec<TrexBox>,
 }
 
-impl MpegBox for MoovBox {
+impl MpegBox for MvexBox {
     #[inline]
     fn len(&self) -> usize {
-        BASIC_BOX_SIZE
-            + self.mvhd.len()
-            + self.tracks.iter().map(|b| b.len()).sum::<usize>()
+        BASIC_BOX_SIZE + self.trex.iter().map(|b| b.len()).sum::<usize>()
     }
 
     fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
         let mut b = w.new_box(self.len());
-        b.basic_box(*b"moov")?;
-        self.mvhd.write(&mut b)?;
-        for track in &self.tracks {
-            track.write(&mut b)?;
+        b.basic_box(*b"mvex")?;
+        for trex in &self.trex {
+            trex.write(&mut b)?;
         }
         Ok(())
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct TrexBox {
+    pub track_id: u32,
+}
+
+impl MpegBox for TrexBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        FULL_BOX_SIZE + 20
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.full_box(*b"trex", 0, 0)?;
+        b.u32(self.track_id)?;
+        b.u32(1)?; // default_sample_description_index
+        b.u32(0)?; // default_sample_duration
+        b.u32(0)?; // default_sample_size
+        b.u32(0) // default_sample_flags
+    }
+}
+
+/// Version 1 of `mvhd`, `tkhd` and `mdhd` has 64-bit times, version 0 has 32-bit ones
+pub fn time_box_version(times: &[u64]) -> u8 {
+    // u64::MAX is a special value for indefinite duration, and it maps to u32::MAX
+    if times.iter().all(|&t| t <= u32::MAX.into() || t == u64::MAX) { 0 } else { 1 }
+}
+
+fn write_time<B: WriterBackend>(b: &mut Writer<B>, version: u8, time: u64) -> Result<(), B::Error> {
+    if version == 1 {
+        b.u64(time)
+    } else {
+        b.u32(u32::try_from(time).unwrap_or(u32::MAX))
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct MvhdBox {
+    pub version: u8,
     pub creation_time: u64,
     pub modification_time: u64,
     pub timescale: u32,
@@ -724,16 +1640,16 @@ pub struct MvhdBox {
 impl MpegBox for MvhdBox {
     #[inline(always)]
     fn len(&self) -> usize {
-        FULL_BOX_SIZE + 108
+        FULL_BOX_SIZE + if self.version == 1 { 108 } else { 96 }
     }
 
     fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
         let mut b = w.new_box(self.len());
-        b.full_box(*b"mvhd", 1, 0)?;
-        b.u64(self.creation_time)?;
-        b.u64(self.modification_time)?;
+        b.full_box(*b"mvhd", self.version, 0)?;
+        write_time(&mut b, self.version, self.creation_time)?;
+        write_time(&mut b, self.version, self.modification_time)?;
         b.u32(self.timescale)?;
-        b.u64(self.duration)?;
+        write_time(&mut b, self.version, self.duration)?;
         b.u32(0x00010000)?; // rate
         b.u16(0x0100)?; // volume
         b.u16(0)?; // reserved
@@ -756,9 +1672,10 @@ impl MpegBox for MvhdBox {
 pub struct TrakBox {
     pub tkhd: TkhdBox,
     pub tref: Option<TrefBox>,
-    // pub edts: EdtsBox,
+    pub edts: Option<EdtsBox>,
     pub meta: Option<MetaBox>,
     pub mdia: MdiaBox,
+    pub custom: Vec<ExternalBox>,
 }
 
 impl MpegBox for TrakBox {
@@ -770,12 +1687,16 @@ impl MpegBox for TrakBox {
                 Some(tref) => tref.len(),
                 _ => 0,
             }
+            + match &self.edts {
+                Some(edts) => edts.len(),
+                _ => 0,
+            }
             + match &self.meta {
                 Some(meta) => meta.len(),
                 _ => 0,
             }
-            // + self.edts.len()
             + self.mdia.len()
+            + self.custom.iter().map(|b| b.len()).sum::<usize>()
     }
 
     fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
@@ -786,17 +1707,24 @@ impl MpegBox for TrakBox {
             Some(tref) => tref.write(&mut b)?,
             _ => (),
         }
+        if let Some(edts) = &self.edts {
+            edts.write(&mut b)?;
+        }
         match &self.meta {
             Some(meta) => meta.write(&mut b)?,
             _ => (),
         }
-        // self.edts.write(&mut b)?;
-        self.mdia.write(&mut b)
+        self.mdia.write(&mut b)?;
+        for custom in &self.custom {
+            custom.write(&mut b)?;
+        }
+        Ok(())
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct TkhdBox {
+    pub version: u8,
     pub creation_time: u64,
     pub modification_time: u64,
     pub track_id: u32,
@@ -808,17 +1736,17 @@ pub struct TkhdBox {
 impl MpegBox for TkhdBox {
     #[inline(always)]
     fn len(&self) -> usize {
-        FULL_BOX_SIZE + 92
+        FULL_BOX_SIZE + if self.version == 1 { 92 } else { 80 }
     }
 
     fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
         let mut b = w.new_box(self.len());
-        b.full_box(*b"tkhd", 1, 1)?;
-        b.u64(self.creation_time)?;
-        b.u64(self.modification_time)?;
+        b.full_box(*b"tkhd", self.version, 1)?;
+        write_time(&mut b, self.version, self.creation_time)?;
+        write_time(&mut b, self.version, self.modification_time)?;
         b.u32(self.track_id)?;
         b.u32(0)?; // reserved
-        b.u64(self.duration)?;
+        write_time(&mut b, self.version, self.duration)?;
         b.u32(0)?; // reserved
         b.u32(0)?; // reserved
         b.u16(0)?; // layer
@@ -835,38 +1763,108 @@ impl MpegBox for TkhdBox {
 
 #[derive(Debug, Clone)]
 pub struct TrefBox {
-    pub ref_type: ReftypeBox,
+    /// One box per reference type
+    pub ref_types: Vec<ReftypeBox>,
+}
+
+impl TrefBox {
+    /// Adds to the existing box of the same type, if there is one
+    pub fn push(&mut self, typ: FourCC, to_ids: &[u32]) {
+        match self.ref_types.iter_mut().find(|r| r.typ == typ) {
+            Some(r) => r.to_ids.extend_from_slice(to_ids),
+            None => self.ref_types.push(ReftypeBox { typ, to_ids: to_ids.to_vec() }),
+        }
+    }
 }
 
 impl MpegBox for TrefBox {
     #[inline(always)]
     fn len(&self) -> usize {
-        BASIC_BOX_SIZE + self.ref_type.len()
+        BASIC_BOX_SIZE + self.ref_types.iter().map(|r| r.len()).sum::<usize>()
     }
 
     fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
         let mut b = w.new_box(self.len());
         b.basic_box(*b"tref")?;
-        self.ref_type.write(&mut b)
+        for ref_type in &self.ref_types {
+            ref_type.write(&mut b)?;
+        }
+        Ok(())
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct ReftypeBox {
     pub typ: FourCC,
-    pub to_id: u32,
+    pub to_ids: Vec<u32>,
 }
 
 impl MpegBox for ReftypeBox {
     #[inline(always)]
     fn len(&self) -> usize {
-        BASIC_BOX_SIZE + 4
+        BASIC_BOX_SIZE + 4 * self.to_ids.len()
     }
 
     fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
         let mut b = w.new_box(self.len());
         b.basic_box(self.typ.0)?;
-        b.u32(self.to_id)
+        for &id in &self.to_ids {
+            b.u32(id)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct EdtsBox {
+    pub elst: ElstBox,
+}
+
+impl MpegBox for EdtsBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + self.elst.len()
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.basic_box(*b"edts")?;
+        self.elst.write(&mut b)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct ElstEntry {
+    /// In movie timescale
+    pub segment_duration: u64,
+    /// In media timescale
+    pub media_time: i64,
+    /// 0 means dwell on the frame at `media_time`
+    pub media_rate: u16,
+}
+
+#[derive(Debug, Clone)]
+pub struct ElstBox {
+    pub entries: ArrayVec<ElstEntry, 2>,
+}
+
+impl MpegBox for ElstBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        FULL_BOX_SIZE + 4 + self.entries.len() * (8 + 8 + 2 + 2)
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.full_box(*b"elst", 1, 0)?;
+        b.u32(self.entries.len() as u32)?; // entry_count
+        for e in &self.entries {
+            b.u64(e.segment_duration)?;
+            b.u64(e.media_time as u64)?;
+            b.u16(e.media_rate)?; // media_rate_integer
+            b.u16(0)?; // media_rate_fraction
+        }
+        Ok(())
     }
 }
 
@@ -897,6 +1895,7 @@ impl MpegBox for MdiaBox {
 
 #[derive(Debug, Clone)]
 pub struct MdhdBox {
+    pub version: u8,
     pub creation_time: u64,
     pub modification_time: u64,
     pub timescale: u32,
@@ -906,16 +1905,16 @@ pub struct MdhdBox {
 impl MpegBox for MdhdBox {
     #[inline(always)]
     fn len(&self) -> usize {
-        FULL_BOX_SIZE + 32
+        FULL_BOX_SIZE + if self.version == 1 { 32 } else { 20 }
     }
 
     fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
         let mut b = w.new_box(self.len());
-        b.full_box(*b"mdhd", 1, 0)?;
-        b.u64(self.creation_time)?;
-        b.u64(self.modification_time)?;
+        b.full_box(*b"mdhd", self.version, 0)?;
+        write_time(&mut b, self.version, self.creation_time)?;
+        write_time(&mut b, self.version, self.modification_time)?;
         b.u32(self.timescale)?;
-        b.u64(self.duration)?;
+        write_time(&mut b, self.version, self.duration)?;
         b.u16(21956)?; // 1 bit padding (0) + 15 bit language ("und")
         b.u16(0) // pre_defined
     }
@@ -923,8 +1922,8 @@ impl MpegBox for MdhdBox {
 
 #[derive(Debug, Clone)]
 pub struct MinfBox {
-    // pub nmhd: NmhdBox,
-    pub vmhd: VmhdBox,
+    /// Tracks that aren't video have `nmhd` instead
+    pub vmhd: Option<VmhdBox>,
     pub dinf: DinfBox,
     pub stbl: StblBox,
 }
@@ -933,7 +1932,7 @@ impl MpegBox for MinfBox {
     #[inline]
     fn len(&self) -> usize {
         BASIC_BOX_SIZE
-            + self.vmhd.len()
+            + self.vmhd.as_ref().map_or(FULL_BOX_SIZE, |v| v.len())
             + self.dinf.len()
             + self.stbl.len()
     }
@@ -941,7 +1940,10 @@ impl MpegBox for MinfBox {
     fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
         let mut b = w.new_box(self.len());
         b.basic_box(*b"minf")?;
-        self.vmhd.write(&mut b)?;
+        match &self.vmhd {
+            Some(vmhd) => vmhd.write(&mut b)?,
+            None => b.new_box(FULL_BOX_SIZE).full_box(*b"nmhd", 0, 0)?,
+        }
         self.dinf.write(&mut b)?;
         self.stbl.write(&mut b)
     }
@@ -1061,7 +2063,55 @@ impl MpegBox for StblBox {
 
 #[derive(Debug, Clone)]
 pub struct StsdBox {
-    pub entry: SampleEntryBox
+    pub entry: SampleEntry,
+}
+
+#[derive(Debug, Clone)]
+pub enum SampleEntry {
+    Visual(SampleEntryBox),
+    Metadata(MettBox),
+}
+
+impl MpegBox for SampleEntry {
+    #[inline]
+    fn len(&self) -> usize {
+        match self {
+            Self::Visual(e) => e.len(),
+            Self::Metadata(e) => e.len(),
+        }
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        match self {
+            Self::Visual(e) => e.write(w),
+            Self::Metadata(e) => e.write(w),
+        }
+    }
+}
+
+/// Text metadata sample entry, for samples of any MIME type
+#[derive(Debug, Clone)]
+pub struct MettBox {
+    pub mime_format: String,
+}
+
+impl MpegBox for MettBox {
+    #[inline]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + 8
+            + 1 // content_encoding
+            + self.mime_format.len() + 1
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.basic_box(*b"mett")?;
+        b.push(&[0; 6])?; // reserved
+        b.u16(1)?; // data_reference_index
+        b.u8(0)?; // content_encoding, empty means none
+        b.push(self.mime_format.as_bytes())?;
+        b.u8(0)
+    }
 }
 
 impl MpegBox for StsdBox {
@@ -1086,16 +2136,22 @@ pub struct SampleEntryBox {
     pub width: u16,
     pub height: u16,
     pub config: Av1CBox,
+    /// Written in the `av1C`, see [`Av1CObusBox`]
+    pub config_obus: Vec<u8>,
     pub ccst: CcstBox,
     pub auxi: Option<AuxiBox>,
     pub colr: Option<ColrBox>,
+    pub dovi: Option<DoviBox>,
+    pub pasp: Option<PaspBox>,
+    pub spherical: Option<(St3dBox, Sv3dBox)>,
+    pub custom: Vec<ExternalBox>,
 }
 
 impl MpegBox for SampleEntryBox {
     #[inline(always)]
     fn len(&self) -> usize {
         BASIC_BOX_SIZE + 78
-        + self.config.len()
+        + self.config.len() + self.config_obus.len()
         + self.ccst.len()
         + match &self.auxi {
             Some(auxi) => auxi.len(),
@@ -1105,6 +2161,10 @@ impl MpegBox for SampleEntryBox {
             Some(colr) => colr.len(),
             _ => 0,
         }
+        + self.dovi.map_or(0, |dovi| dovi.len())
+        + self.pasp.map_or(0, |pasp| pasp.len())
+        + self.spherical.as_ref().map_or(0, |(st3d, sv3d)| st3d.len() + sv3d.len())
+        + self.custom.iter().map(|b| b.len()).sum::<usize>()
     }
 
     fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
@@ -1131,16 +2191,29 @@ impl MpegBox for SampleEntryBox {
         b.push(&[3,65,79,77,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0])?; // compressorname
         b.u16(0x0018)?; // depth
         b.u16(0xffff)?; // pre_defined
-        self.config.write(&mut b)?;
+        self.config.write_with_obus(&mut b, &self.config_obus)?;
+        if let Some(dovi) = &self.dovi {
+            dovi.write(&mut b)?;
+        }
         match &self.colr {
             Some(colr) => colr.write(&mut b)?,
             _ => (),
         }
+        if let Some(pasp) = &self.pasp {
+            pasp.write(&mut b)?;
+        }
         self.ccst.write(&mut b)?;
         match &self.auxi {
             Some(auxi) => auxi.write(&mut b)?,
             _ => (),
         }
+        if let Some((st3d, sv3d)) = &self.spherical {
+            st3d.write(&mut b)?;
+            sv3d.write(&mut b)?;
+        }
+        for custom in &self.custom {
+            custom.write(&mut b)?;
+        }
         Ok(())
     }
 }
@@ -1166,6 +2239,83 @@ impl MpegBox for CcstBox {
     }
 }
 
+/// Stereoscopic 3D video box of Spherical Video V2
+#[derive(Debug, Copy, Clone)]
+pub struct St3dBox {
+    /// 0 = mono, 1 = top-bottom, 2 = left-right
+    pub stereo_mode: u8,
+}
+
+impl MpegBox for St3dBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        FULL_BOX_SIZE + 1
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.full_box(*b"st3d", 0, 0)?;
+        b.u8(self.stereo_mode)
+    }
+}
+
+/// Spherical video box, with a projection header of no rotation, and a projection without cropping or padding
+#[derive(Debug, Copy, Clone)]
+pub struct Sv3dBox {
+    /// `equi` or `cbmp`
+    pub projection: FourCC,
+}
+
+impl Sv3dBox {
+    const METADATA_SOURCE: &'static str = "avifser";
+
+    fn svhd_len() -> usize {
+        FULL_BOX_SIZE + Self::METADATA_SOURCE.len() + 1
+    }
+
+    fn proj_len(&self) -> usize {
+        BASIC_BOX_SIZE
+            + FULL_BOX_SIZE + 12 // prhd
+            + FULL_BOX_SIZE + if self.projection.0 == *b"equi" { 16 } else { 8 }
+    }
+}
+
+impl MpegBox for Sv3dBox {
+    #[inline]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + Self::svhd_len() + self.proj_len()
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.basic_box(*b"sv3d")?;
+        let mut svhd = b.new_box(Self::svhd_len());
+        svhd.full_box(*b"svhd", 0, 0)?;
+        svhd.push(Self::METADATA_SOURCE.as_bytes())?;
+        svhd.u8(0)?;
+        drop(svhd);
+
+        let mut proj = b.new_box(self.proj_len());
+        proj.basic_box(*b"proj")?;
+        let mut prhd = proj.new_box(FULL_BOX_SIZE + 12);
+        prhd.full_box(*b"prhd", 0, 0)?;
+        prhd.u32(0)?; // yaw
+        prhd.u32(0)?; // pitch
+        prhd.u32(0)?; // roll
+        drop(prhd);
+        if self.projection.0 == *b"equi" {
+            let mut equi = proj.new_box(FULL_BOX_SIZE + 16);
+            equi.full_box(*b"equi", 0, 0)?;
+            equi.push(&[0; 16]) // bounds: top, bottom, left, right
+        } else {
+            let mut cbmp = proj.new_box(FULL_BOX_SIZE + 8);
+            cbmp.full_box(*b"cbmp", 0, 0)?;
+            cbmp.u32(0)?; // layout
+            cbmp.u32(0) // padding
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct AuxiBox {
     pub aux_track_type: &'static str,
@@ -1209,21 +2359,25 @@ impl MpegBox for SttsBox {
 
 #[derive(Debug, Clone)]
 pub struct StscBox {
-    pub samples_per_chunk: u32,
+    /// All samples are in one chunk. `None` when samples are in movie fragments.
+    pub samples_per_chunk: Option<u32>,
 }
 
 impl MpegBox for StscBox {
     #[inline(always)]
     fn len(&self) -> usize {
-        FULL_BOX_SIZE + 16
+        FULL_BOX_SIZE + 4 + if self.samples_per_chunk.is_some() { 12 } else { 0 }
     }
 
     fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
         let mut b = w.new_box(self.len());
         b.full_box(*b"stsc", 0, 0)?;
+        let Some(samples_per_chunk) = self.samples_per_chunk else {
+            return b.u32(0); // entry_count
+        };
         b.u32(1)?; // entry_count
         b.u32(1)?; // first_chunk
-        b.u32(self.samples_per_chunk)?;
+        b.u32(samples_per_chunk)?;
         b.u32(1) // sample_description_index
     }
 }
@@ -1254,20 +2408,30 @@ impl MpegBox for StszBox {
 
 #[derive(Debug, Clone)]
 pub struct StcoBox {
-    pub chunk_offset: u32
+    /// The one chunk. `None` when samples are in movie fragments.
+    pub chunk_offset: Option<IlocOffset>,
 }
 
 impl MpegBox for StcoBox {
     #[inline(always)]
     fn len(&self) -> usize {
-        FULL_BOX_SIZE + 8
+        FULL_BOX_SIZE + 4 + if self.chunk_offset.is_some() { 4 } else { 0 }
     }
 
     fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
         let mut b = w.new_box(self.len());
         b.full_box(*b"stco", 0, 0)?;
+        let Some(chunk_offset) = self.chunk_offset else {
+            return b.u32(0); // entry_count
+        };
         b.u32(1)?; // entry_count
-        b.u32(self.chunk_offset) // chunk_offset
+        b.u32(match chunk_offset {
+            IlocOffset::Absolute(val) => val,
+            IlocOffset::Relative(_) => {
+                debug_assert!(false, "absolute offset must be set");
+                0
+            },
+        }) // chunk_offset
     }
 }
 
@@ -1306,10 +2470,158 @@ impl MpegBox for ExifBox {
         4 + self.payload.len()
     }
 
+    /// Not a real box, just the item's payload, so it's written directly into the parent
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        w.u32(self.header_offset)?;
+        w.push(&self.payload)
+    }
+}
+/// Segment Type box, the `ftyp` of a media segment
+#[derive(Debug, Clone)]
+pub struct StypBox {
+    pub major_brand: FourCC,
+    pub compatible_brands: Vec<FourCC>,
+}
+
+impl MpegBox for StypBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE
+        + 4 // brand
+        + 4 // ver
+        + 4 * self.compatible_brands.len()
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.basic_box(*b"styp")?;
+        b.push(&self.major_brand.0)?;
+        b.u32(0)?; // minor_version
+        for cb in &self.compatible_brands {
+            b.push(&cb.0)?;
+        }
+        Ok(())
+    }
+}
+
+/// Segment Index box. Indexes one subsegment (the `moof` + `mdat` that follow it).
+#[derive(Debug, Clone)]
+pub struct SidxBox {
+    pub reference_id: u32,
+    pub timescale: u32,
+    pub earliest_presentation_time: u64,
+    pub referenced_size: u32,
+    pub subsegment_duration: u32,
+    pub starts_with_sap: bool,
+}
+
+impl MpegBox for SidxBox {
+    #[inline(always)]
+    fn len(&self) -> usize {
+        FULL_BOX_SIZE + 4 + 4 + 8 + 8 + 2 + 2 + 12
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.full_box(*b"sidx", 1, 0)?;
+        b.u32(self.reference_id)?;
+        b.u32(self.timescale)?;
+        b.u64(self.earliest_presentation_time)?;
+        b.u64(0)?; // first_offset, moof follows immediately
+        b.u16(0)?; // reserved
+        b.u16(1)?; // reference_count
+        b.u32(self.referenced_size & 0x7fffffff)?; // reference_type 0 = media
+        b.u32(self.subsegment_duration)?;
+        b.u32(if self.starts_with_sap { 1 << 31 | 1 << 28 } else { 0 }) // SAP type 1
+    }
+}
+
+/// Movie Fragment box
+#[derive(Debug, Clone)]
+pub struct MoofBox {
+    pub sequence_number: u32,
+    pub trafs: ArrayVec<TrafBox, 2>,
+}
+
+impl MpegBox for MoofBox {
+    #[inline]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE
+            + FULL_BOX_SIZE + 4 // mfhd
+            + self.trafs.iter().map(|b| b.len()).sum::<usize>()
+    }
+
+    fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
+        let mut b = w.new_box(self.len());
+        b.basic_box(*b"moof")?;
+        {
+            let mut mfhd = b.new_box(FULL_BOX_SIZE + 4);
+            mfhd.full_box(*b"mfhd", 0, 0)?;
+            mfhd.u32(self.sequence_number)?;
+        }
+        for traf in &self.trafs {
+            traf.write(&mut b)?;
+        }
+        Ok(())
+    }
+}
+
+/// Track Fragment box, with `tfhd`, `tfdt` and `trun` inside
+#[derive(Debug, Clone)]
+pub struct TrafBox {
+    pub track_id: u32,
+    pub base_media_decode_time: u64,
+    /// From the start of the `moof`
+    pub data_offset: u32,
+    pub samples: Vec<TrunSample>,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct TrunSample {
+    pub duration: u32,
+    pub size: u32,
+    pub sync: bool,
+}
+
+impl TrafBox {
+    const TFHD_SIZE: usize = FULL_BOX_SIZE + 4;
+    const TFDT_SIZE: usize = FULL_BOX_SIZE + 8;
+
+    fn trun_len(&self) -> usize {
+        FULL_BOX_SIZE + 4 + 4 + self.samples.len() * (4 + 4 + 4)
+    }
+}
+
+impl MpegBox for TrafBox {
+    #[inline]
+    fn len(&self) -> usize {
+        BASIC_BOX_SIZE + Self::TFHD_SIZE + Self::TFDT_SIZE + self.trun_len()
+    }
+
     fn write<B: WriterBackend>(&self, w: &mut Writer<B>) -> Result<(), B::Error> {
         let mut b = w.new_box(self.len());
-        b.u32(self.header_offset)?;
-        b.push(&self.payload)?;
+        b.basic_box(*b"traf")?;
+        {
+            let mut tfhd = b.new_box(Self::TFHD_SIZE);
+            tfhd.full_box(*b"tfhd", 0, 0x020000)?; // default-base-is-moof
+            tfhd.u32(self.track_id)?;
+        }
+        {
+            let mut tfdt = b.new_box(Self::TFDT_SIZE);
+            tfdt.full_box(*b"tfdt", 1, 0)?;
+            tfdt.u64(self.base_media_decode_time)?;
+        }
+        let mut trun = b.new_box(self.trun_len());
+        // data offset, sample duration, size and flags present
+        trun.full_box(*b"trun", 0, 0x000001 | 0x000100 | 0x000200 | 0x000400)?;
+        trun.u32(self.samples.len() as u32)?; // sample_count
+        trun.u32(self.data_offset)?;
+        for s in &self.samples {
+            trun.u32(s.duration)?;
+            trun.u32(s.size)?;
+            // sample_depends_on and sample_is_non_sync_sample
+            trun.u32(if s.sync { 0x02000000 } else { 0x01010000 })?;
+        }
         Ok(())
     }
-}
\ No newline at end of file
+}
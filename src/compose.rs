@@ -0,0 +1,487 @@
+//! Building a still image item by item. See [`Aviffy::compose`].
+
+use crate::boxes::*;
+use crate::region::{region_item, Region};
+use crate::{monochrome_av1c, Aviffy, ColorInfo, CustomBox, UserDescription};
+use std::borrow::Cow;
+use std::io;
+use std::sync::Arc;
+
+/// An item added to a [`Composition`] or its [`ItemGraph`], for making references to it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct ItemId(pub(crate) ItemRef);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum ItemRef {
+    Color,
+    Alpha,
+    Exif,
+    /// Index in [`ItemGraph::items`]
+    Extra(usize),
+}
+
+/// Kind of data added with [`Composition::add_metadata`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MetadataKind {
+    /// Exif in TIFF format (starting with `II` or `MM`), stored in an `Exif` item
+    Exif,
+    /// XMP packet, stored as a `mime` item of `application/rdf+xml` type
+    Xmp,
+    /// IPTC-IIM records, stored as a `mime` item of `application/x-iptc` type
+    Iptc,
+    /// C2PA manifest store in JUMBF format, stored as a `mime` item of `application/c2pa` type
+    C2pa,
+    /// Any other data, stored as a `mime` item with the given content type
+    Mime(String),
+}
+
+/// Pixel layout of [`Composition::add_uncompressed`] images, 8 bits per channel, channels of each pixel next to each other
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum UncompressedFormat {
+    Gray8,
+    Rgb8,
+    Rgba8,
+}
+
+impl UncompressedFormat {
+    /// `uncC` profile and `cmpd` component types
+    fn layout(self) -> ([u8; 4], &'static [u16]) {
+        match self {
+            Self::Gray8 => ([0; 4], &[0]),
+            Self::Rgb8 => (*b"rgb3", &[4, 5, 6]),
+            Self::Rgba8 => (*b"rgba", &[4, 5, 6, 7]),
+        }
+    }
+}
+
+/// One resolution of the image in [`Composition::add_pyramid`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PyramidLayer {
+    /// An image added to the composition
+    pub item: ItemId,
+    /// How many times smaller than the highest resolution it is (1 for the highest)
+    pub binning: u16,
+    /// Number of tiles across and down, if the image is a grid of the pyramid's tiles, or 1
+    pub columns: u16,
+    pub rows: u16,
+}
+
+/// There's no registered MIME type for IPTC-IIM
+pub(crate) const IPTC_CONTENT_TYPE: &str = "application/x-iptc";
+pub(crate) const C2PA_CONTENT_TYPE: &str = "application/c2pa";
+pub(crate) const XMP_CONTENT_TYPE: &str = "application/rdf+xml";
+/// `auxC` type of depth maps in AVIF
+const DEPTH_URN: &str = "urn:mpeg:mpegB:cicp:systems:auxiliary:depth";
+
+/// Item other than the built-in color, alpha and Exif
+#[derive(Debug, Clone)]
+pub(crate) struct GraphItem<'data> {
+    pub typ: [u8; 4],
+    pub name: String,
+    /// Only for `mime` items
+    pub content_type: Option<String>,
+    /// Only for `mime` items, when the data is compressed
+    pub content_encoding: Option<&'static str>,
+    pub data: Cow<'data, [u8]>,
+    /// Metadata is kept together with Exif in the `mdat`, images are placed after the color image
+    pub is_metadata: bool,
+}
+
+/// Items, references and properties that the rest of the file is built around.
+///
+/// This is for constructs that [`Composition`] doesn't have a method for. Item ids, `iloc` offsets
+/// and `ipma` indices are assigned when the file is written, and identical properties are shared.
+///
+/// It's up to you to make the items and their properties valid. Get it from [`Composition::graph`].
+#[derive(Debug, Clone)]
+pub struct ItemGraph<'data> {
+    pub(crate) items: Vec<GraphItem<'data>>,
+    /// From, type, to
+    pub(crate) references: Vec<(ItemRef, [u8; 4], ItemRef)>,
+    /// Item, property, essential flag
+    pub(crate) properties: Vec<(ItemRef, IpcoProp, bool)>,
+    /// Item written in `pitm`
+    pub(crate) primary: ItemRef,
+    /// Entity groups: type, entities, fields after the entity ids
+    pub(crate) groups: Vec<([u8; 4], Vec<ItemRef>, Vec<u8>)>,
+    /// Type of the color and alpha items when they're derived from other items (e.g. `grid`), rather than AV1
+    pub(crate) derived: Option<[u8; 4]>,
+}
+
+/// For files made without a [`Composition`]
+pub(crate) static NO_ITEMS: ItemGraph<'static> = ItemGraph { items: Vec::new(), references: Vec::new(), properties: Vec::new(), primary: ItemRef::Color, groups: Vec::new(), derived: None };
+
+impl<'data> ItemGraph<'data> {
+    /// Adds an item of type `typ` (e.g. `av01`) with `data` as its payload, stored together with the images.
+    ///
+    /// The `name` is informative only, and can be empty.
+    pub fn add_item(&mut self, typ: [u8; 4], name: &str, data: impl Into<Cow<'data, [u8]>>) -> ItemId {
+        self.push(GraphItem { typ, name: name.into(), content_type: None, content_encoding: None, data: data.into(), is_metadata: false })
+    }
+
+    /// Adds a metadata item, stored together with Exif (see [`MdatLayout::MetadataFirst`](crate::MdatLayout::MetadataFirst)).
+    ///
+    /// `content_type` is required for `mime` items, and must be `None` for all other types.
+    pub fn add_metadata_item(&mut self, typ: [u8; 4], name: &str, content_type: Option<&str>, data: impl Into<Cow<'data, [u8]>>) -> ItemId {
+        self.push(GraphItem { typ, name: name.into(), content_type: content_type.map(From::from), content_encoding: None, data: data.into(), is_metadata: true })
+    }
+
+    fn push(&mut self, item: GraphItem<'data>) -> ItemId {
+        self.items.push(item);
+        ItemId(ItemRef::Extra(self.items.len() - 1))
+    }
+
+    /// Adds an `iref` reference of type `typ` (e.g. `cdsc`, `auxl`, `thmb`, `dimg`) from one item to another.
+    ///
+    /// References are written in the order they were added, which matters for `dimg`.
+    pub fn add_reference(&mut self, from: ItemId, typ: [u8; 4], to: ItemId) -> &mut Self {
+        self.references.push((from.0, typ, to.0));
+        self
+    }
+
+    /// Adds an entity group of type `typ` (e.g. `altr`, `ster`) to `grpl`, with the items in the given order.
+    ///
+    /// `data` is written after the entity ids, for group types that have more fields. It's empty for `altr` and `ster`.
+    pub fn add_entity_group(&mut self, typ: [u8; 4], entities: &[ItemId], data: &[u8]) -> &mut Self {
+        self.groups.push((typ, entities.iter().map(|e| e.0).collect(), data.to_vec()));
+        self
+    }
+
+    /// Associates a property with the item. The box is written to `ipco` as-is.
+    ///
+    /// Decoders that don't understand an `essential` property won't show the item.
+    pub fn add_property(&mut self, item: ItemId, property: impl CustomBox + Send + Sync + 'static, essential: bool) -> &mut Self {
+        self.add_ipco_prop(item, IpcoProp::Custom(ExternalBox(Arc::new(property))), essential)
+    }
+
+    pub(crate) fn add_ipco_prop(&mut self, item: ItemId, property: IpcoProp, essential: bool) -> &mut Self {
+        self.properties.push((item.0, property, essential));
+        self
+    }
+}
+
+/// Still image assembled from individual items, with automatic `iloc`/`ipma`/`iref` bookkeeping.
+///
+/// Created with [`Aviffy::compose`], and uses its settings. Write it with [`finish`](Self::finish).
+#[derive(Clone)]
+pub struct Composition<'data> {
+    aviffy: &'data Aviffy,
+    color: Option<(Cow<'data, [u8]>, u32, u32, u8)>,
+    alpha: Option<Cow<'data, [u8]>>,
+    exif: Option<Cow<'data, [u8]>>,
+    /// They need the depth of the color image
+    aux_items: Vec<ItemId>,
+    images: Vec<ItemId>,
+    /// Invalid input found while adding items, reported by `finish`
+    error: Option<&'static str>,
+    graph: ItemGraph<'data>,
+}
+
+impl<'data> Composition<'data> {
+    pub(crate) fn new(aviffy: &'data Aviffy) -> Self {
+        Self { aviffy, color: None, alpha: None, exif: None, aux_items: Vec::new(), images: Vec::new(), error: None, graph: NO_ITEMS.clone() }
+    }
+
+    /// The primary image. See [`Aviffy::write`] for the requirements of the AV1 data.
+    ///
+    /// There can be only one color image. Adding it again replaces the previous one.
+    pub fn add_color(&mut self, av1_data: impl Into<Cow<'data, [u8]>>, width: u32, height: u32, depth_bits: u8) -> ItemId {
+        self.color = Some((av1_data.into(), width, height, depth_bits));
+        self.graph.derived = None;
+        ItemId(ItemRef::Color)
+    }
+
+    /// Makes the color image an overlay (`iovl`): a `width`×`height` canvas filled with `fill` (RGBA),
+    /// with `layers` drawn over it in order, each at its horizontal and vertical offset from the top left corner.
+    ///
+    /// Layers are images added with [`add_image`](Self::add_image). The overlay can't have alpha, but the layers can.
+    /// It replaces the color image, and adding it again replaces the previous overlay.
+    pub fn add_overlay(&mut self, width: u32, height: u32, depth_bits: u8, fill: [u16; 4], layers: &[(ItemId, i32, i32)]) -> ItemId {
+        let large = width > 0xFFFF || height > 0xFFFF
+            || layers.iter().any(|&(_, x, y)| i16::try_from(x).is_err() || i16::try_from(y).is_err());
+        let mut payload = vec![0, u8::from(large)]; // version, flags
+        for value in fill {
+            payload.extend_from_slice(&value.to_be_bytes());
+        }
+        let sizes = [width as i64, height as i64].into_iter().chain(layers.iter().flat_map(|&(_, x, y)| [x.into(), y.into()]));
+        for value in sizes {
+            if large {
+                payload.extend_from_slice(&(value as u32).to_be_bytes());
+            } else {
+                payload.extend_from_slice(&(value as u16).to_be_bytes());
+            }
+        }
+        self.color = Some((payload.into(), width, height, depth_bits));
+        self.graph.derived = Some(*b"iovl");
+        self.graph.references.retain(|&(from, typ, _)| !(from == ItemRef::Color && typ == *b"dimg"));
+        for &(layer, ..) in layers {
+            self.graph.add_reference(ItemId(ItemRef::Color), *b"dimg", layer);
+        }
+        ItemId(ItemRef::Color)
+    }
+
+    /// Small preview of the color image (`thmb`), e.g. for galleries.
+    ///
+    /// It has the same bit depth and color config as the color image.
+    pub fn add_thumbnail(&mut self, av1_data: impl Into<Cow<'data, [u8]>>, width: u32, height: u32) -> ItemId {
+        let id = self.graph.add_item(*b"av01", "Thumbnail", av1_data);
+        self.graph
+            .add_reference(id, *b"thmb", ItemId(ItemRef::Color))
+            .add_ipco_prop(id, IpcoProp::Ispe(IspeBox { width, height }), false);
+        self.images.push(id);
+        id
+    }
+
+    /// Color image that isn't shown on its own, e.g. a layer of an [overlay](Self::add_overlay).
+    ///
+    /// It has the same bit depth and color config as the color image, but can have a different size.
+    pub fn add_image(&mut self, av1_data: impl Into<Cow<'data, [u8]>>, width: u32, height: u32) -> ItemId {
+        let id = self.graph.add_item(*b"av01", "Image", av1_data);
+        self.graph.add_ipco_prop(id, IpcoProp::Ispe(IspeBox { width, height }), false);
+        self.images.push(id);
+        id
+    }
+
+    /// Image stored without compression (`unci`, ISO/IEC 23001-17), e.g. an exact copy of the pixels, or a small palette.
+    ///
+    /// Rows of `pixels` are top to bottom, without padding. It isn't shown on its own unless it's made [primary](Self::set_primary),
+    /// or grouped with the color image. Fails when writing if the data isn't `width`×`height` pixels.
+    pub fn add_uncompressed(&mut self, pixels: impl Into<Cow<'data, [u8]>>, width: u32, height: u32, format: UncompressedFormat) -> ItemId {
+        let (profile, component_types) = format.layout();
+        let pixels = pixels.into();
+        if (width as u64 * height as u64).checked_mul(component_types.len() as u64) != Some(pixels.len() as u64) {
+            self.error.get_or_insert("uncompressed image data doesn't match its size");
+        }
+        let id = self.graph.add_item(*b"unci", "Uncompressed", pixels);
+        self.graph
+            .add_ipco_prop(id, IpcoProp::Ispe(IspeBox { width, height }), false)
+            .add_ipco_prop(id, IpcoProp::Pixi(PixiBox { channels: component_types.len() as u8, depth: 8 }), false)
+            .add_ipco_prop(id, IpcoProp::Cmpd(CmpdBox { component_types: component_types.to_vec() }), true)
+            .add_ipco_prop(id, IpcoProp::UncC(UncCBox {
+                profile,
+                component_count: component_types.len() as u16,
+                bit_depth: 8,
+                interleave_type: 1,
+            }), true);
+        id
+    }
+
+    /// HDR version of the color image, in the colorspace of `hdr_color` (e.g. BT.2100 PQ), grouped with it as an alternative (`altr`).
+    ///
+    /// Decoders that support HDR show it, and others show the color image, which should be the SDR version.
+    /// It has the color image's chroma subsampling, but can have a different size and bit depth.
+    pub fn add_hdr_alternate(&mut self, av1_data: impl Into<Cow<'data, [u8]>>, width: u32, height: u32, depth_bits: u8, hdr_color: ColorInfo) -> ItemId {
+        let config = self.aviffy.color_config(depth_bits, self.aviffy.level(width, height, None));
+        let id = self.graph.add_item(*b"av01", "HDR", av1_data);
+        self.graph
+            .add_ipco_prop(id, IpcoProp::Ispe(IspeBox { width, height }), false)
+            .add_ipco_prop(id, IpcoProp::Pixi(PixiBox { channels: if config.monochrome { 1 } else { 3 }, depth: depth_bits }), false)
+            .add_ipco_prop(id, IpcoProp::Av1C(config), true)
+            // Readers pick the first one they can show
+            .add_entity_group(*b"altr", &[id, ItemId(ItemRef::Color)], &[]);
+        self.set_color_info(id, hdr_color);
+        id
+    }
+
+    /// Monochrome alpha channel of the color image. It must have the same size and depth as the color image.
+    ///
+    /// Adding it again replaces the previous one.
+    pub fn add_alpha(&mut self, av1_data: impl Into<Cow<'data, [u8]>>) -> ItemId {
+        self.alpha = Some(av1_data.into());
+        ItemId(ItemRef::Alpha)
+    }
+
+    /// Monochrome auxiliary image of type `urn` (e.g. a depth map), linked to the image `of`.
+    ///
+    /// It has the same bit depth as the color image, but can have a different size.
+    pub fn add_aux(&mut self, av1_data: impl Into<Cow<'data, [u8]>>, width: u32, height: u32, urn: &str, of: ItemId) -> ItemId {
+        let id = self.graph.add_item(*b"av01", "Aux", av1_data);
+        self.graph
+            .add_reference(id, *b"auxl", of)
+            .add_ipco_prop(id, IpcoProp::Ispe(IspeBox { width, height }), false)
+            .add_ipco_prop(id, IpcoProp::AuxC(AuxCBox { urn: urn.to_owned().into() }), false);
+        self.aux_items.push(id);
+        id
+    }
+
+    /// Monochrome depth map of the image `of`, e.g. from a portrait mode camera. The image can have alpha too.
+    ///
+    /// It has the same bit depth as the color image, but can have a different size.
+    pub fn add_depth_map(&mut self, av1_data: impl Into<Cow<'data, [u8]>>, width: u32, height: u32, of: ItemId) -> ItemId {
+        self.add_aux(av1_data, width, height, DEPTH_URN, of)
+    }
+
+    /// Adds an image to the burst (`brst` entity group), which lists images in the order they were added,
+    /// and records when it was captured in an XMP item, as an ISO 8601 date (e.g. `2024-05-01T12:00:00.250Z`).
+    ///
+    /// Add the images with [`add_color`](Self::add_color) and [`add_image`](Self::add_image).
+    /// The color image is the one shown, unless another is chosen with [`set_primary`](Self::set_primary).
+    pub fn add_burst_image(&mut self, item: ItemId, capture_time: &str) -> &mut Self {
+        match self.graph.groups.iter_mut().find(|(typ, ..)| typ == b"brst") {
+            Some((_, entities, _)) => entities.push(item.0),
+            None => self.graph.groups.push((*b"brst", vec![item.0], Vec::new())),
+        }
+        let capture_time = capture_time.replace('&', "&amp;").replace('<', "&lt;").replace('"', "&quot;");
+        let xmp = format!(concat!(
+            r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">"#,
+            r#"<rdf:Description rdf:about="" xmlns:xmp="http://ns.adobe.com/xap/1.0/" xmp:CreateDate="{}"/>"#,
+            r#"</rdf:RDF></x:xmpmeta>"#,
+        ), capture_time);
+        let id = self.graph.add_metadata_item(*b"mime", "XMP", Some(XMP_CONTENT_TYPE), xmp.into_bytes());
+        self.graph.add_reference(id, *b"cdsc", item);
+        self
+    }
+
+    /// Makes the item the primary one (`pitm`), which decoders show. By default it's the color image.
+    ///
+    /// The other items still describe the color image, e.g. alpha and metadata.
+    pub fn set_primary(&mut self, item: ItemId) -> &mut Self {
+        self.graph.primary = item.0;
+        self
+    }
+
+    /// Gives the item a CICP `colr` property, e.g. for an image in a different colorspace than the color image.
+    ///
+    /// For the color image, this replaces the `colr` made from the [`Aviffy`]'s settings. Setting it again replaces the previous one.
+    pub fn set_color_info(&mut self, item: ItemId, color: ColorInfo) -> &mut Self {
+        self.graph.properties.retain(|(i, prop, _)| !(*i == item.0 && matches!(prop, IpcoProp::Colr(_))));
+        self.graph.add_ipco_prop(item, IpcoProp::Colr(color.into()), false);
+        self
+    }
+
+    /// Text alternative of the item for screen readers (`altt`), in the language given as an RFC 5646 tag (e.g. `en`).
+    ///
+    /// Use [`Aviffy::alt_text`] for the color image.
+    pub fn add_alt_text(&mut self, item: ItemId, text: &str, lang: &str) -> &mut Self {
+        self.graph.add_ipco_prop(item, IpcoProp::Altt(AlttBox { alt_text: text.into(), alt_lang: lang.into() }), false);
+        self
+    }
+
+    /// Groups images of the same content at different resolutions into a multi-resolution pyramid (`pymd`),
+    /// so that zoomable viewers can pick the level they need.
+    ///
+    /// `layers` go from the lowest to the highest resolution. Their tiles are `tile_width`×`tile_height` pixels.
+    /// Fails when writing if a layer's binning, columns or rows are 0.
+    pub fn add_pyramid(&mut self, tile_width: u16, tile_height: u16, layers: &[PyramidLayer]) -> &mut Self {
+        let mut payload = Vec::with_capacity(4 + 6 * layers.len());
+        payload.extend_from_slice(&tile_width.to_be_bytes());
+        payload.extend_from_slice(&tile_height.to_be_bytes());
+        for layer in layers {
+            payload.extend_from_slice(&layer.binning.to_be_bytes());
+            payload.extend_from_slice(&layer.rows.wrapping_sub(1).to_be_bytes());
+            payload.extend_from_slice(&layer.columns.wrapping_sub(1).to_be_bytes());
+        }
+        if layers.iter().any(|l| l.binning == 0 || l.columns == 0 || l.rows == 0) {
+            self.error.get_or_insert("pyramid layer with 0 binning, columns or rows");
+        }
+        self.graph.groups.push((*b"pymd", layers.iter().map(|l| l.item.0).collect(), payload));
+        self
+    }
+
+    /// Areas of the image `of`, e.g. faces or objects, in a region item (`rgan`).
+    /// Coordinates are relative to a `reference_width`×`reference_height` image, which decoders scale to the image's size.
+    ///
+    /// Describe what the regions are with [`add_user_description`](Self::add_user_description) on the returned item.
+    /// Fails when writing if there are more than 255 regions.
+    pub fn add_regions(&mut self, of: ItemId, reference_width: u32, reference_height: u32, regions: &[Region]) -> ItemId {
+        let payload = region_item(reference_width, reference_height, regions).unwrap_or_else(|| {
+            self.error.get_or_insert("more than 255 regions in a region item");
+            Vec::new()
+        });
+        let id = self.graph.add_metadata_item(*b"rgan", "Regions", None, payload);
+        self.graph.add_reference(id, *b"cdsc", of);
+        id
+    }
+
+    /// Name, description and tags of the item (`udes`), e.g. a label of a [region item](Self::add_regions).
+    ///
+    /// Use [`Aviffy::user_description`] for the color image. Fails when writing if any of the strings contains a nul character.
+    pub fn add_user_description(&mut self, item: ItemId, description: UserDescription) -> &mut Self {
+        self.graph.add_ipco_prop(item, IpcoProp::Udes(description.into()), false);
+        self
+    }
+
+    /// Metadata describing the color image.
+    ///
+    /// There can be only one Exif item. Adding it again replaces the previous one.
+    pub fn add_metadata(&mut self, kind: MetadataKind, data: impl Into<Cow<'data, [u8]>>) -> ItemId {
+        let id = match kind {
+            MetadataKind::Exif => {
+                self.exif = Some(data.into());
+                return ItemId(ItemRef::Exif);
+            },
+            MetadataKind::Xmp => self.graph.add_metadata_item(*b"mime", "XMP", Some(XMP_CONTENT_TYPE), data),
+            MetadataKind::Iptc => self.graph.add_metadata_item(*b"mime", "IPTC", Some(IPTC_CONTENT_TYPE), data),
+            MetadataKind::C2pa => self.graph.add_metadata_item(*b"mime", "C2PA", Some(C2PA_CONTENT_TYPE), data),
+            MetadataKind::Mime(content_type) => self.graph.add_metadata_item(*b"mime", "Metadata", Some(&content_type), data),
+        };
+        self.graph.add_reference(id, *b"cdsc", ItemId(ItemRef::Color));
+        id
+    }
+
+    /// Lower-level access for adding arbitrary items, references and properties.
+    ///
+    /// Ids from the graph and the composition can refer to each other.
+    pub fn graph(&mut self) -> &mut ItemGraph<'data> {
+        &mut self.graph
+    }
+
+    /// Writes the file. Fails if there's no color image, or something refers to an item that hasn't been added.
+    pub fn finish<W: io::Write>(mut self, mut into_output: W) -> io::Result<()> {
+        let (color, width, height, depth_bits) = match &self.color {
+            Some(c) => c,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "no color image in the composition")),
+        };
+        if let Some(error) = self.error {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, error));
+        }
+        let exists = |item: &ItemRef| match *item {
+            ItemRef::Color => true,
+            ItemRef::Alpha => self.alpha.is_some(),
+            ItemRef::Exif => !self.exif.as_deref().unwrap_or(&self.aviffy.exif).is_empty(),
+            ItemRef::Extra(index) => index < self.graph.items.len(),
+        };
+        if !exists(&self.graph.primary)
+            || !self.graph.references.iter().all(|(from, _, to)| exists(from) && exists(to))
+            || !self.graph.properties.iter().all(|(item, ..)| exists(item))
+            || !self.graph.groups.iter().all(|(_, entities, _)| entities.iter().all(exists)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "reference to a missing item"));
+        }
+
+        if self.graph.derived.is_some() && self.alpha.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "overlay can't have alpha"));
+        }
+
+        let depth_bits = self.aviffy.bit_depth.unwrap_or(*depth_bits);
+        let aviffy = self.aviffy;
+        // The images can have different sizes
+        let level_of = |properties: &[(ItemRef, IpcoProp, bool)], id: ItemId| properties.iter().find_map(|(item, prop, _)| match prop {
+            IpcoProp::Ispe(ispe) if *item == id.0 => Some(aviffy.level(ispe.width, ispe.height, None)),
+            _ => None,
+        }).unwrap_or(31);
+        for id in &self.images {
+            let color_config = aviffy.color_config(depth_bits, level_of(&self.graph.properties, *id));
+            let properties = &mut self.graph.properties;
+            let pos = properties.iter().position(|p| p.0 == id.0).map_or(properties.len(), |p| p + 1);
+            properties.splice(pos..pos, [
+                (id.0, IpcoProp::Pixi(PixiBox { channels: if color_config.monochrome { 1 } else { 3 }, depth: depth_bits }), false),
+                (id.0, IpcoProp::Av1C(color_config), true),
+            ]);
+        }
+        for id in &self.aux_items {
+            let av1c = monochrome_av1c(depth_bits, level_of(&self.graph.properties, *id));
+            // after ispe, to keep the usual order
+            let properties = &mut self.graph.properties;
+            let pos = properties.iter().position(|p| p.0 == id.0).map_or(properties.len(), |p| p + 1);
+            properties.splice(pos..pos, [
+                (id.0, IpcoProp::Pixi(PixiBox { channels: 1, depth: depth_bits }), false),
+                (id.0, IpcoProp::Av1C(av1c), true),
+            ]);
+        }
+
+        self.aviffy.make_boxes(color, self.alpha.as_deref(), *width, *height, depth_bits, 0, None, None, self.exif.as_deref().unwrap_or(&self.aviffy.exif), &self.graph)?
+            .write(&mut into_output)
+    }
+}
@@ -1,8 +1,47 @@
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+/// Conversions from CICP numbers, and names for `Display`/`FromStr` (which also accepts numbers)
+macro_rules! cicp_conversions {
+    ($name:ident { $($variant:ident => $str:literal,)* }) => {
+        impl TryFrom<u16> for $name {
+            type Error = io::Error;
+
+            fn try_from(value: u16) -> io::Result<Self> {
+                $(if value == Self::$variant as u16 { return Ok(Self::$variant); })*
+                Err(io::Error::new(io::ErrorKind::InvalidInput, concat!("unsupported ", stringify!($name), " value")))
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = io::Error;
+
+            fn from_str(s: &str) -> io::Result<Self> {
+                if let Ok(value) = s.parse::<u16>() {
+                    return Self::try_from(value);
+                }
+                $(if s.eq_ignore_ascii_case($str) { return Ok(Self::$variant); })*
+                Err(io::Error::new(io::ErrorKind::InvalidInput, concat!("unknown ", stringify!($name), " name")))
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str(match self {
+                    $(Self::$variant => $str,)*
+                })
+            }
+        }
+    };
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[non_exhaustive]
 pub enum ColorPrimaries {
     /// ITU-R BT1361
     Bt709 = 1,
+    /// Leaves it to the AV1 payload
     Unspecified = 2,
     /// ITU-R BT601-6 525
     Bt601 = 6,
@@ -19,6 +58,7 @@ pub enum ColorPrimaries {
 pub enum TransferCharacteristics {
     /// ITU-R BT1361
     Bt709 = 1,
+    /// Leaves it to the AV1 payload
     Unspecified = 2,
     /// ITU-R BT601-6 525
     Bt601 = 6,
@@ -34,6 +74,10 @@ pub enum TransferCharacteristics {
     Bt2020_10 = 14,
     /// ITU-R BT2020 for 12-bit system
     Bt2020_12 = 15,
+    /// SMPTE ST 2084 (PQ), for HDR
+    Pq = 16,
+    /// ARIB STD-B67 (HLG), for HDR
+    Hlg = 18,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -43,6 +87,7 @@ pub enum MatrixCoefficients {
     Rgb = 0,
     /// ITU-R BT1361
     Bt709 = 1,
+    /// Leaves it to the AV1 payload
     Unspecified = 2,
     /// ITU-R BT601-6 525
     Bt601 = 6,
@@ -52,3 +97,116 @@ pub enum MatrixCoefficients {
     /// ITU-R BT2020 constant luminance system
     Bt2020Cl = 10,
 }
+
+cicp_conversions!(ColorPrimaries {
+    Bt709 => "bt709",
+    Unspecified => "unspecified",
+    Bt601 => "bt601",
+    Bt2020 => "bt2020",
+    DciP3 => "dci-p3",
+    DisplayP3 => "display-p3",
+});
+
+cicp_conversions!(TransferCharacteristics {
+    Bt709 => "bt709",
+    Unspecified => "unspecified",
+    Bt601 => "bt601",
+    Linear => "linear",
+    Log => "log",
+    LogSqrt => "log-sqrt",
+    Srgb => "srgb",
+    Bt2020_10 => "bt2020-10",
+    Bt2020_12 => "bt2020-12",
+    Pq => "pq",
+    Hlg => "hlg",
+});
+
+cicp_conversions!(MatrixCoefficients {
+    Rgb => "rgb",
+    Bt709 => "bt709",
+    Unspecified => "unspecified",
+    Bt601 => "bt601",
+    Ycgco => "ycgco",
+    Bt2020Ncl => "bt2020-ncl",
+    Bt2020Cl => "bt2020-cl",
+});
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum ChromaSubsampling {
+    /// Full-resolution chroma, no subsampling
+    #[default]
+    Cs444,
+    /// Half horizontal resolution
+    Cs422,
+    /// Half horizontal and vertical resolution
+    Cs420,
+    /// Monochrome, no chroma at all
+    Cs400,
+}
+
+/// Where 4:2:0 chroma samples are, relative to the luma samples. See [`Aviffy::chroma_sample_position`](crate::Aviffy::chroma_sample_position).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum ChromaSamplePosition {
+    /// Leaves it to the decoder's guess
+    #[default]
+    Unknown = 0,
+    /// Between two luma rows, aligned with the left column (MPEG-2 and most video)
+    Vertical = 1,
+    /// At the top left luma sample (BT.2020)
+    Colocated = 2,
+}
+
+/// Common combinations of CICP values. See [`Aviffy::color_space_preset`](crate::Aviffy::color_space_preset).
+///
+/// All of them are full range, which is the norm for images.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ColorSpacePreset {
+    /// BT.709 primaries, sRGB transfer, BT.601 matrix. This is the default.
+    Srgb,
+    /// Display P3 primaries, sRGB transfer, BT.601 matrix, as used by Apple devices
+    DisplayP3,
+    /// BT.709 primaries, transfer and matrix, as used by HD video
+    Bt709,
+    /// BT.2020 primaries and matrix (non-constant luminance), PQ transfer. HDR10 and most HDR photos.
+    Bt2100Pq,
+    /// BT.2020 primaries and matrix (non-constant luminance), HLG transfer
+    Bt2100Hlg,
+}
+
+impl ColorSpacePreset {
+    #[must_use]
+    pub fn color_info(self) -> crate::ColorInfo {
+        let (color_primaries, transfer_characteristics, matrix_coefficients) = match self {
+            Self::Srgb => (ColorPrimaries::Bt709, TransferCharacteristics::Srgb, MatrixCoefficients::Bt601),
+            Self::DisplayP3 => (ColorPrimaries::DisplayP3, TransferCharacteristics::Srgb, MatrixCoefficients::Bt601),
+            Self::Bt709 => (ColorPrimaries::Bt709, TransferCharacteristics::Bt709, MatrixCoefficients::Bt709),
+            Self::Bt2100Pq => (ColorPrimaries::Bt2020, TransferCharacteristics::Pq, MatrixCoefficients::Bt2020Ncl),
+            Self::Bt2100Hlg => (ColorPrimaries::Bt2020, TransferCharacteristics::Hlg, MatrixCoefficients::Bt2020Ncl),
+        };
+        crate::ColorInfo { color_primaries, transfer_characteristics, matrix_coefficients, full_color_range: true }
+    }
+}
+
+/// URN identifying the alpha channel in the `auxC` property
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum AlphaUrn {
+    /// `urn:mpeg:mpegB:cicp:systems:auxiliary:alpha`, used by AVIF and MIAF
+    #[default]
+    Cicp,
+    /// `urn:mpeg:hevc:2015:auxid:1`, from HEIF. Understood by some older decoders.
+    Hevc,
+}
+
+impl AlphaUrn {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Cicp => "urn:mpeg:mpegB:cicp:systems:auxiliary:alpha",
+            Self::Hevc => "urn:mpeg:hevc:2015:auxid:1",
+        }
+    }
+}
@@ -0,0 +1,128 @@
+//! The boxes of a file before it's written. See [`Aviffy::document`].
+
+use crate::boxes::*;
+use crate::compose::NO_ITEMS;
+use crate::writer::Writer;
+use crate::{Aviffy, CustomBox, ImageData};
+use std::io;
+use std::sync::Arc;
+
+/// All boxes of a file, built with the settings of [`Aviffy`], which can be adjusted before writing.
+///
+/// Item ids and property indices are final, but `iloc` offsets and box sizes are computed when the file is written,
+/// so changes don't need any bookkeeping. Created with [`Aviffy::document`].
+#[derive(Debug, Clone)]
+pub struct AvifDocument<'data> {
+    file: AvifFile<'data>,
+}
+
+fn no_item() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "no item with this id")
+}
+
+impl Aviffy {
+    /// Builds the file that [`write`](Self::write) would make with the same arguments, but doesn't write it yet.
+    pub fn document<'data>(&'data self, image: ImageData<'data>) -> io::Result<AvifDocument<'data>> {
+        let exif_data = self.exif_or_default(image.exif_data);
+        let file = self.make_boxes(image.color_av1_data, image.alpha_av1_data, image.width, image.height, image.depth_bits,
+            image.timescale, image.color_frames, image.alpha_frames, exif_data, &NO_ITEMS)?;
+        Ok(AvifDocument { file })
+    }
+}
+
+/// Box type of a property, from its serialized header
+fn property_type(prop: &IpcoProp) -> Option<[u8; 4]> {
+    let mut header = Vec::with_capacity(prop.len());
+    let mut w = Writer::new(&mut header);
+    let _ = prop.write(&mut w.new_box(prop.len()));
+    drop(w);
+    header.get(4..8)?.try_into().ok()
+}
+
+/// Properties that change the image, and have to be after all the descriptive ones, in this order
+const TRANSFORMATIVE: [[u8; 4]; 3] = [*b"clap", *b"irot", *b"imir"];
+
+impl AvifDocument<'_> {
+    /// Brand of the `ftyp` box, e.g. `avif` or `avis`
+    #[must_use]
+    pub fn major_brand(&self) -> [u8; 4] {
+        self.file.ftyp.major_brand.0
+    }
+
+    pub fn set_major_brand(&mut self, brand: [u8; 4]) -> &mut Self {
+        self.file.ftyp.major_brand = FourCC(brand);
+        self
+    }
+
+    /// Brands of the `ftyp` box, in order
+    pub fn compatible_brands(&self) -> impl Iterator<Item = [u8; 4]> + '_ {
+        self.file.ftyp.compatible_brands.iter().map(|b| b.0)
+    }
+
+    /// Replaces all compatible brands. The major brand should be among them.
+    pub fn set_compatible_brands(&mut self, brands: &[[u8; 4]]) -> &mut Self {
+        self.file.ftyp.compatible_brands = brands.iter().copied().map(FourCC).collect();
+        self
+    }
+
+    /// Id of the primary image item
+    #[must_use]
+    pub fn primary_item(&self) -> u16 {
+        self.file.meta.pitm.0
+    }
+
+    /// Id, type and name of every item, in `iinf` order
+    pub fn items(&self) -> impl Iterator<Item = (u16, [u8; 4], &str)> + '_ {
+        self.file.meta.iinf.items.iter().map(|infe| (infe.id, infe.typ.0, &*infe.name))
+    }
+
+    /// The name is informative only, and can be empty
+    pub fn set_item_name(&mut self, item_id: u16, name: &str) -> io::Result<()> {
+        let infe = self.file.meta.iinf.items.iter_mut().find(|infe| infe.id == item_id).ok_or_else(no_item)?;
+        infe.name = name.to_owned().into();
+        Ok(())
+    }
+
+    /// Box types of the item's properties, in order, with their essential flag
+    pub fn item_properties(&self, item_id: u16) -> impl Iterator<Item = ([u8; 4], bool)> + '_ {
+        let ipco = &self.file.meta.iprp.ipco;
+        self.file.meta.iprp.ipma.entries.iter()
+            .filter(move |e| e.item_id == item_id)
+            .flat_map(|e| e.prop_ids.iter())
+            .filter_map(move |&p| Some((property_type(ipco.get(p & 0x7F)?)?, p & 0x80 != 0)))
+    }
+
+    /// Adds a property after the item's existing descriptive properties, and before its transformative ones
+    /// (`clap`, `irot`, `imir`), which decoders apply in order. Identical properties are stored once.
+    ///
+    /// Decoders that don't understand an `essential` property won't show the item.
+    /// A transformative property is added after all the others.
+    pub fn add_property(&mut self, item_id: u16, property: impl CustomBox + Send + Sync + 'static, essential: bool) -> io::Result<()> {
+        if !self.file.meta.iinf.items.iter().any(|infe| infe.id == item_id) {
+            return Err(no_item());
+        }
+        let property = ExternalBox(Arc::new(property));
+        property.check_len()?;
+        let iprp = &mut self.file.meta.iprp;
+        let index = iprp.ipco.push_or_reuse(IpcoProp::Custom(property));
+        let is_transformative = |p: u8| iprp.ipco.get(p & 0x7F).and_then(property_type).is_some_and(|t| TRANSFORMATIVE.contains(&t));
+        let before = if is_transformative(index) { None } else { Some(is_transformative) };
+        let index = if essential { index | 0x80 } else { index };
+
+        let entries = &mut iprp.ipma.entries;
+        match entries.binary_search_by_key(&item_id, |e| e.item_id) {
+            Ok(pos) => {
+                let prop_ids = &mut entries[pos].prop_ids;
+                let at = before.and_then(|before| prop_ids.iter().position(|&p| before(p))).unwrap_or(prop_ids.len());
+                prop_ids.insert(at, index);
+            },
+            Err(pos) => entries.insert(pos, IpmaEntry { item_id, prop_ids: vec![index] }),
+        }
+        Ok(())
+    }
+
+    /// Writes the file, computing all sizes and offsets
+    pub fn write<W: io::Write>(mut self, mut into_output: W) -> io::Result<()> {
+        self.file.write(&mut into_output)
+    }
+}
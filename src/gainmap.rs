@@ -0,0 +1,157 @@
+//! Gain maps (ISO 21496-1), which let decoders render an HDR or SDR version of the image from one file.
+
+use crate::boxes::ColrBox;
+use crate::compose::{GraphItem, XMP_CONTENT_TYPE};
+use crate::{Aviffy, ColorInfo};
+use std::io;
+
+/// How the gain map is applied to one channel. See [`GainMapMetadata`].
+///
+/// Values are fractions (numerator, denominator), and denominators can't be 0.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct GainMapChannel {
+    /// Log2 of the gain of the darkest and brightest values of the gain map image
+    pub min: (i32, u32),
+    pub max: (i32, u32),
+    /// Gamma the gain map image has been encoded with
+    pub gamma: (u32, u32),
+    /// Offsets added to the base and alternate image before computing the gain
+    pub base_offset: (i32, u32),
+    pub alternate_offset: (i32, u32),
+}
+
+/// Tone mapping metadata of a gain map, written as the payload of the `tmap` item. See [`Aviffy::gain_map`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GainMapMetadata {
+    /// Log2 of the HDR headroom of the base image (0 for an SDR base)
+    pub base_hdr_headroom: (u32, u32),
+    /// Log2 of the HDR headroom the alternate image is meant for
+    pub alternate_hdr_headroom: (u32, u32),
+    /// One channel for all of RGB, or three for R, G, B
+    pub channels: Vec<GainMapChannel>,
+    /// Apply the gain map in the base image's colorspace, rather than the alternate image's
+    pub use_base_color_space: bool,
+}
+
+impl GainMapMetadata {
+    pub(crate) fn check(&self) -> io::Result<()> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        if self.channels.len() != 1 && self.channels.len() != 3 {
+            return Err(invalid("gain map must have 1 or 3 channels"));
+        }
+        let mut denominators = [self.base_hdr_headroom.1, self.alternate_hdr_headroom.1].into_iter()
+            .chain(self.channels.iter().flat_map(|c| [c.min.1, c.max.1, c.gamma.1, c.base_offset.1, c.alternate_offset.1]));
+        if denominators.any(|d| d == 0) {
+            return Err(invalid("gain map fraction with a 0 denominator"));
+        }
+        Ok(())
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(6 + 16 + 40 * self.channels.len());
+        out.push(0); // version
+        out.extend_from_slice(&0u16.to_be_bytes()); // minimum_version
+        out.extend_from_slice(&0u16.to_be_bytes()); // writer_version
+        let is_multichannel = self.channels.len() == 3;
+        out.push(u8::from(is_multichannel) << 7 | u8::from(self.use_base_color_space) << 6);
+        for (n, d) in [self.base_hdr_headroom, self.alternate_hdr_headroom] {
+            out.extend_from_slice(&n.to_be_bytes());
+            out.extend_from_slice(&d.to_be_bytes());
+        }
+        for c in &self.channels {
+            out.extend_from_slice(&c.min.0.to_be_bytes());
+            out.extend_from_slice(&c.min.1.to_be_bytes());
+            out.extend_from_slice(&c.max.0.to_be_bytes());
+            out.extend_from_slice(&c.max.1.to_be_bytes());
+            out.extend_from_slice(&c.gamma.0.to_be_bytes());
+            out.extend_from_slice(&c.gamma.1.to_be_bytes());
+            out.extend_from_slice(&c.base_offset.0.to_be_bytes());
+            out.extend_from_slice(&c.base_offset.1.to_be_bytes());
+            out.extend_from_slice(&c.alternate_offset.0.to_be_bytes());
+            out.extend_from_slice(&c.alternate_offset.1.to_be_bytes());
+        }
+        out
+    }
+}
+
+/// How the gain map is linked to the color image. See [`Aviffy::gain_map_format`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum GainMapFormat {
+    /// ISO 21496-1 `tmap` derived item, supported by Chrome and libavif
+    #[default]
+    Iso21496,
+    /// Auxiliary image with Apple's `hdrgainmap` URN and XMP, supported by Apple Photos and Safari.
+    ///
+    /// The [`GainMapMetadata`] isn't written in this format.
+    Apple,
+}
+
+pub(crate) const APPLE_GAIN_MAP_URN: &str = "urn:com:apple:photo:2020:aux:hdrgainmap";
+
+/// Apple requires this on the gain map item, and ignores gain maps without it
+const APPLE_GAIN_MAP_XMP: &str = concat!(
+    r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">"#,
+    r#"<rdf:Description rdf:about="" xmlns:HDRGainMap="http://ns.apple.com/HDRGainMap/1.0/">"#,
+    r#"<HDRGainMap:HDRGainMapVersion>65536</HDRGainMap:HDRGainMapVersion>"#,
+    r#"</rdf:Description></rdf:RDF></x:xmpmeta>"#,
+);
+
+/// The gain map image item and the `tmap` item derived from it and the color image
+#[derive(Debug, Clone)]
+pub(crate) struct GainMap {
+    pub image: GraphItem<'static>,
+    pub width: u32,
+    pub height: u32,
+    pub depth_bits: u8,
+    pub monochrome: bool,
+    pub tmap: GraphItem<'static>,
+    /// Written instead of `tmap` in the Apple format
+    pub apple_xmp: GraphItem<'static>,
+    /// `colr` of the alternate image
+    pub alternate_colr: Option<ColrBox>,
+    pub metadata: GainMapMetadata,
+}
+
+impl GainMap {
+    /// The items written after the graph's items: the gain map image, and `tmap` or XMP
+    pub fn items(&self, format: GainMapFormat) -> [&GraphItem<'static>; 2] {
+        match format {
+            GainMapFormat::Iso21496 => [&self.image, &self.tmap],
+            GainMapFormat::Apple => [&self.image, &self.apple_xmp],
+        }
+    }
+}
+
+impl Aviffy {
+    /// Adds a gain map, so that decoders that support it (e.g. Chrome) can show an HDR version of the image,
+    /// and other decoders show the color image as-is. Setting it again replaces the previous gain map.
+    ///
+    /// `gain_map_av1_data` is an AV1 image of `width`×`height` pixels (usually smaller than the color image),
+    /// monochrome if the metadata has one channel. A 3-channel gain map must use the same chroma subsampling as the color image.
+    ///
+    /// `alternate_color` describes the colorspace of the HDR rendition, e.g. BT.2100 PQ. The color image stays primary.
+    ///
+    /// Fails when writing if the metadata doesn't have 1 or 3 channels, or has a 0 denominator.
+    pub fn gain_map(&mut self, gain_map_av1_data: impl Into<Vec<u8>>, width: u32, height: u32, depth_bits: u8, metadata: &GainMapMetadata, alternate_color: Option<ColorInfo>) -> &mut Self {
+        self.gain_map = Some(GainMap {
+            image: GraphItem { typ: *b"av01", name: "GainMap".into(), content_type: None, content_encoding: None, data: gain_map_av1_data.into().into(), is_metadata: false },
+            width,
+            height,
+            depth_bits,
+            monochrome: metadata.channels.len() == 1,
+            tmap: GraphItem { typ: *b"tmap", name: "ToneMap".into(), content_type: None, content_encoding: None, data: metadata.to_bytes().into(), is_metadata: false },
+            apple_xmp: GraphItem { typ: *b"mime", name: "XMP".into(), content_type: Some(XMP_CONTENT_TYPE.into()), content_encoding: None, data: APPLE_GAIN_MAP_XMP.as_bytes().into(), is_metadata: true },
+            alternate_colr: alternate_color.map(ColrBox::from),
+            metadata: metadata.clone(),
+        });
+        self
+    }
+
+    /// Writes the [gain map](Self::gain_map) in the ISO 21496-1 format (the default), or in the format
+    /// Apple Photos and Safari recognize. Decoders that support only the other format will show the SDR color image.
+    pub fn gain_map_format(&mut self, format: GainMapFormat) -> &mut Self {
+        self.gain_map_format = format;
+        self
+    }
+}
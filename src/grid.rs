@@ -0,0 +1,395 @@
+//! Images made of independently encoded tiles. See [`Aviffy::grid`].
+
+use crate::boxes::*;
+use crate::compose::{ItemGraph, ItemId, ItemRef, NO_ITEMS};
+use crate::template::iloc_extent_fields;
+use crate::{monochrome_av1c, Aviffy};
+use std::borrow::Cow;
+use std::io;
+
+/// Still image made of AV1 tiles of the same size, for images too large for a single AV1 frame.
+///
+/// Created with [`Aviffy::grid`], and uses its settings. Add the tiles row by row, then write it with [`finish`](Self::finish).
+#[derive(Clone)]
+pub struct Grid<'data> {
+    aviffy: &'data Aviffy,
+    layout: GridLayout,
+    depth_bits: u8,
+    tiles: Vec<Cow<'data, [u8]>>,
+    alpha_tiles: Vec<Cow<'data, [u8]>>,
+}
+
+/// Arrangement of a grid's tiles, and the payload of its `grid` item. See [`Aviffy::grid_with_layout`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct GridLayout {
+    /// Number of tiles across and down, from 1 to 256
+    pub columns: u32,
+    pub rows: u32,
+    /// Size of every tile
+    pub tile_width: u32,
+    pub tile_height: u32,
+    /// Size of the image, which can be smaller than the tiles by less than one tile, cutting off the last column and row
+    pub width: u32,
+    pub height: u32,
+}
+
+impl GridLayout {
+    /// Fewest columns and rows of `tile_width`×`tile_height` tiles that cover a `width`×`height` image.
+    ///
+    /// Fails if a size is 0, or more than 256 columns or rows would be needed.
+    pub fn new(width: u32, height: u32, tile_width: u32, tile_height: u32) -> io::Result<Self> {
+        if tile_width == 0 || tile_height == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "grid tile size is 0"));
+        }
+        let layout = Self {
+            columns: width.div_ceil(tile_width),
+            rows: height.div_ceil(tile_height),
+            tile_width,
+            tile_height,
+            width,
+            height,
+        };
+        layout.check()?;
+        Ok(layout)
+    }
+
+    /// Checks the number of columns and rows, and that the tiles cover the image
+    pub fn check(&self) -> io::Result<()> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        if !(1..=256).contains(&self.columns) || !(1..=256).contains(&self.rows) {
+            return Err(invalid("grid must have 1 to 256 columns and rows"));
+        }
+        // The last tile has to have some pixels in the image
+        let fits = |size: u32, tiles: u32, tile_size: u32| {
+            let (size, tiles, tile_size) = (u64::from(size), u64::from(tiles), u64::from(tile_size));
+            tile_size > 0 && size <= tiles * tile_size && size > (tiles - 1) * tile_size
+        };
+        if !fits(self.width, self.columns, self.tile_width) || !fits(self.height, self.rows, self.tile_height) {
+            return Err(invalid("grid output size doesn't match the tiles"));
+        }
+        Ok(())
+    }
+
+    /// Checks the coded size of each tile, in row-major order. Tiles in the last column and row can be smaller
+    /// than the others, but must still reach the image's right and bottom edges.
+    pub fn check_tile_sizes(&self, sizes: &[(u32, u32)]) -> io::Result<()> {
+        self.check()?;
+        if sizes.len() != self.tile_count() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "wrong number of grid tiles"));
+        }
+        let last_width = self.width - (self.columns - 1) * self.tile_width;
+        let last_height = self.height - (self.rows - 1) * self.tile_height;
+        for (index, &(width, height)) in sizes.iter().enumerate() {
+            let (column, row) = (index as u32 % self.columns, index as u32 / self.columns);
+            let width_ok = if column + 1 == self.columns { (last_width..=self.tile_width).contains(&width) } else { width == self.tile_width };
+            let height_ok = if row + 1 == self.rows { (last_height..=self.tile_height).contains(&height) } else { height == self.tile_height };
+            if !width_ok || !height_ok {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!(
+                    "grid tile at column {column}, row {row} is {width}x{height}, but should be {}x{}", self.tile_width, self.tile_height,
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn tile_count(&self) -> usize {
+        (self.columns * self.rows) as usize
+    }
+
+    /// Payload of the `grid` item, with 32-bit sizes only if they don't fit in 16 bits
+    #[must_use]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let large = self.width > 0xFFFF || self.height > 0xFFFF;
+        let mut out = vec![0, u8::from(large), (self.rows - 1) as u8, (self.columns - 1) as u8];
+        if large {
+            out.extend_from_slice(&self.width.to_be_bytes());
+            out.extend_from_slice(&self.height.to_be_bytes());
+        } else {
+            out.extend_from_slice(&(self.width as u16).to_be_bytes());
+            out.extend_from_slice(&(self.height as u16).to_be_bytes());
+        }
+        out
+    }
+}
+
+impl Aviffy {
+    /// Starts an image made of `columns`×`rows` tiles of `tile_width`×`tile_height` pixels each.
+    ///
+    /// There can be at most 256 columns and rows. The image is as large as all of the tiles,
+    /// unless it's cropped with [`Grid::output_size`].
+    #[must_use]
+    pub fn grid(&self, columns: u32, rows: u32, tile_width: u32, tile_height: u32, depth_bits: u8) -> Grid<'_> {
+        self.grid_with_layout(GridLayout {
+            columns,
+            rows,
+            tile_width,
+            tile_height,
+            width: columns.saturating_mul(tile_width),
+            height: rows.saturating_mul(tile_height),
+        }, depth_bits)
+    }
+
+    /// Starts an image with a layout from [`GridLayout::new`]
+    #[must_use]
+    pub fn grid_with_layout(&self, layout: GridLayout, depth_bits: u8) -> Grid<'_> {
+        Grid {
+            aviffy: self,
+            layout,
+            depth_bits,
+            tiles: Vec::new(),
+            alpha_tiles: Vec::new(),
+        }
+    }
+}
+
+impl<'data> Grid<'data> {
+    /// Size of the image when the tiles in the last column and row extend past its right and bottom edges.
+    ///
+    /// It must be smaller than the tiles by less than one tile.
+    pub fn output_size(&mut self, width: u32, height: u32) -> &mut Self {
+        self.layout.width = width;
+        self.layout.height = height;
+        self
+    }
+
+    /// The next tile, left to right, top to bottom. See [`Aviffy::write`] for the requirements of the AV1 data.
+    pub fn add_tile(&mut self, av1_data: impl Into<Cow<'data, [u8]>>) -> &mut Self {
+        self.tiles.push(av1_data.into());
+        self
+    }
+
+    /// Monochrome alpha of the next tile. If any tile has alpha, all of them must have it.
+    pub fn add_alpha_tile(&mut self, av1_data: impl Into<Cow<'data, [u8]>>) -> &mut Self {
+        self.alpha_tiles.push(av1_data.into());
+        self
+    }
+
+    /// Tile items, each followed by its alpha, and `dimg` references to them in row-major order
+    fn item_graph<'tiles>(&self, tiles: &[(&'tiles [u8], Option<&'tiles [u8]>)], depth_bits: u8) -> ItemGraph<'tiles> {
+        let level = self.aviffy.level(self.layout.tile_width, self.layout.tile_height, None);
+        let color_config = self.aviffy.color_config(depth_bits, level);
+        let alpha_config = self.aviffy.alpha_av1c.unwrap_or_else(|| monochrome_av1c(depth_bits, level));
+        let mut graph = NO_ITEMS.clone();
+        graph.derived = Some(*b"grid");
+        let mut alpha_ids = Vec::new();
+        for &(color, alpha) in tiles {
+            for (data, of, channels, config) in [
+                (Some(color), ItemRef::Color, if color_config.monochrome { 1 } else { 3 }, color_config),
+                (alpha, ItemRef::Alpha, 1, alpha_config),
+            ] {
+                let Some(data) = data else { continue };
+                let id = graph.add_item(*b"av01", "Tile", data);
+                graph.add_ipco_prop(id, IpcoProp::Ispe(IspeBox { width: self.layout.tile_width, height: self.layout.tile_height }), false)
+                    .add_ipco_prop(id, IpcoProp::Pixi(PixiBox { channels, depth: depth_bits }), false)
+                    .add_ipco_prop(id, IpcoProp::Av1C(config), true);
+                // Alpha's references go after color's, so that each item's `dimg` is one entry
+                if of == ItemRef::Alpha {
+                    alpha_ids.push(id);
+                } else {
+                    graph.add_reference(ItemId(of), *b"dimg", id);
+                }
+            }
+        }
+        for id in alpha_ids {
+            graph.add_reference(ItemId(ItemRef::Alpha), *b"dimg", id);
+        }
+        graph
+    }
+
+    /// Writes the file. Fails if the number of tiles doesn't match the layout,
+    /// or the output size doesn't fit the tiles.
+    pub fn finish<W: io::Write>(self, mut into_output: W) -> io::Result<()> {
+        self.layout.check()?;
+        let count = self.layout.tile_count();
+        if self.tiles.len() != count || (!self.alpha_tiles.is_empty() && self.alpha_tiles.len() != count) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "wrong number of grid tiles"));
+        }
+        let tiles: Vec<_> = self.tiles.iter().enumerate().map(|(index, tile)| (&tile[..], self.alpha_tiles.get(index).map(|t| &t[..]))).collect();
+        for &(tile, alpha_tile) in &tiles {
+            self.aviffy.verify_input(tile, alpha_tile, self.layout.tile_width, self.layout.tile_height)?;
+        }
+
+        let depth_bits = self.aviffy.bit_depth.unwrap_or(self.depth_bits);
+        let graph = self.item_graph(&tiles, depth_bits);
+        let config = self.layout.to_bytes();
+        let alpha = (!self.alpha_tiles.is_empty()).then_some(&config[..]);
+        self.aviffy.make_boxes(&config, alpha, self.layout.width, self.layout.height, depth_bits, 0, None, None, &self.aviffy.exif, &graph)?
+            .write(&mut into_output)
+    }
+
+    /// Writes the tiles to `into_output` as they're added with [`GridWriter::add_tile`], instead of keeping them in memory.
+    ///
+    /// `has_alpha` sets whether every tile has alpha. Tiles already added to the grid are written first.
+    /// The header is written with placeholder offsets, and rewritten by [`GridWriter::finish`], so the output has to be seekable.
+    pub fn stream<W: io::Write + io::Seek>(self, mut into_output: W, has_alpha: bool) -> io::Result<GridWriter<'data, W>> {
+        self.layout.check()?;
+        if !self.alpha_tiles.is_empty() && (!has_alpha || self.alpha_tiles.len() != self.tiles.len()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "wrong number of grid alpha tiles"));
+        }
+
+        // Tiles are told apart by their address
+        let count = self.layout.tile_count();
+        let probe = vec![0u8; count * 2];
+        let probe_tiles: Vec<_> = probe.chunks(2).map(|p| (&p[..1], has_alpha.then_some(&p[1..]))).collect();
+        let depth_bits = self.aviffy.bit_depth.unwrap_or(self.depth_bits);
+        let graph = self.item_graph(&probe_tiles, depth_bits);
+        let config = self.layout.to_bytes();
+        let alpha = has_alpha.then_some(&config[..]);
+        let mut file = self.aviffy.make_boxes(&config, alpha, self.layout.width, self.layout.height, depth_bits, 0, None, None, &self.aviffy.exif, &graph)?;
+
+        let mut chunks = Vec::new();
+        let mut payload_chunks = Vec::new();
+        let mut relative_starts = Vec::new();
+        let mut relative_pos = 0;
+        for mdat in &file.mdat {
+            chunks.push(Chunk::Mdat);
+            for data in &mdat.data_chunks {
+                relative_starts.push((relative_pos, data.len()));
+                relative_pos += data.len();
+                payload_chunks.push(chunks.len());
+                let is_tile = probe.as_ptr_range().contains(&data.as_ptr());
+                chunks.push(if is_tile { Chunk::Tile } else { Chunk::Fixed(data.to_vec()) });
+            }
+            if let Some(exif) = &mdat.exif {
+                relative_starts.push((relative_pos, exif.len()));
+                relative_pos += exif.len();
+                payload_chunks.push(chunks.len());
+                chunks.push(Chunk::Fixed([&exif.header_offset.to_be_bytes()[..], &exif.payload].concat()));
+            }
+        }
+        let iloc_extents = iloc_extent_fields(&file, &relative_starts).into_iter()
+            .map(|(field, index)| (field, payload_chunks[index]))
+            .collect();
+
+        let mut header = Vec::new();
+        file.write(&mut header)?;
+        header.truncate(header.len() - file.mdat.iter().map(|m| m.len()).sum::<usize>());
+        let start = into_output.stream_position()?;
+        into_output.write_all(&header)?;
+
+        let mut writer = GridWriter {
+            aviffy: self.aviffy,
+            out: into_output,
+            tile_width: self.layout.tile_width,
+            tile_height: self.layout.tile_height,
+            has_alpha,
+            tiles_left: count,
+            start,
+            pos: header.len() as u64,
+            header,
+            chunks,
+            written: Vec::new(),
+            iloc_extents,
+        };
+        for (index, tile) in self.tiles.iter().enumerate() {
+            writer.add_tile(tile, self.alpha_tiles.get(index).map(|t| &t[..]))?;
+        }
+        Ok(writer)
+    }
+}
+
+#[derive(Debug)]
+enum Chunk {
+    /// Start of an `mdat` box, which gets its size at the end
+    Mdat,
+    Tile,
+    /// Grid config and metadata, which are part of the settings
+    Fixed(Vec<u8>),
+}
+
+/// Writes a [`Grid`]'s tiles one at a time, so that a large grid doesn't have to be kept in memory.
+///
+/// Created with [`Grid::stream`]. The file is incomplete until [`finish`](Self::finish) is called.
+pub struct GridWriter<'data, W: io::Write + io::Seek> {
+    aviffy: &'data Aviffy,
+    out: W,
+    tile_width: u32,
+    tile_height: u32,
+    has_alpha: bool,
+    tiles_left: usize,
+    /// Position of the file in the output
+    start: u64,
+    /// Position in the file
+    pos: u64,
+    /// Everything before the first `mdat`, with placeholders in `iloc`
+    header: Vec<u8>,
+    /// Contents of all `mdat` boxes, in order
+    chunks: Vec<Chunk>,
+    /// Position in the file and length of the chunks written so far
+    written: Vec<(u64, u64)>,
+    /// Position of each `iloc` extent in the header, and index of its chunk
+    iloc_extents: Vec<(usize, usize)>,
+}
+
+impl<W: io::Write + io::Seek> GridWriter<'_, W> {
+    /// Writes the next tile, left to right, top to bottom, with its alpha if the grid has alpha.
+    pub fn add_tile(&mut self, av1_data: &[u8], alpha_av1_data: Option<&[u8]>) -> io::Result<()> {
+        if self.tiles_left == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "too many grid tiles"));
+        }
+        if self.has_alpha != alpha_av1_data.is_some() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "alpha doesn't match the grid"));
+        }
+        self.aviffy.verify_input(av1_data, alpha_av1_data, self.tile_width, self.tile_height)?;
+        self.tiles_left -= 1;
+        for data in [Some(av1_data), alpha_av1_data].into_iter().flatten() {
+            self.write_fixed()?;
+            self.write_tile(data)?;
+        }
+        Ok(())
+    }
+
+    /// Writes chunks up to the next tile
+    fn write_fixed(&mut self) -> io::Result<()> {
+        while let Some(chunk) = self.chunks.get(self.written.len()) {
+            let data = match chunk {
+                Chunk::Tile => break,
+                Chunk::Mdat => &b"\0\0\0\0mdat"[..],
+                Chunk::Fixed(data) => &data[..],
+            };
+            self.out.write_all(data)?;
+            self.written.push((self.pos, data.len() as u64));
+            self.pos += data.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn write_tile(&mut self, data: &[u8]) -> io::Result<()> {
+        self.out.write_all(data)?;
+        self.written.push((self.pos, data.len() as u64));
+        self.pos += data.len() as u64;
+        Ok(())
+    }
+
+    /// Writes the rest of the file, and goes back to fill in the header and `mdat` sizes.
+    /// Fails if not all tiles have been added, or the file is too large for 32-bit offsets.
+    pub fn finish(mut self) -> io::Result<()> {
+        if self.tiles_left != 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "wrong number of grid tiles"));
+        }
+        self.write_fixed()?;
+        let too_large = || io::Error::new(io::ErrorKind::InvalidInput, "file too large for 32-bit offsets");
+        for &(field, index) in &self.iloc_extents {
+            let (offset, len) = self.written[index];
+            let offset = u32::try_from(offset).map_err(|_| too_large())?;
+            let len = u32::try_from(len).map_err(|_| too_large())?;
+            self.header[field..field + 4].copy_from_slice(&offset.to_be_bytes());
+            self.header[field + 4..field + 8].copy_from_slice(&len.to_be_bytes());
+        }
+        self.out.seek(io::SeekFrom::Start(self.start))?;
+        self.out.write_all(&self.header)?;
+
+        let mdat_starts: Vec<_> = self.chunks.iter().zip(&self.written)
+            .filter(|(chunk, _)| matches!(chunk, Chunk::Mdat))
+            .map(|(_, &(pos, _))| pos)
+            .chain([self.pos])
+            .collect();
+        for mdat in mdat_starts.windows(2) {
+            let mdat_len = u32::try_from(mdat[1] - mdat[0]).map_err(|_| too_large())?;
+            self.out.seek(io::SeekFrom::Start(self.start + mdat[0]))?;
+            self.out.write_all(&mdat_len.to_be_bytes())?;
+        }
+        self.out.seek(io::SeekFrom::Start(self.start + self.pos))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,35 @@
+//! AV1 levels (Annex A.3 of the AV1 spec), which tell decoders how much memory and speed they need
+
+/// `seq_level_idx`, max picture size in samples, max width, max height, max display rate in samples per second
+const LEVELS: [(u8, u64, u32, u32, u64); 14] = [
+    (0, 147_456, 2048, 1152, 4_423_680),
+    (1, 278_784, 2816, 1584, 8_363_520),
+    (4, 665_856, 4352, 2448, 19_975_680),
+    (5, 1_065_024, 5504, 3096, 31_950_720),
+    (8, 2_359_296, 6144, 3456, 70_778_880),
+    (9, 2_359_296, 6144, 3456, 141_557_760),
+    (12, 8_912_896, 8192, 4352, 267_386_880),
+    (13, 8_912_896, 8192, 4352, 534_773_760),
+    (14, 8_912_896, 8192, 4352, 1_069_547_520),
+    (15, 8_912_896, 8192, 4352, 1_069_547_520),
+    (16, 35_651_584, 16384, 8704, 1_069_547_520),
+    (17, 35_651_584, 16384, 8704, 2_139_095_040),
+    (18, 35_651_584, 16384, 8704, 4_278_190_080),
+    (19, 35_651_584, 16384, 8704, 4_278_190_080),
+];
+
+/// Lowest AV1 level (`seq_level_idx`) that allows images of the given size, shown at `frames_per_second` for sequences.
+///
+/// Only the picture size and display rate limits are checked, not the bitrate. Returns 31 (no level constraints) for images too large for any level.
+///
+/// Level 2.0 is 0, 2.1 is 1, 3.0 is 4, and so on.
+#[must_use]
+pub fn av1_level(width: u32, height: u32, frames_per_second: Option<f64>) -> u8 {
+    let picture_size = u64::from(width) * u64::from(height);
+    LEVELS.iter()
+        .find(|&&(_, max_size, max_width, max_height, max_display_rate)| {
+            picture_size <= max_size && width <= max_width && height <= max_height
+                && frames_per_second.is_none_or(|fps| picture_size as f64 * fps <= max_display_rate as f64)
+        })
+        .map_or(31, |&(level, ..)| level)
+}
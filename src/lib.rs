@@ -7,14 +7,61 @@
 //! 2. Call `avif_serialize::serialize_to_vec(av1_data, None, width, height, 8)`
 //!
 //! See [cavif](https://github.com/kornelski/cavif-rs) for a complete implementation.
+//!
+//! ## Panics
+//!
+//! Functions that return `io::Result` report invalid input, such as a file too large for 32-bit offsets, as an `InvalidInput` error,
+//! and malformed files given to [`remux`] as `InvalidData`.
+//!
+//! [`serialize_to_vec`] and [`Aviffy::to_vec`] are convenience wrappers that panic on invalid input instead.
+//! Use [`Aviffy::try_to_vec`] for input that isn't known to be valid.
 
+#[cfg(feature = "rayon")]
+mod batch;
+pub mod bitstream;
 mod boxes;
+mod compose;
 pub mod constants;
+mod document;
+mod gainmap;
+mod grid;
+mod level;
+mod reader;
+mod region;
+pub mod remux;
+mod stats;
+mod still;
 mod writer;
+#[cfg(feature = "rav1e")]
+mod rav1e_config;
+mod obu;
+#[cfg(feature = "svt-av1")]
+pub mod svt;
+mod template;
+mod verify;
+
+#[cfg(feature = "rayon")]
+pub use crate::batch::BatchImage;
+pub use crate::boxes::Av1CBox;
+pub use crate::boxes::CustomBox;
+pub use crate::compose::{Composition, ItemGraph, ItemId, MetadataKind, PyramidLayer, UncompressedFormat};
+pub use crate::document::AvifDocument;
+pub use crate::gainmap::{GainMapChannel, GainMapFormat, GainMapMetadata};
+pub use crate::grid::{Grid, GridLayout, GridWriter};
+pub use crate::level::av1_level;
+pub use crate::region::Region;
+pub use crate::stats::MuxStats;
+pub use crate::template::HeaderTemplate;
+pub use crate::verify::Mismatch;
 
 use crate::boxes::*;
+use crate::compose::{GraphItem, ItemRef, C2PA_CONTENT_TYPE, IPTC_CONTENT_TYPE, NO_ITEMS, XMP_CONTENT_TYPE};
+use crate::writer::Writer;
 use arrayvec::ArrayVec;
+use std::borrow::Cow;
 use std::io;
+use std::ops::Range;
+use std::sync::Arc;
 // use std::{io, time::SystemTime};
 
 /// Config for the serialization (allows setting advanced image properties).
@@ -23,6 +70,430 @@ use std::io;
 pub struct Aviffy {
     premultiplied_alpha: bool,
     colr: ColrBox,
+    mdat_layout: MdatLayout,
+    color_track_colr: TrackColr,
+    alpha_track_colr: TrackColr,
+    hold_last_frame: bool,
+    custom_boxes: Vec<(CustomBoxLocation, ExternalBox)>,
+    chroma_subsampling: constants::ChromaSubsampling,
+    chroma_sample_position: constants::ChromaSamplePosition,
+    seq_level_idx: Option<u8>,
+    high_tier: bool,
+    seq_profile: Option<u8>,
+    sequence_header_in_av1c: bool,
+    bit_depth: Option<u8>,
+    color_av1c: Option<Av1CBox>,
+    alpha_av1c: Option<Av1CBox>,
+    alpha_urn: constants::AlphaUrn,
+    long_times: bool,
+    alpha_first: bool,
+    blobs: Vec<(BlobLocation, Vec<u8>)>,
+    renditions: Vec<Rendition>,
+    track_references: Vec<(u32, [u8; 4], Vec<u32>)>,
+    /// Items with a `cdsc` reference to the color image
+    metadata: Vec<GraphItem<'static>>,
+    /// Images with an `auxl` reference to the color image
+    auxiliary: Vec<Auxiliary>,
+    metadata_tracks: Vec<MetadataTrack>,
+    spherical: Option<(Projection, StereoMode)>,
+    verify_alpha: bool,
+    verify_size: bool,
+    verify_sync: bool,
+    layout_version: u32,
+    /// Used when the `exif_data` argument is empty
+    exif: Vec<u8>,
+    icc_profile: Option<Arc<[u8]>>,
+    nclx_with_icc: bool,
+    mastering_display: Option<MasteringDisplay>,
+    content_light_level: Option<ContentLightLevel>,
+    ambient_viewing: Option<AmbientViewing>,
+    gain_map: Option<gainmap::GainMap>,
+    gain_map_format: GainMapFormat,
+    dolby_vision: Option<DolbyVision>,
+    #[cfg(feature = "deflate")]
+    compress_metadata: bool,
+    free_padding: usize,
+    user_description: Option<UserDescription>,
+    alt_text: Option<AlttBox>,
+    rotation: Rotation,
+    mirror: Option<Mirror>,
+    clean_aperture: Option<CleanAperture>,
+    /// For a `clap` computed when the coded size is known
+    display_size: Option<(u32, u32)>,
+    /// Horizontal and vertical spacing
+    pixel_aspect_ratio: Option<(u32, u32)>,
+    /// Byte sizes of the color image's spatial layers
+    layer_sizes: Vec<usize>,
+    operating_point: Option<u8>,
+}
+
+/// Samples of a track added with [`Aviffy::add_metadata_track`], stored as one chunk
+#[derive(Debug, Clone)]
+struct MetadataTrack {
+    mime_format: String,
+    data: Vec<u8>,
+    sample_sizes: Vec<usize>,
+}
+
+/// Image added with [`Aviffy::add_auxiliary`]
+#[derive(Debug, Clone)]
+struct Auxiliary {
+    image: GraphItem<'static>,
+    urn: String,
+    width: u32,
+    height: u32,
+}
+
+/// How a rendition added with [`Aviffy::add_rendition`] is linked to the primary image
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RenditionLink {
+    /// A `thmb` reference to the primary image. Decoders may show it while the full image loads.
+    Thumbnail,
+    /// Member of an `altr` group that lists the primary image first, and then renditions in order they were added.
+    Alternative,
+    /// Both a `thmb` reference and a member of the `altr` group
+    ThumbnailAndAlternative,
+}
+
+#[derive(Debug, Clone)]
+struct Rendition {
+    /// `av01` or `jpeg`
+    typ: [u8; 4],
+    data: Vec<u8>,
+    width: u32,
+    height: u32,
+    link: RenditionLink,
+}
+
+/// Where to store data added with [`Aviffy::embed_blob`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BlobLocation {
+    /// Top-level `free` box, which every parser skips
+    Free,
+    /// Top-level `uuid` box with the given 16-byte extended type
+    Uuid([u8; 16]),
+    /// `uuid` box with the given 16-byte extended type at the end of the file-level `meta` box,
+    /// for data about the items. Parsers skip unknown boxes there too.
+    MetaUuid([u8; 16]),
+    /// Box of the given type inside `moov/udta`. Only in animations, ignored in still images.
+    Udta([u8; 4]),
+}
+
+/// Where to insert a [`CustomBox`]. See [`Aviffy::custom_box`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CustomBoxLocation {
+    /// At the end of the file-level `meta` box
+    Meta,
+    /// At the end of every `trak` box of an animation
+    Track,
+    /// At the end of every track's `av01` sample entry
+    SampleEntry,
+}
+
+/// Color description for a `colr` box (CICP values and range)
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ColorInfo {
+    pub color_primaries: constants::ColorPrimaries,
+    pub transfer_characteristics: constants::TransferCharacteristics,
+    pub matrix_coefficients: constants::MatrixCoefficients,
+    pub full_color_range: bool,
+}
+
+impl From<ColrBox> for ColorInfo {
+    fn from(colr: ColrBox) -> Self {
+        Self {
+            color_primaries: colr.color_primaries,
+            transfer_characteristics: colr.transfer_characteristics,
+            matrix_coefficients: colr.matrix_coefficients,
+            full_color_range: colr.full_range_flag,
+        }
+    }
+}
+
+impl From<ColorInfo> for ColrBox {
+    fn from(info: ColorInfo) -> Self {
+        Self {
+            color_primaries: info.color_primaries,
+            transfer_characteristics: info.transfer_characteristics,
+            matrix_coefficients: info.matrix_coefficients,
+            full_range_flag: info.full_color_range,
+        }
+    }
+}
+
+/// Color volume of the display an HDR image was graded on. See [`Aviffy::mastering_display`].
+///
+/// Chromaticity coordinates are (x, y) in CIE 1931, in units of 0.00002 (50000 = 1.0).
+/// Luminance is in units of 0.0001 cd/m².
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MasteringDisplay {
+    pub red: (u16, u16),
+    pub green: (u16, u16),
+    pub blue: (u16, u16),
+    pub white_point: (u16, u16),
+    pub max_luminance: u32,
+    pub min_luminance: u32,
+}
+
+impl From<MasteringDisplay> for MdcvBox {
+    fn from(md: MasteringDisplay) -> Self {
+        Self {
+            display_primaries: [md.green, md.blue, md.red],
+            white_point: md.white_point,
+            max_display_mastering_luminance: md.max_luminance,
+            min_display_mastering_luminance: md.min_luminance,
+        }
+    }
+}
+
+/// Brightness of an HDR image in cd/m². See [`Aviffy::content_light_level`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ContentLightLevel {
+    /// Brightest pixel (MaxCLL)
+    pub max_cll: u16,
+    /// Brightest frame average (MaxFALL)
+    pub max_fall: u16,
+}
+
+/// Viewing conditions an HDR image was made for. See [`Aviffy::ambient_viewing`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AmbientViewing {
+    /// Illuminance of the surroundings, in units of 0.0001 lux
+    pub illuminance: u32,
+    /// Chromaticity (x, y) of the ambient light in CIE 1931, in units of 0.00002
+    pub light: (u16, u16),
+}
+
+/// Dolby Vision configuration of the AV1 data. See [`Aviffy::dolby_vision`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DolbyVision {
+    /// 10 for AV1
+    pub profile: u8,
+    /// 1 to 13
+    pub level: u8,
+    /// Whether the bitstream has RPU (reference processing unit) metadata
+    pub rpu_present: bool,
+    /// Whether it has an enhancement layer
+    pub el_present: bool,
+    /// Whether it has a base layer
+    pub bl_present: bool,
+    /// Which non-Dolby Vision format the base layer is compatible with, e.g. 1 for HDR10, 2 for SDR, 4 for HLG
+    pub bl_signal_compatibility_id: u8,
+}
+
+impl From<DolbyVision> for DoviBox {
+    fn from(dv: DolbyVision) -> Self {
+        Self {
+            profile: dv.profile,
+            level: dv.level,
+            rpu_present: dv.rpu_present,
+            el_present: dv.el_present,
+            bl_present: dv.bl_present,
+            bl_signal_compatibility_id: dv.bl_signal_compatibility_id,
+        }
+    }
+}
+
+/// Human-readable title and description of the image. See [`Aviffy::user_description`].
+///
+/// The strings can't contain nul characters.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct UserDescription {
+    /// Language of the text as an RFC 5646 tag, e.g. `en-US`. Can be empty if unknown.
+    pub lang: String,
+    pub name: String,
+    pub description: String,
+    /// Comma-separated keywords
+    pub tags: String,
+}
+
+impl From<UserDescription> for UdesBox {
+    fn from(ud: UserDescription) -> Self {
+        Self { lang: ud.lang, name: ud.name, description: ud.description, tags: ud.tags }
+    }
+}
+
+/// Which `colr` box to put in an animation track's sample entry.
+///
+/// See [`Aviffy::color_track_colr`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TrackColr {
+    /// Copy of the still image's color settings
+    SameAsItem,
+    /// No `colr` box in the track
+    None,
+    /// Track-specific color information
+    Custom(ColorInfo),
+}
+
+/// Counter-clockwise rotation to apply when displaying the image. See [`Aviffy::rotation`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Rotation {
+    #[default]
+    None,
+    Rotation90,
+    Rotation180,
+    Rotation270,
+}
+
+/// Visible part of the image. See [`Aviffy::clean_aperture`].
+///
+/// Values are fractions (numerator, denominator). The offsets are of the center of the visible area
+/// from the center of the image, so (0, 1) keeps it centered.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CleanAperture {
+    pub width: (u32, u32),
+    pub height: (u32, u32),
+    pub horiz_off: (i32, u32),
+    pub vert_off: (i32, u32),
+}
+
+impl CleanAperture {
+    /// Area of `width`×`height` pixels in the top-left corner of an image coded at `coded_width`×`coded_height`,
+    /// e.g. when the encoder padded the right and bottom edges to make the size even.
+    #[must_use]
+    pub fn top_left(width: u32, height: u32, coded_width: u32, coded_height: u32) -> Self {
+        // The center moves by half of the padding
+        let offset = |size: u32, coded: u32| ((i64::from(size) - i64::from(coded)) as i32, 2);
+        Self {
+            width: (width, 1),
+            height: (height, 1),
+            horiz_off: offset(width, coded_width),
+            vert_off: offset(height, coded_height),
+        }
+    }
+
+    /// Whether the area is within an image of `width`×`height` pixels
+    fn fits(&self, width: u32, height: u32) -> bool {
+        // Edges of the area, exactly, using a common denominator
+        fn fits_1d(size: u32, (ap_n, ap_d): (u32, u32), (off_n, off_d): (i32, u32)) -> bool {
+            if ap_d == 0 || off_d == 0 || ap_n == 0 {
+                return false;
+            }
+            let (size, ap_n, ap_d, off_n, off_d) = (i128::from(size), i128::from(ap_n), i128::from(ap_d), i128::from(off_n), i128::from(off_d));
+            let denom = 2 * ap_d * off_d;
+            // offset + (size - 1) / 2 - (aperture - 1) / 2
+            let start = 2 * off_n * ap_d + (size - 1) * ap_d * off_d - (ap_n - ap_d) * off_d;
+            start >= 0 && start + 2 * ap_n * off_d <= size * denom
+        }
+        fits_1d(width, self.width, self.horiz_off) && fits_1d(height, self.height, self.vert_off)
+    }
+}
+
+impl From<CleanAperture> for ClapBox {
+    fn from(ca: CleanAperture) -> Self {
+        Self { width: ca.width, height: ca.height, horiz_off: ca.horiz_off, vert_off: ca.vert_off }
+    }
+}
+
+/// Mirroring to apply when displaying the image. See [`Aviffy::mirror`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Mirror {
+    /// Left and right swapped (around the vertical axis)
+    LeftRight,
+    /// Top and bottom swapped (around the horizontal axis)
+    TopBottom,
+}
+
+/// How 360° video is mapped onto the frames. See [`Aviffy::spherical_video`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Projection {
+    /// Longitude and latitude mapped linearly to x and y (`equi`)
+    Equirectangular,
+    /// Six cube faces in the standard layout (`cbmp`)
+    Cubemap,
+}
+
+/// Arrangement of views for each eye. See [`Aviffy::spherical_video`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StereoMode {
+    Mono,
+    /// Left eye on top
+    TopBottom,
+    /// Left eye on the left
+    LeftRight,
+}
+
+impl TrackColr {
+    fn to_box(self, item_colr: ColrBox) -> Option<ColrBox> {
+        match self {
+            Self::SameAsItem => Some(item_colr),
+            Self::None => None,
+            Self::Custom(info) => Some(info.into()),
+        }
+    }
+}
+
+/// How the payloads are divided between `mdat` boxes.
+///
+/// See [`Aviffy::mdat_layout`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum MdatLayout {
+    /// Everything in one `mdat` box. This is the most compatible option.
+    #[default]
+    Single,
+    /// Metadata items (Exif) in a small `mdat` of their own, placed before the image data.
+    MetadataFirst,
+    /// Every payload (alpha, color, metadata) in a separate `mdat` box.
+    PerItem,
+}
+
+/// Current settings of [`Aviffy`], for logging and comparing configurations. See [`Aviffy::config`].
+///
+/// Boxes, blobs, renditions and track references that have been added aren't included.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Config {
+    pub premultiplied_alpha: bool,
+    /// The still image's `colr`
+    pub color: ColorInfo,
+    pub color_track_colr: TrackColr,
+    pub alpha_track_colr: TrackColr,
+    pub chroma_subsampling: constants::ChromaSubsampling,
+    pub chroma_sample_position: constants::ChromaSamplePosition,
+    /// `None` if computed from the image size
+    pub seq_level_idx: Option<u8>,
+    pub high_tier: bool,
+    /// `None` if inferred from the chroma subsampling and bit depth
+    pub seq_profile: Option<u8>,
+    pub sequence_header_in_av1c: bool,
+    /// Overrides `depth_bits` if set
+    pub bit_depth: Option<u8>,
+    /// `None` if derived automatically
+    pub color_av1c: Option<Av1CBox>,
+    /// `None` if derived automatically
+    pub alpha_av1c: Option<Av1CBox>,
+    pub alpha_urn: constants::AlphaUrn,
+    pub hold_last_frame: bool,
+    pub long_times: bool,
+    pub alpha_first: bool,
+    pub mdat_layout: MdatLayout,
+    pub spherical: Option<(Projection, StereoMode)>,
+    pub verify_alpha: bool,
+    pub verify_size: bool,
+    pub verify_sync: bool,
+    pub layout_version: u32,
+    pub nclx_with_icc: bool,
+    pub mastering_display: Option<MasteringDisplay>,
+    pub content_light_level: Option<ContentLightLevel>,
+    pub ambient_viewing: Option<AmbientViewing>,
+    pub gain_map_format: GainMapFormat,
+    pub dolby_vision: Option<DolbyVision>,
+    #[cfg(feature = "deflate")]
+    pub compress_metadata: bool,
+    pub free_padding: usize,
+    pub rotation: Rotation,
+    pub mirror: Option<Mirror>,
+    pub clean_aperture: Option<CleanAperture>,
+    pub display_size: Option<(u32, u32)>,
+    /// `None` for square pixels
+    pub pixel_aspect_ratio: Option<(u32, u32)>,
+    pub operating_point: Option<u8>,
 }
 
 /// Makes an AVIF file given encoded AV1 data (create the data with [`rav1e`](//lib.rs/rav1e))
@@ -40,6 +511,7 @@ pub struct Aviffy {
 /// Color and alpha must have the same dimensions and depth.
 ///
 /// Data is written (streamed) to `into_output`.
+#[inline]
 pub fn serialize<W: io::Write>(into_output: W, color_av1_data: &[u8], alpha_av1_data: Option<&[u8]>, width: u32, height: u32, depth_bits: u8, timescale: u32, color_frames: Option<&[FrameInfo]>, alpha_frames: Option<&[FrameInfo]>, exif_data: &[u8]) -> io::Result<()> {
     Aviffy::new().write(into_output, color_av1_data, alpha_av1_data, width, height, depth_bits, timescale, color_frames, alpha_frames, exif_data)
 }
@@ -50,9 +522,137 @@ impl Aviffy {
         Self {
             premultiplied_alpha: false,
             colr: Default::default(),
+            mdat_layout: MdatLayout::Single,
+            color_track_colr: TrackColr::SameAsItem,
+            alpha_track_colr: TrackColr::None,
+            hold_last_frame: false,
+            custom_boxes: Vec::new(),
+            chroma_subsampling: constants::ChromaSubsampling::Cs444,
+            chroma_sample_position: constants::ChromaSamplePosition::Unknown,
+            seq_level_idx: None,
+            high_tier: false,
+            seq_profile: None,
+            sequence_header_in_av1c: false,
+            bit_depth: None,
+            color_av1c: None,
+            alpha_av1c: None,
+            alpha_urn: constants::AlphaUrn::Cicp,
+            long_times: false,
+            alpha_first: true,
+            blobs: Vec::new(),
+            renditions: Vec::new(),
+            track_references: Vec::new(),
+            metadata: Vec::new(),
+            auxiliary: Vec::new(),
+            metadata_tracks: Vec::new(),
+            spherical: None,
+            verify_alpha: false,
+            verify_size: false,
+            verify_sync: false,
+            layout_version: Self::LATEST_LAYOUT_VERSION,
+            exif: Vec::new(),
+            icc_profile: None,
+            nclx_with_icc: false,
+            mastering_display: None,
+            content_light_level: None,
+            ambient_viewing: None,
+            gain_map: None,
+            gain_map_format: GainMapFormat::Iso21496,
+            dolby_vision: None,
+            #[cfg(feature = "deflate")]
+            compress_metadata: false,
+            free_padding: 0,
+            user_description: None,
+            alt_text: None,
+            rotation: Rotation::None,
+            mirror: None,
+            clean_aperture: None,
+            display_size: None,
+            pixel_aspect_ratio: None,
+            layer_sizes: Vec::new(),
+            operating_point: None,
+        }
+    }
+
+    /// Newest layout this release can write. See [`layout_version`](Self::layout_version).
+    pub const LATEST_LAYOUT_VERSION: u32 = 3;
+
+    /// Settings that have been set so far, including defaults
+    #[must_use]
+    pub fn config(&self) -> Config {
+        Config {
+            premultiplied_alpha: self.premultiplied_alpha,
+            color: self.colr.into(),
+            color_track_colr: self.color_track_colr,
+            alpha_track_colr: self.alpha_track_colr,
+            chroma_subsampling: self.chroma_subsampling,
+            chroma_sample_position: self.chroma_sample_position,
+            seq_level_idx: self.seq_level_idx,
+            high_tier: self.high_tier,
+            seq_profile: self.seq_profile,
+            sequence_header_in_av1c: self.sequence_header_in_av1c,
+            bit_depth: self.bit_depth,
+            color_av1c: self.color_av1c,
+            alpha_av1c: self.alpha_av1c,
+            alpha_urn: self.alpha_urn,
+            hold_last_frame: self.hold_last_frame,
+            long_times: self.long_times,
+            alpha_first: self.alpha_first,
+            mdat_layout: self.mdat_layout,
+            spherical: self.spherical,
+            verify_alpha: self.verify_alpha,
+            verify_size: self.verify_size,
+            verify_sync: self.verify_sync,
+            layout_version: self.layout_version,
+            nclx_with_icc: self.nclx_with_icc,
+            mastering_display: self.mastering_display,
+            content_light_level: self.content_light_level,
+            ambient_viewing: self.ambient_viewing,
+            gain_map_format: self.gain_map_format,
+            dolby_vision: self.dolby_vision,
+            #[cfg(feature = "deflate")]
+            compress_metadata: self.compress_metadata,
+            free_padding: self.free_padding,
+            rotation: self.rotation,
+            mirror: self.mirror,
+            clean_aperture: self.clean_aperture,
+            display_size: self.display_size,
+            pixel_aspect_ratio: self.pixel_aspect_ratio,
+            operating_point: self.operating_point,
         }
     }
 
+    /// Whether the settings allow writing a still image without alpha with the [fast path](Self::write).
+    ///
+    /// All fields are listed, so a new one has to be added here: either the fast path writes it,
+    /// it's used only for alpha, animations or items that don't take the fast path anyway, or it must be unset.
+    pub(crate) fn is_default_for_still(&self) -> bool {
+        let Self {
+            // Written by the fast path
+            colr: _, chroma_subsampling: _, chroma_sample_position: _, seq_level_idx: _, high_tier: _,
+            bit_depth: _, color_av1c: _, layout_version: _, verify_size: _,
+            // Used only for alpha, animations, or items that are checked below
+            premultiplied_alpha: _, mdat_layout: _, color_track_colr: _, alpha_track_colr: _, hold_last_frame: _,
+            alpha_av1c: _, alpha_urn: _, long_times: _, alpha_first: _, track_references: _, spherical: _,
+            verify_alpha: _, verify_sync: _, nclx_with_icc: _, gain_map_format: _,
+            #[cfg(feature = "deflate")]
+            compress_metadata: _,
+            // Must be unset
+            custom_boxes, seq_profile, sequence_header_in_av1c, blobs, renditions, metadata, auxiliary, metadata_tracks,
+            exif, icc_profile, mastering_display, content_light_level, ambient_viewing, gain_map, dolby_vision,
+            free_padding, user_description, alt_text, rotation, mirror, clean_aperture, display_size,
+            pixel_aspect_ratio, layer_sizes, operating_point,
+        } = self;
+        !custom_boxes.iter().any(|(location, _)| *location == CustomBoxLocation::Meta)
+            && seq_profile.is_none() && !sequence_header_in_av1c
+            && blobs.is_empty() && renditions.is_empty() && metadata.is_empty() && auxiliary.is_empty() && metadata_tracks.is_empty()
+            && exif.is_empty() && icc_profile.is_none() && mastering_display.is_none() && content_light_level.is_none()
+            && ambient_viewing.is_none() && gain_map.is_none() && dolby_vision.is_none()
+            && *free_padding == 0 && user_description.is_none() && alt_text.is_none() && *rotation == Rotation::None
+            && mirror.is_none() && clean_aperture.is_none() && display_size.is_none()
+            && pixel_aspect_ratio.is_none() && layer_sizes.is_empty() && operating_point.is_none()
+    }
+
     /// Set whether image's colorspace uses premultiplied alpha, i.e. RGB channels were multiplied by their alpha value,
     /// so that transparent areas are all black. Image decoders will be instructed to undo the premultiplication.
     ///
@@ -87,6 +687,24 @@ impl Aviffy {
         self
     }
 
+    /// Sets color primaries, transfer characteristics, matrix coefficients and range at once.
+    ///
+    /// They must match the AV1 color payload. A `colr` box is written for presets other than [`Srgb`](constants::ColorSpacePreset::Srgb).
+    pub fn color_space_preset(&mut self, preset: constants::ColorSpacePreset) -> &mut Self {
+        self.colr = preset.color_info().into();
+        self
+    }
+
+    /// Writes a `colr` box with all CICP values unspecified (2/2/2), so that decoders use values from the AV1 payload.
+    ///
+    /// Without a `colr` box some decoders (Safari) assume BT.601 instead.
+    pub fn unspecified_color(&mut self) -> &mut Self {
+        self.colr.color_primaries = constants::ColorPrimaries::Unspecified;
+        self.colr.transfer_characteristics = constants::TransferCharacteristics::Unspecified;
+        self.colr.matrix_coefficients = constants::MatrixCoefficients::Unspecified;
+        self
+    }
+
     /// If set, must match the AV1 color payload, and will result in `colr` box added to AVIF.
     /// Defaults to full.
     pub fn full_color_range(&mut self, full_range: bool) -> &mut Self {
@@ -94,537 +712,4043 @@ impl Aviffy {
         self
     }
 
-    /// Makes an AVIF file given encoded AV1 data (create the data with [`rav1e`](//lib.rs/rav1e))
+    /// Embeds an ICC profile in a `colr` box of `prof` type, for colorspaces that CICP values can't express.
+    /// An empty profile removes it.
     ///
-    /// `color_av1_data` is already-encoded AV1 image data for the color channels (YUV, RGB, etc.).
-    /// The color image MUST have been encoded without chroma subsampling AKA YUV444 (`Cs444` in `rav1e`)
-    /// AV1 handles full-res color so effortlessly, you should never need chroma subsampling ever again.
+    /// The profile replaces the CICP `colr` box of the still image, unless [`nclx_with_icc`](Self::nclx_with_icc) is set.
+    /// Animation tracks keep their own `colr` (see [`color_track_colr`](Self::color_track_colr)).
+    /// The AV1 payload's matrix coefficients and range still apply to the YUV to RGB conversion.
+    pub fn icc_profile(&mut self, icc_data: impl Into<Vec<u8>>) -> &mut Self {
+        let icc_data = icc_data.into();
+        self.icc_profile = if icc_data.is_empty() { None } else { Some(icc_data.into()) };
+        self
+    }
+
+    /// Writes the CICP `colr` box (`nclx`) in addition to the [ICC profile](Self::icc_profile), instead of only the profile.
     ///
-    /// Optional `alpha_av1_data` is a monochrome image (`rav1e` calls it "YUV400"/`Cs400`) representing transparency.
-    /// Alpha adds a lot of header bloat, so don't specify it unless it's necessary.
+    /// Decoders that understand only one of them still get correct color, as long as both describe the same colorspace.
+    pub fn nclx_with_icc(&mut self, both: bool) -> &mut Self {
+        self.nclx_with_icc = both;
+        self
+    }
+
+    /// Writes an `mdcv` property with the color volume of the mastering display, for HDR images (e.g. HDR10).
+    /// `None` removes it.
+    pub fn mastering_display(&mut self, mastering_display: Option<MasteringDisplay>) -> &mut Self {
+        self.mastering_display = mastering_display;
+        self
+    }
+
+    /// Writes a `clli` property with MaxCLL and MaxFALL of an HDR image. `None` removes it.
+    pub fn content_light_level(&mut self, content_light_level: Option<ContentLightLevel>) -> &mut Self {
+        self.content_light_level = content_light_level;
+        self
+    }
+
+    /// Writes an `amve` property with the ambient viewing environment the HDR image was mastered for,
+    /// e.g. 5 lux and D65 (`illuminance: 50000, light: (15635, 16450)`) for a dim room. `None` removes it.
+    pub fn ambient_viewing(&mut self, ambient_viewing: Option<AmbientViewing>) -> &mut Self {
+        self.ambient_viewing = ambient_viewing;
+        self
+    }
+
+    /// Writes a `udes` property with the name, description and tags of the primary image,
+    /// e.g. a title for content management systems. `None` removes it.
     ///
-    /// `width`/`height` is image size in pixels. It must of course match the size of encoded image data.
-    /// `depth_bits` should be 8, 10 or 12, depending on how the image has been encoded in AV1.
+    /// Fails when writing if any of the strings contains a nul character.
+    pub fn user_description(&mut self, user_description: Option<UserDescription>) -> &mut Self {
+        self.user_description = user_description;
+        self
+    }
+
+    /// Writes an `altt` property with a text alternative of the primary image for screen readers,
+    /// in the language given as an RFC 5646 tag (e.g. `en`). Empty `text` removes it.
     ///
-    /// Color and alpha must have the same dimensions and depth.
+    /// Other items can have alt text too, see [`Composition::add_alt_text`]. Fails when writing if the text contains a nul character.
+    pub fn alt_text(&mut self, text: &str, lang: &str) -> &mut Self {
+        self.alt_text = (!text.is_empty()).then(|| AlttBox { alt_text: text.into(), alt_lang: lang.into() });
+        self
+    }
+
+    /// Writes a `pasp` property (and a `pasp` box in the color track) for images with non-square pixels,
+    /// e.g. `pixel_aspect_ratio(4, 3)` makes pixels displayed 4/3 times wider than tall. Equal values (square pixels) remove it.
     ///
-    /// Data is written (streamed) to `into_output`.
-    pub fn write<W: io::Write>(&self, into_output: W, color_av1_data: &[u8], alpha_av1_data: Option<&[u8]>, width: u32, height: u32, depth_bits: u8, timescale: u32, color_frames: Option<&[FrameInfo]>, alpha_frames: Option<&[FrameInfo]>, exif_data: &[u8]) -> io::Result<()> {
-        self.make_boxes(color_av1_data, alpha_av1_data, width, height, depth_bits, timescale, color_frames, alpha_frames, exif_data).write(into_output)
+    /// Fails when writing if either value is 0.
+    pub fn pixel_aspect_ratio(&mut self, num: u32, den: u32) -> &mut Self {
+        self.pixel_aspect_ratio = (num != den).then_some((num, den));
+        self
     }
 
-    fn make_boxes<'data>(&self, color_av1_data: &'data [u8], alpha_av1_data: Option<&'data [u8]>, width: u32, height: u32, depth_bits: u8, timescale: u32, color_frames: Option<&[FrameInfo]>, alpha_frames: Option<&[FrameInfo]>, exif_data: &[u8]) -> AvifFile<'data> {
-        let mut image_items = ArrayVec::new();
-        let mut iloc_items = ArrayVec::new();
-        let mut compatible_brands = vec![];
-        let mut ipma_entries = ArrayVec::new();
-        let mut data_chunks = ArrayVec::new();
-        let mut irefs = ArrayVec::new();
-        let mut ipco = IpcoBox::new();
-        let color_image_id = 1;
-        let alpha_image_id = 2;
-        let exif_image_id = match alpha_av1_data {
-            Some(_) => 3,
-            _ => 2
-        };
-        const ESSENTIAL_BIT: u8 = 0x80;
-        let color_depth_bits = depth_bits;
-        let alpha_depth_bits = depth_bits; // Sadly, the spec requires these to match.
+    /// Marks the color image as spatially layered, with the byte size of each layer of its AV1 data, from the lowest quality one.
+    /// Browsers can then show the first layers while the rest is still loading (`a1lx` and `lsel` properties).
+    ///
+    /// There can be 2 to 4 layers, and an empty list removes it. Fails when writing if they don't add up to the size of the color AV1 data.
+    pub fn layer_sizes(&mut self, layer_sizes: &[usize]) -> &mut Self {
+        self.layer_sizes = layer_sizes.to_vec();
+        self
+    }
 
-        image_items.push(InfeBox {
-            id: color_image_id,
-            typ: FourCC(*b"av01"),
-            name: "Color",
-        });
-        let ispe_prop = ipco.push(IpcoProp::Ispe(IspeBox { width, height }));
-        // Useless bloat
-        let pixi_3 = ipco.push(IpcoProp::Pixi(PixiBox {
-            channels: 3,
-            depth: color_depth_bits,
-        }));
-        let color_config = Av1CBox {
-            seq_profile: if color_depth_bits >= 12 { 2 } else { 1 },
-            seq_level_idx_0: 31,
-            seq_tier_0: false,
-            high_bitdepth: color_depth_bits >= 10,
-            twelve_bit: color_depth_bits >= 12,
-            monochrome: false,
-            chroma_subsampling_x: false,
-            chroma_subsampling_y: false,
-            chroma_sample_position: 0,
-        };
-        // This is redundant, but Chrome wants it, and checks that it matches :(
-        let av1c_color_prop = ipco.push(IpcoProp::Av1C(color_config));
-        let mut prop_ids: ArrayVec<u8, 5> = [ispe_prop, pixi_3, av1c_color_prop | ESSENTIAL_BIT].into_iter().collect();
-        // Redundant info, already in AV1
-        if self.colr != Default::default() {
-            let colr_color_prop = ipco.push(IpcoProp::Colr(self.colr));
-            prop_ids.push(colr_color_prop);
-        }
-        ipma_entries.push(IpmaEntry {
-            item_id: color_image_id,
-            prop_ids,
-        });
+    /// Writes an `a1op` property that makes decoders use the given operating point (0-31) of color AV1 data that has several,
+    /// instead of the first one. `None` removes it.
+    ///
+    /// Fails when writing if it's larger than 31.
+    pub fn operating_point(&mut self, op_index: Option<u8>) -> &mut Self {
+        self.operating_point = op_index;
+        self
+    }
 
-        let alpha_config = Av1CBox {
-            seq_profile: if alpha_depth_bits >= 12 { 2 } else { 0 },
-            seq_level_idx_0: 31,
-            seq_tier_0: false,
-            high_bitdepth: alpha_depth_bits >= 10,
-            twelve_bit: alpha_depth_bits >= 12,
-            monochrome: true,
-            chroma_subsampling_x: true,
-            chroma_subsampling_y: true,
-            chroma_sample_position: 0,
-        };
+    fn pasp(&self) -> Option<PaspBox> {
+        self.pixel_aspect_ratio.map(|(h_spacing, v_spacing)| PaspBox { h_spacing, v_spacing })
+    }
 
-        if let Some(alpha_data) = alpha_av1_data {
-            image_items.push(InfeBox {
-                id: alpha_image_id,
-                typ: FourCC(*b"av01"),
-                name: "Alpha",
-            });
-            // So pointless
-            let pixi_1 = ipco.push(IpcoProp::Pixi(PixiBox {
-                channels: 1,
-                depth: alpha_depth_bits,
-            }));
-            let av1c_alpha_prop = ipco.push(boxes::IpcoProp::Av1C(alpha_config));
+    /// Writes a `clap` property, which makes decoders crop the image to the given area when displaying it,
+    /// e.g. when the encoder padded it. The size given when writing is of the image before cropping. `None` removes it.
+    ///
+    /// Cropping is applied before [`rotation`](Self::rotation) and [`mirror`](Self::mirror).
+    /// It applies to the still image and its alpha, not to animation tracks.
+    ///
+    /// Fails when writing if the area doesn't fit in the image, or has a 0 denominator.
+    pub fn clean_aperture(&mut self, clean_aperture: Option<CleanAperture>) -> &mut Self {
+        self.clean_aperture = clean_aperture;
+        self.display_size = None;
+        self
+    }
 
-            // that's a silly way to add 1 bit of information, isn't it?
-            let auxc_prop = ipco.push(IpcoProp::AuxC(AuxCBox {
-                urn: "urn:mpeg:mpegB:cicp:systems:auxiliary:alpha",
-            }));
-            irefs.push(IrefBox {
-                entry: IrefEntryBox {
-                    from_id: alpha_image_id,
-                    to_id: color_image_id,
-                    typ: FourCC(*b"auxl"),
-                },
-            });
-            if self.premultiplied_alpha {
-                irefs.push(IrefBox {
-                    entry: IrefEntryBox {
-                        from_id: color_image_id,
-                        to_id: alpha_image_id,
-                        typ: FourCC(*b"prem"),
-                    },
-                });
-            }
-            ipma_entries.push(IpmaEntry {
-                item_id: alpha_image_id,
-                prop_ids: [ispe_prop, pixi_1, av1c_alpha_prop | ESSENTIAL_BIT, auxc_prop].into_iter().collect(),
-            });
+    /// Crops the image to `width`×`height` when displayed, for AV1 data that was encoded at a larger size
+    /// (e.g. rounded up to even dimensions for 4:2:0). Give the coded size when writing, and this writes
+    /// the matching [`clean_aperture`](Self::clean_aperture) for the top-left corner. `None` removes it.
+    ///
+    /// Fails when writing if the display size is larger than the coded size.
+    pub fn display_size(&mut self, display_size: Option<(u32, u32)>) -> &mut Self {
+        self.display_size = display_size;
+        self.clean_aperture = None;
+        self
+    }
 
-            // Use interleaved color and alpha, with alpha first.
-            // Makes it possible to display partial image.
-            iloc_items.push(IlocItem {
-                id: color_image_id,
-                extents: [
-                    IlocExtent {
-                        offset: IlocOffset::Relative(alpha_data.len()),
-                        len: color_av1_data.len(),
-                    },
-                ].into(),
-            });
-            iloc_items.push(IlocItem {
-                id: alpha_image_id,
-                extents: [
-                    IlocExtent {
-                        offset: IlocOffset::Relative(0),
-                        len: alpha_data.len(),
-                    },
-                ].into(),
-            });
-            data_chunks.push(alpha_data);
-            data_chunks.push(color_av1_data);
-        } else {
-            iloc_items.push(IlocItem {
-                id: color_image_id,
-                extents: [
-                    IlocExtent {
-                        offset: IlocOffset::Relative(0),
-                        len: color_av1_data.len(),
-                    },
-                ].into(),
-            });
-            data_chunks.push(color_av1_data);
-        };
+    /// Writes an `irot` property, which makes decoders rotate the image counter-clockwise when displaying it,
+    /// e.g. to show a phone photo upright without re-encoding it. The size is of the image before rotation.
+    ///
+    /// It applies to the still image and its alpha, not to animation tracks.
+    pub fn rotation(&mut self, rotation: Rotation) -> &mut Self {
+        self.rotation = rotation;
+        self
+    }
 
-        if exif_data.len() > 0 {
-            image_items.push(InfeBox {
-                id: exif_image_id,
-                typ: FourCC(*b"Exif"),
-                name: "Exif",
-            });
-            iloc_items.push(IlocItem {
-                id: exif_image_id,
-                extents: [
-                    IlocExtent {
-                        offset: IlocOffset::Relative(match alpha_av1_data {
-                            Some(alpha_data) => alpha_data.len(),
-                            _ => 0
-                        } + color_av1_data.len()),
-                        len: exif_data.len(),
-                    },
-                ].into(),
-            });
-            irefs.push(IrefBox {
-                entry: IrefEntryBox {
-                    from_id: exif_image_id,
-                    to_id: color_image_id,
-                    typ: FourCC(*b"cdsc"),
-                },
-            });
-        }
+    /// Writes an `imir` property, which makes decoders mirror the image when displaying it. `None` removes it.
+    ///
+    /// Mirroring is applied after the [`rotation`](Self::rotation), so together they can express all 8 Exif orientations.
+    /// It applies to the still image and its alpha, not to animation tracks.
+    pub fn mirror(&mut self, mirror: Option<Mirror>) -> &mut Self {
+        self.mirror = mirror;
+        self
+    }
 
-        let mut moov_box: Option<MoovBox> = None;
-        if let Some(_color_frames) = color_frames {
-            /*let now = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
-                Ok(n) => n.as_secs() + 2082844800, // Seconds since 1904-01-01
-                Err(_) => 0
-            };*/
-            let now = 0;
-            let mut media_duration = 0;
-            for frame in _color_frames {
-                media_duration += frame.duration_in_timescales;
-            }
+    /// Writes a Dolby Vision configuration (`dvvC` for profile 10) for the color image and the color track,
+    /// so that Dolby Vision players use the RPU metadata in the AV1 data. `None` removes it.
+    ///
+    /// Fails when writing if the profile, level or compatibility id doesn't fit in its field.
+    pub fn dolby_vision(&mut self, dolby_vision: Option<DolbyVision>) -> &mut Self {
+        self.dolby_vision = dolby_vision;
+        self
+    }
 
-            let mut stts_sample_delta: Vec<ArrayVec<u32, 2>> = vec![];
-            let mut sample_count: u32 = 0;
-            let mut sync_sample_count: u32 = 0;
-            let mut sample_number: Vec<u32> = vec![];
-            for i in 0.._color_frames.len() {
-                if _color_frames[i].sync {
-                    sync_sample_count += 1;
-                    sample_number.push((i + 1) as u32)
-                }
+    /// Color information of the color track in animations, independent of the still image item.
+    /// Defaults to [`TrackColr::SameAsItem`].
+    pub fn color_track_colr(&mut self, colr: TrackColr) -> &mut Self {
+        self.color_track_colr = colr;
+        self
+    }
 
-                sample_count += 1;
-                if i < (_color_frames.len() - 1) {
-                    if _color_frames[i].duration_in_timescales == _color_frames[i + 1].duration_in_timescales {
-                        continue;
-                    }
-                }
+    /// Color information of the alpha track in animations.
+    /// Defaults to [`TrackColr::None`], since alpha has no color.
+    pub fn alpha_track_colr(&mut self, colr: TrackColr) -> &mut Self {
+        self.alpha_track_colr = colr;
+        self
+    }
 
-                let mut sample: ArrayVec<u32, 2> = ArrayVec::new();
-                sample.push(sample_count);
-                sample.push(_color_frames[i].duration_in_timescales as u32);
-                stts_sample_delta.push(sample);
+    /// Must match the AV1 color payload. Defaults to no subsampling (4:4:4).
+    ///
+    /// Alpha is always monochrome, and is not affected by this setting.
+    pub fn chroma_subsampling(&mut self, chroma_subsampling: constants::ChromaSubsampling) -> &mut Self {
+        self.chroma_subsampling = chroma_subsampling;
+        self
+    }
 
-                sample_count = 0;
+    /// Must match the AV1 color payload's `chroma_sample_position`, otherwise strict decoders shift the chroma.
+    /// Defaults to unknown.
+    ///
+    /// It's only written for 4:2:0 [`chroma_subsampling`](Self::chroma_subsampling).
+    pub fn chroma_sample_position(&mut self, position: constants::ChromaSamplePosition) -> &mut Self {
+        self.chroma_sample_position = position;
+        self
+    }
+
+    /// AV1 level written in the automatically derived `av1C`s. It should be the level in the AV1 sequence header.
+    ///
+    /// `None` (the default) uses the lowest level that allows the image's size and frame rate (see [`av1_level`]).
+    /// [Layout version](Self::layout_version) 1 writes 31 (no level constraints) instead.
+    pub fn seq_level_idx(&mut self, level: Option<u8>) -> &mut Self {
+        self.seq_level_idx = level;
+        self
+    }
+
+    /// Signals the high tier (`seq_tier_0`) in the color image's and track's `av1C`, for AV1 data encoded at high tier.
+    /// Defaults to the main tier.
+    ///
+    /// Levels below 4.0 only have the main tier, so it's not written for them.
+    pub fn high_tier(&mut self, high_tier: bool) -> &mut Self {
+        self.high_tier = high_tier;
+        self
+    }
+
+    /// AV1 profile (0 = Main, 1 = High, 2 = Professional) written in the color image's and track's `av1C`.
+    /// It must be the profile in the AV1 sequence header.
+    ///
+    /// `None` (the default) infers it from the [`chroma_subsampling`](Self::chroma_subsampling) and bit depth,
+    /// which picks the lowest profile that supports them. Fails when writing if it's larger than 2.
+    pub fn seq_profile(&mut self, profile: Option<u8>) -> &mut Self {
+        self.seq_profile = profile;
+        self
+    }
+
+    /// Copies the sequence header OBU of the color and alpha AV1 data into their `av1C`s (`configOBUs`),
+    /// which some decoders use to set up before reading `mdat`. libavif does it by default.
+    ///
+    /// Writing fails if the AV1 data has no sequence header.
+    pub fn sequence_header_in_av1c(&mut self, enabled: bool) -> &mut Self {
+        self.sequence_header_in_av1c = enabled;
+        self
+    }
+
+    /// The `configOBUs` of an `av1C` if [`sequence_header_in_av1c`](Self::sequence_header_in_av1c) is enabled.
+    /// Derived images don't have `av1C`.
+    fn config_obus(&self, av1_data: &[u8], graph: &ItemGraph<'_>) -> io::Result<Vec<u8>> {
+        if !self.sequence_header_in_av1c || graph.derived.is_some() {
+            return Ok(Vec::new());
+        }
+        Ok(obu::sequence_header_obu(av1_data)?.raw.to_vec())
+    }
+
+    /// If set, overrides `depth_bits` given to the `write`/`to_vec` functions.
+    pub fn bit_depth(&mut self, depth_bits: u8) -> &mut Self {
+        self.bit_depth = Some(depth_bits);
+        self
+    }
+
+    /// Replaces the automatically derived `av1C` of the color image and track.
+    /// It's derived from the sequence header of the AV1 data if there is one, or else from the settings.
+    ///
+    /// Browsers check that it matches the AV1 sequence header, so it must be accurate.
+    pub fn color_av1c(&mut self, av1c: Av1CBox) -> &mut Self {
+        self.color_av1c = Some(av1c);
+        self
+    }
+
+    /// Replaces the automatically derived `av1C` of the alpha image and track.
+    pub fn alpha_av1c(&mut self, av1c: Av1CBox) -> &mut Self {
+        self.alpha_av1c = Some(av1c);
+        self
+    }
+
+    /// URN of the alpha image's `auxC` property. Defaults to the one from the AVIF spec.
+    ///
+    /// Animation tracks always use the AVIF one.
+    pub fn alpha_urn(&mut self, urn: constants::AlphaUrn) -> &mut Self {
+        self.alpha_urn = urn;
+        self
+    }
+
+    /// Attaches a monochrome auxiliary image of `width`×`height` pixels to the color image, e.g. a depth map,
+    /// disparity map or segmentation mask, identified by the `urn` in its `auxC` property.
+    ///
+    /// It has the bit depth of the color image. Images are added, not replaced, and are separate from alpha.
+    pub fn add_auxiliary(&mut self, urn: &str, av1_data: impl Into<Vec<u8>>, width: u32, height: u32) -> &mut Self {
+        self.auxiliary.push(Auxiliary {
+            image: GraphItem { typ: *b"av01", name: "Aux".into(), content_type: None, content_encoding: None, data: av1_data.into().into(), is_metadata: false },
+            urn: urn.into(),
+            width,
+            height,
+        });
+        self
+    }
+
+    /// Parse the sequence header of `alpha_av1_data` when writing, and fail with `InvalidInput`
+    /// if it's not a monochrome image of the given size. See [`check_alpha`](Self::check_alpha).
+    ///
+    /// Off by default. Color data passed as alpha by mistake makes files that decoders reject or show incorrectly.
+    pub fn verify_alpha(&mut self, verify: bool) -> &mut Self {
+        self.verify_alpha = verify;
+        self
+    }
+
+    /// Parse the sequence header of `color_av1_data` when writing, and fail with `InvalidInput`
+    /// if `width`/`height` aren't the coded size. See [`check_size`](Self::check_size).
+    ///
+    /// Off by default. Catches swapped width and height, which make images stretched or rejected by decoders.
+    pub fn verify_size(&mut self, verify: bool) -> &mut Self {
+        self.verify_size = verify;
+        self
+    }
+
+    /// Parse the frames of animations when writing, and fail with `InvalidInput` if [`FrameInfo::sync`]
+    /// isn't set exactly for the key frames. See [`check_sync`](Self::check_sync).
+    ///
+    /// Off by default. A wrong sync sample table (`stss`) makes seeking show broken frames.
+    pub fn verify_sync(&mut self, verify: bool) -> &mut Self {
+        self.verify_sync = verify;
+        self
+    }
+
+    /// Keep showing the last frame of an animation indefinitely after it ends.
+    ///
+    /// This is expressed with an edit list that dwells on the last frame, and an indefinite movie duration,
+    /// so there's no need to give the last frame a huge duration (`stts` can only store 32-bit durations anyway).
+    pub fn hold_last_frame(&mut self, hold: bool) -> &mut Self {
+        self.hold_last_frame = hold;
+        self
+    }
+
+    /// Add a box that this crate doesn't support itself. Boxes are written in the order they were added.
+    ///
+    /// It's up to you to ensure the box is valid in the given location.
+    pub fn custom_box(&mut self, location: CustomBoxLocation, custom_box: impl CustomBox + Send + Sync + 'static) -> &mut Self {
+        self.custom_boxes.push((location, ExternalBox(Arc::new(custom_box))));
+        self
+    }
+
+    /// The [`seq_level_idx`](Self::seq_level_idx) setting, or the lowest level for the size
+    pub(crate) fn level(&self, width: u32, height: u32, frames_per_second: Option<f64>) -> u8 {
+        self.seq_level_idx.unwrap_or_else(|| if self.layout_version >= 2 { av1_level(width, height, frames_per_second) } else { 31 })
+    }
+
+    /// `av1C` of the color image, either set explicitly or matching the settings
+    fn color_config(&self, depth_bits: u8, level: u8) -> Av1CBox {
+        // Useless bloat
+        let (monochrome, chroma_subsampling_x, chroma_subsampling_y) = match self.chroma_subsampling {
+            constants::ChromaSubsampling::Cs444 => (false, false, false),
+            constants::ChromaSubsampling::Cs422 => (false, true, false),
+            constants::ChromaSubsampling::Cs420 => (false, true, true),
+            constants::ChromaSubsampling::Cs400 => (true, true, true),
+        };
+        self.color_av1c.unwrap_or(Av1CBox {
+            // Main profile is 4:2:0 and mono, High is 4:4:4, and Professional is 4:2:2 and 12-bit
+            seq_profile: self.seq_profile.unwrap_or(match (depth_bits >= 12, chroma_subsampling_x, chroma_subsampling_y) {
+                (true, _, _) | (_, true, false) => 2,
+                (_, true, true) => 0,
+                _ => 1,
+            }),
+            seq_level_idx_0: level,
+            seq_tier_0: self.high_tier && level > 7,
+            high_bitdepth: depth_bits >= 10,
+            twelve_bit: depth_bits >= 12,
+            monochrome,
+            chroma_subsampling_x,
+            chroma_subsampling_y,
+            // The sequence header only has it for 4:2:0
+            chroma_sample_position: if self.chroma_subsampling == constants::ChromaSubsampling::Cs420 { self.chroma_sample_position as u8 } else { 0 },
+        })
+    }
+
+    fn custom_boxes_in(&self, location: CustomBoxLocation) -> Vec<ExternalBox> {
+        self.custom_boxes.iter().filter(|(l, _)| *l == location).map(|(_, b)| b.clone()).collect()
+    }
+
+    /// Always use 64-bit times and durations in animation's `mvhd`/`tkhd`/`mdhd` boxes (version 1).
+    ///
+    /// By default the smaller version 0 boxes are used whenever the values fit in 32 bits.
+    pub fn long_times(&mut self, always_64bit: bool) -> &mut Self {
+        self.long_times = always_64bit;
+        self
+    }
+
+    /// Order of color and alpha data in the file. Defaults to alpha first, which allows
+    /// progressive decoders to display the image as soon as the color data arrives.
+    ///
+    /// Color first gives the fastest preview for decoders that can show the image without alpha.
+    pub fn alpha_first(&mut self, alpha_first: bool) -> &mut Self {
+        self.alpha_first = alpha_first;
+        self
+    }
+
+    /// Stores arbitrary data in the file, such as encoder name, version and settings,
+    /// to make files reproducible and easier to debug. Decoders ignore it.
+    pub fn embed_blob(&mut self, location: BlobLocation, data: impl Into<Vec<u8>>) -> &mut Self {
+        self.blobs.push((location, data.into()));
+        self
+    }
+
+    /// Reserves `len` bytes (plus an 8-byte box header) in a `free` box right after `meta`, so that tools
+    /// can add Exif, XMP or C2PA to the file later by growing `meta` into that space, without moving the image data.
+    ///
+    /// 0 (the default) adds no box.
+    pub fn free_padding(&mut self, len: usize) -> &mut Self {
+        self.free_padding = len;
+        self
+    }
+
+    /// Adds a smaller version of the image (e.g. medium size or a thumbnail) as another AV1 item.
+    ///
+    /// It must be encoded like the color image (same depth, chroma subsampling and colors), but at `width`×`height`.
+    /// The full-size image remains the primary item. Renditions have no alpha, and aren't added to animation tracks.
+    pub fn add_rendition(&mut self, av1_data: impl Into<Vec<u8>>, width: u32, height: u32, link: RenditionLink) -> &mut Self {
+        self.renditions.push(Rendition { typ: *b"av01", data: av1_data.into(), width, height, link });
+        self
+    }
+
+    /// Adds a baseline JPEG version of the image as a `jpeg` item, for software that can parse HEIF, but can't decode AV1.
+    ///
+    /// `width`×`height` must be the size of the JPEG. It's linked to the primary image like [renditions](Self::add_rendition),
+    /// and stored with them.
+    pub fn add_jpeg_preview(&mut self, jpeg_data: impl Into<Vec<u8>>, width: u32, height: u32, link: RenditionLink) -> &mut Self {
+        self.renditions.push(Rendition { typ: *b"jpeg", data: jpeg_data.into(), width, height, link });
+        self
+    }
+
+    /// Embeds Exif metadata (orientation, capture time, camera info), as an `Exif` item describing the color image.
+    /// It's the TIFF structure, starting with `II` or `MM`, without a JPEG's `Exif\0\0` prefix.
+    ///
+    /// It's used when the `exif_data` argument of [`write`](Self::write) and similar methods is empty,
+    /// so one `Aviffy` can add the same Exif to many images. Setting it again replaces the previous data.
+    pub fn exif(&mut self, exif_data: impl Into<Vec<u8>>) -> &mut Self {
+        self.exif = exif_data.into();
+        self
+    }
+
+    fn exif_or_default<'a>(&'a self, exif_data: &'a [u8]) -> &'a [u8] {
+        if exif_data.is_empty() { &self.exif } else { exif_data }
+    }
+
+    /// Embeds an XMP packet, as a `mime` item of `application/rdf+xml` type describing the color image.
+    /// Setting it again replaces the previous packet.
+    pub fn xmp(&mut self, xmp_data: impl Into<Vec<u8>>) -> &mut Self {
+        self.set_metadata(XMP_CONTENT_TYPE, "XMP", xmp_data.into())
+    }
+
+    /// Embeds IPTC-IIM records (photo captions, credits, keywords), as a `mime` item of `application/x-iptc` type
+    /// describing the color image. Setting it again replaces the previous data.
+    ///
+    /// IPTC Core, which is XMP-based, goes into the XMP packet instead.
+    pub fn iptc(&mut self, iptc_data: impl Into<Vec<u8>>) -> &mut Self {
+        self.set_metadata(IPTC_CONTENT_TYPE, "IPTC", iptc_data.into())
+    }
+
+    /// Embeds a C2PA manifest store (content credentials) in JUMBF format, as a `mime` item of `application/c2pa` type
+    /// describing the color image. Setting it again replaces the previous manifest.
+    ///
+    /// The manifest's hard binding has to exclude the item's data, which can be found in `iloc`.
+    pub fn c2pa_manifest(&mut self, jumbf_data: impl Into<Vec<u8>>) -> &mut Self {
+        self.set_metadata(C2PA_CONTENT_TYPE, "C2PA", jumbf_data.into())
+    }
+
+    /// Makes room for a C2PA manifest of exactly `len` bytes that will be signed after the file is written.
+    /// Replaces the previous manifest.
+    ///
+    /// Write the file with [`write_with_c2pa_range`](Self::write_with_c2pa_range), sign it excluding the returned range,
+    /// and overwrite that range with the manifest, padded to `len` bytes.
+    pub fn reserve_c2pa_manifest(&mut self, len: usize) -> &mut Self {
+        self.c2pa_manifest(vec![0; len])
+    }
+
+    /// Embeds any data as a `mime` item of the `content_type` (e.g. `application/json`) describing the color image.
+    ///
+    /// Items are added, not replaced, so there can be many of the same type.
+    pub fn add_metadata_item(&mut self, content_type: &str, data: impl Into<Vec<u8>>) -> &mut Self {
+        self.push_metadata(content_type, "Metadata", data.into())
+    }
+
+    /// Replaces the metadata item of the same content type
+    fn set_metadata(&mut self, content_type: &str, name: &str, data: Vec<u8>) -> &mut Self {
+        self.metadata.retain(|item| item.content_type.as_deref() != Some(content_type));
+        self.push_metadata(content_type, name, data)
+    }
+
+    fn push_metadata(&mut self, content_type: &str, name: &str, data: Vec<u8>) -> &mut Self {
+        self.metadata.push(GraphItem { typ: *b"mime", name: name.into(), content_type: Some(content_type.into()), content_encoding: None, data: data.into(), is_metadata: true });
+        self
+    }
+
+    /// Data and content encoding of a metadata item as written
+    fn encode_metadata<'a>(&self, item: &'a GraphItem<'_>) -> (Cow<'a, [u8]>, Option<&'static str>) {
+        // C2PA manifests are signed and overwritten in place
+        #[cfg(feature = "deflate")]
+        if self.compress_metadata && item.content_type.as_deref() != Some(C2PA_CONTENT_TYPE) {
+            return (miniz_oxide::deflate::compress_to_vec_zlib(&item.data, 9).into(), Some("deflate"));
+        }
+        (Cow::Borrowed(&item.data[..]), item.content_encoding)
+    }
+
+    /// Compresses XMP, IPTC and other `mime` metadata items with `deflate` content encoding, regardless of whether
+    /// they're added before or after this is set.
+    ///
+    /// Not all readers support compressed items, so it's off by default. [`remux::metadata`] decompresses them.
+    ///
+    /// This is the `infe` content encoding of HEIF, not the Brotli `brob` boxes of JPEG XL, which AVIF readers don't understand.
+    /// Exif isn't compressed, because HEIF only has content encoding for `mime` items, and the `Exif` item type isn't one.
+    /// C2PA manifests are never compressed, because they're signed and overwritten in place.
+    #[cfg(feature = "deflate")]
+    pub fn compress_metadata(&mut self, compress: bool) -> &mut Self {
+        self.compress_metadata = compress;
+        self
+    }
+
+    /// Adds a `tref` reference of type `typ` from the animation track `from_track_id` to `to_track_ids`.
+    ///
+    /// The color track has id 1, and the alpha track has id 2. References of the same type are merged,
+    /// and the alpha track always has its `auxl` reference to the color track.
+    pub fn add_track_reference(&mut self, from_track_id: u32, typ: [u8; 4], to_track_ids: &[u32]) -> &mut Self {
+        self.track_references.push((from_track_id, typ, to_track_ids.to_vec()));
+        self
+    }
+
+    fn track_tref(&self, track_id: u32, mut tref: TrefBox) -> Option<TrefBox> {
+        for (_, typ, to_ids) in self.track_references.iter().filter(|r| r.0 == track_id) {
+            tref.push(FourCC(*typ), to_ids);
+        }
+        if tref.ref_types.is_empty() { None } else { Some(tref) }
+    }
+
+    /// Adds a timed metadata track to animations, with one sample for every frame of the color track, e.g. exposure,
+    /// sensor readings, or captions. Samples are raw payloads of `mime_format` type, and are described by a `mett` sample entry.
+    ///
+    /// The track has a `cdsc` reference to the color track, and the same sample durations.
+    /// Writing fails if the number of samples doesn't match the number of frames, or a sample is 4GB or larger.
+    /// Track ids follow the color and alpha tracks.
+    pub fn add_metadata_track<S: AsRef<[u8]>>(&mut self, mime_format: &str, samples: impl IntoIterator<Item = S>) -> &mut Self {
+        let mut data = Vec::new();
+        let sample_sizes = samples.into_iter().map(|sample| {
+            data.extend_from_slice(sample.as_ref());
+            sample.as_ref().len()
+        }).collect();
+        self.metadata_tracks.push(MetadataTrack { mime_format: mime_format.into(), data, sample_sizes });
+        self
+    }
+
+    /// Marks animations as 360° video, with Spherical Video V2 `st3d` and `sv3d` boxes in the color track's sample entry,
+    /// so that VR players can project it. `None` removes the marking.
+    ///
+    /// The projection has no rotation, cropping or padding. It has no effect on still images.
+    pub fn spherical_video(&mut self, projection: Option<Projection>, stereo_mode: StereoMode) -> &mut Self {
+        self.spherical = projection.map(|p| (p, stereo_mode));
+        self
+    }
+
+    fn spherical_boxes(&self) -> Option<(St3dBox, Sv3dBox)> {
+        let (projection, stereo_mode) = self.spherical?;
+        Some((St3dBox {
+            stereo_mode: match stereo_mode {
+                StereoMode::Mono => 0,
+                StereoMode::TopBottom => 1,
+                StereoMode::LeftRight => 2,
+            },
+        }, Sv3dBox {
+            projection: FourCC(match projection {
+                Projection::Equirectangular => *b"equi",
+                Projection::Cubemap => *b"cbmp",
+            }),
+        }))
+    }
+
+    /// Pins the byte layout of the output. Files written with the same layout version, settings and inputs
+    /// are byte-identical in all future releases of this crate, so their hashes can be cached.
+    /// Changes to the order or contents of boxes are only made under new version numbers.
+    ///
+    /// Defaults to [`LATEST_LAYOUT_VERSION`](Self::LATEST_LAYOUT_VERSION), which may increase in new releases.
+    /// Writing fails with `Unsupported` if the version is unknown to this release.
+    pub fn layout_version(&mut self, version: u32) -> &mut Self {
+        self.layout_version = version;
+        self
+    }
+
+    /// Split the data into multiple `mdat` boxes. `iloc` and `stco` offsets are adjusted to match.
+    ///
+    /// Defaults to a single `mdat`, which some older parsers require.
+    pub fn mdat_layout(&mut self, layout: MdatLayout) -> &mut Self {
+        self.mdat_layout = layout;
+        self
+    }
+
+    /// Makes an AVIF file given encoded AV1 data (create the data with [`rav1e`](//lib.rs/rav1e))
+    ///
+    /// `color_av1_data` is already-encoded AV1 image data for the color channels (YUV, RGB, etc.).
+    /// The color image MUST have been encoded without chroma subsampling AKA YUV444 (`Cs444` in `rav1e`),
+    /// unless a different [`chroma_subsampling`](Self::chroma_subsampling) has been set.
+    ///
+    /// Optional `alpha_av1_data` is a monochrome image (`rav1e` calls it "YUV400"/`Cs400`) representing transparency.
+    /// Alpha adds a lot of header bloat, so don't specify it unless it's necessary.
+    ///
+    /// `width`/`height` is image size in pixels. It must of course match the size of encoded image data.
+    /// `depth_bits` should be 8, 10 or 12, depending on how the image has been encoded in AV1.
+    ///
+    /// Color and alpha must have the same dimensions and depth.
+    ///
+    /// Data is written (streamed) to `into_output`.
+    ///
+    /// Still images that consist only of the color image (no alpha, Exif, renditions, blobs, or custom `meta` boxes)
+    /// are written without any heap allocations, unless writing fails.
+    #[inline]
+    pub fn write<W: io::Write>(&self, mut into_output: W, color_av1_data: &[u8], alpha_av1_data: Option<&[u8]>, width: u32, height: u32, depth_bits: u8, timescale: u32, color_frames: Option<&[FrameInfo]>, alpha_frames: Option<&[FrameInfo]>, exif_data: &[u8]) -> io::Result<()> {
+        self.write_dyn(&mut into_output, color_av1_data, alpha_av1_data, width, height, depth_bits, timescale, color_frames, alpha_frames, exif_data)
+    }
+
+    /// Same as [`write`](Self::write), but not generic.
+    ///
+    /// All the writers share one copy of the serialization code, instead of one per `io::Write` type.
+    pub fn write_dyn(&self, into_output: &mut dyn io::Write, color_av1_data: &[u8], alpha_av1_data: Option<&[u8]>, width: u32, height: u32, depth_bits: u8, timescale: u32, color_frames: Option<&[FrameInfo]>, alpha_frames: Option<&[FrameInfo]>, exif_data: &[u8]) -> io::Result<()> {
+        let exif_data = self.exif_or_default(exif_data);
+        if self.is_simple_still(alpha_av1_data, color_frames, exif_data) {
+            self.verify_input(color_av1_data, None, width, height)?;
+            return self.write_simple_still(into_output, color_av1_data, width, height, depth_bits);
+        }
+        self.make_boxes(color_av1_data, alpha_av1_data, width, height, depth_bits, timescale, color_frames, alpha_frames, exif_data, &NO_ITEMS)?.write(into_output)
+    }
+
+    /// Size of the file that [`write`](Self::write) would make from the same `image`, minus the lengths of
+    /// `color_av1_data`, `alpha_av1_data`, `exif_data` (or the [`exif`](Self::exif) set), and data of the renditions.
+    ///
+    /// It can be used to budget the file size before compressing the image, with empty slices as the AV1 data
+    /// (and `Some` empty slice if there will be alpha). Only the number, durations and sync flags of animation frames matter, not their sizes.
+    ///
+    /// Settings that read the AV1 data fail without it. [`sequence_header_in_av1c`](Self::sequence_header_in_av1c) adds the sequence
+    /// headers to the result, and [`verify_size`](Self::verify_size) and [`verify_alpha`](Self::verify_alpha) check them,
+    /// so these need the data to start with its sequence header (e.g. from the encoder's config). [`verify_sync`](Self::verify_sync) needs all the frames.
+    pub fn header_size(&self, image: &ImageData<'_>) -> io::Result<usize> {
+        let exif_data = self.exif_or_default(image.exif_data);
+        Ok(self.make_boxes(image.color_av1_data, image.alpha_av1_data, image.width, image.height, image.depth_bits,
+            image.timescale, image.color_frames, image.alpha_frames, exif_data, &NO_ITEMS)?.header_len())
+    }
+
+    /// Same as [`write`](Self::write), but also tells where every animation frame ended up in the output,
+    /// e.g. for building seek indexes or byte-range requests without parsing the file.
+    ///
+    /// The map is empty for still images.
+    pub fn write_with_sample_map(&self, into_output: &mut dyn io::Write, image: &ImageData<'_>) -> io::Result<SampleMap> {
+        let exif_data = self.exif_or_default(image.exif_data);
+        let mut file = self.make_boxes(image.color_av1_data, image.alpha_av1_data, image.width, image.height, image.depth_bits,
+            image.timescale, image.color_frames, image.alpha_frames, exif_data, &NO_ITEMS)?;
+        file.write(into_output)?;
+
+        let mut map = SampleMap::default();
+        if let Some(moov) = &file.moov {
+            let image_tracks = moov.tracks.iter().filter(|t| t.mdia.hdlr.handler_type != FourCC(*b"meta"));
+            for (track, ranges) in image_tracks.zip([&mut map.color, &mut map.alpha]) {
+                let stbl = &track.mdia.minf.stbl;
+                let mut start = match stbl.stco.chunk_offset {
+                    Some(IlocOffset::Absolute(offset)) => u64::from(offset),
+                    _ => continue,
+                };
+                ranges.extend(stbl.stsz.entry_size.iter().map(|&size| {
+                    let range = start..start + u64::from(size);
+                    start = range.end;
+                    range
+                }));
             }
+        }
+        Ok(map)
+    }
 
-            let mut stss_box: Option<StssBox> = None;
-            if sync_sample_count != _color_frames.len() as u32 {
-                stss_box = Some(StssBox { entry_count: sync_sample_count, sample_number: sample_number })
+    /// Same as [`write`](Self::write), but also tells which byte ranges of the output are needed to show the image:
+    /// all the boxes before the image data, the primary image's color and alpha data, and the first frames of an animation.
+    ///
+    /// The ranges are sorted and merged. CDNs can preload or prioritize them (e.g. with early hints) for a faster first paint.
+    pub fn write_with_first_paint_ranges(&self, into_output: &mut dyn io::Write, image: &ImageData<'_>) -> io::Result<Vec<Range<u64>>> {
+        let exif_data = self.exif_or_default(image.exif_data);
+        let mut file = self.make_boxes(image.color_av1_data, image.alpha_av1_data, image.width, image.height, image.depth_bits,
+            image.timescale, image.color_frames, image.alpha_frames, exif_data, &NO_ITEMS)?;
+        file.write(into_output)?;
+
+        let image_ids = if image.alpha_av1_data.is_some() { &[1, 2][..] } else { &[1] };
+        let mut ranges: Vec<_> = file.meta.iloc.items.iter()
+            .filter(|item| image_ids.contains(&item.id))
+            .flat_map(|item| &item.extents)
+            .filter_map(|ex| match ex.offset {
+                IlocOffset::Absolute(offset) => Some(u64::from(offset)..u64::from(offset) + ex.len as u64),
+                IlocOffset::Relative(_) => None,
+            })
+            .collect();
+        let header_end = file.mdat_payload_start_offset() - BASIC_BOX_SIZE;
+        ranges.push(0..header_end as u64);
+        if let Some(moov) = &file.moov {
+            for track in moov.tracks.iter().filter(|t| t.mdia.hdlr.handler_type != FourCC(*b"meta")) {
+                let stbl = &track.mdia.minf.stbl;
+                if let (Some(IlocOffset::Absolute(offset)), Some(&size)) = (stbl.stco.chunk_offset, stbl.stsz.entry_size.first()) {
+                    ranges.push(u64::from(offset)..u64::from(offset) + u64::from(size));
+                }
             }
+        }
 
+        ranges.sort_by_key(|r| r.start);
+        let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        Ok(merged)
+    }
 
-            moov_box = Some(MoovBox {
-                mvhd: MvhdBox {
-                    creation_time: now,
-                    modification_time: now,
-                    timescale: timescale,
-                    duration: media_duration,
-                    next_track_id: match alpha_frames {
-                        Some(_) => 2,
-                        _ => 1
-                    }
-                },
-                tracks: vec![
-                    TrakBox{
-                        tkhd: TkhdBox {
-                            creation_time: now,
-                            modification_time: now,
-                            track_id: 1,
-                            duration: media_duration,
-                            width: width << 16, 
-                            height: height << 16
-                        },
-                        tref: None, // TODO: implement
-                        /*meta: Some(MetaBox {
-                            hdlr: HdlrBox { handler_type: FourCC(*b"pict")},
-                            iinf: IinfBox { items: image_items.clone() },
-                            pitm: PitmBox(color_image_id),
-                            iloc: IlocBox { items: iloc_items.clone() },
-                            iprp: IprpBox {
-                                ipco: ipco.clone(),
-                                // It's not enough to define these properties,
-                                // they must be assigned to the image
-                                ipma: IpmaBox {
-                                    entries: ipma_entries.clone(),
-                                },
-                            },
-                            iref: irefs.clone(),
-                        }),*/
-                        meta: None,
-                        mdia: MdiaBox {
-                            mdhd: MdhdBox {
-                                creation_time: now,
-                                modification_time: now,
-                                timescale: timescale,
-                                duration: media_duration
-                            },
-                            hdlr: HdlrBox { handler_type: FourCC(*b"pict"), name: "avifser" },
-                            minf: MinfBox {
-                                vmhd: VmhdBox {},
-                                dinf: DinfBox {
-                                    dref: DrefBox { url: UrlBox {} }
-                                },
-                                stbl: StblBox {
-                                    stsd: StsdBox {
-                                        entry: SampleEntryBox {
-                                            typ: FourCC(*b"av01"),
-                                            width: width as u16,
-                                            height: height as u16,
-                                            config: color_config,
-                                            colr: Some(self.colr.clone()),
-                                            ccst: CcstBox {},
-                                            auxi: None
-                                        }
-                                    },
-                                    stts: SttsBox {
-                                        sample_delta: stts_sample_delta
-                                    },
-                                    stsc: StscBox {
-                                        samples_per_chunk: _color_frames.len() as u32
-                                    },
-                                    stsz: StszBox {
-                                        sample_count: _color_frames.len() as u32,
-                                        entry_size: _color_frames.iter().map(|x| x.size).collect::<Vec<u32>>()
-                                    },
-                                    stco: StcoBox { chunk_offset: 1 },
-                                    stss: stss_box
-                                }
-                            }
-                        }
-                    }
-                ]
+    /// Same as [`write`](Self::write), but also tells where in the output the [C2PA manifest](Self::c2pa_manifest) is,
+    /// or `None` if there's no manifest.
+    ///
+    /// Signing needs this range to exclude it from the hard binding, and to overwrite a [reserved](Self::reserve_c2pa_manifest) manifest.
+    pub fn write_with_c2pa_range(&self, into_output: &mut dyn io::Write, image: &ImageData<'_>) -> io::Result<Option<Range<u64>>> {
+        let exif_data = self.exif_or_default(image.exif_data);
+        let mut file = self.make_boxes(image.color_av1_data, image.alpha_av1_data, image.width, image.height, image.depth_bits,
+            image.timescale, image.color_frames, image.alpha_frames, exif_data, &NO_ITEMS)?;
+        file.write(into_output)?;
+
+        let Some(c2pa_id) = file.meta.iinf.items.iter().find(|infe| infe.content_type.as_deref() == Some(C2PA_CONTENT_TYPE)).map(|infe| infe.id) else {
+            return Ok(None);
+        };
+        Ok(file.meta.iloc.items.iter()
+            .filter(|item| item.id == c2pa_id)
+            .flat_map(|item| &item.extents)
+            .find_map(|ex| match ex.offset {
+                IlocOffset::Absolute(offset) => Some(u64::from(offset)..u64::from(offset) + ex.len as u64),
+                IlocOffset::Relative(_) => None,
+            }))
+    }
+
+    fn make_boxes<'data>(&'data self, color_av1_data: &'data [u8], alpha_av1_data: Option<&'data [u8]>, width: u32, height: u32, depth_bits: u8, timescale: u32, color_frames: Option<&[FrameInfo]>, alpha_frames: Option<&[FrameInfo]>, exif_data: &[u8], graph: &'data ItemGraph<'_>) -> io::Result<AvifFile<'data>> {
+        // Item ids are 16-bit, and sample numbers are 32-bit
+        if self.renditions.len() + graph.items.len() + self.metadata.len() > usize::from(u16::MAX) - 6 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "too many items"));
+        }
+        if [color_frames, alpha_frames].into_iter().flatten().any(|f| u32::try_from(f.len()).is_err()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "too many frames"));
+        }
+        if [color_frames, alpha_frames].into_iter().flatten().flatten().any(|f| f.duration_in_timescales > u32::MAX.into()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame duration too long for 32-bit durations"));
+        }
+        if color_frames.is_some() && (width > u16::MAX.into() || height > u16::MAX.into()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "animation too large for 16-bit sample entry sizes"));
+        }
+        if self.metadata_tracks.iter().any(|t| color_frames.is_none_or(|f| f.len() != t.sample_sizes.len())) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "metadata track doesn't match the color frames"));
+        }
+        // A derived image's inputs are checked instead, if at all
+        if graph.derived.is_none() {
+            self.verify_input(color_av1_data, alpha_av1_data, width, height)?;
+        }
+        self.verify_frames(color_av1_data, color_frames, alpha_av1_data, alpha_frames)?;
+        if self.dolby_vision.is_some_and(|dv| dv.profile > 127 || dv.level > 63 || dv.bl_signal_compatibility_id > 15) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Dolby Vision profile, level or compatibility id out of range"));
+        }
+        let user_description = self.user_description.clone().map(UdesBox::from);
+        let graph_descriptions = graph.properties.iter().filter_map(|(_, prop, _)| match prop {
+            IpcoProp::Udes(udes) => Some(udes),
+            _ => None,
+        });
+        if user_description.iter().chain(graph_descriptions).any(|udes| udes.has_nul()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "user description contains a nul character"));
+        }
+        if self.pixel_aspect_ratio.is_some_and(|(h, v)| h == 0 || v == 0) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "pixel aspect ratio can't be 0"));
+        }
+        if self.seq_profile.is_some_and(|profile| profile > 2) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "AV1 profile out of range"));
+        }
+        if self.operating_point.is_some_and(|op| op > 31) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "AV1 operating point out of range"));
+        }
+        if !self.layer_sizes.is_empty() && (!(2..=4).contains(&self.layer_sizes.len()) || self.layer_sizes.iter().sum::<usize>() != color_av1_data.len()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "layer sizes don't match the color AV1 data"));
+        }
+        let clean_aperture = self.clean_aperture
+            .or_else(|| self.display_size.map(|(w, h)| CleanAperture::top_left(w, h, width, height)));
+        if clean_aperture.is_some_and(|ca| !ca.fits(width, height)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "clean aperture doesn't fit in the image"));
+        }
+        let graph_alt_texts = graph.properties.iter().filter_map(|(_, prop, _)| match prop {
+            IpcoProp::Altt(altt) => Some(altt),
+            _ => None,
+        });
+        if self.alt_text.iter().chain(graph_alt_texts).any(|altt| altt.has_nul()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "alt text contains a nul character"));
+        }
+        if let Some(gm) = self.gain_map.as_ref().filter(|_| self.gain_map_format == GainMapFormat::Iso21496) {
+            gm.metadata.check()?;
+        }
+        for (_, custom_box) in &self.custom_boxes {
+            custom_box.check_len()?;
+        }
+        for (_, prop, _) in &graph.properties {
+            if let IpcoProp::Custom(custom_box) = prop {
+                custom_box.check_len()?;
+            }
+        }
+        let mut image_items = Vec::new();
+        let mut iloc_items = Vec::new();
+        let mut compatible_brands = vec![];
+        let mut ipma_entries = Vec::new();
+        let mut data_chunks = Vec::new();
+        let mut iref = IrefBox::default();
+        let mut ipco = IpcoBox::new();
+        let color_image_id = 1;
+        let alpha_image_id = 2;
+        let first_rendition_id = match alpha_av1_data {
+            Some(_) => 3,
+            _ => 2
+        };
+        let exif_image_id = first_rendition_id + self.renditions.len() as u16;
+        const ESSENTIAL_BIT: u8 = 0x80;
+        let alpha_len = alpha_av1_data.map_or(0, |a| a.len());
+        let renditions_len = self.renditions.iter().map(|r| r.data.len()).sum::<usize>();
+        let exif_len = match exif_data.len() {
+            0 => 0,
+            len => 4 + len, // header offset
+        };
+        // Graph ids stay the same, and the gain map and the file's own metadata are after them
+        let gain_map_items = self.gain_map.iter().flat_map(|gm| gm.items(self.gain_map_format));
+        let aux_items = self.auxiliary.iter().map(|aux| &aux.image);
+        // Metadata is compressed here rather than when it's added, so that the order of setters doesn't matter
+        let metadata_items = self.metadata.iter().map(|item| (item, self.encode_metadata(item)));
+        let extra_items: Vec<_> = graph.items.iter().chain(gain_map_items).chain(aux_items)
+            .map(|item| (item, (Cow::Borrowed(&item.data[..]), item.content_encoding)))
+            .chain(metadata_items).collect();
+        let extra_items_count = extra_items.len();
+        let extra_metadata_len = extra_items.iter().filter(|(i, _)| i.is_metadata).map(|(_, (data, _))| data.len()).sum::<usize>();
+        let extra_images_len = extra_items.iter().filter(|(i, _)| !i.is_metadata).map(|(_, (data, _))| data.len()).sum::<usize>()
+            + self.metadata_tracks.iter().map(|t| t.data.len()).sum::<usize>(); // stored after images
+        // Relative offsets are counted as if all the mdat boxes were one
+        let (image_data_start, exif_start) = match self.mdat_layout {
+            MdatLayout::MetadataFirst => (extra_metadata_len + exif_len, extra_metadata_len),
+            _ => (0, alpha_len + color_av1_data.len() + renditions_len + extra_images_len + extra_metadata_len),
+        };
+        let (alpha_start, color_start) = if self.alpha_first {
+            (image_data_start, image_data_start + alpha_len)
+        } else {
+            (image_data_start + color_av1_data.len(), image_data_start)
+        };
+        let depth_bits = self.bit_depth.unwrap_or(depth_bits);
+        let color_depth_bits = depth_bits;
+        let alpha_depth_bits = depth_bits; // Sadly, the spec requires these to match.
+
+        image_items.push(InfeBox {
+            id: color_image_id,
+            typ: FourCC(graph.derived.unwrap_or(*b"av01")),
+            name: "Color".into(),
+            content_type: None,
+            content_encoding: None,
+        });
+        let ispe_prop = ipco.push(IpcoProp::Ispe(IspeBox { width, height }));
+        // Sequences need a level that allows their frame rate too
+        let frames_per_second = color_frames.filter(|_| timescale > 0)
+            .and_then(|frames| frames.iter().map(sample_duration).filter(|&d| d > 0).min())
+            .map(|shortest| f64::from(timescale) / shortest as f64);
+        let level = self.level(width, height, frames_per_second);
+        // A derived image's data isn't AV1
+        let auto_color_config = match graph.derived {
+            None => self.auto_av1c(color_av1_data, self.color_av1c, color_depth_bits)?,
+            Some(_) => None,
+        };
+        let color_config = auto_color_config.unwrap_or_else(|| self.color_config(color_depth_bits, level));
+        let pixi_3 = ipco.push(IpcoProp::Pixi(PixiBox {
+            channels: if color_config.monochrome { 1 } else { 3 },
+            depth: color_depth_bits,
+        }));
+        // This is redundant, but Chrome wants it, and checks that it matches :(
+        let color_obus = self.config_obus(color_av1_data, graph)?;
+        let av1c_color_prop = ipco.push(av1c_prop(color_config, &color_obus));
+        let mut prop_ids = vec![ispe_prop, pixi_3];
+        // A derived image's inputs have it instead
+        if graph.derived.is_none() {
+            prop_ids.push(av1c_color_prop | ESSENTIAL_BIT);
+        }
+        // Redundant info, already in AV1. An item can have one `colr` of each kind.
+        // A composition can give the color image a CICP `colr` of its own, but renditions still use the settings.
+        let color_has_own_nclx = graph.properties.iter().any(|(item, prop, _)| *item == ItemRef::Color && matches!(prop, IpcoProp::Colr(_)));
+        let mut colr_color_props = ArrayVec::<u8, 2>::new();
+        if let Some(icc) = &self.icc_profile {
+            colr_color_props.push(ipco.push(IpcoProp::ColrIcc(ColrIccBox { icc: icc.clone() })));
+        }
+        prop_ids.extend_from_slice(&colr_color_props);
+        let writes_nclx = (self.icc_profile.is_none() && self.colr != Default::default()) || (self.icc_profile.is_some() && self.nclx_with_icc);
+        if writes_nclx && !(color_has_own_nclx && self.renditions.is_empty()) {
+            let nclx_prop = ipco.push(IpcoProp::Colr(self.colr));
+            colr_color_props.push(nclx_prop);
+            if !color_has_own_nclx {
+                prop_ids.push(nclx_prop);
+            }
+        }
+        if let Some(md) = self.mastering_display {
+            prop_ids.push(ipco.push(IpcoProp::Mdcv(md.into())));
+        }
+        if let Some(cll) = self.content_light_level {
+            prop_ids.push(ipco.push(IpcoProp::Clli(ClliBox { max_content_light_level: cll.max_cll, max_pic_average_light_level: cll.max_fall })));
+        }
+        if let Some(amve) = self.ambient_viewing {
+            prop_ids.push(ipco.push(IpcoProp::Amve(AmveBox { ambient_illuminance: amve.illuminance, ambient_light_x: amve.light.0, ambient_light_y: amve.light.1 })));
+        }
+        if let Some(dv) = self.dolby_vision {
+            prop_ids.push(ipco.push(IpcoProp::Dovi(dv.into())));
+        }
+        if let Some(udes) = user_description {
+            prop_ids.push(ipco.push(IpcoProp::Udes(udes)));
+        }
+        if let Some(altt) = &self.alt_text {
+            prop_ids.push(ipco.push(IpcoProp::Altt(altt.clone())));
+        }
+        let pasp_prop = self.pasp().map(|pasp| ipco.push(IpcoProp::Pasp(pasp)));
+        prop_ids.extend(pasp_prop);
+        if !self.layer_sizes.is_empty() {
+            let mut layer_sizes = [0; 3];
+            // The last layer's size is implied
+            for (size, &layer_size) in layer_sizes.iter_mut().zip(&self.layer_sizes[..self.layer_sizes.len() - 1]) {
+                *size = u32::try_from(layer_size).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "layer too large"))?;
+            }
+            prop_ids.push(ipco.push(IpcoProp::A1lx(A1lxBox { layer_sizes })));
+            // Allows decoders to show any layer
+            prop_ids.push(ipco.push(IpcoProp::Lsel(LselBox { layer_id: 0xFFFF })) | ESSENTIAL_BIT);
+        }
+        if let Some(op_index) = self.operating_point {
+            prop_ids.push(ipco.push(IpcoProp::A1op(A1opBox { op_index })) | ESSENTIAL_BIT);
+        }
+        // Transformative properties go after all the descriptive ones, and apply to alpha too
+        let mut transform_props = Vec::new();
+        if let Some(ca) = clean_aperture {
+            transform_props.push(ipco.push(IpcoProp::Clap(ca.into())) | ESSENTIAL_BIT);
+        }
+        if self.rotation != Rotation::None {
+            transform_props.push(ipco.push(IpcoProp::Irot(IrotBox { angle: self.rotation as u8 })) | ESSENTIAL_BIT);
+        }
+        if let Some(mirror) = self.mirror {
+            transform_props.push(ipco.push(IpcoProp::Imir(ImirBox { axis: mirror as u8 })) | ESSENTIAL_BIT);
+        }
+        prop_ids.extend_from_slice(&transform_props);
+        ipma_entries.push(IpmaEntry {
+            item_id: color_image_id,
+            prop_ids,
+        });
+
+        let auto_alpha_config = match alpha_av1_data {
+            Some(alpha_data) if graph.derived.is_none() => self.auto_av1c(alpha_data, self.alpha_av1c, alpha_depth_bits)?,
+            _ => None,
+        };
+        let alpha_config = auto_alpha_config.or(self.alpha_av1c).unwrap_or_else(|| monochrome_av1c(alpha_depth_bits, level));
+        let alpha_obus = match alpha_av1_data {
+            Some(alpha_data) => self.config_obus(alpha_data, graph)?,
+            None => Vec::new(),
+        };
+
+        if let Some(alpha_data) = alpha_av1_data {
+            image_items.push(InfeBox {
+                id: alpha_image_id,
+                typ: FourCC(graph.derived.unwrap_or(*b"av01")),
+                name: "Alpha".into(),
+                content_type: None,
+                content_encoding: None,
             });
-            if let Some(_alpha_frames) = alpha_frames {
-                let mut alpha_stts_sample_delta: Vec<ArrayVec<u32, 2>> = vec![];
-                let mut alpha_sample_count: u32 = 0;
-                let mut alpha_sync_sample_count: u32 = 0;
-                let mut alpha_sample_number: Vec<u32> = vec![];
-                for i in 0.._alpha_frames.len() {
-                    if _alpha_frames[i].sync {
-                        alpha_sync_sample_count += 1;
-                        alpha_sample_number.push((i + 1) as u32)
+            // So pointless
+            let pixi_1 = ipco.push(IpcoProp::Pixi(PixiBox {
+                channels: 1,
+                depth: alpha_depth_bits,
+            }));
+            let av1c_alpha_prop = ipco.push(av1c_prop(alpha_config, &alpha_obus));
+
+            // that's a silly way to add 1 bit of information, isn't it?
+            let auxc_prop = ipco.push(IpcoProp::AuxC(AuxCBox {
+                urn: self.alpha_urn.as_str().into(),
+            }));
+            iref.entries.push(IrefEntryBox {
+                from_id: alpha_image_id,
+                to_ids: vec![color_image_id],
+                typ: FourCC(*b"auxl"),
+            });
+            if self.premultiplied_alpha {
+                iref.entries.push(IrefEntryBox {
+                    from_id: color_image_id,
+                    to_ids: vec![alpha_image_id],
+                    typ: FourCC(*b"prem"),
+                });
+            }
+            let mut prop_ids = vec![ispe_prop, pixi_1];
+            if graph.derived.is_none() {
+                prop_ids.push(av1c_alpha_prop | ESSENTIAL_BIT);
+            }
+            prop_ids.push(auxc_prop);
+            prop_ids.extend(pasp_prop);
+            prop_ids.extend_from_slice(&transform_props);
+            ipma_entries.push(IpmaEntry {
+                item_id: alpha_image_id,
+                prop_ids,
+            });
+
+            // Use interleaved color and alpha, by default with alpha first.
+            // Makes it possible to display partial image.
+            iloc_items.push(IlocItem {
+                id: color_image_id,
+                extents: [
+                    IlocExtent {
+                        offset: IlocOffset::Relative(color_start),
+                        len: color_av1_data.len(),
+                    },
+                ].into(),
+            });
+            iloc_items.push(IlocItem {
+                id: alpha_image_id,
+                extents: [
+                    IlocExtent {
+                        offset: IlocOffset::Relative(alpha_start),
+                        len: alpha_data.len(),
+                    },
+                ].into(),
+            });
+            if self.alpha_first {
+                data_chunks.push(alpha_data.into());
+                data_chunks.push(color_av1_data.into());
+            } else {
+                data_chunks.push(color_av1_data.into());
+                data_chunks.push(alpha_data.into());
+            }
+        } else {
+            iloc_items.push(IlocItem {
+                id: color_image_id,
+                extents: [
+                    IlocExtent {
+                        offset: IlocOffset::Relative(color_start),
+                        len: color_av1_data.len(),
+                    },
+                ].into(),
+            });
+            data_chunks.push(color_av1_data.into());
+        };
+
+        let mut rendition_start = image_data_start + alpha_len + color_av1_data.len();
+        let mut altr_ids = vec![u32::from(color_image_id)];
+        for (rendition, id) in self.renditions.iter().zip(first_rendition_id..) {
+            let is_jpeg = rendition.typ == *b"jpeg";
+            image_items.push(InfeBox {
+                id,
+                typ: FourCC(rendition.typ),
+                name: if is_jpeg { "Preview" } else { "Rendition" }.into(),
+                content_type: None,
+                content_encoding: None,
+            });
+            let ispe_prop = ipco.push(IpcoProp::Ispe(IspeBox { width: rendition.width, height: rendition.height }));
+            let prop_ids = if is_jpeg {
+                // JPEG has its own color information
+                vec![ispe_prop, ipco.push_or_reuse(IpcoProp::Pixi(PixiBox { channels: 3, depth: 8 }))]
+            } else {
+                let mut prop_ids = vec![ispe_prop, pixi_3, av1c_color_prop | ESSENTIAL_BIT];
+                prop_ids.extend_from_slice(&colr_color_props);
+                prop_ids
+            };
+            ipma_entries.push(IpmaEntry {
+                item_id: id,
+                prop_ids,
+            });
+            iloc_items.push(IlocItem {
+                id,
+                extents: [
+                    IlocExtent {
+                        offset: IlocOffset::Relative(rendition_start),
+                        len: rendition.data.len(),
+                    },
+                ].into(),
+            });
+            rendition_start += rendition.data.len();
+            data_chunks.push(Cow::Borrowed(&rendition.data[..]));
+            if matches!(rendition.link, RenditionLink::Thumbnail | RenditionLink::ThumbnailAndAlternative) {
+                iref.entries.push(IrefEntryBox {
+                    from_id: id,
+                    to_ids: vec![color_image_id],
+                    typ: FourCC(*b"thmb"),
+                });
+            }
+            if matches!(rendition.link, RenditionLink::Alternative | RenditionLink::ThumbnailAndAlternative) {
+                altr_ids.push(id.into());
+            }
+        }
+        let extra_item_id = |index: usize| exif_image_id + 1 + index as u16;
+        let resolve = |item: ItemRef| match item {
+            ItemRef::Color => color_image_id,
+            ItemRef::Alpha => alpha_image_id,
+            ItemRef::Exif => exif_image_id,
+            ItemRef::Extra(index) => extra_item_id(index),
+        };
+        // Metadata goes after the images, unless it's in an mdat of its own before them
+        let mut extra_image_start = rendition_start;
+        let mut extra_metadata_start = match self.mdat_layout {
+            MdatLayout::MetadataFirst => 0,
+            _ => rendition_start + extra_images_len,
+        };
+        let mut extra_metadata_chunks = Vec::new();
+        for (index, (item, (data, content_encoding))) in extra_items.into_iter().enumerate() {
+            let id = extra_item_id(index);
+            image_items.push(InfeBox {
+                id,
+                typ: FourCC(item.typ),
+                name: item.name.clone().into(),
+                content_type: item.content_type.clone(),
+                content_encoding,
+            });
+            let start = if item.is_metadata { &mut extra_metadata_start } else { &mut extra_image_start };
+            iloc_items.push(IlocItem {
+                id,
+                extents: [
+                    IlocExtent {
+                        offset: IlocOffset::Relative(*start),
+                        len: data.len(),
+                    },
+                ].into(),
+            });
+            *start += data.len();
+            if item.is_metadata {
+                extra_metadata_chunks.push(data);
+            } else {
+                data_chunks.push(data);
+            }
+        }
+        let mut metadata_track_starts = Vec::with_capacity(self.metadata_tracks.len());
+        for track in &self.metadata_tracks {
+            metadata_track_starts.push(extra_image_start);
+            extra_image_start += track.data.len();
+            data_chunks.push(Cow::Borrowed(&track.data[..]));
+        }
+        if let Some(gm) = &self.gain_map {
+            let gain_map_id = extra_item_id(graph.items.len());
+            let level = self.level(gm.width, gm.height, None);
+            let av1c = if gm.monochrome { monochrome_av1c(gm.depth_bits, level) } else { self.color_config(gm.depth_bits, level) };
+            let mut prop_ids = vec![
+                ipco.push_or_reuse(IpcoProp::Ispe(IspeBox { width: gm.width, height: gm.height })),
+                ipco.push_or_reuse(IpcoProp::Pixi(PixiBox { channels: if gm.monochrome { 1 } else { 3 }, depth: gm.depth_bits })),
+                ipco.push_or_reuse(IpcoProp::Av1C(av1c)) | ESSENTIAL_BIT,
+            ];
+            match self.gain_map_format {
+                GainMapFormat::Iso21496 => {
+                    let tmap_id = gain_map_id + 1;
+                    // The tone-mapped image has the size of the color image, and the alternate image's colorspace
+                    let mut tmap_prop_ids = vec![ispe_prop, pixi_3];
+                    tmap_prop_ids.extend(gm.alternate_colr.map(|colr| ipco.push_or_reuse(IpcoProp::Colr(colr))));
+                    ipma_entries.push(IpmaEntry { item_id: tmap_id, prop_ids: tmap_prop_ids });
+                    iref.entries.push(IrefEntryBox {
+                        from_id: tmap_id,
+                        to_ids: vec![color_image_id, gain_map_id],
+                        typ: FourCC(*b"dimg"),
+                    });
+                    // Readers that understand tmap prefer it to the color image
+                    altr_ids.insert(0, tmap_id.into());
+                },
+                GainMapFormat::Apple => {
+                    let xmp_id = gain_map_id + 1;
+                    prop_ids.push(ipco.push_or_reuse(IpcoProp::AuxC(AuxCBox { urn: gainmap::APPLE_GAIN_MAP_URN.into() })));
+                    iref.entries.push(IrefEntryBox {
+                        from_id: gain_map_id,
+                        to_ids: vec![color_image_id],
+                        typ: FourCC(*b"auxl"),
+                    });
+                    iref.entries.push(IrefEntryBox {
+                        from_id: xmp_id,
+                        to_ids: vec![gain_map_id],
+                        typ: FourCC(*b"cdsc"),
+                    });
+                },
+            }
+            ipma_entries.push(IpmaEntry { item_id: gain_map_id, prop_ids });
+        }
+        let gain_map_items_len = if self.gain_map.is_some() { 2 } else { 0 };
+        let first_aux_index = graph.items.len() + gain_map_items_len;
+        for (aux, aux_id) in self.auxiliary.iter().zip((first_aux_index..).map(extra_item_id)) {
+            ipma_entries.push(IpmaEntry {
+                item_id: aux_id,
+                prop_ids: vec![
+                    ipco.push_or_reuse(IpcoProp::Ispe(IspeBox { width: aux.width, height: aux.height })),
+                    ipco.push_or_reuse(IpcoProp::Pixi(PixiBox { channels: 1, depth: depth_bits })),
+                    ipco.push_or_reuse(IpcoProp::Av1C(monochrome_av1c(depth_bits, self.level(aux.width, aux.height, None)))) | ESSENTIAL_BIT,
+                    ipco.push_or_reuse(IpcoProp::AuxC(AuxCBox { urn: aux.urn.clone().into() })),
+                ],
+            });
+            iref.entries.push(IrefEntryBox {
+                from_id: aux_id,
+                to_ids: vec![color_image_id],
+                typ: FourCC(*b"auxl"),
+            });
+        }
+        let metadata_refs = (first_aux_index + self.auxiliary.len()..extra_items_count).map(|index| (ItemRef::Extra(index), *b"cdsc", ItemRef::Color));
+        for (from, typ, to) in graph.references.iter().copied().chain(metadata_refs) {
+            let (from_id, to_id, typ) = (resolve(from), resolve(to), FourCC(typ));
+            // Consecutive references of the same type share an entry, which keeps the order of `dimg`
+            match iref.entries.last_mut() {
+                Some(last) if last.from_id == from_id && last.typ == typ => last.to_ids.push(to_id),
+                _ => iref.entries.push(IrefEntryBox { from_id, to_ids: vec![to_id], typ }),
+            }
+        }
+        for (item, prop, essential) in &graph.properties {
+            let item_id = resolve(*item);
+            let prop_id = ipco.push_or_reuse(prop.clone());
+            let prop_id = if *essential { prop_id | ESSENTIAL_BIT } else { prop_id };
+            match ipma_entries.iter_mut().find(|e| e.item_id == item_id) {
+                Some(entry) => entry.prop_ids.push(prop_id),
+                None => ipma_entries.push(IpmaEntry { item_id, prop_ids: vec![prop_id] }),
+            }
+        }
+        // The spec wants them in order of item ids
+        ipma_entries.sort_by_key(|e| e.item_id);
+
+        // Group ids can't clash with item ids
+        let mut groups = Vec::new();
+        if altr_ids.len() > 1 {
+            groups.push((*b"altr", altr_ids, Vec::new()));
+        }
+        groups.extend(graph.groups.iter().map(|(typ, entities, payload)| (*typ, entities.iter().map(|&e| u32::from(resolve(e))).collect(), payload.clone())));
+        let first_group_id = u32::from(extra_item_id(extra_items_count));
+        let grpl = (!groups.is_empty()).then(|| GrplBox {
+            groups: groups.into_iter().zip(first_group_id..).map(|((typ, entity_ids, payload), group_id)| EntityGroupBox {
+                typ: FourCC(typ),
+                group_id,
+                entity_ids,
+                payload,
+            }).collect(),
+        });
+
+        if exif_data.len() > 0 {
+            image_items.push(InfeBox {
+                id: exif_image_id,
+                typ: FourCC(*b"Exif"),
+                name: "Exif".into(),
+                content_type: None,
+                content_encoding: None,
+            });
+            iloc_items.push(IlocItem {
+                id: exif_image_id,
+                extents: [
+                    IlocExtent {
+                        offset: IlocOffset::Relative(exif_start),
+                        len: exif_len,
+                    },
+                ].into(),
+            });
+            iref.entries.push(IrefEntryBox {
+                from_id: exif_image_id,
+                to_ids: vec![color_image_id],
+                typ: FourCC(*b"cdsc"),
+            });
+        }
+
+        let mut moov_box: Option<MoovBox> = None;
+        if let Some(_color_frames) = color_frames {
+            /*let now = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+                Ok(n) => n.as_secs() + 2082844800, // Seconds since 1904-01-01
+                Err(_) => 0
+            };*/
+            let now = 0;
+            let media_duration = _color_frames.iter().map(sample_duration).sum::<u64>();
+            // All 1s means indefinite
+            let movie_duration = if self.hold_last_frame { u64::MAX } else { media_duration };
+            let time_version = if self.long_times { 1 } else { time_box_version(&[now, movie_duration, media_duration]) };
+
+            let mut stts_sample_delta: Vec<ArrayVec<u32, 2>> = vec![];
+            let mut sample_count: u32 = 0;
+            let mut sync_sample_count: u32 = 0;
+            let mut sample_number: Vec<u32> = vec![];
+            for i in 0.._color_frames.len() {
+                if _color_frames[i].sync {
+                    sync_sample_count += 1;
+                    sample_number.push((i + 1) as u32)
+                }
+
+                sample_count += 1;
+                if i < (_color_frames.len() - 1) {
+                    if sample_duration(&_color_frames[i]) == sample_duration(&_color_frames[i + 1]) {
+                        continue;
                     }
+                }
+
+                let mut sample: ArrayVec<u32, 2> = ArrayVec::new();
+                sample.push(sample_count);
+                sample.push(sample_duration(&_color_frames[i]) as u32);
+                stts_sample_delta.push(sample);
+
+                sample_count = 0;
+            }
+
+            let mut stss_box: Option<StssBox> = None;
+            if sync_sample_count != _color_frames.len() as u32 {
+                stss_box = Some(StssBox { entry_count: sync_sample_count, sample_number: sample_number })
+            }
+            let metadata_stts = if self.metadata_tracks.is_empty() { Vec::new() } else { stts_sample_delta.clone() };
+
+
+            let mut moov = MoovBox {
+                mvhd: MvhdBox {
+                    version: time_version,
+                    creation_time: now,
+                    modification_time: now,
+                    timescale: timescale,
+                    duration: movie_duration,
+                    next_track_id: match alpha_frames {
+                        Some(_) => 2,
+                        _ => 1
+                    }
+                },
+                tracks: vec![
+                    TrakBox{
+                        tkhd: TkhdBox {
+                            version: time_version,
+                            creation_time: now,
+                            modification_time: now,
+                            track_id: 1,
+                            duration: movie_duration,
+                            width: width << 16, 
+                            height: height << 16
+                        },
+                        tref: self.track_tref(1, TrefBox { ref_types: Vec::new() }),
+                        edts: if self.hold_last_frame { Some(hold_last_frame_edts(_color_frames)) } else { None },
+                        /*meta: Some(MetaBox {
+                            hdlr: HdlrBox { handler_type: FourCC(*b"pict")},
+                            iinf: IinfBox { items: image_items.clone() },
+                            pitm: PitmBox(color_image_id),
+                            iloc: IlocBox { items: iloc_items.clone() },
+                            iprp: IprpBox {
+                                ipco: ipco.clone(),
+                                // It's not enough to define these properties,
+                                // they must be assigned to the image
+                                ipma: IpmaBox {
+                                    entries: ipma_entries.clone(),
+                                },
+                            },
+                            iref: iref.clone(),
+                        }),*/
+                        meta: None,
+                        mdia: MdiaBox {
+                            mdhd: MdhdBox {
+                                version: time_version,
+                                creation_time: now,
+                                modification_time: now,
+                                timescale: timescale,
+                                duration: media_duration
+                            },
+                            hdlr: HdlrBox { handler_type: FourCC(*b"pict"), name: "avifser" },
+                            minf: MinfBox {
+                                vmhd: Some(VmhdBox {}),
+                                dinf: DinfBox {
+                                    dref: DrefBox { url: UrlBox {} }
+                                },
+                                stbl: StblBox {
+                                    stsd: StsdBox {
+                                        entry: SampleEntry::Visual(SampleEntryBox {
+                                            typ: FourCC(*b"av01"),
+                                            width: width as u16,
+                                            height: height as u16,
+                                            config: color_config,
+                                            config_obus: color_obus.clone(),
+                                            colr: self.color_track_colr.to_box(self.colr),
+                                            dovi: self.dolby_vision.map(DoviBox::from),
+                                            pasp: self.pasp(),
+                                            ccst: CcstBox {},
+                                            auxi: None,
+                                            spherical: self.spherical_boxes(),
+                                            custom: self.custom_boxes_in(CustomBoxLocation::SampleEntry),
+                                        }),
+                                    },
+                                    stts: SttsBox {
+                                        sample_delta: stts_sample_delta
+                                    },
+                                    stsc: StscBox {
+                                        samples_per_chunk: Some(_color_frames.len() as u32)
+                                    },
+                                    stsz: StszBox {
+                                        sample_count: _color_frames.len() as u32,
+                                        entry_size: _color_frames.iter().map(sample_size).collect::<io::Result<Vec<u32>>>()?
+                                    },
+                                    stco: StcoBox { chunk_offset: Some(IlocOffset::Relative(color_start)) },
+                                    stss: stss_box
+                                }
+                            }
+                        },
+                        custom: self.custom_boxes_in(CustomBoxLocation::Track),
+                    }
+                ],
+                mvex: None,
+                udta: None,
+            };
+            if let Some(_alpha_frames) = alpha_frames {
+                let mut alpha_stts_sample_delta: Vec<ArrayVec<u32, 2>> = vec![];
+                let mut alpha_sample_count: u32 = 0;
+                let mut alpha_sync_sample_count: u32 = 0;
+                let mut alpha_sample_number: Vec<u32> = vec![];
+                for i in 0.._alpha_frames.len() {
+                    if _alpha_frames[i].sync {
+                        alpha_sync_sample_count += 1;
+                        alpha_sample_number.push((i + 1) as u32)
+                    }
+
+                    alpha_sample_count += 1;
+                    if i < (_alpha_frames.len() - 1) {
+                        if sample_duration(&_alpha_frames[i]) == sample_duration(&_alpha_frames[i + 1]) {
+                            continue;
+                        }
+                    }
+
+                    let mut sample: ArrayVec<u32, 2> = ArrayVec::new();
+                    sample.push(alpha_sample_count);
+                    sample.push(sample_duration(&_alpha_frames[i]) as u32);
+                    alpha_stts_sample_delta.push(sample);
+
+                    alpha_sample_count = 0;
+                }
+
+                let mut alpha_stss_box: Option<StssBox> = None;
+                if alpha_sync_sample_count != _alpha_frames.len() as u32 {
+                    alpha_stss_box = Some(StssBox { entry_count: alpha_sync_sample_count, sample_number: alpha_sample_number })
+                }
+
+                moov.tracks.push(TrakBox{
+                    tkhd: TkhdBox {
+                        version: time_version,
+                        creation_time: now,
+                        modification_time: now,
+                        track_id: 2,
+                        duration: movie_duration,
+                        width: width << 16, 
+                        height: height << 16
+                    },
+                    tref: self.track_tref(2, TrefBox {
+                        ref_types: vec![ReftypeBox {
+                            typ: FourCC(*b"auxl"),
+                            to_ids: vec![1],
+                        }],
+                    }),
+                    edts: if self.hold_last_frame { Some(hold_last_frame_edts(_alpha_frames)) } else { None },
+                    meta: None,
+                    mdia: MdiaBox {
+                        mdhd: MdhdBox {
+                            version: time_version,
+                            creation_time: now,
+                            modification_time: now,
+                            timescale: timescale,
+                            duration: media_duration
+                        },
+                        hdlr: HdlrBox { handler_type: FourCC(*b"auxv"), name: "avifser" },
+                        minf: MinfBox {
+                            vmhd: Some(VmhdBox {}),
+                            dinf: DinfBox {
+                                dref: DrefBox { url: UrlBox {} }
+                            },
+                            stbl: StblBox {
+                                stsd: StsdBox {
+                                    entry: SampleEntry::Visual(SampleEntryBox {
+                                        typ: FourCC(*b"av01"),
+                                        width: width as u16,
+                                        height: height as u16,
+                                        config: alpha_config,
+                                        config_obus: alpha_obus.clone(),
+                                        colr: self.alpha_track_colr.to_box(self.colr),
+                                        dovi: None,
+                                        pasp: self.pasp(),
+                                        ccst: CcstBox {},
+                                        auxi: Some(AuxiBox { aux_track_type: constants::AlphaUrn::Cicp.as_str() }),
+                                        spherical: None,
+                                        custom: self.custom_boxes_in(CustomBoxLocation::SampleEntry),
+                                    }),
+                                },
+                                stts: SttsBox {
+                                    sample_delta: alpha_stts_sample_delta
+                                },
+                                stsc: StscBox {
+                                    samples_per_chunk: Some(_alpha_frames.len() as u32)
+                                },
+                                stsz: StszBox {
+                                    sample_count: _alpha_frames.len() as u32,
+                                    entry_size: _alpha_frames.iter().map(sample_size).collect::<io::Result<Vec<u32>>>()?
+                                },
+                                stco: StcoBox { chunk_offset: Some(IlocOffset::Relative(alpha_start)) },
+                                stss: alpha_stss_box
+                            }
+                        }
+                    },
+                    custom: self.custom_boxes_in(CustomBoxLocation::Track),
+                });
+            }
+            for (track, &start) in self.metadata_tracks.iter().zip(&metadata_track_starts) {
+                let track_id = moov.tracks.len() as u32 + 1;
+                moov.tracks.push(TrakBox {
+                    tkhd: TkhdBox {
+                        version: time_version,
+                        creation_time: now,
+                        modification_time: now,
+                        track_id,
+                        duration: movie_duration,
+                        width: 0,
+                        height: 0,
+                    },
+                    tref: self.track_tref(track_id, TrefBox {
+                        ref_types: vec![ReftypeBox {
+                            typ: FourCC(*b"cdsc"),
+                            to_ids: vec![1],
+                        }],
+                    }),
+                    edts: if self.hold_last_frame { Some(hold_last_frame_edts(_color_frames)) } else { None },
+                    meta: None,
+                    mdia: MdiaBox {
+                        mdhd: MdhdBox {
+                            version: time_version,
+                            creation_time: now,
+                            modification_time: now,
+                            timescale,
+                            duration: media_duration,
+                        },
+                        hdlr: HdlrBox { handler_type: FourCC(*b"meta"), name: "avifser" },
+                        minf: MinfBox {
+                            vmhd: None,
+                            dinf: DinfBox {
+                                dref: DrefBox { url: UrlBox {} }
+                            },
+                            stbl: StblBox {
+                                stsd: StsdBox {
+                                    entry: SampleEntry::Metadata(MettBox { mime_format: track.mime_format.clone() }),
+                                },
+                                stts: SttsBox {
+                                    sample_delta: metadata_stts.clone(),
+                                },
+                                stsc: StscBox {
+                                    samples_per_chunk: Some(track.sample_sizes.len() as u32),
+                                },
+                                stsz: StszBox {
+                                    sample_count: track.sample_sizes.len() as u32,
+                                    entry_size: track.sample_sizes.iter().map(|&size| u32::try_from(size))
+                                        .collect::<Result<_, _>>()
+                                        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "metadata sample too large for 32-bit sizes"))?,
+                                },
+                                stco: StcoBox { chunk_offset: Some(IlocOffset::Relative(start)) },
+                                stss: None,
+                            }
+                        }
+                    },
+                    custom: self.custom_boxes_in(CustomBoxLocation::Track),
+                });
+            }
+            moov_box = Some(moov);
+        }
+
+        let exif = match exif_data.len() {
+            0 => None,
+            _ => Some(ExifBox {
+                header_offset: 0,
+                payload: exif_data.to_vec(),
+            })
+        };
+        let mut extra = vec![];
+        let mut udta = vec![];
+        let mut meta_blobs = vec![];
+        for (location, data) in &self.blobs {
+            let data = data.clone();
+            match *location {
+                BlobLocation::Free => extra.push(BlobBox { typ: FourCC(*b"free"), uuid: None, data }),
+                BlobLocation::Uuid(uuid) => extra.push(BlobBox { typ: FourCC(*b"uuid"), uuid: Some(uuid), data }),
+                BlobLocation::MetaUuid(uuid) => meta_blobs.push(BlobBox { typ: FourCC(*b"uuid"), uuid: Some(uuid), data }),
+                BlobLocation::Udta(typ) => udta.push(BlobBox { typ: FourCC(typ), uuid: None, data }),
+            }
+        }
+        if let Some(moov) = moov_box.as_mut() {
+            if !udta.is_empty() {
+                moov.udta = Some(UdtaBox { children: udta });
+            }
+        }
+
+        let mut mdat = Vec::new();
+        match self.mdat_layout {
+            MdatLayout::Single => {
+                data_chunks.extend(extra_metadata_chunks);
+                mdat.push(MdatBox { data_chunks, exif });
+            },
+            MdatLayout::MetadataFirst => {
+                if exif.is_some() || !extra_metadata_chunks.is_empty() {
+                    mdat.push(MdatBox { data_chunks: extra_metadata_chunks, exif });
+                }
+                mdat.push(MdatBox { data_chunks, exif: None });
+            },
+            MdatLayout::PerItem => {
+                for chunk in data_chunks.into_iter().chain(extra_metadata_chunks) {
+                    mdat.push(MdatBox { data_chunks: vec![chunk], exif: None });
+                }
+                if exif.is_some() {
+                    mdat.push(MdatBox { data_chunks: Vec::new(), exif });
+                }
+            },
+        }
+
+        compatible_brands.push(FourCC(*b"avif"));
+        match moov_box {
+            Some(_) => {
+                compatible_brands.push(FourCC(*b"avis"));
+                compatible_brands.push(FourCC(*b"msf1"));
+                compatible_brands.push(FourCC(*b"iso8"));
+            }
+            _ => ()
+        }
+        compatible_brands.push(FourCC(*b"mif1"));
+        compatible_brands.push(FourCC(*b"miaf"));
+        compatible_brands.push(FourCC(*b"MA1A"));
+        if self.gain_map.is_some() && self.gain_map_format == GainMapFormat::Iso21496 {
+            compatible_brands.push(FourCC(*b"tmap"));
+        }
+        Ok(AvifFile {
+            ftyp: FtypBox {
+                major_brand: match moov_box {
+                    Some(_) => FourCC(*b"avis"),
+                    _ => FourCC(*b"avif")
+                },
+                minor_version: 0,
+                compatible_brands,
+            },
+            meta: MetaBox {
+                hdlr: HdlrBox { handler_type: FourCC(*b"pict"), name: "avifser" },
+                iinf: IinfBox { items: image_items },
+                pitm: PitmBox(resolve(graph.primary)),
+                iloc: IlocBox { items: iloc_items },
+                iprp: IprpBox {
+                    ipco,
+                    // It's not enough to define these properties,
+                    // they must be assigned to the image
+                    ipma: IpmaBox {
+                        entries: ipma_entries,
+                    },
+                },
+                iref,
+                grpl,
+                blobs: meta_blobs,
+                custom: self.custom_boxes_in(CustomBoxLocation::Meta),
+            },
+            padding: (self.free_padding > 0).then(|| BlobBox { typ: FourCC(*b"free"), uuid: None, data: vec![0; self.free_padding] }),
+            moov: moov_box,
+            extra,
+            // Here's the actual data. If HEIF wasn't such a kitchen sink, this
+            // would have been the only data this file needs.
+            mdat,
+        })
+    }
+
+    /// Same as [`try_to_vec`](Self::try_to_vec), but panics instead of returning an error.
+    ///
+    /// # Panics
+    ///
+    /// If the input is invalid, e.g. the file would be over 4GB, or the settings don't match the AV1 data.
+    #[must_use] pub fn to_vec(&self, color_av1_data: &[u8], alpha_av1_data: Option<&[u8]>, width: u32, height: u32, depth_bits: u8, timescale: u32, color_frames: Option<&[FrameInfo]>, alpha_frames: Option<&[FrameInfo]>, exif_data: &[u8]) -> Vec<u8> {
+        self.try_to_vec(color_av1_data, alpha_av1_data, width, height, depth_bits, timescale, color_frames, alpha_frames, exif_data).unwrap()
+    }
+
+    /// See [`write`](Self::write). Fails only if the input is invalid, e.g. the file would be over 4GB.
+    pub fn try_to_vec(&self, color_av1_data: &[u8], alpha_av1_data: Option<&[u8]>, width: u32, height: u32, depth_bits: u8, timescale: u32, color_frames: Option<&[FrameInfo]>, alpha_frames: Option<&[FrameInfo]>, exif_data: &[u8]) -> io::Result<Vec<u8>> {
+        let image = ImageData { timescale, color_frames, alpha_frames, exif_data, ..ImageData::new(color_av1_data, alpha_av1_data, width, height, depth_bits) };
+        let mut out = Vec::new();
+        self.to_vec_in(&mut out, &image)?;
+        Ok(out)
+    }
+
+    /// Same as [`to_vec`](Self::to_vec), but writes into `out`, replacing its contents.
+    ///
+    /// Reusing one buffer avoids allocating a new one for every image.
+    pub fn to_vec_in(&self, out: &mut Vec<u8>, image: &ImageData<'_>) -> io::Result<()> {
+        out.clear();
+        out.reserve(image.color_av1_data.len() + image.alpha_av1_data.map_or(0, |a| a.len()) + 410);
+        self.write_dyn(out, image.color_av1_data, image.alpha_av1_data, image.width, image.height, image.depth_bits,
+            image.timescale, image.color_frames, image.alpha_frames, image.exif_data)
+    }
+
+    /// Starts building a still image from individual items, e.g. with auxiliary images and XMP metadata.
+    ///
+    /// This is a more flexible alternative to [`write`](Self::write) for files that aren't animated.
+    #[must_use]
+    pub fn compose(&self) -> Composition<'_> {
+        Composition::new(self)
+    }
+
+    /// Makes a fragmented animation split into an initialization segment and media segments,
+    /// with up to `frames_per_segment` frames in each.
+    ///
+    /// Frames are taken in order from `color_av1_data` and `alpha_av1_data`, according to `size` in [`FrameInfo`].
+    /// Each media segment should start with a keyframe. There's no still image item, so the result
+    /// is for video-like streaming, not for use as a standalone image, and `exif_data` isn't used.
+    ///
+    /// Fails if there are no `color_frames`, the frame sizes don't add up to the length of the data, a segment is over 4GB,
+    /// or there are [metadata tracks](Self::add_metadata_track).
+    pub fn to_segments(&self, image: &ImageData<'_>, frames_per_segment: usize) -> io::Result<Segments> {
+        if !self.metadata_tracks.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "metadata tracks in segments"));
+        }
+        let &ImageData { color_av1_data, alpha_av1_data, width, height, depth_bits, timescale, color_frames, alpha_frames, .. } = image;
+        let color_frames = color_frames.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no frames"))?;
+        let file = self.make_boxes(color_av1_data, alpha_av1_data, width, height, depth_bits, timescale, Some(color_frames), alpha_frames, &[], &NO_ITEMS)?;
+        let frames_too_large = || io::Error::new(io::ErrorKind::InvalidInput, "frame sizes exceed the data");
+        let too_large = || io::Error::new(io::ErrorKind::InvalidInput, "segment too large for 32-bit offsets");
+        let mut moov = file.moov.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no frames"))?;
+        moov.mvhd.duration = 0; // unknown, it's in the fragments
+        for track in moov.tracks.iter_mut() {
+            track.tkhd.duration = 0;
+            track.mdia.mdhd.duration = 0;
+            let stbl = &mut track.mdia.minf.stbl;
+            stbl.stts.sample_delta.clear();
+            stbl.stsc.samples_per_chunk = None;
+            stbl.stsz.sample_count = 0;
+            stbl.stsz.entry_size.clear();
+            stbl.stco.chunk_offset = None;
+            stbl.stss = None;
+        }
+        moov.mvex = Some(MvexBox {
+            trex: moov.tracks.iter().map(|t| TrexBox { track_id: t.tkhd.track_id }).collect(),
+        });
+        let ftyp = FtypBox {
+            major_brand: FourCC(*b"iso6"),
+            minor_version: 0,
+            compatible_brands: [*b"iso6", *b"avis", *b"msf1", *b"iso8"].into_iter().map(FourCC).collect(),
+        };
+
+        let mut init = Vec::with_capacity(ftyp.len() + moov.len());
+        let mut w = Writer::new(&mut init);
+        ftyp.write(&mut w).unwrap(); // Vec can't fail
+        moov.write(&mut w).unwrap(); // Vec can't fail
+        drop(w);
+
+        let mut color_start = 0usize;
+        let mut color_time = 0;
+        let mut alpha_start = 0usize;
+        let mut alpha_time = 0;
+        let media = color_frames.chunks(frames_per_segment.max(1)).enumerate().map(|(i, color_chunk)| -> io::Result<Vec<u8>> {
+            let first_frame = i * frames_per_segment.max(1);
+            let alpha_chunk = match (alpha_av1_data, alpha_frames) {
+                (Some(_), Some(alpha_frames)) => alpha_frames.get(first_frame..(first_frame + color_chunk.len()).min(alpha_frames.len())).unwrap_or_default(),
+                _ => &[],
+            };
+            let color_len = color_chunk.iter().try_fold(0usize, |sum, f| sum.checked_add(f.size)).ok_or_else(frames_too_large)?;
+            let alpha_len = alpha_chunk.iter().try_fold(0usize, |sum, f| sum.checked_add(f.size)).ok_or_else(frames_too_large)?;
+            let samples = |frames: &[FrameInfo]| frames.iter().map(|f| Ok(TrunSample {
+                duration: sample_duration(f) as u32,
+                size: sample_size(f)?,
+                sync: f.sync,
+            })).collect::<io::Result<_>>();
+
+            let mut trafs = ArrayVec::new();
+            trafs.push(TrafBox {
+                track_id: 1,
+                base_media_decode_time: color_time,
+                data_offset: 0,
+                samples: samples(color_chunk)?,
+            });
+            if !alpha_chunk.is_empty() {
+                trafs.push(TrafBox {
+                    track_id: 2,
+                    base_media_decode_time: alpha_time,
+                    data_offset: 0,
+                    samples: samples(alpha_chunk)?,
+                });
+            }
+            let sequence_number = u32::try_from(i + 1).map_err(|_| too_large())?;
+            let mut moof = MoofBox { sequence_number, trafs };
+            // Same interleaving as in still images
+            let data_start = moof.len() + 8;
+            let (alpha_offset, color_offset) = if self.alpha_first { (0, alpha_len) } else { (color_len, 0) };
+            let data_offset = |offset: usize| u32::try_from(data_start + offset).map_err(|_| too_large());
+            for traf in &mut moof.trafs {
+                traf.data_offset = data_offset(if traf.track_id == 1 { color_offset } else { alpha_offset })?;
+            }
+            let mut data_chunks = Vec::new();
+            data_chunks.push(color_start.checked_add(color_len).and_then(|end| color_av1_data.get(color_start..end)).ok_or_else(frames_too_large)?.into());
+            if let Some(alpha_data) = alpha_av1_data {
+                let alpha_chunk = alpha_start.checked_add(alpha_len).and_then(|end| alpha_data.get(alpha_start..end)).ok_or_else(frames_too_large)?;
+                if self.alpha_first {
+                    data_chunks.insert(0, alpha_chunk.into());
+                } else {
+                    data_chunks.push(alpha_chunk.into());
+                }
+            }
+            let mdat = MdatBox { data_chunks, exif: None };
+
+            let segment_duration = color_chunk.iter().map(sample_duration).sum::<u64>();
+            let sidx = SidxBox {
+                reference_id: 1,
+                timescale,
+                earliest_presentation_time: color_time,
+                referenced_size: u32::try_from(moof.len() + mdat.len()).map_err(|_| too_large())?,
+                subsegment_duration: segment_duration.min(u32::MAX.into()) as u32,
+                starts_with_sap: color_chunk.first().is_some_and(|f| f.sync),
+            };
+            let styp = StypBox {
+                major_brand: FourCC(*b"msdh"),
+                compatible_brands: [*b"msdh", *b"msix"].into_iter().map(FourCC).collect(),
+            };
+
+            color_start += color_len;
+            color_time += segment_duration;
+            alpha_start += alpha_len;
+            alpha_time += alpha_chunk.iter().map(sample_duration).sum::<u64>();
+
+            let mut out = Vec::with_capacity(styp.len() + sidx.len() + moof.len() + mdat.len());
+            let mut w = Writer::new(&mut out);
+            styp.write(&mut w).unwrap(); // Vec can't fail
+            sidx.write(&mut w).unwrap(); // Vec can't fail
+            moof.write(&mut w).unwrap(); // Vec can't fail
+            mdat.write(&mut w).unwrap(); // Vec can't fail
+            drop(w);
+            Ok(out)
+        }).collect::<io::Result<_>>()?;
+
+        Ok(Segments { init, media })
+    }
+}
+
+/// Animation split into segments for DASH-like delivery. See [`Aviffy::to_segments`].
+pub struct Segments {
+    /// `ftyp` + `moov` without any samples. Must be loaded before any media segment.
+    pub init: Vec<u8>,
+    /// Each is `styp` + `sidx` + `moof` + `mdat`
+    pub media: Vec<Vec<u8>>,
+}
+
+/// See [`serialize`] for description. This one makes a `Vec` instead of using `io::Write`.
+///
+/// # Panics
+///
+/// If the input is invalid, e.g. the file would be over 4GB. Use [`Aviffy::try_to_vec`] to get an error instead.
+#[must_use] pub fn serialize_to_vec(color_av1_data: &[u8], alpha_av1_data: Option<&[u8]>, width: u32, height: u32, depth_bits: u8, timescale: u32, color_frames: Option<&[FrameInfo]>, alpha_frames: Option<&[FrameInfo]>, exif_data: &[u8]) -> Vec<u8> {
+    Aviffy::new().to_vec(color_av1_data, alpha_av1_data, width, height, depth_bits, timescale, color_frames, alpha_frames, exif_data)
+}
+
+/// `stts` and `trun` store 32-bit durations. Longer ones are rejected by `make_boxes`.
+fn sample_duration(frame: &FrameInfo) -> u64 {
+    frame.duration_in_timescales.min(u32::MAX.into())
+}
+
+/// `stsz` and `trun` can't store larger sizes (and neither can `stco` offsets, so such files can't be written anyway)
+fn sample_size(frame: &FrameInfo) -> io::Result<u32> {
+    u32::try_from(frame.size).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame too large for 32-bit sizes"))
+}
+
+/// `av1C`, with `configOBUs` if there are any
+fn av1c_prop(config: Av1CBox, config_obus: &[u8]) -> IpcoProp {
+    if config_obus.is_empty() {
+        IpcoProp::Av1C(config)
+    } else {
+        IpcoProp::Av1CObus(Av1CObusBox { config, config_obus: config_obus.to_vec() })
+    }
+}
+
+/// Default `av1C` of alpha and other auxiliary images
+fn monochrome_av1c(depth_bits: u8, level: u8) -> Av1CBox {
+    Av1CBox {
+        seq_profile: if depth_bits >= 12 { 2 } else { 0 },
+        seq_level_idx_0: level,
+        seq_tier_0: false,
+        high_bitdepth: depth_bits >= 10,
+        twelve_bit: depth_bits >= 12,
+        monochrome: true,
+        chroma_subsampling_x: true,
+        chroma_subsampling_y: true,
+        chroma_sample_position: 0,
+    }
+}
+
+/// Plays all the frames, and then dwells on the last one forever
+fn hold_last_frame_edts(frames: &[FrameInfo]) -> EdtsBox {
+    let media_duration = frames.iter().map(sample_duration).sum::<u64>();
+    let last_frame_start = media_duration - frames.last().map_or(0, sample_duration);
+    EdtsBox {
+        elst: ElstBox {
+            entries: [
+                ElstEntry { segment_duration: media_duration, media_time: 0, media_rate: 1 },
+                ElstEntry { segment_duration: u64::MAX, media_time: last_frame_start as i64, media_rate: 0 },
+            ].into(),
+        },
+    }
+}
+
+/// Byte ranges of animation frames in the file. See [`Aviffy::write_with_sample_map`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SampleMap {
+    /// Absolute position of every frame of the color track, in order
+    pub color: Vec<Range<u64>>,
+    /// Same for the alpha track, if there is one
+    pub alpha: Vec<Range<u64>>,
+}
+
+/// Timing, size and type of a frame of an animation. See [`FrameInfo::new`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct FrameInfo {
+    /// How long the frame is shown, in units of the `timescale`
+    pub duration_in_timescales: u64,
+    /// Whether it's a key frame that can be decoded without the previous frames
+    pub sync: bool,
+    /// Length of the frame's AV1 data in bytes
+    pub size: usize,
+}
+
+impl FrameInfo {
+    /// Fields that may be added later will get defaults here, and setters of their own.
+    #[must_use]
+    pub fn new(duration_in_timescales: u64, sync: bool, size: usize) -> Self {
+        Self { duration_in_timescales, sync, size }
+    }
+}
+
+/// The image data and its description, for functions that take them all at once. The fields are the arguments of [`Aviffy::to_vec`].
+#[derive(Debug, Copy, Clone)]
+#[non_exhaustive]
+pub struct ImageData<'a> {
+    pub color_av1_data: &'a [u8],
+    pub alpha_av1_data: Option<&'a [u8]>,
+    pub width: u32,
+    pub height: u32,
+    pub depth_bits: u8,
+    pub timescale: u32,
+    pub color_frames: Option<&'a [FrameInfo]>,
+    pub alpha_frames: Option<&'a [FrameInfo]>,
+    pub exif_data: &'a [u8],
+}
+
+impl<'a> ImageData<'a> {
+    /// A still image without Exif. Set the other fields for animations and metadata.
+    #[must_use]
+    pub fn new(color_av1_data: &'a [u8], alpha_av1_data: Option<&'a [u8]>, width: u32, height: u32, depth_bits: u8) -> Self {
+        Self {
+            color_av1_data,
+            alpha_av1_data,
+            width,
+            height,
+            depth_bits,
+            timescale: 1,
+            color_frames: None,
+            alpha_frames: None,
+            exif_data: &[],
+        }
+    }
+}
+
+#[test]
+fn test_roundtrip_parse_mp4() {
+    let test_img = b"av12356abc";
+    let exif_data = b"";
+    let avif = serialize_to_vec(test_img, None, 10, 20, 8, 1, None, None, exif_data);
+
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+
+    assert_eq!(&test_img[..], ctx.primary_item_coded_data());
+}
+
+#[test]
+fn test_roundtrip_parse_mp4_alpha() {
+    let test_img = b"av12356abc";
+    let test_a = b"alpha";
+    let exif_data = b"";
+    let avif = serialize_to_vec(test_img, Some(test_a), 10, 20, 8, 1, None, None, exif_data);
+
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+
+    assert_eq!(&test_img[..], ctx.primary_item_coded_data());
+    assert_eq!(&test_a[..], ctx.alpha_item_coded_data());
+}
+
+#[test]
+fn test_roundtrip_parse_avif() {
+    let test_img = [1,2,3,4,5,6];
+    let test_alpha = [77,88,99];
+    let exif_data: [u8; 0] = [];
+    let avif = serialize_to_vec(&test_img, Some(&test_alpha), 10, 20, 8, 1, None, None, &exif_data);
+
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+
+    assert_eq!(&test_img[..], ctx.primary_item.as_slice());
+    assert_eq!(&test_alpha[..], ctx.alpha_item.as_deref().unwrap());
+}
+
+#[test]
+fn test_roundtrip_parse_avif_colr() {
+    let test_img = [1,2,3,4,5,6];
+    let test_alpha = [77,88,99];
+    let exif_data: [u8; 0] = [];
+    let avif = Aviffy::new()
+        .matrix_coefficients(constants::MatrixCoefficients::Bt709)
+        .to_vec(&test_img, Some(&test_alpha), 10, 20, 8, 1, None, None, &exif_data);
+
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+
+    assert_eq!(&test_img[..], ctx.primary_item.as_slice());
+    assert_eq!(&test_alpha[..], ctx.alpha_item.as_deref().unwrap());
+}
+
+#[test]
+fn premultiplied_flag() {
+    let test_img = [1,2,3,4];
+    let test_alpha = [55,66,77,88,99];
+    let exif_data: [u8; 0] = [];
+    let avif = Aviffy::new().premultiplied_alpha(true).to_vec(&test_img, Some(&test_alpha), 5, 5, 8, 1, None, None, &exif_data);
+
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+
+    assert!(ctx.premultiplied_alpha);
+    assert_eq!(&test_img[..], ctx.primary_item.as_slice());
+    assert_eq!(&test_alpha[..], ctx.alpha_item.as_deref().unwrap());
+}
+
+#[test]
+fn test_roundtrip_parse_avif_mdat_per_item() {
+    let test_img = [1,2,3,4,5,6];
+    let test_alpha = [77,88,99];
+    let exif_data: [u8; 0] = [];
+    let avif = Aviffy::new()
+        .mdat_layout(MdatLayout::PerItem)
+        .to_vec(&test_img, Some(&test_alpha), 10, 20, 8, 1, None, None, &exif_data);
+
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+
+    assert_eq!(&test_img[..], ctx.primary_item.as_slice());
+    assert_eq!(&test_alpha[..], ctx.alpha_item.as_deref().unwrap());
+}
+
+#[test]
+fn custom_meta_box() {
+    struct Free;
+    impl CustomBox for Free {
+        fn len(&self) -> usize { 12 }
+        fn write(&self, out: &mut Vec<u8>) {
+            out.extend_from_slice(&12u32.to_be_bytes());
+            out.extend_from_slice(b"free1234");
+        }
+    }
+
+    let test_img = [1,2,3,4,5,6];
+    let exif_data: [u8; 0] = [];
+    let avif = Aviffy::new()
+        .custom_box(CustomBoxLocation::Meta, Free)
+        .to_vec(&test_img, None, 10, 20, 8, 1, None, None, &exif_data);
+
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(&test_img[..], ctx.primary_item.as_slice());
+}
+
+#[test]
+fn renditions() {
+    let test_img = [1,2,3,4,5,6];
+    let test_alpha = [77,88,99];
+    let test_thumb = [9,8,7];
+    let exif_data = [0,0,0,0];
+    let avif = Aviffy::new()
+        .add_rendition(&test_thumb[..], 2, 4, RenditionLink::ThumbnailAndAlternative)
+        .to_vec(&test_img, Some(&test_alpha), 10, 20, 8, 1, None, None, &exif_data);
+
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+
+    assert_eq!(&test_img[..], ctx.primary_item.as_slice());
+    assert_eq!(&test_alpha[..], ctx.alpha_item.as_deref().unwrap());
+
+    let boxes = reader::parse_boxes(&avif, 0).unwrap();
+    let meta = boxes.iter().find(|b| b.typ == *b"meta").unwrap();
+    let pitm = meta.child(*b"pitm").unwrap().payload();
+    let mut r = reader::Reader::new(&pitm);
+    r.full_box().unwrap();
+    let primary = u32::from(r.u16().unwrap());
+
+    // The rendition is an extra image item
+    let iloc = reader::Iloc::parse(&meta.child(*b"iloc").unwrap().payload()).unwrap();
+    let item_data = |id| iloc.items.iter().find(|item| item.id == id).map(|item| {
+        let ex = item.extents[0];
+        let start = (item.base_offset + ex.offset) as usize;
+        &avif[start..start + ex.len as usize]
+    });
+    let items: Vec<_> = meta.child(*b"iinf").unwrap().children().iter()
+        .map(|infe| reader::infe_id_type(&infe.payload()).unwrap()).collect();
+    assert_eq!(4, items.len());
+    assert_eq!(Some(&test_img[..]), item_data(primary));
+    let thumb = items.iter().find(|&&(id, _)| item_data(id) == Some(&test_thumb[..])).unwrap();
+    assert_eq!(*b"av01", thumb.1);
+    let thumb = thumb.0;
+
+    let iref = reader::Iref::parse(&meta.child(*b"iref").unwrap().payload()).unwrap();
+    assert!(iref.refs.contains(&(*b"thmb", thumb, vec![primary])));
+
+    let grpl = meta.child(*b"grpl").unwrap();
+    let altr = grpl.children().iter().find(|g| g.typ == *b"altr").unwrap().payload();
+    let mut r = reader::Reader::new(&altr);
+    r.full_box().unwrap();
+    r.u32().unwrap(); // group id
+    let entities = (0..r.u32().unwrap()).map(|_| r.u32().unwrap()).collect::<Vec<_>>();
+    assert_eq!([primary, thumb], entities[..]);
+}
+
+#[test]
+fn retime_keeps_data() {
+    let test_img = [1,2,3,4,5,6];
+    let frames = [
+        FrameInfo { duration_in_timescales: 3, sync: true, size: 4 },
+        FrameInfo { duration_in_timescales: 5, sync: false, size: 2 },
+    ];
+    let avif = Aviffy::new().to_vec(&test_img, None, 10, 20, 8, 10, Some(&frames), None, &[]);
+    let avif = remux::retime(&avif, remux::Retiming::ConstantFrameRate { timescale: 30, frame_duration: 1 }).unwrap();
+
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(&test_img[..], ctx.primary_item.as_slice());
+}
+
+#[test]
+fn retime_rejects_zero_speed() {
+    let frames = [FrameInfo::new(3, true, 4), FrameInfo::new(5, false, 2)];
+    let avif = Aviffy::new().to_vec(&[1, 2, 3, 4, 5, 6], None, 10, 20, 8, 10, Some(&frames), None, &[]);
+    for (num, den) in [(1, 0), (0, 1)] {
+        let err = remux::retime(&avif, remux::Retiming::Speed { num, den }).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+    assert!(remux::retime(&avif, remux::Retiming::Speed { num: u32::MAX, den: 1 }).is_err());
+    assert!(remux::retime(&avif, remux::Retiming::Speed { num: 2, den: 1 }).is_ok());
+}
+
+#[test]
+fn retime_rejects_overflowing_edit_list() {
+    let frames = [FrameInfo::new(2, true, 3), FrameInfo::new(3, false, 3)];
+    let avif = Aviffy::new().hold_last_frame(true).to_vec(b"abcdef", None, 10, 20, 8, 10, Some(&frames), None, &[]);
+    let entry = avif.windows(4).position(|w| w == b"elst").unwrap() + 12;
+    let retime = |segment_duration: u64, media_time: u64| {
+        let mut avif = avif.clone();
+        avif[entry..entry + 8].copy_from_slice(&segment_duration.to_be_bytes());
+        avif[entry + 8..entry + 16].copy_from_slice(&media_time.to_be_bytes());
+        remux::retime(&avif, remux::Retiming::ConstantFrameRate { timescale: u32::MAX, frame_duration: u32::MAX })
+    };
+    assert!(retime(5, 0).is_ok());
+    // Empty edit scaled to the new timescale, and the end of an edit
+    for (segment_duration, media_time) in [(u64::MAX - 1, u64::MAX), (u64::MAX - 1, 0), (1 << 40, i64::MAX as u64)] {
+        let err = retime(segment_duration, media_time).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+}
+
+#[test]
+fn remux_rejects_deeply_nested_boxes() {
+    let depth = 10_000;
+    let mut avif = Vec::with_capacity(depth * 8);
+    for i in 0..depth {
+        avif.extend_from_slice(&(((depth - i) * 8) as u32).to_be_bytes());
+        avif.extend_from_slice(b"moov");
+    }
+    let err = remux::metadata(&avif).unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    assert!(remux::retime(&avif, remux::Retiming::Speed { num: 2, den: 1 }).is_err());
+}
+
+#[test]
+fn concat_keeps_data() {
+    let test_img = [1,2,3,4,5,6];
+    let frames = [
+        FrameInfo { duration_in_timescales: 3, sync: true, size: 4 },
+        FrameInfo { duration_in_timescales: 5, sync: false, size: 2 },
+    ];
+    let avif = Aviffy::new().to_vec(&test_img, None, 10, 20, 8, 10, Some(&frames), None, &[]);
+    assert_eq!(avif, remux::concat(&[&avif]).unwrap());
+
+    let joined = remux::concat(&[&avif, &avif]).unwrap();
+    let ctx = avif_parse::read_avif(&mut joined.as_slice()).unwrap();
+    assert_eq!(&test_img[..], ctx.primary_item.as_slice());
+
+    let other_size = Aviffy::new().to_vec(&test_img, None, 20, 20, 8, 10, Some(&frames), None, &[]);
+    assert!(remux::concat(&[&avif, &other_size]).is_err());
+}
+
+#[test]
+fn remux_rejects_oversized_sample_tables() {
+    /// Sets u32 fields after the version and flags of every box of type `typ`
+    fn patch(avif: &[u8], typ: &[u8; 4], fields: &[u32]) -> Vec<u8> {
+        let mut avif = avif.to_vec();
+        for pos in avif.windows(4).enumerate().filter(|&(_, w)| w == typ).map(|(pos, _)| pos).collect::<Vec<_>>() {
+            for (i, field) in fields.iter().enumerate() {
+                avif[pos + 8 + 4 * i..pos + 12 + 4 * i].copy_from_slice(&field.to_be_bytes());
+            }
+        }
+        avif
+    }
+
+    let frames = [FrameInfo::new(1, true, 3), FrameInfo::new(1, false, 3)];
+    let avif = Aviffy::new().to_vec(b"abcdef", Some(b"ghijkl"), 10, 20, 8, 10, Some(&frames), Some(&frames), &[]);
+    assert!(remux::concat(&[&avif, &avif]).is_ok());
+    assert!(remux::strip_alpha(&avif).is_ok());
+
+    // A constant sample size with billions of samples
+    let many = patch(&avif, b"stsz", &[3, u32::MAX]);
+    assert_eq!(io::ErrorKind::InvalidData, remux::concat(&[&many, &avif]).unwrap_err().kind());
+    assert_eq!(io::ErrorKind::InvalidData, remux::strip_alpha(&many).unwrap_err().kind());
+
+    // Durations that add up to more than 64 bits
+    let long = patch(&avif, b"stts", &[1, u32::MAX, u32::MAX]);
+    assert_eq!(io::ErrorKind::InvalidData, remux::concat(&[&long, &long]).unwrap_err().kind());
+}
+
+#[test]
+fn strip_alpha() {
+    let test_img = [1,2,3,4,5,6];
+    let test_alpha = [77,88,99];
+    let avif = Aviffy::new().premultiplied_alpha(true).to_vec(&test_img, Some(&test_alpha), 10, 20, 8, 1, None, None, &[]);
+    let avif = remux::strip_alpha(&avif).unwrap();
+
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(&test_img[..], ctx.primary_item.as_slice());
+    assert!(ctx.alpha_item.is_none());
+    assert_eq!(avif, serialize_to_vec(&test_img, None, 10, 20, 8, 1, None, None, &[]));
+}
+
+#[test]
+fn crop_keeps_data() {
+    let test_img = [1,2,3,4,5,6];
+    let test_alpha = [77,88,99];
+    let avif = Aviffy::new().to_vec(&test_img, Some(&test_alpha), 10, 20, 8, 1, None, None, &[]);
+    let cropped = remux::crop(&avif, Some(remux::Crop { left: 2, top: 4, width: 6, height: 8 })).unwrap();
+
+    let ctx = avif_parse::read_avif(&mut cropped.as_slice()).unwrap();
+    assert_eq!(&test_img[..], ctx.primary_item.as_slice());
+    assert_eq!(&test_alpha[..], ctx.alpha_item.as_deref().unwrap());
+    assert_eq!(avif, remux::crop(&cropped, None).unwrap());
+}
+
+#[test]
+fn optimize_keeps_data() {
+    let test_img = [1,2,3,4,5,6];
+    let test_alpha = [77,88,99];
+    let avif = Aviffy::new().to_vec(&test_img, Some(&test_alpha), 10, 20, 8, 1, None, None, &[]);
+    assert_eq!(avif, remux::optimize(&avif).unwrap());
+
+    let padded = Aviffy::new().embed_blob(BlobLocation::Free, vec![0; 100]).to_vec(&test_img, Some(&test_alpha), 10, 20, 8, 1, None, None, &[]);
+    let optimized = remux::optimize(&padded).unwrap();
+    assert!(optimized.len() < padded.len());
+    let ctx = avif_parse::read_avif(&mut optimized.as_slice()).unwrap();
+    assert_eq!(&test_img[..], ctx.primary_item.as_slice());
+    assert_eq!(&test_alpha[..], ctx.alpha_item.as_deref().unwrap());
+}
+
+#[test]
+fn metadata_roundtrip() {
+    let test_img = [1,2,3,4,5,6];
+    let exif_data = b"II*\0\x08\0\0\0";
+    let avif = Aviffy::new().to_vec(&test_img, None, 10, 20, 8, 1, None, None, exif_data);
+
+    let metadata = remux::metadata(&avif).unwrap();
+    assert_eq!(Some(&exif_data[..]), metadata.exif.as_deref());
+    assert_eq!(None, metadata.xmp);
+    assert_eq!(None, metadata.icc);
+
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(&test_img[..], ctx.primary_item.as_slice());
+}
+
+#[test]
+fn config_reflects_setters() {
+    let mut aviffy = Aviffy::new();
+    let default = aviffy.config();
+    assert!(!default.premultiplied_alpha);
+    assert_eq!(MdatLayout::Single, default.mdat_layout);
+
+    aviffy.premultiplied_alpha(true).full_color_range(false).bit_depth(10);
+    let config = aviffy.config();
+    assert_ne!(default, config);
+    assert!(config.premultiplied_alpha);
+    assert!(!config.color.full_color_range);
+    assert_eq!(Some(10), config.bit_depth);
+}
+
+#[test]
+fn frame_sizes_from_slices() {
+    let frames_data: [&[u8]; 2] = [&[1,2,3,4], &[5,6]];
+    let frames: Vec<_> = frames_data.iter().map(|f| FrameInfo::new(3, true, f.len())).collect();
+    let color = frames_data.concat();
+    let mut out = Vec::new();
+    let map = Aviffy::new().write_with_sample_map(&mut out, &ImageData { timescale: 10, color_frames: Some(&frames), ..ImageData::new(&color, None, 10, 20, 8) }).unwrap();
+    assert_eq!(2, map.color.len());
+    for (range, data) in map.color.iter().zip(frames_data) {
+        assert_eq!(data, &out[range.start as usize..range.end as usize]);
+    }
+}
+
+#[test]
+fn cicp_conversions() {
+    use constants::*;
+    assert_eq!(MatrixCoefficients::Bt709, MatrixCoefficients::try_from(1).unwrap());
+    assert!(MatrixCoefficients::try_from(3).is_err());
+    assert_eq!(TransferCharacteristics::Srgb, "13".parse().unwrap());
+    assert_eq!(ColorPrimaries::DisplayP3, "Display-P3".parse().unwrap());
+    assert!("p3".parse::<ColorPrimaries>().is_err());
+    for tc in [TransferCharacteristics::Linear, TransferCharacteristics::Bt2020_12] {
+        assert_eq!(tc, tc.to_string().parse().unwrap());
+    }
+}
+
+/// Fails after accepting `limit` bytes
+#[cfg(test)]
+struct Truncated { limit: usize }
+
+#[cfg(test)]
+impl io::Write for Truncated {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.limit == 0 {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, "disk full"));
+        }
+        let len = buf.len().min(self.limit);
+        self.limit -= len;
+        Ok(len)
+    }
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+#[test]
+fn write_error_names_box() {
+    let test_img = [1,2,3,4,5,6];
+    let len = Aviffy::new().to_vec(&test_img, None, 10, 20, 8, 1, None, None, &[]).len();
+    let err = Aviffy::new().write(Truncated { limit: 40 }, &test_img, None, 10, 20, 8, 1, None, None, &[]).unwrap_err();
+    assert_eq!(io::ErrorKind::WriteZero, err.kind());
+    assert!(err.to_string().contains("meta"), "{err}");
+    let err = Aviffy::new().write(Truncated { limit: len - 1 }, &test_img, None, 10, 20, 8, 1, None, None, &[]).unwrap_err();
+    assert!(err.to_string().contains("mdat"), "{err}");
+
+    // Inside the first of several chunks: alpha, then color
+    let len = Aviffy::new().to_vec(&test_img, Some(b"ghijkl"), 10, 20, 8, 1, None, None, &[]).len();
+    let err = Aviffy::new().write(Truncated { limit: len - 9 }, &test_img, Some(b"ghijkl"), 10, 20, 8, 1, None, None, &[]).unwrap_err();
+    assert_eq!(io::ErrorKind::WriteZero, err.kind());
+    assert!(err.to_string().contains("mdat"), "{err}");
+}
+
+#[test]
+fn header_size_is_exact() {
+    let test_img = [1,2,3,4,5,6];
+    let test_alpha = [77,88,99];
+    let exif_data = b"II*\0\x08\0\0\0";
+    let frames = [
+        FrameInfo::new(3, true, 4),
+        FrameInfo::new(5, false, 2),
+    ];
+    for layout in [MdatLayout::Single, MdatLayout::MetadataFirst, MdatLayout::PerItem] {
+        let mut aviffy = Aviffy::new();
+        aviffy.mdat_layout(layout);
+        let header = aviffy.header_size(&ImageData::new(&[], None, 10, 20, 8)).unwrap();
+        assert_eq!(header + test_img.len(), aviffy.to_vec(&test_img, None, 10, 20, 8, 1, None, None, &[]).len());
+
+        let header = aviffy.header_size(&ImageData { timescale: 10, color_frames: Some(&frames), alpha_frames: Some(&frames), exif_data, ..ImageData::new(&[], Some(&[]), 10, 20, 8) }).unwrap();
+        let avif = aviffy.to_vec(&test_img, Some(&test_img), 10, 20, 8, 10, Some(&frames), Some(&frames), exif_data);
+        assert_eq!(header + 2 * test_img.len() + exif_data.len(), avif.len());
+
+        let header = aviffy.header_size(&ImageData { exif_data, ..ImageData::new(&[], Some(&[]), 10, 20, 8) }).unwrap();
+        assert_eq!(header + test_img.len() + test_alpha.len() + exif_data.len(), aviffy.to_vec(&test_img, Some(&test_alpha), 10, 20, 8, 1, None, None, exif_data).len());
+    }
+}
+
+#[test]
+fn compose_items() {
+    let test_img = [1,2,3,4,5,6];
+    let test_alpha = [77,88,99];
+    let test_depth = [5,5];
+    let xmp = b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"/>";
+    let aviffy = Aviffy::new();
+
+    let mut composition = aviffy.compose();
+    composition.add_color(&test_img[..], 10, 20, 8);
+    composition.add_alpha(&test_alpha[..]);
+    let mut composed = Vec::new();
+    composition.finish(&mut composed).unwrap();
+    assert_eq!(aviffy.to_vec(&test_img, Some(&test_alpha), 10, 20, 8, 1, None, None, &[]), composed);
+
+    let mut composition = aviffy.compose();
+    let color = composition.add_color(&test_img[..], 10, 20, 8);
+    composition.add_aux(&test_depth[..], 5, 10, "urn:mpeg:hevc:2015:auxid:2", color);
+    composition.add_metadata(MetadataKind::Xmp, &xmp[..]);
+    let mut avif = Vec::new();
+    composition.finish(&mut avif).unwrap();
+
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(&test_img[..], ctx.primary_item.as_slice());
+    assert!(ctx.alpha_item.is_none());
+    assert_eq!(Some(&xmp[..]), remux::metadata(&avif).unwrap().xmp.as_deref());
+}
+
+#[test]
+fn item_graph() {
+    struct Prop;
+    impl CustomBox for Prop {
+        fn len(&self) -> usize { 12 }
+        fn write(&self, out: &mut Vec<u8>) {
+            out.extend_from_slice(&12u32.to_be_bytes());
+            out.extend_from_slice(b"xprp1234");
+        }
+    }
+
+    let test_img = [1,2,3,4,5,6];
+    let aviffy = Aviffy::new();
+    let mut composition = aviffy.compose();
+    let color = composition.add_color(&test_img[..], 10, 20, 8);
+    let graph = composition.graph();
+    let note = graph.add_metadata_item(*b"mime", "Note", Some("text/plain"), &b"hello"[..]);
+    graph.add_reference(note, *b"cdsc", color)
+        .add_property(color, Prop, false)
+        .add_property(note, Prop, false);
+    let mut avif = Vec::new();
+    composition.finish(&mut avif).unwrap();
+
+    // Identical properties are stored once
+    assert_eq!(1, avif.windows(8).filter(|w| w == b"xprp1234").count());
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(&test_img[..], ctx.primary_item.as_slice());
+}
+
+#[test]
+fn to_vec_in_reuses_buffer() {
+    let aviffy = Aviffy::new();
+    let mut out = vec![0xFF; 4000];
+    let capacity = out.capacity();
+    aviffy.to_vec_in(&mut out, &ImageData::new(&[1,2,3,4,5,6], Some(&[7,8,9]), 10, 20, 8)).unwrap();
+    assert_eq!(aviffy.to_vec(&[1,2,3,4,5,6], Some(&[7,8,9]), 10, 20, 8, 1, None, None, &[]), out);
+    assert_eq!(capacity, out.capacity());
+}
+
+#[test]
+#[cfg(feature = "rayon")]
+fn batch_in_order() {
+    let aviffy = Aviffy::new();
+    // Starting with a zero byte, a reserved OBU type, the data isn't mistaken for a sequence header
+    let images: Vec<Vec<u8>> = (0..50u8).map(|i| [&[0][..], &vec![i; usize::from(i)]].concat()).collect();
+    let batch: Vec<_> = images.iter().map(|img| BatchImage::new(img, None, 10, 20, 8)).collect();
+    let files = aviffy.to_vec_batch(&batch);
+    assert_eq!(images.len(), files.len());
+    for (img, file) in images.iter().zip(&files) {
+        assert_eq!(&aviffy.to_vec(img, None, 10, 20, 8, 1, None, None, &[]), file.as_ref().unwrap());
+    }
+}
+
+#[test]
+#[cfg(feature = "rav1e")]
+fn from_rav1e_config() {
+    use constants::*;
+    use rav1e::color;
+
+    let config = rav1e::EncoderConfig {
+        bit_depth: 10,
+        chroma_sampling: color::ChromaSampling::Cs444,
+        pixel_range: color::PixelRange::Full,
+        color_description: Some(color::ColorDescription {
+            color_primaries: color::ColorPrimaries::BT2020,
+            transfer_characteristics: color::TransferCharacteristics::SMPTE2084,
+            matrix_coefficients: color::MatrixCoefficients::BT2020NCL,
+        }),
+        ..Default::default()
+    };
+    let aviffy = Aviffy::from_rav1e_config(&config);
+    let expected = ColorInfo {
+        color_primaries: ColorPrimaries::Bt2020,
+        transfer_characteristics: TransferCharacteristics::Pq,
+        matrix_coefficients: MatrixCoefficients::Bt2020Ncl,
+        full_color_range: true,
+    };
+    assert_eq!(expected, aviffy.config().color);
+    assert_eq!(Some(10), aviffy.config().bit_depth);
+    assert_eq!(ChromaSubsampling::Cs444, aviffy.config().chroma_subsampling);
+
+    let config = rav1e::EncoderConfig {
+        chroma_sampling: color::ChromaSampling::Cs420,
+        chroma_sample_position: color::ChromaSamplePosition::Colocated,
+        color_description: None,
+        ..config
+    };
+    let aviffy = Aviffy::from_rav1e_config(&config);
+    assert_eq!(ColorPrimaries::Unspecified, aviffy.config().color.color_primaries);
+    assert_eq!(TransferCharacteristics::Unspecified, aviffy.config().color.transfer_characteristics);
+    assert_eq!(MatrixCoefficients::Unspecified, aviffy.config().color.matrix_coefficients);
+    assert_eq!(ChromaSubsampling::Cs420, aviffy.config().chroma_subsampling);
+    assert_eq!(ChromaSamplePosition::Colocated, aviffy.config().chroma_sample_position);
+    // Unspecified values are still written
+    let avif = aviffy.to_vec(b"abcdef", None, 10, 20, 10, 1, None, None, &[]);
+    let nclx = avif.windows(4).position(|w| w == b"nclx").unwrap();
+    assert_eq!(&avif[nclx + 4..nclx + 11], &[0, 2, 0, 2, 0, 2, 0x80]);
+
+    let config = rav1e::EncoderConfig {
+        color_description: Some(color::ColorDescription {
+            color_primaries: color::ColorPrimaries::XYZ,
+            transfer_characteristics: color::TransferCharacteristics::SRGB,
+            matrix_coefficients: color::MatrixCoefficients::Identity,
+        }),
+        ..config
+    };
+    let color = Aviffy::from_rav1e_config(&config).config().color;
+    assert_eq!(ColorPrimaries::Unspecified, color.color_primaries);
+    assert_eq!(TransferCharacteristics::Srgb, color.transfer_characteristics);
+    assert_eq!(MatrixCoefficients::Rgb, color.matrix_coefficients);
+}
+
+#[test]
+fn header_template_matches_write() {
+    let aviffy = Aviffy::new();
+    let template = aviffy.header_template(true, 10, 20, 8, false).unwrap();
+    for (color, alpha) in [(&[1,2,3][..], &[4][..]), (&[1,2,3,4,5,6,7,8][..], &[9,9,9][..])] {
+        let mut out = Vec::new();
+        template.write(&mut out, color, Some(alpha), &[]).unwrap();
+        assert_eq!(aviffy.to_vec(color, Some(alpha), 10, 20, 8, 1, None, None, &[]), out);
+    }
+    assert!(template.write(Vec::new(), &[1], None, &[]).is_err());
+
+    let mut aviffy = Aviffy::new();
+    aviffy.xmp(&b"<x/>"[..]);
+    let template = aviffy.header_template(false, 10, 20, 8, true).unwrap();
+    let mut out = Vec::new();
+    template.write(&mut out, &[1, 2, 3], None, b"Exif").unwrap();
+    assert_eq!(aviffy.to_vec(&[1, 2, 3], None, 10, 20, 8, 1, None, None, b"Exif"), out);
+}
+
+#[test]
+fn header_template_rejects_data_dependent_settings() {
+    assert!(Aviffy::new().sequence_header_in_av1c(true).header_template(false, 10, 20, 8, false).is_err());
+    assert!(Aviffy::new().verify_size(true).header_template(false, 10, 20, 8, false).is_err());
+    assert!(Aviffy::new().verify_alpha(true).header_template(true, 10, 20, 8, false).is_err());
+    assert!(Aviffy::new().layer_sizes(&[1, 1]).header_template(false, 10, 20, 8, false).is_err());
+}
+
+#[test]
+fn simple_still_matches_general() {
+    struct Prop;
+    impl CustomBox for Prop {
+        fn len(&self) -> usize { 12 }
+        fn write(&self, out: &mut Vec<u8>) {
+            out.extend_from_slice(&12u32.to_be_bytes());
+            out.extend_from_slice(b"xprp1234");
+        }
+    }
+
+    let test_img = [1,2,3,4,5,6];
+    let av1c = Aviffy::new().color_config(8, 1);
+    let channel = GainMapChannel { min: (0, 1), max: (3, 1), gamma: (1, 1), base_offset: (1, 64), alternate_offset: (1, 64) };
+    let gain_map = GainMapMetadata { base_hdr_headroom: (0, 1), alternate_hdr_headroom: (3, 1), channels: vec![channel], use_base_color_space: true };
+
+    // Settings that the fast path writes, or that don't apply to a still image without alpha
+    let fast: [&dyn Fn(&mut Aviffy); 13] = [
+        &|_| {},
+        &|a| { a.full_color_range(true); },
+        &|a| { a.color_space_preset(constants::ColorSpacePreset::Bt2100Pq); },
+        &|a| { a.unspecified_color(); },
+        &|a| { a.chroma_subsampling(constants::ChromaSubsampling::Cs420).chroma_sample_position(constants::ChromaSamplePosition::Colocated); },
+        &|a| { a.seq_level_idx(Some(31)).high_tier(true); },
+        &|a| { a.bit_depth(10); },
+        &|a| { a.color_av1c(Av1CBox { seq_level_idx_0: 5, ..av1c }); },
+        &|a| { a.layout_version(1); },
+        &|a| { a.premultiplied_alpha(true).mdat_layout(MdatLayout::PerItem).color_track_colr(TrackColr::None).alpha_track_colr(TrackColr::SameAsItem); },
+        &|a| { a.hold_last_frame(true).long_times(true).alpha_first(false).alpha_av1c(av1c).alpha_urn(constants::AlphaUrn::Hevc); },
+        &|a| { a.add_track_reference(1, *b"test", &[2]).spherical_video(Some(Projection::Cubemap), StereoMode::Mono).custom_box(CustomBoxLocation::Track, Prop); },
+        &|a| { a.mdat_layout(MdatLayout::MetadataFirst).verify_alpha(true).verify_sync(true).nclx_with_icc(true).gain_map_format(GainMapFormat::Apple); },
+    ];
+    for set in fast {
+        let mut aviffy = Aviffy::new();
+        set(&mut aviffy);
+        assert!(aviffy.is_simple_still(None, None, &[]));
+        let mut general = Vec::new();
+        aviffy.make_boxes(&test_img, None, 10, 20, 8, 1, None, None, &[], &NO_ITEMS).unwrap().write(&mut general).unwrap();
+        assert_eq!(general, aviffy.to_vec(&test_img, None, 10, 20, 8, 1, None, None, &[]));
+    }
+
+    let general: [&dyn Fn(&mut Aviffy); 24] = [
+        &|a| { a.custom_box(CustomBoxLocation::Meta, Prop); },
+        &|a| { a.seq_profile(Some(2)); },
+        &|a| { a.sequence_header_in_av1c(true); },
+        &|a| { a.embed_blob(BlobLocation::Free, &b"free"[..]); },
+        &|a| { a.add_jpeg_preview(&b"jpeg"[..], 2, 4, RenditionLink::Thumbnail); },
+        &|a| { a.xmp(&b"xmp"[..]); },
+        &|a| { a.add_auxiliary("urn:test", &b"aux"[..], 10, 20); },
+        &|a| { a.add_metadata_track("text/plain", [&b"a"[..]]); },
+        &|a| { a.exif(&b"exif"[..]); },
+        &|a| { a.icc_profile(&b"icc"[..]); },
+        &|a| { a.mastering_display(Some(MasteringDisplay { red: (1, 1), green: (1, 1), blue: (1, 1), white_point: (1, 1), max_luminance: 2, min_luminance: 1 })); },
+        &|a| { a.content_light_level(Some(ContentLightLevel { max_cll: 1000, max_fall: 400 })); },
+        &|a| { a.ambient_viewing(Some(AmbientViewing { illuminance: 50000, light: (15635, 16450) })); },
+        &|a| { a.gain_map(&b"gainmap"[..], 5, 10, 8, &gain_map, None); },
+        &|a| { a.dolby_vision(Some(DolbyVision { profile: 10, level: 5, rpu_present: true, el_present: false, bl_present: true, bl_signal_compatibility_id: 1 })); },
+        &|a| { a.free_padding(1); },
+        &|a| { a.user_description(Some(UserDescription::default())); },
+        &|a| { a.alt_text("text", "en"); },
+        &|a| { a.rotation(Rotation::Rotation90); },
+        &|a| { a.mirror(Some(Mirror::LeftRight)); },
+        &|a| { a.clean_aperture(Some(CleanAperture { width: (9, 1), height: (19, 1), horiz_off: (-1, 2), vert_off: (-1, 2) })); },
+        &|a| { a.display_size(Some((9, 19))); },
+        &|a| { a.pixel_aspect_ratio(1, 2).layer_sizes(&[3, 3]); },
+        &|a| { a.operating_point(Some(0)); },
+    ];
+    for set in general {
+        let mut aviffy = Aviffy::new();
+        set(&mut aviffy);
+        assert!(!aviffy.is_simple_still(None, None, &[]));
+    }
+    assert!(!Aviffy::new().is_simple_still(Some(&test_img), None, &[]));
+    assert!(!Aviffy::new().is_simple_still(None, Some(&[]), &[]));
+    assert!(!Aviffy::new().is_simple_still(None, None, b"exif"));
+}
+
+#[test]
+fn invalid_input_is_an_error() {
+    struct Liar;
+    impl CustomBox for Liar {
+        fn len(&self) -> usize { 100 }
+        fn write(&self, out: &mut Vec<u8>) {
+            out.extend_from_slice(&12u32.to_be_bytes());
+            out.extend_from_slice(b"free1234");
+        }
+    }
+
+    let test_img = [1,2,3,4,5,6];
+    let mut aviffy = Aviffy::new();
+    assert!(aviffy.try_to_vec(&test_img, None, 10, 20, 8, 1, None, None, &[]).is_ok());
+    let frames = [FrameInfo::new(1, true, 4), FrameInfo::new(1, false, 4)];
+    assert!(aviffy.to_segments(&ImageData { color_frames: Some(&frames), ..ImageData::new(&test_img, None, 10, 20, 8) }, 1).is_err());
+    #[cfg(target_pointer_width = "64")]
+    {
+        let huge = [FrameInfo::new(1, true, 1 << 32)];
+        let err = aviffy.header_size(&ImageData { color_frames: Some(&huge), ..ImageData::new(&[], None, 10, 20, 8) }).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    }
+    let long = [FrameInfo::new(1 << 32, true, 6)];
+    assert!(aviffy.try_to_vec(&test_img, None, 10, 20, 8, 1, Some(&long), None, &[]).is_err());
+    let frame = [FrameInfo::new(1, true, 6)];
+    assert!(aviffy.try_to_vec(&test_img, None, 70000, 20, 8, 1, Some(&frame), None, &[]).is_err());
+    assert!(aviffy.try_to_vec(&test_img, None, 10, 20, 8, 1, Some(&frame), None, &[]).is_ok());
+    aviffy.custom_box(CustomBoxLocation::Meta, Liar);
+    assert!(aviffy.try_to_vec(&test_img, None, 10, 20, 8, 1, None, None, &[]).is_err());
+}
+
+#[test]
+fn edit_document() {
+    struct Prop;
+    impl CustomBox for Prop {
+        fn len(&self) -> usize { 12 }
+        fn write(&self, out: &mut Vec<u8>) {
+            out.extend_from_slice(&12u32.to_be_bytes());
+            out.extend_from_slice(b"xprp1234");
+        }
+    }
+
+    let test_img = [1,2,3,4,5,6];
+    let aviffy = Aviffy::new();
+    let mut doc = aviffy.document(ImageData::new(&test_img, None, 10, 20, 8)).unwrap();
+    let primary = doc.primary_item();
+    assert_eq!(vec![(primary, *b"av01", "Color")], doc.items().map(|(id, typ, name)| (id, typ, name.to_owned())).collect::<Vec<_>>());
+    let mut brands: Vec<_> = doc.compatible_brands().collect();
+    brands.reverse();
+    doc.set_compatible_brands(&brands);
+    doc.set_item_name(primary, "Photo").unwrap();
+    doc.add_property(primary, Prop, false).unwrap();
+    assert!(doc.add_property(99, Prop, false).is_err());
+    assert_eq!(Some((*b"xprp", false)), doc.item_properties(primary).last());
+
+    let mut avif = Vec::new();
+    doc.write(&mut avif).unwrap();
+    assert!(avif.windows(6).any(|w| w == b"Photo\0"));
+    assert!(avif.windows(8).any(|w| w == b"xprp1234"));
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(&test_img[..], ctx.primary_item.as_slice());
+}
+
+#[test]
+fn document_property_before_transforms() {
+    struct Prop;
+    impl CustomBox for Prop {
+        fn len(&self) -> usize { 12 }
+        fn write(&self, out: &mut Vec<u8>) {
+            out.extend_from_slice(&12u32.to_be_bytes());
+            out.extend_from_slice(b"xprp1234");
+        }
+    }
+
+    let test_img = [1,2,3,4,5,6];
+    let mut aviffy = Aviffy::new();
+    aviffy.display_size(Some((8, 20))).rotation(Rotation::Rotation90).mirror(Some(Mirror::LeftRight));
+    let mut doc = aviffy.document(ImageData::new(&test_img, None, 10, 20, 8)).unwrap();
+    let primary = doc.primary_item();
+    doc.add_property(primary, Prop, false).unwrap();
+    let types: Vec<_> = doc.item_properties(primary).map(|(typ, _)| typ).collect();
+    assert_eq!(&types[types.len() - 4..], [*b"xprp", *b"clap", *b"irot", *b"imir"]);
+}
+
+#[test]
+fn iptc_item() {
+    let test_img = [1,2,3,4,5,6];
+    let iptc = [0x1C, 2, 120, 0, 5, b'h', b'e', b'l', b'l', b'o'];
+    for layout in [MdatLayout::Single, MdatLayout::MetadataFirst, MdatLayout::PerItem] {
+        let avif = Aviffy::new().mdat_layout(layout).iptc(&b"old"[..]).iptc(&iptc[..])
+            .to_vec(&test_img, Some(&[7, 8]), 10, 20, 8, 1, None, None, b"II*\0");
+        let metadata = remux::metadata(&avif).unwrap();
+        assert_eq!(Some(&iptc[..]), metadata.iptc.as_deref());
+        assert_eq!(Some(&b"II*\0"[..]), metadata.exif.as_deref());
+        let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+        assert_eq!(&test_img[..], ctx.primary_item.as_slice());
+    }
+}
+
+#[test]
+fn c2pa_item() {
+    let test_img = [1,2,3,4,5,6];
+    let jumbf = b"\0\0\0\x20jumb\0\0\0\x18jumdc2pa\0\x11\0\x10\x80\0\xaa\0\x38\x9b\x71";
+    let avif = Aviffy::new().c2pa_manifest(&jumbf[..]).iptc(&b"iptc"[..])
+        .to_vec(&test_img, None, 10, 20, 8, 1, None, None, &[]);
+    let metadata = remux::metadata(&avif).unwrap();
+    assert_eq!(Some(&jumbf[..]), metadata.c2pa.as_deref());
+    assert_eq!(Some(&b"iptc"[..]), metadata.iptc.as_deref());
+}
+
+#[test]
+fn jpeg_preview() {
+    let test_img = [1,2,3,4,5,6];
+    let test_jpeg = [0xFF, 0xD8, 0xFF, 0xD9];
+    let avif = Aviffy::new()
+        .add_jpeg_preview(&test_jpeg[..], 2, 4, RenditionLink::Thumbnail)
+        .to_vec(&test_img, None, 10, 20, 8, 1, None, None, &[]);
+
+    assert!(avif.windows(12).any(|w| w == b"jpegPreview\0"));
+    assert!(avif.ends_with(&[1,2,3,4,5,6,0xFF, 0xD8, 0xFF, 0xD9]));
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(&test_img[..], ctx.primary_item.as_slice());
+}
+
+#[test]
+fn metadata_track() {
+    let frames = [FrameInfo::new(10, true, 3), FrameInfo::new(20, false, 3)];
+    let mut aviffy = Aviffy::new();
+    aviffy.add_metadata_track("application/json", [&b"{\"iso\":100}"[..], b"{\"iso\":200}"]);
+    let mut out = Vec::new();
+    let map = aviffy.write_with_sample_map(&mut out, &ImageData { timescale: 10, color_frames: Some(&frames), alpha_frames: Some(&frames), ..ImageData::new(b"abcdef", Some(b"ghijkl"), 10, 20, 8) }).unwrap();
+    assert_eq!(vec![0..3, 3..6], map.color.iter().map(|r| r.start - map.color[0].start..r.end - map.color[0].start).collect::<Vec<_>>());
+    assert_eq!(2, map.alpha.len());
+    assert!(out.windows(4).any(|w| w == b"mett"));
+    assert!(out.windows(22).any(|w| w == b"{\"iso\":100}{\"iso\":200}"));
+    let ctx = mp4parse::read_avif(&mut out.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+    assert_eq!(&b"abcdef"[..], ctx.primary_item_coded_data());
+
+    assert!(aviffy.try_to_vec(b"abc", None, 10, 20, 8, 10, Some(&frames[..1]), None, &[]).is_err());
+}
+
+#[test]
+fn spherical_video() {
+    let frames = [FrameInfo::new(1, true, 3), FrameInfo::new(1, false, 3)];
+    let avif = Aviffy::new()
+        .spherical_video(Some(Projection::Equirectangular), StereoMode::TopBottom)
+        .to_vec(b"abcdef", Some(b"ghijkl"), 10, 20, 8, 10, Some(&frames), Some(&frames), &[]);
+    assert_eq!(1, avif.windows(4).filter(|w| w == b"st3d").count());
+    assert_eq!(1, avif.windows(4).filter(|w| w == b"equi").count());
+    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+    assert_eq!(&b"abcdef"[..], ctx.primary_item_coded_data());
+}
+
+#[test]
+fn mux_stats() {
+    let frames = [FrameInfo::new(5, true, 3), FrameInfo::new(5, false, 1), FrameInfo::new(5, false, 2), FrameInfo::new(5, true, 2)];
+    let mut out = Vec::new();
+    let image = ImageData { timescale: 10, color_frames: Some(&frames), ..ImageData::new(b"abcdefgh", None, 10, 20, 8) };
+    let stats = Aviffy::new().write_with_stats(&mut out, image).unwrap();
+    assert_eq!(4, stats.frame_count);
+    assert_eq!((1, 2., 3), (stats.min_frame_size, stats.avg_frame_size, stats.max_frame_size));
+    assert_eq!((2, 3), (stats.keyframe_count, stats.max_keyframe_interval));
+    assert_eq!(2., stats.duration);
+    assert_eq!(out.len() as u64, stats.file_size);
+    assert_eq!(out.len() as f64 * 4., stats.avg_bitrate);
+}
+
+#[test]
+fn colr_mismatch() {
+    use crate::constants::ColorPrimaries;
+
+    // Reduced still picture sequence header: 10x20, BT.709 primaries, sRGB transfer, BT.601 matrix, full range
+    let av1 = [0x0A, 9, 0x18, 0x0D, 0x26, 0x60, 0x10, 0x10, 0xD0, 0x68, 0x40];
+    let mut aviffy = Aviffy::new();
+    // No colr box is written for the defaults
+    assert!(aviffy.check_colr(&[]).unwrap().is_empty());
+
+    aviffy.color_primaries(ColorPrimaries::Bt2020).full_color_range(false);
+    assert!(aviffy.check_colr(&[]).is_err());
+    assert_eq!(aviffy.check_colr(&av1).unwrap(), [
+        Mismatch::ColorPrimaries { colr: 9, av1: 1 },
+        Mismatch::FullColorRange { colr: false, av1: true },
+    ]);
+}
+
+#[test]
+fn alpha_must_be_monochrome() {
+    // Reduced still picture sequence header of a 10x20 4:2:0 image
+    let color = [0x0A, 9, 0x18, 0x0D, 0x26, 0x60, 0x10, 0x10, 0xD0, 0x68, 0x40];
+    let mut aviffy = Aviffy::new();
+    assert_eq!(aviffy.check_alpha(&color, 10, 30).unwrap(), [
+        Mismatch::AlphaNotMonochrome,
+        Mismatch::AlphaSize { width: 10, height: 30, av1_width: 10, av1_height: 20 },
+    ]);
+    assert!(aviffy.try_to_vec(&color, Some(&color), 10, 20, 8, 0, None, None, &[]).is_ok());
+    aviffy.verify_alpha(true);
+    let err = aviffy.try_to_vec(&color, Some(&color), 10, 20, 8, 0, None, None, &[]).unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+}
+
+#[test]
+fn size_must_match_sequence_header() {
+    // Reduced still picture sequence header of a 10x20 image
+    let color = [0x0A, 9, 0x18, 0x0D, 0x26, 0x60, 0x10, 0x10, 0xD0, 0x68, 0x40];
+    let mut aviffy = Aviffy::new();
+    assert!(aviffy.check_size(&color, 10, 20).unwrap().is_empty());
+    let swapped = aviffy.check_size(&color, 20, 10).unwrap();
+    assert_eq!(swapped, [Mismatch::ImageSize { width: 20, height: 10, av1_width: 10, av1_height: 20 }]);
+    assert!(swapped[0].to_string().contains("swapped"));
+
+    aviffy.verify_size(true);
+    assert!(aviffy.try_to_vec(&color, None, 10, 20, 8, 0, None, None, &[]).is_ok());
+    let err = aviffy.try_to_vec(&color, None, 20, 10, 8, 0, None, None, &[]).unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+}
+
+#[test]
+fn av1c_from_sequence_header() {
+    // Reduced still picture sequence header of a 10x20 8-bit 4:2:0 image
+    let color = [0x0A, 9, 0x18, 0x0D, 0x26, 0x60, 0x10, 0x10, 0xD0, 0x68, 0x40];
+    let mut aviffy = Aviffy::new();
+    aviffy.av1c_from_sequence_header(&color).unwrap();
+    let config = aviffy.config();
+    assert_eq!(config.chroma_subsampling, constants::ChromaSubsampling::Cs420);
+    assert_eq!(config.bit_depth, Some(8));
+    let av1c = config.color_av1c.unwrap();
+    assert_eq!((av1c.seq_profile, av1c.high_bitdepth, av1c.chroma_subsampling_x, av1c.chroma_subsampling_y), (0, false, true, true));
+    assert!(aviffy.av1c_from_sequence_header(&color).is_ok());
+
+    let err = Aviffy::new().bit_depth(10).av1c_from_sequence_header(&color).map(drop).unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    assert!(Aviffy::new().color_av1c(Av1CBox { seq_profile: 1, ..av1c }).av1c_from_sequence_header(&color).is_err());
+}
+
+#[test]
+fn av1c_is_made_from_sequence_header() {
+    // Reduced still picture sequence header of a 10x20 8-bit 4:2:0 image
+    let color = [0x0A, 9, 0x18, 0x0D, 0x26, 0x60, 0x10, 0x10, 0xD0, 0x68, 0x40];
+    let auto = Aviffy::new().to_vec(&color, None, 10, 20, 8, 1, None, None, &[]);
+    let mut explicit = Aviffy::new();
+    explicit.av1c_from_sequence_header(&color).unwrap();
+    assert_eq!(auto, explicit.to_vec(&color, None, 10, 20, 8, 1, None, None, &[]));
+    let mut general = Vec::new();
+    Aviffy::new().document(ImageData::new(&color, None, 10, 20, 8)).unwrap().write(&mut general).unwrap();
+    assert_eq!(auto, general);
+    // Older layouts use the settings, which are 4:4:4 by default
+    assert_ne!(auto, Aviffy::new().layout_version(2).to_vec(&color, None, 10, 20, 8, 1, None, None, &[]));
+
+    let err = Aviffy::new().try_to_vec(&color, None, 10, 20, 10, 1, None, None, &[]).unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+    assert!(Aviffy::new().seq_profile(Some(1)).try_to_vec(&color, None, 10, 20, 8, 1, None, None, &[]).is_err());
+
+    // Templates can't see the data, so its sequence header has to match the settings
+    let template = Aviffy::new().header_template(false, 10, 20, 8, false).unwrap();
+    assert!(template.write(Vec::new(), &color, None, &[]).is_err());
+    let template = explicit.header_template(false, 10, 20, 8, false).unwrap();
+    let mut out = Vec::new();
+    template.write(&mut out, &color, None, &[]).unwrap();
+    assert_eq!(auto, out);
+}
+
+#[test]
+fn chroma_sample_position() {
+    let mut aviffy = Aviffy::new();
+    aviffy.chroma_subsampling(constants::ChromaSubsampling::Cs420)
+        .chroma_sample_position(constants::ChromaSamplePosition::Colocated);
+    let avif = aviffy.to_vec(b"av1", None, 10, 20, 8, 0, None, None, &[]);
+    let av1c = avif.windows(4).position(|w| w == b"av1C").unwrap();
+    assert_eq!(avif[av1c + 6], 0b0000_1110);
+
+    aviffy.chroma_subsampling(constants::ChromaSubsampling::Cs422);
+    let avif = aviffy.to_vec(b"av1", None, 10, 20, 8, 0, None, None, &[]);
+    let av1c = avif.windows(4).position(|w| w == b"av1C").unwrap();
+    assert_eq!(avif[av1c + 6], 0b0000_1000);
+}
+
+#[test]
+#[cfg(any(debug_assertions, feature = "check-box-len"))]
+#[should_panic(expected = "box 'test' wrote 8 bytes, but its len() is 10")]
+fn box_len_mismatch_panics() {
+    let mut out = Vec::new();
+    let mut w = writer::Writer::new(&mut out);
+    let mut b = w.new_box(10);
+    let _ = b.basic_box(*b"test");
+}
+
+/// Writing checks every box's length in debug builds, so this writes every kind of box
+#[test]
+fn box_lengths_match() {
+    struct Prop;
+    impl CustomBox for Prop {
+        fn len(&self) -> usize { 12 }
+        fn write(&self, out: &mut Vec<u8>) {
+            out.extend_from_slice(&12u32.to_be_bytes());
+            out.extend_from_slice(b"xprp1234");
+        }
+    }
+
+    let frames = [FrameInfo::new(1, true, 3), FrameInfo::new(1, false, 3)];
+    for layout in [MdatLayout::Single, MdatLayout::MetadataFirst, MdatLayout::PerItem] {
+        let mut aviffy = Aviffy::new();
+        aviffy.mdat_layout(layout)
+            .premultiplied_alpha(true)
+            .long_times(true)
+            .hold_last_frame(true)
+            .alpha_track_colr(TrackColr::SameAsItem)
+            .custom_box(CustomBoxLocation::Meta, Prop)
+            .custom_box(CustomBoxLocation::Track, Prop)
+            .custom_box(CustomBoxLocation::SampleEntry, Prop)
+            .embed_blob(BlobLocation::Free, &b"free"[..])
+            .embed_blob(BlobLocation::Uuid([7; 16]), &b"uuid"[..])
+            .embed_blob(BlobLocation::MetaUuid([8; 16]), &b"meta"[..])
+            .free_padding(16)
+            .embed_blob(BlobLocation::Udta(*b"\xa9too"), &b"udta"[..])
+            .add_rendition(&b"thumb"[..], 2, 4, RenditionLink::ThumbnailAndAlternative)
+            .add_jpeg_preview(&b"jpeg"[..], 2, 4, RenditionLink::Thumbnail)
+            .iptc(&b"iptc"[..])
+            .c2pa_manifest(&b"jumb"[..])
+            .add_track_reference(1, *b"test", &[2])
+            .spherical_video(Some(Projection::Cubemap), StereoMode::LeftRight);
+        assert!(!aviffy.to_vec(b"abcdef", Some(b"ghijkl"), 10, 20, 8, 10, None, None, b"exif").is_empty());
+        assert!(!aviffy.to_vec(b"abcdef", Some(b"ghijkl"), 10, 20, 8, 10, Some(&frames), Some(&frames), b"exif").is_empty());
+        aviffy.add_metadata_track("text/plain", [&b"a"[..], b"b"]);
+        assert!(!aviffy.to_vec(b"abcdef", None, 10, 20, 8, 10, Some(&frames), None, &[]).is_empty());
+    }
+
+    let aviffy = Aviffy::new();
+    assert!(!aviffy.to_vec(b"abcdef", None, 10, 20, 8, 10, None, None, &[]).is_empty());
+    aviffy.to_segments(&ImageData { timescale: 10, color_frames: Some(&frames), alpha_frames: Some(&frames), ..ImageData::new(b"abcdef", Some(b"ghijkl"), 10, 20, 8) }, 1).unwrap();
+    let mut composition = aviffy.compose();
+    let color = composition.add_color(&b"abcdef"[..], 10, 20, 8);
+    composition.add_aux(&b"dd"[..], 5, 10, "urn:mpeg:hevc:2015:auxid:2", color);
+    let graph = composition.graph();
+    let note = graph.add_metadata_item(*b"mime", "Note", Some("text/plain"), &b"hello"[..]);
+    graph.add_reference(note, *b"cdsc", color).add_property(note, Prop, true);
+    composition.finish(&mut Vec::new()).unwrap();
+}
+
+/// A failed write leaves boxes incomplete, which must be an error, not a length check panic
+#[test]
+fn write_error_inside_mdat() {
+    let frames = [FrameInfo::new(1, true, 3), FrameInfo::new(1, false, 3)];
+    for color_frames in [None, Some(&frames[..])] {
+        let alpha_frames = color_frames;
+        let len = Aviffy::new().to_vec(b"abcdef", Some(b"ghijkl"), 10, 20, 8, 10, color_frames, alpha_frames, &[]).len();
+        // From the mdat header to the last byte of data
+        for limit in len - 20..len {
+            let err = Aviffy::new().write(Truncated { limit }, b"abcdef", Some(b"ghijkl"), 10, 20, 8, 10, color_frames, alpha_frames, &[]).unwrap_err();
+            assert_eq!(io::ErrorKind::WriteZero, err.kind());
+        }
+    }
+}
+
+#[test]
+fn segments_match_to_vec() {
+    fn boxes<'a>(data: &'a [u8], typ: &[u8; 4]) -> Vec<&'a [u8]> {
+        data.windows(4).enumerate().filter(|&(pos, w)| w == typ && pos >= 4).map(|(pos, _)| {
+            let len = u32::from_be_bytes(data[pos - 4..pos].try_into().unwrap()) as usize;
+            &data[pos - 4..pos - 4 + len]
+        }).collect()
+    }
+
+    let frames = [FrameInfo::new(1, true, 3), FrameInfo::new(2, false, 3)];
+    let aviffy = Aviffy::new();
+    let segments = aviffy.to_segments(&ImageData { timescale: 10, color_frames: Some(&frames), alpha_frames: Some(&frames), ..ImageData::new(b"abcdef", Some(b"ghijkl"), 10, 20, 8) }, 1).unwrap();
+    let avif = aviffy.to_vec(b"abcdef", Some(b"ghijkl"), 10, 20, 8, 10, Some(&frames), Some(&frames), &[]);
+    // Sample entries are the same as in the whole file
+    assert_eq!(boxes(&avif, b"stsd"), boxes(&segments.init, b"stsd"));
+    assert_eq!(2, boxes(&segments.init, b"stsd").len());
+
+    // The segments are complete boxes, and have all the frames in order, alpha first
+    let all: Vec<u8> = segments.media.concat();
+    let mut pos = 0;
+    let mut payloads = Vec::new();
+    while pos < all.len() {
+        let len = u32::from_be_bytes(all[pos..pos + 4].try_into().unwrap()) as usize;
+        if &all[pos + 4..pos + 8] == b"mdat" {
+            payloads.extend_from_slice(&all[pos + 8..pos + len]);
+        }
+        pos += len;
+    }
+    assert_eq!(all.len(), pos);
+    assert_eq!(b"ghiabcjkldef"[..], payloads);
+
+    let err = aviffy.to_segments(&ImageData::new(b"abcdef", None, 10, 20, 8), 1).err().unwrap();
+    assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+}
+
+#[test]
+fn hold_last_frame_edit_list() {
+    let frames = [FrameInfo::new(2, true, 3), FrameInfo::new(3, false, 3)];
+    let avif = Aviffy::new().hold_last_frame(true).to_vec(b"abcdef", None, 10, 20, 8, 10, Some(&frames), None, &[]);
+    let mut edts = vec![0, 0, 0, 64];
+    edts.extend_from_slice(b"edts");
+    edts.extend_from_slice(&[0, 0, 0, 56]);
+    edts.extend_from_slice(b"elst");
+    edts.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 2]); // version 1, 2 entries
+    edts.extend_from_slice(&5u64.to_be_bytes()); // plays all frames
+    edts.extend_from_slice(&0u64.to_be_bytes());
+    edts.extend_from_slice(&[0, 1, 0, 0]);
+    edts.extend_from_slice(&u64::MAX.to_be_bytes()); // then dwells on the last one
+    edts.extend_from_slice(&2u64.to_be_bytes());
+    edts.extend_from_slice(&[0, 0, 0, 0]);
+    assert_eq!(1, avif.windows(edts.len()).filter(|w| *w == edts).count());
+    assert!(!Aviffy::new().to_vec(b"abcdef", None, 10, 20, 8, 10, Some(&frames), None, &[]).windows(4).any(|w| w == b"edts"));
+}
+
+#[test]
+fn exif_orientations() {
+    use remux::Mirror;
+
+    // How a 2×2 image [a, b, c, d] (row-major) is displayed: rotated anti-clockwise, then mirrored
+    fn display(quarter_turns: u8, mirror: Option<Mirror>, img: [u8; 4]) -> [u8; 4] {
+        let [a, b, c, d] = (0..quarter_turns % 4).fold(img, |[a, b, c, d], _| [b, d, a, c]);
+        match mirror {
+            None => [a, b, c, d],
+            Some(Mirror::LeftRight) => [b, a, d, c],
+            Some(Mirror::TopBottom) => [c, d, a, b],
+        }
+    }
+    fn orientation_of(avif: &[u8]) -> (u8, Option<Mirror>) {
+        let avif = remux::optimize(avif).unwrap(); // drops replaced properties
+        let prop = |typ: &[u8; 4]| avif.windows(5).find(|w| &w[..4] == typ).map(|w| w[4]);
+        (prop(b"irot").unwrap_or(0), prop(b"imir").map(|axis| if axis == 0 { Mirror::LeftRight } else { Mirror::TopBottom }))
+    }
+
+    let img = [1, 2, 3, 4];
+    // Exif orientations 1 to 8, as displayed
+    let exif = [[1, 2, 3, 4], [2, 1, 4, 3], [4, 3, 2, 1], [3, 4, 1, 2], [1, 3, 2, 4], [3, 1, 4, 2], [4, 2, 3, 1], [2, 4, 1, 3]];
+    let orientations = [(0, None), (0, Some(Mirror::LeftRight)), (2, None), (0, Some(Mirror::TopBottom)),
+        (1, Some(Mirror::TopBottom)), (3, None), (3, Some(Mirror::TopBottom)), (1, None)];
+    let avif = Aviffy::new().to_vec(b"abcdef", Some(b"gh"), 10, 20, 8, 1, None, None, &[]);
+    for (&displayed, &(quarter_turns, mirror)) in exif.iter().zip(&orientations) {
+        assert_eq!(displayed, display(quarter_turns, mirror, img));
+        let oriented = remux::set_orientation(&avif, quarter_turns, mirror).unwrap();
+        assert_eq!((quarter_turns, mirror), orientation_of(&oriented));
+        for turns in 0..6 {
+            let (rotated_turns, rotated_mirror) = orientation_of(&remux::rotate(&oriented, turns).unwrap());
+            assert_eq!(display(turns, None, displayed), display(rotated_turns, rotated_mirror, img), "{quarter_turns} {mirror:?} +{turns}");
+        }
+    }
+}
+
+#[test]
+fn layout_version_1_is_stable() {
+    fn fnv1a(data: &[u8]) -> u64 {
+        data.iter().fold(0xcbf29ce484222325, |h, &b| (h ^ u64::from(b)).wrapping_mul(0x100000001b3))
+    }
+
+    let frames = [FrameInfo::new(1, true, 3), FrameInfo::new(1, false, 3)];
+    let mut aviffy = Aviffy::new();
+    aviffy.layout_version(1);
+    let still = aviffy.to_vec(b"abcdef", None, 10, 20, 8, 10, None, None, &[]);
+    let alpha = aviffy.to_vec(b"abcdef", Some(b"ghijkl"), 10, 20, 8, 10, None, None, b"exif");
+    let anim = aviffy.to_vec(b"abcdef", Some(b"ghijkl"), 10, 20, 8, 10, Some(&frames), Some(&frames), &[]);
+    // If this fails, the new layout needs a new version number, and version 1 must keep writing the old one
+    assert_eq!([0x238d2ec527b1cb64, 0xd89b848dc175d77c, 0x61fe52bcdda9e262], [fnv1a(&still), fnv1a(&alpha), fnv1a(&anim)]);
+
+    aviffy.layout_version(Aviffy::LATEST_LAYOUT_VERSION + 1);
+    let err = aviffy.try_to_vec(b"abcdef", None, 10, 20, 8, 10, None, None, &[]).unwrap_err();
+    assert_eq!(io::ErrorKind::Unsupported, err.kind());
+}
+
+#[test]
+fn first_paint_ranges() {
+    let mut out = Vec::new();
+    let aviffy = Aviffy::new();
+    let ranges = aviffy.write_with_first_paint_ranges(&mut out, &ImageData { exif_data: b"exif", ..ImageData::new(b"color", Some(b"alpha"), 10, 20, 8) }).unwrap();
+    // Header, then alpha and color next to each other, but not Exif
+    assert_eq!(2, ranges.len());
+    assert_eq!(0, ranges[0].start);
+    assert_eq!(&out[ranges[1].start as usize..ranges[1].end as usize], b"alphacolor");
+
+    let frames = [FrameInfo::new(1, true, 3), FrameInfo::new(1, false, 3)];
+    out.clear();
+    let ranges = aviffy.write_with_first_paint_ranges(&mut out, &ImageData { color_frames: Some(&frames), ..ImageData::new(b"abcdef", None, 10, 20, 8) }).unwrap();
+    // The first frame is within the still image's data
+    assert_eq!(2, ranges.len());
+    assert_eq!(&out[ranges[1].start as usize..ranges[1].end as usize], b"abcdef");
+}
+
+#[test]
+fn exif_setter() {
+    let exif_data = b"II*\0\x08\0\0\0";
+    let mut aviffy = Aviffy::new();
+    aviffy.exif(&exif_data[..]);
+    let avif = aviffy.to_vec(b"abcdef", None, 10, 20, 8, 1, None, None, &[]);
+    assert_eq!(Aviffy::new().to_vec(b"abcdef", None, 10, 20, 8, 1, None, None, exif_data), avif);
+    assert_eq!(Some(&exif_data[..]), remux::metadata(&avif).unwrap().exif.as_deref());
+    assert_eq!(avif.len() - 6 - exif_data.len(), aviffy.header_size(&ImageData::new(&[], None, 10, 20, 8)).unwrap());
+
+    // The argument takes precedence
+    let avif = aviffy.to_vec(b"abcdef", None, 10, 20, 8, 1, None, None, b"MM\0*");
+    assert_eq!(Some(&b"MM\0*"[..]), remux::metadata(&avif).unwrap().exif.as_deref());
+}
+
+#[test]
+fn xmp_item() {
+    let xmp = b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"/>";
+    let avif = Aviffy::new().xmp(&b"old"[..]).xmp(&xmp[..]).to_vec(b"abcdef", None, 10, 20, 8, 1, None, None, &[]);
+    assert_eq!(1, avif.windows(19).filter(|w| w == b"application/rdf+xml").count());
+    assert_eq!(Some(&xmp[..]), remux::metadata(&avif).unwrap().xmp.as_deref());
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(&b"abcdef"[..], ctx.primary_item.as_slice());
+}
+
+#[test]
+fn icc_profile() {
+    let icc = b"\0\0\0\x14fake icc profile";
+    let mut aviffy = Aviffy::new();
+    aviffy.full_color_range(false).icc_profile(&icc[..]);
+    let avif = aviffy.to_vec(b"abcdef", None, 10, 20, 8, 1, None, None, &[]);
+    assert_eq!(Some(&icc[..]), remux::metadata(&avif).unwrap().icc.as_deref());
+    assert_eq!(0, avif.windows(4).filter(|w| w == b"nclx").count());
+
+    let avif = aviffy.icc_profile(Vec::new()).to_vec(b"abcdef", None, 10, 20, 8, 1, None, None, &[]);
+    assert_eq!(None, remux::metadata(&avif).unwrap().icc);
+}
+
+#[test]
+fn nclx_with_icc() {
+    let icc = b"\0\0\0\x14fake icc data!!";
+    let avif = Aviffy::new().icc_profile(&icc[..]).nclx_with_icc(true).to_vec(b"abcdef", None, 10, 20, 8, 1, None, None, &[]);
+    assert_eq!(Some(&icc[..]), remux::metadata(&avif).unwrap().icc.as_deref());
+    assert_eq!(1, avif.windows(4).filter(|w| w == b"nclx").count());
+    assert_eq!(1, avif.windows(4).filter(|w| w == b"prof").count());
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(&b"abcdef"[..], ctx.primary_item.as_slice());
+}
+
+#[test]
+fn mastering_display() {
+    // BT.2020 primaries, D65, 1000 to 0.005 cd/m²
+    let md = MasteringDisplay {
+        red: (35400, 14600),
+        green: (8500, 39850),
+        blue: (6550, 2300),
+        white_point: (15635, 16450),
+        max_luminance: 10_000_000,
+        min_luminance: 50,
+    };
+    let avif = Aviffy::new().mastering_display(Some(md)).to_vec(b"abcdef", None, 10, 20, 8, 1, None, None, &[]);
+    let mdcv = avif.windows(4).position(|w| w == b"mdcv").unwrap();
+    assert_eq!(&avif[mdcv - 4..mdcv + 4], &[0, 0, 0, 32, b'm', b'd', b'c', b'v']);
+    assert_eq!(&avif[mdcv + 4..mdcv + 8], &[0x21, 0x34, 0x9B, 0xAA]); // green first
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(&b"abcdef"[..], ctx.primary_item.as_slice());
+}
+
+#[test]
+fn content_light_level() {
+    let cll = ContentLightLevel { max_cll: 1000, max_fall: 400 };
+    let avif = Aviffy::new().content_light_level(Some(cll)).to_vec(b"abcdef", None, 10, 20, 8, 1, None, None, &[]);
+    let clli = avif.windows(4).position(|w| w == b"clli").unwrap();
+    assert_eq!(&avif[clli - 4..clli + 8], &[0, 0, 0, 12, b'c', b'l', b'l', b'i', 0x03, 0xE8, 0x01, 0x90]);
+}
+
+#[test]
+fn ambient_viewing() {
+    let amve = AmbientViewing { illuminance: 50000, light: (15635, 16450) };
+    let avif = Aviffy::new().ambient_viewing(Some(amve)).to_vec(b"abcdef", None, 10, 20, 8, 1, None, None, &[]);
+    let pos = avif.windows(4).position(|w| w == b"amve").unwrap();
+    assert_eq!(&avif[pos - 4..pos + 12], &[0, 0, 0, 16, b'a', b'm', b'v', b'e', 0, 0, 0xC3, 0x50, 0x3D, 0x13, 0x40, 0x42]);
+}
+
+#[test]
+fn color_space_presets() {
+    use crate::constants::{ColorSpacePreset, TransferCharacteristics};
+
+    let mut aviffy = Aviffy::new();
+    assert_eq!(aviffy.config().color, ColorSpacePreset::Srgb.color_info());
+    aviffy.color_space_preset(ColorSpacePreset::Bt2100Pq);
+    assert_eq!(TransferCharacteristics::Pq, aviffy.config().color.transfer_characteristics);
+    let avif = aviffy.to_vec(b"abcdef", None, 10, 20, 8, 1, None, None, &[]);
+    let nclx = avif.windows(4).position(|w| w == b"nclx").unwrap();
+    assert_eq!(&avif[nclx + 4..nclx + 11], &[0, 9, 0, 16, 0, 9, 0x80]);
+    assert_eq!("hlg".parse::<TransferCharacteristics>().unwrap(), ColorSpacePreset::Bt2100Hlg.color_info().transfer_characteristics);
+}
+
+#[test]
+fn gain_map() {
+    let channel = GainMapChannel { min: (0, 1), max: (3, 1), gamma: (1, 1), base_offset: (1, 64), alternate_offset: (1, 64) };
+    let mut metadata = GainMapMetadata { base_hdr_headroom: (0, 1), alternate_hdr_headroom: (3, 1), channels: vec![channel], use_base_color_space: true };
+    let mut aviffy = Aviffy::new();
+    aviffy.gain_map(&b"gainmap"[..], 5, 10, 8, &metadata, Some(crate::constants::ColorSpacePreset::Bt2100Pq.color_info()));
+    let avif = aviffy.to_vec(b"abcdef", None, 10, 20, 8, 1, None, None, &[]);
+    assert!(avif[..40].windows(4).any(|w| w == b"tmap"));
+    let dimg = avif.windows(4).position(|w| w == b"dimg").unwrap();
+    assert_eq!(&avif[dimg + 4..dimg + 12], &[0, 4, 0, 2, 0, 1, 0, 3]); // from tmap to color and gain map
+    let altr = avif.windows(4).position(|w| w == b"altr").unwrap();
+    assert_eq!(&avif[altr + 12..altr + 24], &[0, 0, 0, 2, 0, 0, 0, 4, 0, 0, 0, 1]);
+    let tmap_payload = avif.windows(7).position(|w| w == b"gainmap").unwrap() + 7;
+    assert_eq!(&avif[tmap_payload..tmap_payload + 6], &[0, 0, 0, 0, 0, 0x40]);
+    assert_eq!(avif.len(), tmap_payload + 6 + 16 + 40);
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(&b"abcdef"[..], ctx.primary_item.as_slice());
+
+    metadata.channels.push(channel);
+    aviffy.gain_map(&b"gainmap"[..], 5, 10, 8, &metadata, None);
+    assert!(aviffy.write(&mut Vec::new(), b"abcdef", None, 10, 20, 8, 1, None, None, &[]).is_err());
+}
+
+#[test]
+fn apple_gain_map() {
+    let channel = GainMapChannel { min: (0, 1), max: (3, 1), gamma: (1, 1), base_offset: (1, 64), alternate_offset: (1, 64) };
+    let metadata = GainMapMetadata { base_hdr_headroom: (0, 1), alternate_hdr_headroom: (3, 1), channels: vec![channel], use_base_color_space: true };
+    let mut aviffy = Aviffy::new();
+    aviffy.gain_map(&b"gainmap"[..], 5, 10, 8, &metadata, None).gain_map_format(GainMapFormat::Apple);
+    assert_eq!(GainMapFormat::Apple, aviffy.config().gain_map_format);
+    let avif = aviffy.to_vec(b"abcdef", None, 10, 20, 8, 1, None, None, &[]);
+    assert!(!avif.windows(4).any(|w| w == b"tmap"));
+    assert!(avif.windows(39).any(|w| w == b"urn:com:apple:photo:2020:aux:hdrgainmap"));
+    assert!(avif.windows(35).any(|w| w == b"HDRGainMapVersion>65536</HDRGainMap"));
+    let auxl = avif.windows(4).position(|w| w == b"auxl").unwrap();
+    assert_eq!(&avif[auxl + 4..auxl + 10], &[0, 3, 0, 1, 0, 1]);
+    let cdsc = avif.windows(4).position(|w| w == b"cdsc").unwrap();
+    assert_eq!(&avif[cdsc + 4..cdsc + 10], &[0, 4, 0, 1, 0, 3]);
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(&b"abcdef"[..], ctx.primary_item.as_slice());
+}
+
+#[test]
+fn dolby_vision() {
+    let dv = DolbyVision { profile: 10, level: 5, rpu_present: true, el_present: false, bl_present: true, bl_signal_compatibility_id: 1 };
+    let mut aviffy = Aviffy::new();
+    aviffy.dolby_vision(Some(dv));
+    let frames = [FrameInfo::new(1, true, 6)];
+    let avif = aviffy.to_vec(b"abcdef", None, 10, 20, 8, 10, Some(&frames), None, &[]);
+    let positions: Vec<_> = avif.windows(4).enumerate().filter(|(_, w)| *w == b"dvvC").map(|(i, _)| i).collect();
+    assert_eq!(2, positions.len()); // item property and sample entry
+    for pos in positions {
+        assert_eq!(&avif[pos - 4..pos + 10], &[0, 0, 0, 32, b'd', b'v', b'v', b'C', 1, 0, 0x14, 0x2D, 0x10, 0]);
+    }
+
+    aviffy.dolby_vision(Some(DolbyVision { level: 64, ..dv }));
+    assert!(aviffy.write(&mut Vec::new(), b"abcdef", None, 10, 20, 8, 1, None, None, &[]).is_err());
+}
+
+#[test]
+fn c2pa_reserved_range() {
+    let mut aviffy = Aviffy::new();
+    let mut out = Vec::new();
+    assert_eq!(None, aviffy.write_with_c2pa_range(&mut out, &ImageData::new(b"abcdef", None, 10, 20, 8)).unwrap());
+
+    aviffy.reserve_c2pa_manifest(100).xmp(&b"xmp"[..]);
+    for layout in [MdatLayout::Single, MdatLayout::MetadataFirst, MdatLayout::PerItem] {
+        let mut out = Vec::new();
+        let range = aviffy.mdat_layout(layout)
+            .write_with_c2pa_range(&mut out, &ImageData { exif_data: b"exif", ..ImageData::new(b"abcdef", None, 10, 20, 8) }).unwrap().unwrap();
+        assert_eq!(100, range.end - range.start);
+        assert!(out[range.start as usize..range.end as usize].iter().all(|&b| b == 0));
+        assert_eq!(out, aviffy.to_vec(b"abcdef", None, 10, 20, 8, 1, None, None, b"exif"));
+    }
+}
+
+#[test]
+fn mime_metadata_items() {
+    let avif = Aviffy::new()
+        .add_metadata_item("application/json", &b"{}"[..])
+        .add_metadata_item("application/json", &b"[]"[..])
+        .to_vec(b"abcdef", None, 10, 20, 8, 1, None, None, &[]);
+    assert_eq!(2, avif.windows(17).filter(|w| w == b"application/json\0").count());
+    assert_eq!(2, avif.windows(4).filter(|w| w == b"cdsc").count());
+    assert!(avif.ends_with(b"abcdef{}[]"));
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(&b"abcdef"[..], ctx.primary_item.as_slice());
+}
+
+#[test]
+#[cfg(feature = "deflate")]
+fn compressed_metadata() {
+    let xmp = "<x:xmpmeta xmlns:x='adobe:ns:meta/'>".repeat(100);
+    let avif = Aviffy::new().compress_metadata(true).xmp(xmp.as_bytes())
+        .to_vec(b"abcdef", None, 10, 20, 8, 1, None, None, &[]);
+    // Setters work in any order
+    assert_eq!(avif, Aviffy::new().xmp(xmp.as_bytes()).compress_metadata(true).to_vec(b"abcdef", None, 10, 20, 8, 1, None, None, &[]));
+    assert!(Aviffy::new().compress_metadata(true).config().compress_metadata);
+    assert!(avif.windows(28).any(|w| w == b"application/rdf+xml\0deflate\0"));
+    assert!(avif.len() < xmp.len());
+    let start = avif.windows(6).position(|w| w == b"abcdef").unwrap() + 6;
+    assert_eq!(xmp.as_bytes(), miniz_oxide::inflate::decompress_to_vec_zlib(&avif[start..]).unwrap());
+    assert_eq!(xmp.as_bytes(), remux::metadata(&avif).unwrap().xmp.unwrap());
+}
+
+#[test]
+fn uuid_boxes() {
+    let avif = Aviffy::new()
+        .embed_blob(BlobLocation::Uuid([7; 16]), &b"top"[..])
+        .embed_blob(BlobLocation::MetaUuid([8; 16]), &b"meta"[..])
+        .to_vec(b"abcdef", None, 10, 20, 8, 1, None, None, &[]);
+    let meta_start = u32::from_be_bytes(avif[0..4].try_into().unwrap()) as usize;
+    assert_eq!(&avif[meta_start + 4..meta_start + 8], b"meta");
+    let meta_end = meta_start + u32::from_be_bytes(avif[meta_start..meta_start + 4].try_into().unwrap()) as usize;
+    assert_eq!(&avif[meta_end - 28..meta_end - 20], &[0, 0, 0, 28, b'u', b'u', b'i', b'd']);
+    assert_eq!(&avif[meta_end - 20..meta_end], &[[8; 16].as_slice(), b"meta"].concat()[..]);
+    assert_eq!(&avif[meta_end..meta_end + 8], &[0, 0, 0, 27, b'u', b'u', b'i', b'd']);
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(&b"abcdef"[..], ctx.primary_item.as_slice());
+}
+
+#[test]
+fn free_padding() {
+    let frames = [FrameInfo::new(1, true, 6)];
+    let plain = Aviffy::new().to_vec(b"abcdef", None, 10, 20, 8, 1, Some(&frames), None, &[]);
+    let mut aviffy = Aviffy::new();
+    aviffy.free_padding(100);
+    let avif = aviffy.to_vec(b"abcdef", None, 10, 20, 8, 1, Some(&frames), None, &[]);
+    assert_eq!(plain.len() + 108, avif.len());
+    assert_eq!(plain.len() + 108, aviffy.header_size(&ImageData { color_frames: Some(&frames), ..ImageData::new(&[], None, 10, 20, 8) }).unwrap() + 6);
+    let meta_start = u32::from_be_bytes(avif[0..4].try_into().unwrap()) as usize;
+    let meta_end = meta_start + u32::from_be_bytes(avif[meta_start..meta_start + 4].try_into().unwrap()) as usize;
+    assert_eq!(&avif[meta_end..meta_end + 8], &[0, 0, 0, 108, b'f', b'r', b'e', b'e']);
+    assert!(avif.ends_with(b"abcdef"));
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(&b"abcdef"[..], ctx.primary_item.as_slice());
+}
+
+#[test]
+fn user_description() {
+    let ud = UserDescription { lang: "en".into(), name: "Title".into(), description: String::new(), tags: "a,b".into() };
+    let mut aviffy = Aviffy::new();
+    aviffy.user_description(Some(ud.clone()));
+    let avif = aviffy.to_vec(b"abcdef", None, 10, 20, 8, 1, None, None, &[]);
+    let pos = avif.windows(4).position(|w| w == b"udes").unwrap();
+    assert_eq!(&avif[pos - 4..pos + 22], b"\0\0\0\x1audes\0\0\0\0en\0Title\0\0a,b\0");
+
+    aviffy.user_description(Some(UserDescription { name: "a\0b".into(), ..ud }));
+    assert!(aviffy.write(&mut Vec::new(), b"abcdef", None, 10, 20, 8, 1, None, None, &[]).is_err());
+}
+
+#[test]
+fn alt_text() {
+    let mut aviffy = Aviffy::new();
+    aviffy.alt_text("A cat", "en");
+    let avif = aviffy.to_vec(b"abcdef", None, 10, 20, 8, 1, None, None, &[]);
+    let pos = avif.windows(4).position(|w| w == b"altt").unwrap();
+    assert_eq!(&avif[pos - 4..pos + 17], b"\0\0\0\x15altt\0\0\0\0A cat\0en\0");
+
+    let mut composition = aviffy.alt_text("", "").compose();
+    composition.add_color(&b"abcdef"[..], 10, 20, 8);
+    let thumb = composition.graph().add_item(*b"av01", "Thumbnail", &b"thumb"[..]);
+    composition.add_alt_text(thumb, "Ein Kätzchen", "de");
+    let mut avif = Vec::new();
+    composition.finish(&mut avif).unwrap();
+    assert_eq!(1, avif.windows(4).filter(|w| w == b"altt").count());
+    assert!(avif.windows(17).any(|w| w == "Ein Kätzchen\0de\0".as_bytes()));
+}
+
+#[test]
+fn per_item_colr() {
+    use crate::constants::ColorSpacePreset;
+
+    let mut aviffy = Aviffy::new();
+    aviffy.color_space_preset(ColorSpacePreset::Bt709);
+    let mut composition = aviffy.compose();
+    let color = composition.add_color(&b"abcdef"[..], 10, 20, 8);
+    let thumb = composition.graph().add_item(*b"av01", "Thumbnail", &b"thumb"[..]);
+    composition.set_color_info(thumb, ColorSpacePreset::DisplayP3.color_info());
+    composition.set_color_info(color, ColorSpacePreset::Srgb.color_info());
+    composition.set_color_info(color, ColorSpacePreset::Bt2100Pq.color_info());
+    let mut avif = Vec::new();
+    composition.finish(&mut avif).unwrap();
+    let nclx: Vec<_> = avif.windows(4).enumerate().filter(|(_, w)| *w == b"nclx").map(|(i, _)| &avif[i + 4..i + 11]).collect();
+    // Display P3 for the thumbnail, BT.2100 PQ instead of BT.709 for the color image
+    assert_eq!(nclx, [&[0, 12, 0, 13, 0, 6, 0x80][..], &[0, 9, 0, 16, 0, 9, 0x80]]);
+}
+
+#[test]
+fn rotation() {
+    let mut aviffy = Aviffy::new();
+    aviffy.rotation(Rotation::Rotation270);
+    let avif = aviffy.to_vec(b"abcdef", Some(b"ghijkl"), 10, 20, 8, 1, None, None, &[]);
+    let pos = avif.windows(4).position(|w| w == b"irot").unwrap();
+    assert_eq!(&avif[pos - 4..pos + 5], &[0, 0, 0, 9, b'i', b'r', b'o', b't', 3]);
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(&b"abcdef"[..], ctx.primary_item.as_slice());
+}
+
+#[test]
+fn mirror() {
+    let mut aviffy = Aviffy::new();
+    aviffy.rotation(Rotation::Rotation90).mirror(Some(Mirror::TopBottom));
+    let avif = aviffy.to_vec(b"abcdef", None, 10, 20, 8, 1, None, None, &[]);
+    let irot = avif.windows(4).position(|w| w == b"irot").unwrap();
+    assert_eq!(&avif[irot + 4..irot + 13], &[1, 0, 0, 0, 9, b'i', b'm', b'i', b'r']);
+    assert_eq!(1, avif[irot + 13]);
+    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    assert_eq!(&b"abcdef"[..], ctx.primary_item.as_slice());
+}
+
+#[test]
+fn clean_aperture() {
+    // 9×19 in the top-left corner of a padded 10×20 image
+    let ca = CleanAperture { width: (9, 1), height: (19, 1), horiz_off: (-1, 2), vert_off: (-1, 2) };
+    let mut aviffy = Aviffy::new();
+    aviffy.clean_aperture(Some(ca)).rotation(Rotation::Rotation90);
+    let avif = aviffy.to_vec(b"abcdef", None, 10, 20, 8, 1, None, None, &[]);
+    let clap = avif.windows(4).position(|w| w == b"clap").unwrap();
+    assert_eq!(&avif[clap - 4..clap + 12], &[0, 0, 0, 40, b'c', b'l', b'a', b'p', 0, 0, 0, 9, 0, 0, 0, 1]);
+    assert_eq!(&avif[clap + 20..clap + 28], &[0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 2]);
+    assert_eq!(&avif[clap + 40..clap + 44], b"irot");
+
+    for bad in [CleanAperture { width: (11, 1), ..ca }, CleanAperture { horiz_off: (3, 2), ..ca }, CleanAperture { height: (19, 0), ..ca }] {
+        aviffy.clean_aperture(Some(bad));
+        assert!(aviffy.write(&mut Vec::new(), b"abcdef", None, 10, 20, 8, 1, None, None, &[]).is_err());
+    }
+    aviffy.clean_aperture(Some(CleanAperture { horiz_off: (1, 2), vert_off: (1, 2), ..ca }));
+    assert!(aviffy.write(&mut Vec::new(), b"abcdef", None, 10, 20, 8, 1, None, None, &[]).is_ok());
+}
+
+#[test]
+fn pixel_aspect_ratio() {
+    let mut aviffy = Aviffy::new();
+    aviffy.pixel_aspect_ratio(4, 3);
+    let frames = [FrameInfo::new(1, true, 6)];
+    let avif = aviffy.to_vec(b"abcdef", Some(b"ghijkl"), 10, 20, 8, 10, Some(&frames), Some(&frames), &[]);
+    let positions: Vec<_> = avif.windows(4).enumerate().filter(|(_, w)| *w == b"pasp").map(|(i, _)| i).collect();
+    assert_eq!(3, positions.len()); // shared item property, and both tracks
+    for pos in positions {
+        assert_eq!(&avif[pos - 4..pos + 12], &[0, 0, 0, 16, b'p', b'a', b's', b'p', 0, 0, 0, 4, 0, 0, 0, 3]);
+    }
+    assert_eq!(None, aviffy.pixel_aspect_ratio(2, 2).config().pixel_aspect_ratio);
+    aviffy.pixel_aspect_ratio(0, 1);
+    assert!(aviffy.write(&mut Vec::new(), b"abcdef", None, 10, 20, 8, 1, None, None, &[]).is_err());
+}
 
-                    alpha_sample_count += 1;
-                    if i < (_alpha_frames.len() - 1) {
-                        if _alpha_frames[i].duration_in_timescales == _alpha_frames[i + 1].duration_in_timescales {
-                            continue;
-                        }
-                    }
+#[test]
+fn display_size() {
+    assert_eq!(CleanAperture { width: (9, 1), height: (19, 1), horiz_off: (-1, 2), vert_off: (-1, 2) }, CleanAperture::top_left(9, 19, 10, 20));
 
-                    let mut sample: ArrayVec<u32, 2> = ArrayVec::new();
-                    sample.push(alpha_sample_count);
-                    sample.push(_alpha_frames[i].duration_in_timescales as u32);
-                    alpha_stts_sample_delta.push(sample);
+    let mut aviffy = Aviffy::new();
+    aviffy.display_size(Some((9, 20)));
+    let avif = aviffy.to_vec(b"abcdef", None, 10, 20, 8, 1, None, None, &[]);
+    let clap = avif.windows(4).position(|w| w == b"clap").unwrap();
+    assert_eq!(&avif[clap + 4..clap + 36], &[0, 0, 0, 9, 0, 0, 0, 1, 0, 0, 0, 20, 0, 0, 0, 1, 0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 2]);
 
-                    alpha_sample_count = 0;
-                }
+    aviffy.display_size(Some((11, 20)));
+    assert!(aviffy.write(&mut Vec::new(), b"abcdef", None, 10, 20, 8, 1, None, None, &[]).is_err());
+}
 
-                let mut alpha_stss_box: Option<StssBox> = None;
-                if alpha_sync_sample_count != _alpha_frames.len() as u32 {
-                    alpha_stss_box = Some(StssBox { entry_count: alpha_sync_sample_count, sample_number: alpha_sample_number })
-                }
+#[test]
+fn grid() {
+    let aviffy = Aviffy::new();
+    let mut grid = aviffy.grid(2, 2, 10, 20, 8);
+    grid.output_size(18, 40)
+        .add_tile(&b"tile0"[..]).add_tile(&b"tile1"[..])
+        .add_tile(&b"tile2"[..]).add_tile(&b"tile3"[..]);
+    let mut too_few = aviffy.grid(2, 2, 10, 20, 8);
+    too_few.add_tile(&b"tile0"[..]).add_tile(&b"tile1"[..]).add_tile(&b"tile2"[..]);
+    assert!(too_few.finish(&mut Vec::new()).is_err());
+    let mut too_small = grid.clone();
+    too_small.output_size(10, 40);
+    assert!(too_small.finish(&mut Vec::new()).is_err());
 
-                moov_box.as_mut().unwrap().tracks.push(TrakBox{
-                    tkhd: TkhdBox {
-                        creation_time: now,
-                        modification_time: now,
-                        track_id: 2,
-                        duration: media_duration,
-                        width: width << 16, 
-                        height: height << 16
-                    },
-                    tref:Some(TrefBox {
-                        ref_type: ReftypeBox {
-                            typ: FourCC(*b"auxl"),
-                            to_id: 1
-                        }
-                    }),
-                    meta: None,
-                    mdia: MdiaBox {
-                        mdhd: MdhdBox {
-                            creation_time: now,
-                            modification_time: now,
-                            timescale: timescale,
-                            duration: media_duration
-                        },
-                        hdlr: HdlrBox { handler_type: FourCC(*b"auxv"), name: "avifser" },
-                        minf: MinfBox {
-                            vmhd: VmhdBox {},
-                            dinf: DinfBox {
-                                dref: DrefBox { url: UrlBox {} }
-                            },
-                            stbl: StblBox {
-                                stsd: StsdBox {
-                                    entry: SampleEntryBox {
-                                        typ: FourCC(*b"av01"),
-                                        width: width as u16,
-                                        height: height as u16,
-                                        config: alpha_config,
-                                        colr: None,
-                                        ccst: CcstBox {},
-                                        auxi: Some(AuxiBox { aux_track_type: "urn:mpeg:mpegB:cicp:systems:auxiliary:alpha" })
-                                    }
-                                },
-                                stts: SttsBox {
-                                    sample_delta: alpha_stts_sample_delta
-                                },
-                                stsc: StscBox {
-                                    samples_per_chunk: _alpha_frames.len() as u32
-                                },
-                                stsz: StszBox {
-                                    sample_count: _alpha_frames.len() as u32,
-                                    entry_size: _alpha_frames.iter().map(|x| x.size).collect::<Vec<u32>>()
-                                },
-                                stco: StcoBox { chunk_offset: 1 },
-                                stss: alpha_stss_box
-                            }
-                        }
-                    }
-                });
-            }
-        }
+    let mut avif = Vec::new();
+    grid.finish(&mut avif).unwrap();
+    assert!(avif.windows(9).any(|w| w == b"gridColor"));
+    assert_eq!(4, avif.windows(9).filter(|w| w.starts_with(b"av01Tile")).count());
+    let dimg = avif.windows(4).position(|w| w == b"dimg").unwrap();
+    assert_eq!(&avif[dimg + 4..dimg + 16], &[0, 1, 0, 4, 0, 3, 0, 4, 0, 5, 0, 6]);
+    assert!(avif.windows(12).any(|w| w == [0, 0, 1, 1, 0, 18, 0, 40, b't', b'i', b'l', b'e']));
+}
 
-        compatible_brands.push(FourCC(*b"avif"));
-        match moov_box {
-            Some(_) => {
-                compatible_brands.push(FourCC(*b"avis"));
-                compatible_brands.push(FourCC(*b"msf1"));
-                compatible_brands.push(FourCC(*b"iso8"));
-            }
-            _ => ()
-        }
-        compatible_brands.push(FourCC(*b"mif1"));
-        compatible_brands.push(FourCC(*b"miaf"));
-        compatible_brands.push(FourCC(*b"MA1A"));
-        AvifFile {
-            ftyp: FtypBox {
-                major_brand: match moov_box {
-                    Some(_) => FourCC(*b"avis"),
-                    _ => FourCC(*b"avif")
-                },
-                minor_version: 0,
-                compatible_brands,
-            },
-            meta: MetaBox {
-                hdlr: HdlrBox { handler_type: FourCC(*b"pict"), name: "avifser" },
-                iinf: IinfBox { items: image_items },
-                pitm: PitmBox(color_image_id),
-                iloc: IlocBox { items: iloc_items },
-                iprp: IprpBox {
-                    ipco,
-                    // It's not enough to define these properties,
-                    // they must be assigned to the image
-                    ipma: IpmaBox {
-                        entries: ipma_entries,
-                    },
-                },
-                iref: irefs,
-            },
-            moov: moov_box,
-            // Here's the actual data. If HEIF wasn't such a kitchen sink, this
-            // would have been the only data this file needs.
-            mdat: MdatBox {
-                data_chunks,
-                exif: match exif_data.len() {
-                    0 => None,
-                    _ => Some(ExifBox {
-                        header_offset: 0,
-                        payload: exif_data.to_vec(),
-                    })
-                }
-            },
-        }
+#[test]
+fn grid_writer() {
+    let mut aviffy = Aviffy::new();
+    aviffy.exif(b"exif");
+    let mut grid = aviffy.grid(3, 1, 10, 20, 8);
+    grid.add_tile(&b"tile0"[..]).add_alpha_tile(&b"alpha0"[..]);
+    let mut expected = Vec::new();
+    grid.clone().add_tile(&b"tile1!"[..]).add_alpha_tile(&b"a1"[..])
+        .add_tile(&b"tile2"[..]).add_alpha_tile(&b"alpha2"[..])
+        .clone().finish(&mut expected).unwrap();
+
+    let mut out = io::Cursor::new(b"junk".to_vec());
+    out.set_position(4);
+    let mut writer = grid.stream(&mut out, true).unwrap();
+    assert!(writer.add_tile(b"tile1!", None).is_err());
+    writer.add_tile(b"tile1!", Some(b"a1")).unwrap();
+    writer.add_tile(b"tile2", Some(b"alpha2")).unwrap();
+    assert!(writer.add_tile(b"tile3", Some(b"alpha3")).is_err());
+    writer.finish().unwrap();
+    assert_eq!(&out.get_ref()[4..], &expected[..]);
+}
+
+#[test]
+fn grid_layout() {
+    let layout = GridLayout::new(1000, 70000, 512, 512).unwrap();
+    assert_eq!((2, 137), (layout.columns, layout.rows));
+    assert_eq!(&layout.to_bytes(), &[0, 1, 136, 1, 0, 0, 0x03, 0xE8, 0, 1, 0x11, 0x70]);
+    assert_eq!(&GridLayout::new(100, 60, 50, 40).unwrap().to_bytes(), &[0, 0, 1, 1, 0, 100, 0, 60]);
+    assert!(GridLayout::new(100, 60, 0, 40).is_err());
+    assert!(GridLayout::new(257, 1, 1, 1).is_err());
+
+    let layout = GridLayout::new(90, 60, 50, 40).unwrap();
+    layout.check_tile_sizes(&[(50, 40), (40, 40), (50, 20), (48, 24)]).unwrap();
+    assert!(layout.check_tile_sizes(&[(50, 40), (40, 40), (50, 20)]).is_err());
+    assert!(layout.check_tile_sizes(&[(48, 40), (50, 40), (50, 40), (50, 40)]).is_err());
+    assert!(layout.check_tile_sizes(&[(50, 40), (38, 40), (50, 40), (50, 40)]).is_err());
+    assert!(GridLayout { width: 200, ..layout }.check().is_err());
+}
+
+#[test]
+fn overlay() {
+    let aviffy = Aviffy::new();
+    let mut composition = aviffy.compose();
+    let background = composition.add_image(&b"background"[..], 100, 50);
+    let watermark = composition.add_image(&b"watermark"[..], 20, 10);
+    composition.add_overlay(100, 50, 8, [0, 0, 0, 255], &[(background, 0, 0), (watermark, 80, -5)]);
+    let mut avif = Vec::new();
+    composition.clone().finish(&mut avif).unwrap();
+    assert!(avif.windows(9).any(|w| w == b"iovlColor"));
+    let dimg = avif.windows(4).position(|w| w == b"dimg").unwrap();
+    assert_eq!(&avif[dimg + 4..dimg + 12], &[0, 1, 0, 2, 0, 3, 0, 4]);
+    assert!(avif.windows(22).any(|w| w == [0, 0, 0, 0, 0, 0, 0, 0, 0, 255, 0, 100, 0, 50, 0, 0, 0, 0, 0, 80, 0xFF, 0xFB]));
+
+    composition.add_overlay(100_000, 50, 8, [0; 4], &[(watermark, -1, 0)]);
+    avif.clear();
+    composition.clone().finish(&mut avif).unwrap();
+    assert!(avif.windows(26).any(|w| w == [0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x86, 0xA0, 0, 0, 0, 50, 0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0]));
+
+    composition.add_alpha(&b"alpha"[..]);
+    assert!(composition.finish(&mut Vec::new()).is_err());
+}
+
+#[test]
+fn thumbnail() {
+    let aviffy = Aviffy::new();
+    let mut composition = aviffy.compose();
+    composition.add_color(&b"color"[..], 1000, 2000, 10);
+    composition.add_thumbnail(&b"thumb"[..], 100, 200);
+    let mut avif = Vec::new();
+    composition.finish(&mut avif).unwrap();
+    let thmb = avif.windows(4).position(|w| w == b"thmb").unwrap();
+    assert_eq!(&avif[thmb + 4..thmb + 10], &[0, 3, 0, 1, 0, 1]);
+    assert!(avif.windows(13).any(|w| w == b"av01Thumbnail"));
+    // Same config as the color image, except a lower level
+    let levels: Vec<_> = avif.windows(4).enumerate().filter(|(_, w)| *w == b"av1C").map(|(pos, _)| avif[pos + 5] & 0x1F).collect();
+    assert_eq!(levels, [8, 0]);
+    assert!(avif.windows(16).any(|w| w == [0, 0, 0, 20, b'i', b's', b'p', b'e', 0, 0, 0, 0, 0, 0, 0, 100]));
+}
+
+#[test]
+fn pyramid() {
+    let aviffy = Aviffy::new();
+    let mut composition = aviffy.compose();
+    let full = composition.add_color(&b"full"[..], 512, 512, 8);
+    let half = composition.add_image(&b"half"[..], 256, 256);
+    let layers = [
+        PyramidLayer { item: half, binning: 2, columns: 1, rows: 1 },
+        PyramidLayer { item: full, binning: 1, columns: 2, rows: 2 },
+    ];
+    composition.add_pyramid(256, 256, &layers);
+    let mut avif = Vec::new();
+    composition.clone().finish(&mut avif).unwrap();
+    let pymd = avif.windows(4).position(|w| w == b"pymd").unwrap();
+    assert_eq!(&avif[pymd - 12..pymd], &[0, 0, 0, 52, b'g', b'r', b'p', b'l', 0, 0, 0, 44]);
+    assert_eq!(&avif[pymd + 4..pymd + 32], &[0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 1, 1, 0, 1, 0, 0, 2, 0, 0]);
+    assert_eq!(&avif[pymd + 32..pymd + 40], &[0, 0, 0, 1, 0, 1, 0, 1]);
+
+    composition.add_pyramid(256, 256, &[PyramidLayer { binning: 0, ..layers[0] }]);
+    assert!(composition.finish(&mut Vec::new()).is_err());
+}
+
+#[test]
+fn depth_map() {
+    let aviffy = Aviffy::new();
+    let mut composition = aviffy.compose();
+    let color = composition.add_color(&b"color"[..], 10, 20, 8);
+    composition.add_alpha(&b"alpha"[..]);
+    composition.add_depth_map(&b"depth"[..], 5, 10, color);
+    let mut avif = Vec::new();
+    composition.finish(&mut avif).unwrap();
+    assert!(avif.windows(43).any(|w| w == b"urn:mpeg:mpegB:cicp:systems:auxiliary:depth"));
+    assert!(avif.windows(43).any(|w| w == b"urn:mpeg:mpegB:cicp:systems:auxiliary:alpha"));
+    let auxl: Vec<_> = avif.windows(4).enumerate().filter(|(_, w)| *w == b"auxl").map(|(pos, _)| &avif[pos + 4..pos + 10]).collect();
+    assert_eq!(auxl, [&[0, 2, 0, 1, 0, 1], &[0, 4, 0, 1, 0, 1]]);
+}
+
+#[test]
+fn auxiliary() {
+    let mut aviffy = Aviffy::new();
+    aviffy.add_auxiliary("urn:example:segmentation", b"mask".to_vec(), 10, 20)
+        .add_auxiliary("urn:example:disparity", b"disparity".to_vec(), 5, 10)
+        .add_metadata_item("application/json", b"{}".to_vec());
+    let avif = aviffy.to_vec(b"color", Some(b"alpha"), 10, 20, 8, 1, None, None, &[]);
+    assert!(avif.windows(24).any(|w| w == b"urn:example:segmentation"));
+    assert!(avif.windows(21).any(|w| w == b"urn:example:disparity"));
+    assert!(avif.windows(44).any(|w| w == b"urn:mpeg:mpegB:cicp:systems:auxiliary:alpha\0"));
+    let auxl: Vec<_> = avif.windows(4).enumerate().filter(|(_, w)| *w == b"auxl").map(|(pos, _)| &avif[pos + 4..pos + 10]).collect();
+    assert_eq!(auxl, [&[0, 2, 0, 1, 0, 1], &[0, 4, 0, 1, 0, 1], &[0, 5, 0, 1, 0, 1]]);
+    let cdsc = avif.windows(4).position(|w| w == b"cdsc").unwrap();
+    assert_eq!(&avif[cdsc + 4..cdsc + 10], &[0, 6, 0, 1, 0, 1]);
+}
+
+#[test]
+fn primary_item() {
+    let aviffy = Aviffy::new();
+    let mut composition = aviffy.compose();
+    let background = composition.add_image(&b"background"[..], 100, 50);
+    composition.add_color(&b"color"[..], 100, 50, 8);
+    composition.set_primary(background);
+    let mut avif = Vec::new();
+    composition.clone().finish(&mut avif).unwrap();
+    let pitm = avif.windows(4).position(|w| w == b"pitm").unwrap();
+    assert_eq!(&avif[pitm + 4..pitm + 10], &[0, 0, 0, 0, 0, 3]);
+
+    composition.set_primary(ItemId(compose::ItemRef::Alpha));
+    assert!(composition.finish(&mut Vec::new()).is_err());
+}
+
+#[test]
+fn entity_groups() {
+    let mut aviffy = Aviffy::new();
+    aviffy.add_rendition(b"small".to_vec(), 5, 10, RenditionLink::Alternative);
+    let mut composition = aviffy.compose();
+    let left = composition.add_color(&b"left"[..], 10, 20, 8);
+    let right = composition.add_image(&b"right"[..], 10, 20);
+    composition.graph()
+        .add_entity_group(*b"ster", &[left, right], &[])
+        .add_entity_group(*b"cust", &[right], &[1, 2]);
+    let mut avif = Vec::new();
+    composition.finish(&mut avif).unwrap();
+    let grpl = avif.windows(4).position(|w| w == b"grpl").unwrap();
+    assert_eq!(&avif[grpl - 4..grpl], &[0, 0, 0, 8 + 28 + 28 + 26]);
+    assert_eq!(&avif[grpl + 8..grpl + 24], &[b'a', b'l', b't', b'r', 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 2]);
+    assert_eq!(&avif[grpl + 32..grpl + 60], &[0, 0, 0, 28, b's', b't', b'e', b'r', 0, 0, 0, 0, 0, 0, 0, 6, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 4]);
+    assert_eq!(&avif[grpl + 60..grpl + 86], &[0, 0, 0, 26, b'c', b'u', b's', b't', 0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, 1, 0, 0, 0, 4, 1, 2]);
+}
+
+#[test]
+fn hdr_alternate() {
+    let mut aviffy = Aviffy::new();
+    aviffy.color_space_preset(constants::ColorSpacePreset::Bt709);
+    let mut composition = aviffy.compose();
+    composition.add_color(&b"sdr"[..], 10, 20, 8);
+    composition.add_hdr_alternate(&b"hdr"[..], 10, 20, 10, constants::ColorSpacePreset::Bt2100Pq.color_info());
+    let mut avif = Vec::new();
+    composition.finish(&mut avif).unwrap();
+    let altr = avif.windows(4).position(|w| w == b"altr").unwrap();
+    assert_eq!(&avif[altr + 4..altr + 20], &[0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 2, 0, 0, 0, 3]);
+    assert_eq!(&avif[altr + 20..altr + 24], &[0, 0, 0, 1]);
+    assert!(avif.windows(10).any(|w| w == [b'n', b'c', b'l', b'x', 0, 1, 0, 1, 0, 1]));
+    assert!(avif.windows(10).any(|w| w == [b'n', b'c', b'l', b'x', 0, 9, 0, 16, 0, 9]));
+    assert_eq!(2, avif.windows(4).filter(|&w| w == b"av1C").count());
+}
+
+#[test]
+fn layer_sizes() {
+    let mut aviffy = Aviffy::new();
+    aviffy.layer_sizes(&[2, 3, 1]);
+    let avif = aviffy.to_vec(b"abcdef", None, 10, 20, 8, 1, None, None, &[]);
+    let a1lx = avif.windows(4).position(|w| w == b"a1lx").unwrap();
+    assert_eq!(&avif[a1lx - 4..a1lx + 21], &[0, 0, 0, 15, b'a', b'1', b'l', b'x', 0, 0, 2, 0, 3, 0, 0, 0, 0, 0, 10, b'l', b's', b'e', b'l', 0xFF, 0xFF]);
+
+    aviffy.layer_sizes(&[2, 3]);
+    assert!(aviffy.write(&mut Vec::new(), b"abcdef", None, 10, 20, 8, 1, None, None, &[]).is_err());
+    aviffy.layer_sizes(&[6]);
+    assert!(aviffy.write(&mut Vec::new(), b"abcdef", None, 10, 20, 8, 1, None, None, &[]).is_err());
+}
+
+#[test]
+fn operating_point() {
+    let mut aviffy = Aviffy::new();
+    aviffy.operating_point(Some(2));
+    let avif = aviffy.to_vec(b"abcdef", None, 10, 20, 8, 1, None, None, &[]);
+    let a1op = avif.windows(4).position(|w| w == b"a1op").unwrap();
+    assert_eq!(&avif[a1op - 4..a1op + 5], &[0, 0, 0, 9, b'a', b'1', b'o', b'p', 2]);
+    assert_eq!(Some(2), aviffy.config().operating_point);
+
+    aviffy.operating_point(Some(32));
+    assert!(aviffy.write(&mut Vec::new(), b"abcdef", None, 10, 20, 8, 1, None, None, &[]).is_err());
+}
+
+#[test]
+fn burst() {
+    let aviffy = Aviffy::new();
+    let mut composition = aviffy.compose();
+    let first = composition.add_image(&b"first"[..], 10, 20);
+    let best = composition.add_color(&b"best"[..], 10, 20, 8);
+    let last = composition.add_image(&b"last"[..], 10, 20);
+    composition.add_burst_image(first, "2024-05-01T12:00:00.000Z")
+        .add_burst_image(best, "2024-05-01T12:00:00.100Z")
+        .add_burst_image(last, "2024-05-01T12:00:00.200Z");
+    let mut avif = Vec::new();
+    composition.finish(&mut avif).unwrap();
+    let brst = avif.windows(4).position(|w| w == b"brst").unwrap();
+    assert_eq!(&avif[brst + 8..brst + 28], &[0, 0, 0, 8, 0, 0, 0, 3, 0, 0, 0, 3, 0, 0, 0, 1, 0, 0, 0, 4]);
+    assert!(avif.windows(24).any(|w| w == b"xmp:CreateDate=\"2024-05-"));
+    let cdsc: Vec<_> = avif.windows(4).enumerate().filter(|(_, w)| *w == b"cdsc").map(|(pos, _)| &avif[pos + 4..pos + 10]).collect();
+    assert_eq!(cdsc, [&[0, 5, 0, 1, 0, 3], &[0, 6, 0, 1, 0, 1], &[0, 7, 0, 1, 0, 4]]);
+    let pitm = avif.windows(4).position(|w| w == b"pitm").unwrap();
+    assert_eq!(&avif[pitm + 4..pitm + 10], &[0, 0, 0, 0, 0, 1]);
+}
+
+#[test]
+fn regions() {
+    let aviffy = Aviffy::new();
+    let mut composition = aviffy.compose();
+    let color = composition.add_color(&b"color"[..], 1000, 2000, 8);
+    let faces = composition.add_regions(color, 1000, 2000, &[
+        Region::Rectangle { x: 10, y: 20, width: 30, height: 40 },
+        Region::Point { x: -1, y: 2 },
+    ]);
+    composition.add_user_description(faces, UserDescription { lang: "en".into(), name: "face".into(), ..Default::default() });
+    composition.add_regions(color, 100_000, 1, &[Region::Ellipse { x: 1, y: 2, radius_x: 3, radius_y: 4 }]);
+    let mut avif = Vec::new();
+    composition.clone().finish(&mut avif).unwrap();
+    assert!(avif.windows(11).any(|w| w == b"rganRegions"));
+    assert!(avif.windows(21).any(|w| w == [0, 0, 0x03, 0xE8, 0x07, 0xD0, 2, 1, 0, 10, 0, 20, 0, 30, 0, 40, 0, 0xFF, 0xFF, 0, 2]));
+    assert!(avif.windows(28).any(|w| w == [0, 1, 0, 1, 0x86, 0xA0, 0, 0, 0, 1, 1, 2, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4]));
+    assert!(avif.windows(12).any(|w| w == b"udes\0\0\0\0en\0f"));
+    let cdsc = avif.windows(4).position(|w| w == b"cdsc").unwrap();
+    assert_eq!(&avif[cdsc + 4..cdsc + 10], &[0, 3, 0, 1, 0, 1]);
+
+    composition.add_user_description(faces, UserDescription { name: "\0".into(), ..Default::default() });
+    assert!(composition.finish(&mut Vec::new()).is_err());
+}
+
+#[test]
+fn uncompressed() {
+    let aviffy = Aviffy::new();
+    let mut composition = aviffy.compose();
+    let color = composition.add_color(&b"color"[..], 2, 1, 8);
+    let exact = composition.add_uncompressed(&[1, 2, 3, 4, 5, 6][..], 2, 1, UncompressedFormat::Rgb8);
+    composition.graph().add_entity_group(*b"altr", &[exact, color], &[]);
+    let mut avif = Vec::new();
+    composition.clone().finish(&mut avif).unwrap();
+    assert!(avif.windows(16).any(|w| w == b"unciUncompressed"));
+    assert!(avif.windows(14).any(|w| w == [b'c', b'm', b'p', b'd', 0, 0, 0, 3, 0, 4, 0, 5, 0, 6]));
+    assert!(avif.windows(16).any(|w| w == [b'u', b'n', b'c', b'C', 0, 0, 0, 0, b'r', b'g', b'b', b'3', 0, 0, 0, 3]));
+    assert!(avif.windows(6).any(|w| w == [1, 2, 3, 4, 5, 6]));
+
+    composition.add_uncompressed(&[0; 3][..], 2, 1, UncompressedFormat::Gray8);
+    assert!(composition.finish(&mut Vec::new()).is_err());
+}
+
+#[test]
+fn seq_level_idx() {
+    assert_eq!(av1_level(1920, 1080, None), 8);
+    assert_eq!(av1_level(1920, 1080, Some(60.)), 9);
+    assert_eq!(av1_level(1080, 1920, None), 8);
+    assert_eq!(av1_level(64, 64, Some(30.)), 0);
+    assert_eq!(av1_level(20000, 100, None), 31);
+
+    let level_of = |avif: &[u8]| avif.windows(4).position(|w| w == b"av1C").map(|pos| avif[pos + 5] & 0x1F).unwrap();
+    let mut aviffy = Aviffy::new();
+    assert_eq!(level_of(&aviffy.to_vec(b"av1", None, 4000, 2000, 8, 0, None, None, &[])), 12);
+    let frames = [FrameInfo::new(1, true, 1), FrameInfo::new(1, false, 2)];
+    assert_eq!(level_of(&aviffy.to_vec(b"av1", None, 1920, 1080, 8, 60, Some(&frames), None, &[])), 9);
+    aviffy.seq_level_idx(Some(13));
+    assert_eq!(level_of(&aviffy.to_vec(b"av1", None, 10, 20, 8, 0, None, None, &[])), 13);
+    aviffy.seq_level_idx(None).layout_version(1);
+    assert_eq!(level_of(&aviffy.to_vec(b"av1", None, 10, 20, 8, 0, None, None, &[])), 31);
+}
+
+#[test]
+fn high_tier() {
+    let frames = [FrameInfo::new(1, true, 1), FrameInfo::new(1, false, 2)];
+    let mut aviffy = Aviffy::new();
+    aviffy.high_tier(true);
+    let avif = aviffy.to_vec(b"av1", None, 1920, 1080, 8, 30, Some(&frames), None, &[]);
+    let tiers: Vec<_> = avif.windows(4).enumerate().filter(|(_, w)| *w == b"av1C").map(|(pos, _)| avif[pos + 6] >> 7).collect();
+    assert_eq!(tiers, [1, 1]);
+    let avif = aviffy.to_vec(b"av1", None, 10, 20, 8, 0, None, None, &[]);
+    let av1c = avif.windows(4).position(|w| w == b"av1C").unwrap();
+    assert_eq!(avif[av1c + 6] >> 7, 0);
+}
+
+#[test]
+fn seq_profile() {
+    let mut aviffy = Aviffy::new();
+    aviffy.chroma_subsampling(constants::ChromaSubsampling::Cs420).seq_profile(Some(2));
+    let avif = aviffy.to_vec(b"av1", None, 10, 20, 10, 0, None, None, &[]);
+    let av1c = avif.windows(4).position(|w| w == b"av1C").unwrap();
+    assert_eq!(avif[av1c + 5] >> 5, 2);
+
+    aviffy.seq_profile(Some(3));
+    let err = aviffy.try_to_vec(b"av1", None, 10, 20, 10, 0, None, None, &[]).unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+}
+
+#[test]
+fn sequence_header_in_av1c() {
+    // Reduced still picture sequence header of a 10x20 image
+    let seq_header = [0x0A, 9, 0x18, 0x0D, 0x26, 0x60, 0x10, 0x10, 0xD0, 0x68, 0x40];
+    let mut data = seq_header.to_vec();
+    data.extend_from_slice(&[0x32, 1, 0]); // frame
+    let frames = [FrameInfo::new(1, true, data.len())];
+    let mut aviffy = Aviffy::new();
+    aviffy.sequence_header_in_av1c(true);
+    let avif = aviffy.to_vec(&data, Some(&data), 10, 20, 8, 10, Some(&frames), Some(&frames), &[]);
+    let av1c: Vec<_> = avif.windows(4).enumerate().filter(|(_, w)| *w == b"av1C").map(|(pos, _)| &avif[pos - 4..pos + 8 + seq_header.len()]).collect();
+    assert_eq!(av1c.len(), 4);
+    for av1c in av1c {
+        assert_eq!(&av1c[..4], &[0, 0, 0, 23]);
+        assert_eq!(&av1c[12..], &seq_header);
     }
 
-    #[must_use] pub fn to_vec(&self, color_av1_data: &[u8], alpha_av1_data: Option<&[u8]>, width: u32, height: u32, depth_bits: u8, timescale: u32, color_frames: Option<&[FrameInfo]>, alpha_frames: Option<&[FrameInfo]>, exif_data: &[u8]) -> Vec<u8> {
-        let mut out = Vec::with_capacity(color_av1_data.len() + alpha_av1_data.map_or(0, |a| a.len()) + 410);
-        self.write(&mut out, color_av1_data, alpha_av1_data, width, height, depth_bits, timescale, color_frames, alpha_frames, exif_data).unwrap(); // Vec can't fail
-        out
+    assert!(aviffy.try_to_vec(&[0x32, 1, 0], None, 10, 20, 8, 0, None, None, &[]).is_err());
+
+    // The header size needs the sequence headers, but not the frames
+    assert!(aviffy.header_size(&ImageData::new(&[], Some(&[]), 10, 20, 8)).is_err());
+    let image = ImageData { timescale: 10, color_frames: Some(&frames), alpha_frames: Some(&frames), ..ImageData::new(&seq_header, Some(&seq_header), 10, 20, 8) };
+    assert_eq!(avif.len() - 2 * data.len(), aviffy.header_size(&image).unwrap());
+    aviffy.sequence_header_in_av1c(false).verify_size(true);
+    assert!(aviffy.header_size(&ImageData::new(&[], None, 10, 20, 8)).is_err());
+    assert!(aviffy.header_size(&ImageData::new(&seq_header, None, 10, 20, 8)).is_ok());
+}
+
+#[test]
+fn annex_b() {
+    let seq_header = [0x18, 0x0D, 0x26, 0x60, 0x10, 0x10, 0xD0, 0x68, 0x40];
+    let mut annex_b = vec![17, 16, 1, 0x10, 10, 0x08];
+    annex_b.extend_from_slice(&seq_header);
+    annex_b.extend_from_slice(&[2, 0x30, 0]);
+    annex_b.extend_from_slice(&[5, 4, 3, 0x32, 1, 7]);
+    let (data, sizes) = bitstream::annex_b_to_low_overhead(&annex_b).unwrap();
+    assert_eq!(sizes, [14, 3]);
+    assert_eq!(&data[..2], &[0x0A, 9]);
+    assert_eq!(&data[11..], &[0x32, 1, 0, 0x32, 1, 7]);
+    assert!(Aviffy::new().check_size(&data, 10, 20).unwrap().is_empty());
+
+    assert!(bitstream::annex_b_to_low_overhead(&annex_b[..annex_b.len() - 1]).is_err());
+}
+
+#[test]
+fn ivf_frames() {
+    let seq_header = [0x0A, 9, 0x18, 0x0D, 0x26, 0x60, 0x10, 0x10, 0xD0, 0x68, 0x40];
+    let mut ivf = b"DKIF\0\0\x20\0AV01\x0A\0\x14\0\x1E\0\0\0\x01\0\0\0\x02\0\0\0\0\0\0\0".to_vec();
+    let mut key = vec![0x12, 0]; // temporal delimiter
+    key.extend_from_slice(&seq_header);
+    key.extend_from_slice(&[0x32, 1, 0x10]);
+    for (packet, pts) in [(&key[..], 0u64), (&[0x12, 0, 0x32, 1, 0x60][..], 3)] {
+        ivf.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        ivf.extend_from_slice(&pts.to_le_bytes());
+        ivf.extend_from_slice(packet);
     }
+    let frames = bitstream::IvfFrames::read(&ivf[..]).unwrap();
+    assert_eq!((frames.width, frames.height, frames.timescale), (10, 20, 30));
+    assert_eq!(frames.frames, [FrameInfo::new(3, true, 14), FrameInfo::new(1, false, 3)]);
+    assert_eq!(&frames.data[..11], &seq_header);
+    assert!(Aviffy::new().try_to_vec(&frames.data, None, frames.width, frames.height, 8, frames.timescale, Some(&frames.frames), None, &[]).is_ok());
+
+    assert!(bitstream::IvfFrames::read(&ivf[..ivf.len() - 1]).is_err());
+
+    // timebase_num of u32::MAX and a pts gap of 2^33 ticks
+    ivf[20..24].copy_from_slice(&u32::MAX.to_le_bytes());
+    let second_pts = 32 + 12 + key.len() + 4;
+    ivf[second_pts..second_pts + 8].copy_from_slice(&(1u64 << 33).to_le_bytes());
+    let err = bitstream::IvfFrames::read(&ivf[..]).unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidData, err.kind());
 }
 
-/// See [`serialize`] for description. This one makes a `Vec` instead of using `io::Write`.
-#[must_use] pub fn serialize_to_vec(color_av1_data: &[u8], alpha_av1_data: Option<&[u8]>, width: u32, height: u32, depth_bits: u8, timescale: u32, color_frames: Option<&[FrameInfo]>, alpha_frames: Option<&[FrameInfo]>, exif_data: &[u8]) -> Vec<u8> {
-    Aviffy::new().to_vec(color_av1_data, alpha_av1_data, width, height, depth_bits, timescale, color_frames, alpha_frames, exif_data)
+#[test]
+fn split_temporal_units() {
+    // Not reduced: a shown key frame, a hidden frame and a frame showing it, and a shown frame
+    let mut data = vec![0x12, 0, 0x0A, 2, 0, 0, 0x32, 1, 0x10];
+    data.extend_from_slice(&[0x32, 1, 0x20, 0x1A, 1, 0x80]);
+    data.extend_from_slice(&[0x12, 0, 0x32, 2, 0x30, 9]);
+    let (stream, sizes) = bitstream::split_temporal_units(&data).unwrap();
+    assert_eq!(sizes, [7, 6, 4]);
+    assert_eq!(stream.len(), 17);
+    assert_eq!(&stream[13..], &[0x32, 2, 0x30, 9]);
+
+    // Reduced still picture headers have one frame in each temporal unit
+    let (_, sizes) = bitstream::split_temporal_units(&[0x0A, 1, 0x18, 0x32, 1, 0, 0x32, 1, 0]).unwrap();
+    assert_eq!(sizes, [6, 3]);
 }
 
-pub struct FrameInfo {
-    pub duration_in_timescales: u64,
-    pub sync: bool,
-    pub size: u32,
+#[test]
+fn key_frames() {
+    // Key frame with its sequence header, and an inter frame
+    let data = [0x0A, 2, 0, 0, 0x32, 1, 0x10, 0x32, 1, 0x30];
+    let mut frames = [FrameInfo::new(1, false, 7), FrameInfo::new(1, true, 3)];
+    let mut aviffy = Aviffy::new();
+    assert_eq!(aviffy.check_sync(&data, &frames).unwrap(), [
+        Mismatch::Sync { frame: 0, sync: false, key_frame: true },
+        Mismatch::Sync { frame: 1, sync: true, key_frame: false },
+    ]);
+    assert!(aviffy.try_to_vec(&data, None, 10, 20, 8, 10, Some(&frames), None, &[]).is_ok());
+    aviffy.verify_sync(true);
+    let err = aviffy.try_to_vec(&data, None, 10, 20, 8, 10, Some(&frames), None, &[]).unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+    bitstream::detect_key_frames(&data, &mut frames).unwrap();
+    assert_eq!(frames, [FrameInfo::new(1, true, 7), FrameInfo::new(1, false, 3)]);
+    assert!(aviffy.try_to_vec(&data, None, 10, 20, 8, 10, Some(&frames), None, &[]).is_ok());
+    assert!(bitstream::detect_key_frames(&data[..9], &mut frames).is_err());
 }
 
 #[test]
-fn test_roundtrip_parse_mp4() {
-    let test_img = b"av12356abc";
-    let exif_data = b"";
-    let avif = serialize_to_vec(test_img, None, 10, 20, 8, 1, None, None, exif_data);
+fn track_colr() {
+    fn nclx(primaries: u16, transfer: u16, matrix: u16, full_range: bool) -> Vec<u8> {
+        let mut colr = vec![0, 0, 0, 19];
+        colr.extend_from_slice(b"colrnclx");
+        for value in [primaries, transfer, matrix] {
+            colr.extend_from_slice(&value.to_be_bytes());
+        }
+        colr.push(if full_range { 0x80 } else { 0 });
+        colr
+    }
+    fn count(avif: &[u8], colr: &[u8]) -> usize {
+        avif.windows(colr.len()).filter(|w| *w == colr).count()
+    }
 
-    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+    let frames = [FrameInfo::new(1, true, 3), FrameInfo::new(1, false, 3)];
+    let srgb = nclx(1, 13, 6, true);
+    let hdr = nclx(9, 14, 9, false);
+    let write = |aviffy: &Aviffy| aviffy.to_vec(b"abcdef", Some(b"ghijkl"), 10, 20, 8, 10, Some(&frames), Some(&frames), &[]);
 
-    assert_eq!(&test_img[..], ctx.primary_item_coded_data());
+    // Only the color track has it by default, and the item doesn't need the default one
+    let avif = write(&Aviffy::new());
+    assert_eq!((1, 0), (count(&avif, &srgb), count(&avif, &hdr)));
+
+    let hdr_info = ColorInfo {
+        color_primaries: constants::ColorPrimaries::Bt2020,
+        transfer_characteristics: constants::TransferCharacteristics::Bt2020_10,
+        matrix_coefficients: constants::MatrixCoefficients::Bt2020Ncl,
+        full_color_range: false,
+    };
+    let avif = write(Aviffy::new().color_track_colr(TrackColr::Custom(hdr_info)).alpha_track_colr(TrackColr::SameAsItem));
+    assert_eq!((1, 1), (count(&avif, &srgb), count(&avif, &hdr)));
+
+    let avif = write(Aviffy::new().color_track_colr(TrackColr::None));
+    assert!(!avif.windows(4).any(|w| w == b"colr"));
 }
 
 #[test]
-fn test_roundtrip_parse_mp4_alpha() {
-    let test_img = b"av12356abc";
-    let test_a = b"alpha";
-    let exif_data = b"";
-    let avif = serialize_to_vec(test_img, Some(test_a), 10, 20, 8, 1, None, None, exif_data);
+#[cfg(feature = "svt-av1")]
+fn svt_packets() {
+    let mut key = vec![0x12, 0, 0x0A, 9, 0x18, 0x0D, 0x26, 0x60, 0x10, 0x10, 0xD0, 0x68, 0x40];
+    key.extend_from_slice(&[0x32, 1, 0x10]);
+    let inter = [0x12, 0, 0x32, 1, 0x60];
+    let mut svt = svt::SvtFrames::new(10, 20, 1, 30);
+    for (packet, pts) in [(&key[..], 0), (&inter[..], 2), (&inter[..], 3)] {
+        svt.push_packet(packet, pts).unwrap();
+    }
+    assert_eq!(svt.frames, [FrameInfo::new(2, true, 16), FrameInfo::new(1, false, 5), FrameInfo::new(1, false, 5)]);
+    let avif = Aviffy::new().try_to_vec(&svt.data, None, svt.width, svt.height, 8, svt.timescale, Some(&svt.frames), None, &[]).unwrap();
 
-    let ctx = mp4parse::read_avif(&mut avif.as_slice(), mp4parse::ParseStrictness::Normal).unwrap();
+    // Two entries: one sample of 2 ticks, then two of 1
+    let stts = [0, 0, 0, 32, b's', b't', b't', b's', 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0, 1];
+    assert!(avif.windows(stts.len()).any(|w| w == stts));
+    // Only the first sample is a sync sample
+    let stss = [0, 0, 0, 20, b's', b't', b's', b's', 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1];
+    assert!(avif.windows(stss.len()).any(|w| w == stss));
+    assert!(avif.windows(key.len()).any(|w| w == key));
+}
 
-    assert_eq!(&test_img[..], ctx.primary_item_coded_data());
-    assert_eq!(&test_a[..], ctx.alpha_item_coded_data());
+#[test]
+fn time_box_versions() {
+    /// `mvhd`/`tkhd`/`mdhd` up to their duration field (timescale 10 and creation time 0)
+    fn time_box(typ: &[u8; 4], len: u32, version: u8, track_id: Option<u32>, duration: u64) -> Vec<u8> {
+        let mut b = len.to_be_bytes().to_vec();
+        b.extend_from_slice(typ);
+        b.extend_from_slice(&[version, 0, 0, u8::from(track_id.is_some())]);
+        let time = |b: &mut Vec<u8>, t: u64| if version == 1 {
+            b.extend_from_slice(&t.to_be_bytes());
+        } else {
+            b.extend_from_slice(&(t as u32).to_be_bytes());
+        };
+        time(&mut b, 0);
+        time(&mut b, 0);
+        match track_id {
+            Some(id) => b.extend_from_slice(&[id.to_be_bytes(), [0; 4]].concat()),
+            None => b.extend_from_slice(&10u32.to_be_bytes()),
+        }
+        time(&mut b, duration);
+        b
+    }
+    fn has(avif: &[u8], b: &[u8]) -> bool {
+        avif.windows(b.len()).any(|w| w == b)
+    }
+
+    let frames = [FrameInfo::new(1, true, 3), FrameInfo::new(1, false, 3)];
+    let write = |aviffy: &Aviffy| aviffy.to_vec(b"abcdef", None, 10, 20, 8, 10, Some(&frames), None, &[]);
+
+    let avif = write(&Aviffy::new());
+    assert!(has(&avif, &time_box(b"mvhd", 108, 0, None, 2)));
+    assert!(has(&avif, &time_box(b"tkhd", 92, 0, Some(1), 2)));
+    assert!(has(&avif, &time_box(b"mdhd", 32, 0, None, 2)));
+
+    let avif = write(Aviffy::new().long_times(true));
+    assert!(has(&avif, &time_box(b"mvhd", 120, 1, None, 2)));
+    assert!(has(&avif, &time_box(b"tkhd", 104, 1, Some(1), 2)));
+    assert!(has(&avif, &time_box(b"mdhd", 44, 1, None, 2)));
+
+    // Indefinite duration is all 1s in version 0 too
+    let avif = write(Aviffy::new().hold_last_frame(true));
+    assert!(has(&avif, &time_box(b"mvhd", 108, 0, None, u64::MAX)));
+    assert!(has(&avif, &time_box(b"mdhd", 32, 0, None, 2)));
 }
 
 #[test]
-fn test_roundtrip_parse_avif() {
-    let test_img = [1,2,3,4,5,6];
-    let test_alpha = [77,88,99];
-    let exif_data: [u8; 0] = [];
-    let avif = serialize_to_vec(&test_img, Some(&test_alpha), 10, 20, 8, 1, None, None, &exif_data);
+fn alpha_first_order() {
+    fn item_data(avif: &[u8]) -> Vec<&[u8]> {
+        let boxes = reader::parse_boxes(avif, 0).unwrap();
+        let meta = boxes.iter().find(|b| b.typ == *b"meta").unwrap();
+        let iloc = reader::Iloc::parse(&meta.child(*b"iloc").unwrap().payload()).unwrap();
+        iloc.items.iter().map(|item| {
+            let ex = item.extents[0];
+            let start = (item.base_offset + ex.offset) as usize;
+            &avif[start..start + ex.len as usize]
+        }).collect()
+    }
 
-    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    let avif = Aviffy::new().to_vec(b"COLOR", Some(b"alpha"), 10, 20, 8, 1, None, None, &[]);
+    assert!(avif.ends_with(b"alphaCOLOR"));
+    assert_eq!(item_data(&avif), [&b"COLOR"[..], b"alpha"]);
 
-    assert_eq!(&test_img[..], ctx.primary_item.as_slice());
-    assert_eq!(&test_alpha[..], ctx.alpha_item.as_deref().unwrap());
+    let avif = Aviffy::new().alpha_first(false).to_vec(b"COLOR", Some(b"alpha"), 10, 20, 8, 1, None, None, &[]);
+    assert!(avif.ends_with(b"COLORalpha"));
+    assert_eq!(item_data(&avif), [&b"COLOR"[..], b"alpha"]);
 }
 
 #[test]
-fn test_roundtrip_parse_avif_colr() {
-    let test_img = [1,2,3,4,5,6];
-    let test_alpha = [77,88,99];
-    let exif_data: [u8; 0] = [];
-    let avif = Aviffy::new()
-        .matrix_coefficients(constants::MatrixCoefficients::Bt709)
-        .to_vec(&test_img, Some(&test_alpha), 10, 20, 8, 1, None, None, &exif_data);
+fn encoder_info_blob() {
+    let frames = [FrameInfo::new(1, true, 6)];
+    let mut aviffy = Aviffy::new();
+    aviffy.embed_blob(BlobLocation::Free, &b"enc 1.0"[..])
+        .embed_blob(BlobLocation::Udta(*b"\xa9too"), &b"enc 1.0 --speed 6"[..]);
+    let avif = aviffy.to_vec(b"abcdef", None, 10, 20, 8, 1, Some(&frames), None, &[]);
+    let boxes = reader::parse_boxes(&avif, 0).unwrap();
+    let types = boxes.iter().map(|b| b.typ).collect::<Vec<_>>();
+    assert_eq!(types, [*b"ftyp", *b"meta", *b"moov", *b"free", *b"mdat"]);
+    assert_eq!(&b"enc 1.0"[..], &*boxes[3].payload());
+    let udta = boxes[2].child(*b"udta").unwrap();
+    assert_eq!(&b"\0\0\0\x19\xa9tooenc 1.0 --speed 6"[..], &udta.to_vec()[8..]);
+    assert_eq!(&b"abcdef"[..], &*boxes[4].payload());
 
-    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    // Still images have no moov for the udta
+    let avif = aviffy.to_vec(b"abcdef", None, 10, 20, 8, 1, None, None, &[]);
+    let types = reader::parse_boxes(&avif, 0).unwrap().iter().map(|b| b.typ).collect::<Vec<_>>();
+    assert_eq!(types, [*b"ftyp", *b"meta", *b"free", *b"mdat"]);
+    assert!(!avif.windows(4).any(|w| w == b"udta"));
+}
 
-    assert_eq!(&test_img[..], ctx.primary_item.as_slice());
-    assert_eq!(&test_alpha[..], ctx.alpha_item.as_deref().unwrap());
+#[test]
+fn sample_map() {
+    let frames = [FrameInfo::new(1, true, 2), FrameInfo::new(1, false, 4)];
+    for alpha_first in [true, false] {
+        let mut aviffy = Aviffy::new();
+        aviffy.alpha_first(alpha_first);
+        let mut out = Vec::new();
+        let map = aviffy.write_with_sample_map(&mut out, &ImageData { timescale: 10, color_frames: Some(&frames), alpha_frames: Some(&frames), ..ImageData::new(b"abCDEF", Some(b"ghIJKL"), 10, 20, 8) }).unwrap();
+        assert_eq!(out, aviffy.to_vec(b"abCDEF", Some(b"ghIJKL"), 10, 20, 8, 10, Some(&frames), Some(&frames), &[]));
+        let samples = |ranges: &[Range<u64>]| ranges.iter().map(|r| &out[r.start as usize..r.end as usize]).collect::<Vec<_>>();
+        assert_eq!(samples(&map.color), [&b"ab"[..], b"CDEF"]);
+        assert_eq!(samples(&map.alpha), [&b"gh"[..], b"IJKL"]);
+    }
+
+    let mut out = Vec::new();
+    let map = Aviffy::new().write_with_sample_map(&mut out, &ImageData { timescale: 10, ..ImageData::new(b"abcdef", Some(b"gh"), 10, 20, 8) }).unwrap();
+    assert_eq!(SampleMap::default(), map);
+    assert!(out.ends_with(b"ghabcdef"));
 }
 
 #[test]
-fn premultiplied_flag() {
-    let test_img = [1,2,3,4];
-    let test_alpha = [55,66,77,88,99];
-    let exif_data: [u8; 0] = [];
-    let avif = Aviffy::new().premultiplied_alpha(true).to_vec(&test_img, Some(&test_alpha), 5, 5, 8, 1, None, None, &exif_data);
+fn track_reference_types() {
+    fn trefs(avif: &[u8]) -> Vec<Option<Vec<u8>>> {
+        let boxes = reader::parse_boxes(avif, 0).unwrap();
+        let moov = boxes.iter().find(|b| b.typ == *b"moov").unwrap();
+        moov.children().iter().filter(|b| b.typ == *b"trak")
+            .map(|trak| trak.child(*b"tref").map(|tref| tref.payload().into_owned()))
+            .collect()
+    }
 
-    let ctx = avif_parse::read_avif(&mut avif.as_slice()).unwrap();
+    let frames = [FrameInfo::new(1, true, 3), FrameInfo::new(1, false, 3)];
+    let mut aviffy = Aviffy::new();
+    let avif = aviffy.to_vec(b"abcdef", Some(b"ghijkl"), 10, 20, 8, 10, Some(&frames), Some(&frames), &[]);
+    assert_eq!(trefs(&avif), [None, Some(b"\0\0\0\x0cauxl\0\0\0\x01".to_vec())]);
 
-    assert!(ctx.premultiplied_alpha);
-    assert_eq!(&test_img[..], ctx.primary_item.as_slice());
-    assert_eq!(&test_alpha[..], ctx.alpha_item.as_deref().unwrap());
+    aviffy.add_track_reference(1, *b"hint", &[2])
+        .add_track_reference(2, *b"auxl", &[3])
+        .add_track_reference(2, *b"cdsc", &[1]);
+    let avif = aviffy.to_vec(b"abcdef", Some(b"ghijkl"), 10, 20, 8, 10, Some(&frames), Some(&frames), &[]);
+    assert_eq!(trefs(&avif), [
+        Some(b"\0\0\0\x0chint\0\0\0\x02".to_vec()),
+        // Same type is merged into one box with both ids
+        Some(b"\0\0\0\x10auxl\0\0\0\x01\0\0\0\x03\0\0\0\x0ccdsc\0\0\0\x01".to_vec()),
+    ]);
 }
@@ -0,0 +1,325 @@
+//! Just enough AV1 bitstream parsing to find frame boundaries, keyframes, and the sequence header
+
+use std::io;
+
+pub const OBU_SEQUENCE_HEADER: u8 = 1;
+pub const OBU_TEMPORAL_DELIMITER: u8 = 2;
+pub const OBU_FRAME_HEADER: u8 = 3;
+pub const OBU_METADATA: u8 = 5;
+pub const OBU_FRAME: u8 = 6;
+
+#[derive(Debug, Copy, Clone)]
+pub struct Obu<'a> {
+    pub typ: u8,
+    pub payload: &'a [u8],
+    /// The whole OBU, including its header
+    pub raw: &'a [u8],
+}
+
+/// Iterates OBUs in the low-overhead bitstream format (with `obu_size` fields)
+pub struct Obus<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Obus<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn parse_next(&mut self) -> io::Result<Obu<'a>> {
+        let header = self.data[0];
+        let typ = (header >> 3) & 0x0f;
+        let has_extension = header & 0x04 != 0;
+        let has_size = header & 0x02 != 0;
+        let mut pos = if has_extension { 2 } else { 1 };
+        let size = if has_size {
+            let (size, leb_len) = leb128(self.data.get(pos..).unwrap_or_default())?;
+            pos += leb_len;
+            size
+        } else {
+            self.data.len().saturating_sub(pos)
+        };
+        let payload = pos.checked_add(size).and_then(|end| self.data.get(pos..end)).ok_or_else(invalid)?;
+        let (raw, rest) = self.data.split_at(pos + size);
+        self.data = rest;
+        Ok(Obu { typ, payload, raw })
+    }
+}
+
+impl<'a> Iterator for Obus<'a> {
+    type Item = io::Result<Obu<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+        let res = self.parse_next();
+        if res.is_err() {
+            self.data = &[];
+        }
+        Some(res)
+    }
+}
+
+/// Returns value and number of bytes used
+pub fn leb128(data: &[u8]) -> io::Result<(usize, usize)> {
+    let mut value = 0u64;
+    for (i, &byte) in data.iter().take(8).enumerate() {
+        value |= u64::from(byte & 0x7f) << (i * 7);
+        if byte & 0x80 == 0 {
+            return Ok((usize::try_from(value).map_err(|_| invalid())?, i + 1));
+        }
+    }
+    Err(invalid())
+}
+
+pub fn push_leb128(out: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Whether the frame header at the start of a frame or frame header OBU's `payload` is of a frame that is shown,
+/// which is the last frame of a temporal unit
+pub fn shows_frame(payload: &[u8], reduced_still_picture_header: bool) -> io::Result<bool> {
+    if reduced_still_picture_header {
+        return Ok(true);
+    }
+    let first = *payload.first().ok_or_else(invalid)?;
+    // show_existing_frame(1), frame_type(2), show_frame(1)
+    Ok(first & 0x80 != 0 || first & 0x10 != 0)
+}
+
+/// Whether a temporal unit (all OBUs of one frame) starts with a keyframe
+pub fn is_keyframe(temporal_unit: &[u8]) -> io::Result<bool> {
+    let mut reduced_still_picture_header = false;
+    for obu in Obus::new(temporal_unit) {
+        let obu = obu?;
+        match obu.typ {
+            OBU_SEQUENCE_HEADER => {
+                // seq_profile(3), still_picture(1), reduced_still_picture_header(1)
+                reduced_still_picture_header = obu.payload.first().is_some_and(|&b| b & 0x08 != 0);
+            },
+            OBU_FRAME_HEADER | OBU_FRAME => {
+                if reduced_still_picture_header {
+                    return Ok(true);
+                }
+                let first = *obu.payload.first().ok_or_else(invalid)?;
+                let show_existing_frame = first & 0x80 != 0;
+                let frame_type = (first >> 5) & 0x03;
+                return Ok(!show_existing_frame && frame_type == 0); // KEY_FRAME
+            },
+            _ => {},
+        }
+    }
+    Ok(false)
+}
+
+/// Fields of the sequence header that are relevant to the container
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SequenceHeader {
+    pub seq_profile: u8,
+    pub still_picture: bool,
+    pub reduced_still_picture_header: bool,
+    /// Of the first operating point
+    pub seq_level_idx_0: u8,
+    pub seq_tier_0: bool,
+    pub max_frame_width: u32,
+    pub max_frame_height: u32,
+    pub bit_depth: u8,
+    pub monochrome: bool,
+    pub subsampling_x: bool,
+    pub subsampling_y: bool,
+    pub chroma_sample_position: u8,
+    pub color_primaries: u8,
+    pub transfer_characteristics: u8,
+    pub matrix_coefficients: u8,
+    pub full_color_range: bool,
+}
+
+/// Finds and parses the first sequence header OBU
+pub fn sequence_header(data: &[u8]) -> io::Result<SequenceHeader> {
+    SequenceHeader::parse(sequence_header_obu(data)?.payload)
+}
+
+/// Finds the first sequence header OBU
+pub fn sequence_header_obu(data: &[u8]) -> io::Result<Obu<'_>> {
+    for obu in Obus::new(data) {
+        let obu = obu?;
+        if obu.typ == OBU_SEQUENCE_HEADER {
+            return Ok(obu);
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::InvalidData, "no AV1 sequence header"))
+}
+
+impl SequenceHeader {
+    fn parse(payload: &[u8]) -> io::Result<Self> {
+        let mut r = BitReader { data: payload, pos: 0 };
+        let seq_profile = r.bits(3)? as u8;
+        let still_picture = r.flag()?;
+        let reduced_still_picture_header = r.flag()?;
+        let (seq_level_idx_0, seq_tier_0);
+        if reduced_still_picture_header {
+            seq_level_idx_0 = r.bits(5)? as u8;
+            seq_tier_0 = false;
+        } else {
+            let mut decoder_model_info_present = false;
+            let mut buffer_delay_length = 0;
+            if r.flag()? { // timing_info_present_flag
+                r.bits(32)?; // num_units_in_display_tick
+                r.bits(32)?; // time_scale
+                if r.flag()? { // equal_picture_interval
+                    r.uvlc()?; // num_ticks_per_picture_minus_1
+                }
+                decoder_model_info_present = r.flag()?;
+                if decoder_model_info_present {
+                    buffer_delay_length = r.bits(5)? + 1;
+                    r.bits(32)?; // num_units_in_decoding_tick
+                    r.bits(5)?; // buffer_removal_time_length_minus_1
+                    r.bits(5)?; // frame_presentation_time_length_minus_1
+                }
+            }
+            let initial_display_delay_present = r.flag()?;
+            let operating_points = r.bits(5)? + 1;
+            let mut first = None;
+            for _ in 0..operating_points {
+                r.bits(12)?; // operating_point_idc
+                let seq_level_idx = r.bits(5)? as u8;
+                let seq_tier = seq_level_idx > 7 && r.flag()?;
+                first.get_or_insert((seq_level_idx, seq_tier));
+                if decoder_model_info_present && r.flag()? {
+                    r.bits(buffer_delay_length)?; // decoder_buffer_delay
+                    r.bits(buffer_delay_length)?; // encoder_buffer_delay
+                    r.flag()?; // low_delay_mode_flag
+                }
+                if initial_display_delay_present && r.flag()? {
+                    r.bits(4)?; // initial_display_delay_minus_1
+                }
+            }
+            (seq_level_idx_0, seq_tier_0) = first.unwrap_or_default();
+        }
+        let frame_width_bits = r.bits(4)? + 1;
+        let frame_height_bits = r.bits(4)? + 1;
+        let max_frame_width = r.bits(frame_width_bits)? + 1;
+        let max_frame_height = r.bits(frame_height_bits)? + 1;
+        if !reduced_still_picture_header && r.flag()? { // frame_id_numbers_present_flag
+            r.bits(4)?; // delta_frame_id_length_minus_2
+            r.bits(3)?; // additional_frame_id_length_minus_1
+        }
+        r.bits(3)?; // use_128x128_superblock, enable_filter_intra, enable_intra_edge_filter
+        if !reduced_still_picture_header {
+            r.bits(4)?; // enable_interintra_compound, enable_masked_compound, enable_warped_motion, enable_dual_filter
+            let enable_order_hint = r.flag()?;
+            if enable_order_hint {
+                r.bits(2)?; // enable_jnt_comp, enable_ref_frame_mvs
+            }
+            let seq_force_screen_content_tools = if r.flag()? { 2 } else { r.bits(1)? }; // seq_choose_screen_content_tools
+            if seq_force_screen_content_tools > 0 && !r.flag()? { // seq_choose_integer_mv
+                r.bits(1)?; // seq_force_integer_mv
+            }
+            if enable_order_hint {
+                r.bits(3)?; // order_hint_bits_minus_1
+            }
+        }
+        r.bits(3)?; // enable_superres, enable_cdef, enable_restoration
+
+        // color_config()
+        let high_bitdepth = r.flag()?;
+        let bit_depth = if seq_profile == 2 && high_bitdepth {
+            if r.flag()? { 12 } else { 10 }
+        } else if high_bitdepth { 10 } else { 8 };
+        let monochrome = seq_profile != 1 && r.flag()?;
+        let (color_primaries, transfer_characteristics, matrix_coefficients) = if r.flag()? { // color_description_present_flag
+            (r.bits(8)? as u8, r.bits(8)? as u8, r.bits(8)? as u8)
+        } else {
+            (2, 2, 2)
+        };
+        let mut chroma_sample_position = 0;
+        let (full_color_range, subsampling_x, subsampling_y);
+        if monochrome {
+            full_color_range = r.flag()?;
+            (subsampling_x, subsampling_y) = (true, true);
+        } else if (color_primaries, transfer_characteristics, matrix_coefficients) == (1, 13, 0) { // sRGB
+            full_color_range = true;
+            (subsampling_x, subsampling_y) = (false, false);
+        } else {
+            full_color_range = r.flag()?;
+            (subsampling_x, subsampling_y) = match seq_profile {
+                0 => (true, true),
+                1 => (false, false),
+                _ if bit_depth == 12 => {
+                    let x = r.flag()?;
+                    (x, x && r.flag()?)
+                },
+                _ => (true, false),
+            };
+            if subsampling_x && subsampling_y {
+                chroma_sample_position = r.bits(2)? as u8;
+            }
+        }
+
+        Ok(Self {
+            seq_profile,
+            still_picture,
+            reduced_still_picture_header,
+            seq_level_idx_0,
+            seq_tier_0,
+            max_frame_width,
+            max_frame_height,
+            bit_depth,
+            monochrome,
+            subsampling_x,
+            subsampling_y,
+            chroma_sample_position,
+            color_primaries,
+            transfer_characteristics,
+            matrix_coefficients,
+            full_color_range,
+        })
+    }
+}
+
+/// MSB-first bits, as in the AV1 spec's `f(n)`
+struct BitReader<'a> {
+    data: &'a [u8],
+    /// In bits
+    pos: usize,
+}
+
+impl BitReader<'_> {
+    fn bits(&mut self, n: u32) -> io::Result<u32> {
+        let mut value = 0u64;
+        for _ in 0..n {
+            let byte = *self.data.get(self.pos / 8).ok_or_else(invalid)?;
+            value = value << 1 | u64::from((byte >> (7 - self.pos % 8)) & 1);
+            self.pos += 1;
+        }
+        u32::try_from(value).map_err(|_| invalid())
+    }
+
+    fn flag(&mut self) -> io::Result<bool> {
+        Ok(self.bits(1)? != 0)
+    }
+
+    fn uvlc(&mut self) -> io::Result<u32> {
+        let mut leading_zeros = 0;
+        while !self.flag()? {
+            leading_zeros += 1;
+        }
+        if leading_zeros >= 32 {
+            return Ok(u32::MAX);
+        }
+        Ok(self.bits(leading_zeros)?.saturating_add((1 << leading_zeros) - 1))
+    }
+}
+
+pub fn invalid() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "malformed AV1 data")
+}
@@ -0,0 +1,89 @@
+use crate::constants::ChromaSamplePosition;
+use crate::constants::ChromaSubsampling;
+use crate::constants::ColorPrimaries;
+use crate::constants::MatrixCoefficients;
+use crate::constants::TransferCharacteristics;
+use crate::Aviffy;
+use rav1e::color;
+use rav1e::EncoderConfig;
+
+impl Aviffy {
+    /// Copies color description, range, bit depth, chroma subsampling and sample position from `rav1e`'s settings,
+    /// so that the AVIF headers match the encoded AV1 data.
+    ///
+    /// `rav1e::Config` doesn't expose its settings, so use the `EncoderConfig` given to `Config::with_encoder_config`.
+    ///
+    /// Color values that can't be expressed by this crate, and all of them if there's no `color_description`,
+    /// are written as unspecified (2) in the `colr` box. Decoders that read `colr` then use their own defaults for them,
+    /// not the values in the AV1 payload.
+    #[must_use]
+    pub fn from_rav1e_config(config: &EncoderConfig) -> Self {
+        let mut aviffy = Self::new();
+        match config.color_description {
+            Some(desc) => {
+                aviffy.color_primaries(color_primaries(desc.color_primaries))
+                    .transfer_characteristics(transfer_characteristics(desc.transfer_characteristics))
+                    .matrix_coefficients(matrix_coefficients(desc.matrix_coefficients));
+            },
+            // AV1 without color description is unspecified too
+            None => {
+                aviffy.color_primaries(ColorPrimaries::Unspecified)
+                    .transfer_characteristics(TransferCharacteristics::Unspecified)
+                    .matrix_coefficients(MatrixCoefficients::Unspecified);
+            },
+        }
+        aviffy.full_color_range(config.pixel_range == color::PixelRange::Full)
+            .bit_depth(config.bit_depth as u8)
+            .chroma_subsampling(match config.chroma_sampling {
+                color::ChromaSampling::Cs420 => ChromaSubsampling::Cs420,
+                color::ChromaSampling::Cs422 => ChromaSubsampling::Cs422,
+                color::ChromaSampling::Cs444 => ChromaSubsampling::Cs444,
+                color::ChromaSampling::Cs400 => ChromaSubsampling::Cs400,
+            })
+            .chroma_sample_position(match config.chroma_sample_position {
+                color::ChromaSamplePosition::Unknown => ChromaSamplePosition::Unknown,
+                color::ChromaSamplePosition::Vertical => ChromaSamplePosition::Vertical,
+                color::ChromaSamplePosition::Colocated => ChromaSamplePosition::Colocated,
+            });
+        aviffy
+    }
+}
+
+fn color_primaries(cp: color::ColorPrimaries) -> ColorPrimaries {
+    match cp {
+        color::ColorPrimaries::BT709 => ColorPrimaries::Bt709,
+        color::ColorPrimaries::BT601 => ColorPrimaries::Bt601,
+        color::ColorPrimaries::BT2020 => ColorPrimaries::Bt2020,
+        color::ColorPrimaries::SMPTE431 => ColorPrimaries::DciP3,
+        color::ColorPrimaries::SMPTE432 => ColorPrimaries::DisplayP3,
+        _ => ColorPrimaries::Unspecified,
+    }
+}
+
+fn transfer_characteristics(tc: color::TransferCharacteristics) -> TransferCharacteristics {
+    match tc {
+        color::TransferCharacteristics::BT709 => TransferCharacteristics::Bt709,
+        color::TransferCharacteristics::BT601 => TransferCharacteristics::Bt601,
+        color::TransferCharacteristics::Linear => TransferCharacteristics::Linear,
+        color::TransferCharacteristics::Log100 => TransferCharacteristics::Log,
+        color::TransferCharacteristics::Log100Sqrt10 => TransferCharacteristics::LogSqrt,
+        color::TransferCharacteristics::SRGB => TransferCharacteristics::Srgb,
+        color::TransferCharacteristics::BT2020_10Bit => TransferCharacteristics::Bt2020_10,
+        color::TransferCharacteristics::BT2020_12Bit => TransferCharacteristics::Bt2020_12,
+        color::TransferCharacteristics::SMPTE2084 => TransferCharacteristics::Pq,
+        color::TransferCharacteristics::HLG => TransferCharacteristics::Hlg,
+        _ => TransferCharacteristics::Unspecified,
+    }
+}
+
+fn matrix_coefficients(mc: color::MatrixCoefficients) -> MatrixCoefficients {
+    match mc {
+        color::MatrixCoefficients::Identity => MatrixCoefficients::Rgb,
+        color::MatrixCoefficients::BT709 => MatrixCoefficients::Bt709,
+        color::MatrixCoefficients::BT601 => MatrixCoefficients::Bt601,
+        color::MatrixCoefficients::YCgCo => MatrixCoefficients::Ycgco,
+        color::MatrixCoefficients::BT2020NCL => MatrixCoefficients::Bt2020Ncl,
+        color::MatrixCoefficients::BT2020CL => MatrixCoefficients::Bt2020Cl,
+        _ => MatrixCoefficients::Unspecified,
+    }
+}
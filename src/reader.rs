@@ -0,0 +1,804 @@
+//! Just enough ISO-BMFF parsing to edit existing files without touching their AV1 data.
+//!
+//! Boxes are kept as a tree of borrowed payloads. When boxes change size, `iloc` and `stco`
+//! offsets are updated to follow the data they point to.
+
+use std::borrow::Cow;
+use std::io;
+use std::ops::Range;
+
+pub fn invalid(what: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what)
+}
+
+pub fn unsupported(what: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, what)
+}
+
+/// Reads big-endian fields of a box payload
+pub struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if len > self.data.len() {
+            return Err(invalid("truncated box"));
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// Variable-size field, as in `iloc`
+    pub fn uint(&mut self, size: u8) -> io::Result<u64> {
+        match size {
+            0 => Ok(0),
+            2 => self.u16().map(u64::from),
+            4 => self.u32().map(u64::from),
+            8 => self.u64(),
+            _ => Err(invalid("bad field size")),
+        }
+    }
+
+    /// Returns version and flags
+    pub fn full_box(&mut self) -> io::Result<(u8, u32)> {
+        let v = self.u32()?;
+        Ok(((v >> 24) as u8, v & 0xFFFFFF))
+    }
+
+    /// Nul-terminated string (the terminator is optional at the end of the box)
+    pub fn cstr(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.data.iter().position(|&c| c == 0).unwrap_or(self.data.len());
+        let s = self.bytes(len)?;
+        if !self.data.is_empty() {
+            self.data = &self.data[1..];
+        }
+        Ok(s)
+    }
+
+    pub fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.data)
+    }
+}
+
+pub fn put_full_box(out: &mut Vec<u8>, version: u8, flags: u32) {
+    out.extend_from_slice(&(u32::from(version) << 24 | flags).to_be_bytes());
+}
+
+/// Variable-size field, as in `iloc`
+pub fn put_uint(out: &mut Vec<u8>, size: u8, val: u64) -> io::Result<()> {
+    let too_large = || invalid("offset doesn't fit in the field");
+    match size {
+        0 if val == 0 => {},
+        0 => return Err(too_large()),
+        2 => out.extend_from_slice(&u16::try_from(val).map_err(|_| too_large())?.to_be_bytes()),
+        4 => out.extend_from_slice(&u32::try_from(val).map_err(|_| too_large())?.to_be_bytes()),
+        8 => out.extend_from_slice(&val.to_be_bytes()),
+        _ => return Err(invalid("bad field size")),
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct Node<'a> {
+    pub typ: [u8; 4],
+    pub body: Body<'a>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Body<'a> {
+    /// Payload, made of pieces with their position in the original file (if they came from it)
+    Data(Vec<(Option<u64>, Cow<'a, [u8]>)>),
+    /// `header` is the part of the payload before the children (version, flags, entry count)
+    Children { header: Cow<'a, [u8]>, children: Vec<Node<'a>> },
+}
+
+/// Size of the payload part before child boxes, for boxes that are parsed as containers
+fn container_header_len(typ: [u8; 4], payload: &[u8]) -> Option<usize> {
+    match &typ {
+        b"moov" | b"trak" | b"mdia" | b"minf" | b"stbl" | b"edts" | b"dinf" | b"mvex" | b"iprp" | b"ipco" | b"grpl" => Some(0),
+        b"meta" => Some(4),
+        b"stsd" => Some(8),
+        b"iinf" => Some(if payload.first() == Some(&0) { 4 + 2 } else { 4 + 4 }),
+        _ => None,
+    }
+}
+
+/// Real files nest boxes about 8 levels deep. The limit keeps recursion over malicious files from overflowing the stack.
+const MAX_BOX_DEPTH: usize = 32;
+
+pub fn parse_boxes(data: &[u8], file_pos: u64) -> io::Result<Vec<Node<'_>>> {
+    parse_boxes_nested(data, file_pos, 0)
+}
+
+fn parse_boxes_nested(data: &[u8], file_pos: u64, depth: usize) -> io::Result<Vec<Node<'_>>> {
+    if depth > MAX_BOX_DEPTH {
+        return Err(invalid("boxes nested too deeply"));
+    }
+    let mut nodes = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let mut r = Reader::new(rest);
+        let size = r.u32()?;
+        let typ: [u8; 4] = r.bytes(4)?.try_into().unwrap();
+        let (header_len, size) = match size {
+            0 => (8, rest.len() as u64),
+            1 => (16, r.u64()?),
+            n => (8, u64::from(n)),
+        };
+        let end = usize::try_from(size).ok().filter(|&s| s >= header_len && s <= rest.len()).ok_or_else(|| invalid("bad box size"))?;
+        let payload = &rest[header_len..end];
+        let payload_pos = file_pos + (data.len() - rest.len() + header_len) as u64;
+
+        let body = match container_header_len(typ, payload) {
+            Some(len) if len <= payload.len() => Body::Children {
+                header: Cow::Borrowed(&payload[..len]),
+                children: parse_boxes_nested(&payload[len..], payload_pos + len as u64, depth + 1)?,
+            },
+            Some(_) => return Err(invalid("truncated box")),
+            None => Body::Data(vec![(Some(payload_pos), Cow::Borrowed(payload))]),
+        };
+        nodes.push(Node { typ, body });
+        rest = &rest[end..];
+    }
+    Ok(nodes)
+}
+
+impl<'a> Node<'a> {
+    pub fn new(typ: [u8; 4], payload: Vec<u8>) -> Self {
+        Self { typ, body: Body::Data(vec![(None, Cow::Owned(payload))]) }
+    }
+
+    pub fn new_container(typ: [u8; 4], children: Vec<Node<'a>>) -> Self {
+        Self { typ, body: Body::Children { header: Cow::Borrowed(&[]), children } }
+    }
+
+    /// Payload of a non-container box
+    pub fn payload(&self) -> Cow<'_, [u8]> {
+        match &self.body {
+            Body::Data(pieces) if pieces.len() == 1 => Cow::Borrowed(&pieces[0].1),
+            Body::Data(pieces) => Cow::Owned(pieces.iter().flat_map(|(_, p)| p.iter().copied()).collect()),
+            Body::Children { .. } => Cow::Borrowed(&[]),
+        }
+    }
+
+    pub fn set_payload(&mut self, payload: Vec<u8>) {
+        self.body = Body::Data(vec![(None, Cow::Owned(payload))]);
+    }
+
+    pub fn children(&self) -> &[Node<'a>] {
+        match &self.body {
+            Body::Children { children, .. } => children,
+            Body::Data(_) => &[],
+        }
+    }
+
+    pub fn children_mut(&mut self) -> &mut [Node<'a>] {
+        match &mut self.body {
+            Body::Children { children, .. } => children,
+            Body::Data(_) => &mut [],
+        }
+    }
+
+    pub fn child(&self, typ: [u8; 4]) -> Option<&Node<'a>> {
+        self.children().iter().find(|c| c.typ == typ)
+    }
+
+    pub fn child_mut(&mut self, typ: [u8; 4]) -> Option<&mut Node<'a>> {
+        self.children_mut().iter_mut().find(|c| c.typ == typ)
+    }
+
+    /// Part of a container's payload before the children
+    pub fn header(&self) -> &[u8] {
+        match &self.body {
+            Body::Children { header, .. } => header,
+            Body::Data(_) => &[],
+        }
+    }
+
+    pub fn retain_children(&mut self, f: impl FnMut(&Node<'a>) -> bool) {
+        if let Body::Children { children, .. } = &mut self.body {
+            children.retain(f);
+        }
+    }
+
+    pub fn push_child(&mut self, child: Node<'a>) {
+        if let Body::Children { children, .. } = &mut self.body {
+            children.push(child);
+        }
+    }
+
+    pub fn insert_child(&mut self, index: usize, child: Node<'a>) {
+        if let Body::Children { children, .. } = &mut self.body {
+            children.insert(index, child);
+        }
+    }
+
+    pub fn set_header(&mut self, new_header: Vec<u8>) {
+        if let Body::Children { header, .. } = &mut self.body {
+            *header = Cow::Owned(new_header);
+        }
+    }
+
+    /// Follows a path of box types
+    pub fn descendant_mut(&mut self, path: &[[u8; 4]]) -> Option<&mut Node<'a>> {
+        path.iter().try_fold(self, |node, &typ| node.child_mut(typ))
+    }
+
+    fn payload_len(&self) -> u64 {
+        match &self.body {
+            Body::Data(pieces) => pieces.iter().map(|(_, p)| p.len() as u64).sum(),
+            Body::Children { header, children } => header.len() as u64 + children.iter().map(|c| c.len()).sum::<u64>(),
+        }
+    }
+
+    fn header_len(&self) -> u64 {
+        if self.payload_len() + 8 > u64::from(u32::MAX) { 16 } else { 8 }
+    }
+
+    pub fn len(&self) -> u64 {
+        self.header_len() + self.payload_len()
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.len() as usize);
+        self.write(&mut out);
+        out
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        if self.header_len() == 16 {
+            out.extend_from_slice(&1u32.to_be_bytes());
+            out.extend_from_slice(&self.typ);
+            out.extend_from_slice(&self.len().to_be_bytes());
+        } else {
+            out.extend_from_slice(&(self.len() as u32).to_be_bytes());
+            out.extend_from_slice(&self.typ);
+        }
+        match &self.body {
+            Body::Data(pieces) => for (_, p) in pieces {
+                out.extend_from_slice(p);
+            },
+            Body::Children { header, children } => {
+                out.extend_from_slice(header);
+                for c in children {
+                    c.write(out);
+                }
+            },
+        }
+    }
+
+    /// Collects where the unmodified pieces of original data will end up
+    fn relocations(&self, mut pos: u64, out: &mut Vec<Relocation>) {
+        pos += self.header_len();
+        match &self.body {
+            Body::Data(pieces) => for (orig, p) in pieces {
+                if let Some(old) = *orig {
+                    out.push(Relocation { old, len: p.len() as u64, new: pos });
+                }
+                pos += p.len() as u64;
+            },
+            Body::Children { header, children } => {
+                pos += header.len() as u64;
+                for c in children {
+                    c.relocations(pos, out);
+                    pos += c.len();
+                }
+            },
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Relocation {
+    old: u64,
+    len: u64,
+    new: u64,
+}
+
+struct Relocations(Vec<Relocation>);
+
+impl Relocations {
+    fn map(&self, old: u64) -> io::Result<u64> {
+        self.0.iter().find(|r| old >= r.old && old < r.old + r.len)
+            // pointing at the very end is fine for empty items
+            .or_else(|| self.0.iter().find(|r| old == r.old + r.len))
+            .map(|r| r.new + (old - r.old))
+            .ok_or_else(|| invalid("offset points outside of the file's data"))
+    }
+}
+
+/// A whole file as a tree of boxes
+pub struct BoxFile<'a> {
+    pub boxes: Vec<Node<'a>>,
+}
+
+impl<'a> BoxFile<'a> {
+    pub fn parse(data: &'a [u8]) -> io::Result<Self> {
+        Ok(Self { boxes: parse_boxes(data, 0)? })
+    }
+
+    pub fn get_mut(&mut self, typ: [u8; 4]) -> Option<&mut Node<'a>> {
+        self.boxes.iter_mut().find(|b| b.typ == typ)
+    }
+
+    /// Cuts out ranges of the original file from top-level boxes' payloads (i.e. from `mdat`)
+    pub fn remove_data(&mut self, ranges: &[Range<u64>]) {
+        for node in &mut self.boxes {
+            let Body::Data(pieces) = &mut node.body else { continue };
+            for range in ranges {
+                *pieces = std::mem::take(pieces).into_iter().flat_map(|piece| {
+                    let (Some(start), Cow::Borrowed(data)) = piece else { return [Some(piece), None] };
+                    let end = start + data.len() as u64;
+                    if range.end <= start || range.start >= end {
+                        return [Some((Some(start), Cow::Borrowed(data))), None];
+                    }
+                    let before = range.start.saturating_sub(start) as usize;
+                    let after = range.end.min(end).saturating_sub(start) as usize;
+                    [
+                        Some((Some(start), Cow::Borrowed(&data[..before]))).filter(|_| before > 0),
+                        Some((Some(start + after as u64), Cow::Borrowed(&data[after..]))).filter(|_| after < data.len()),
+                    ]
+                }).flatten().collect();
+            }
+        }
+    }
+
+    /// Serializes the boxes, updating file offsets in `iloc`, `stco` and `co64` to where the data moved
+    pub fn into_vec(mut self) -> io::Result<Vec<u8>> {
+        let mut relocations = Vec::new();
+        let mut pos = 0;
+        for b in &self.boxes {
+            b.relocations(pos, &mut relocations);
+            pos += b.len();
+        }
+        let relocations = Relocations(relocations);
+
+        // Offsets are written in fields of the same size, so the layout doesn't change
+        if let Some(iloc) = self.get_mut(*b"meta").and_then(|m| m.child_mut(*b"iloc")) {
+            let mut parsed = Iloc::parse(&iloc.payload())?;
+            let has_base_offset = parsed.base_offset_size > 0;
+            for item in parsed.items.iter_mut().filter(|i| i.construction_method == 0 && i.data_reference_index == 0) {
+                item.relocate(&relocations, has_base_offset)?;
+            }
+            iloc.set_payload(parsed.to_vec()?);
+        }
+        if let Some(moov) = self.get_mut(*b"moov") {
+            for trak in moov.children_mut().iter_mut().filter(|t| t.typ == *b"trak") {
+                let Some(stbl) = trak.descendant_mut(&[*b"mdia", *b"minf", *b"stbl"]) else { continue };
+                for co in stbl.children_mut().iter_mut().filter(|c| c.typ == *b"stco" || c.typ == *b"co64") {
+                    let size = if co.typ == *b"stco" { 4 } else { 8 };
+                    let payload = co.payload();
+                    let mut r = Reader::new(&payload);
+                    let (version, flags) = r.full_box()?;
+                    let count = r.u32()?;
+                    let mut out = Vec::with_capacity(payload.len());
+                    put_full_box(&mut out, version, flags);
+                    out.extend_from_slice(&count.to_be_bytes());
+                    for _ in 0..count {
+                        put_uint(&mut out, size, relocations.map(r.uint(size)?)?)?;
+                    }
+                    drop(payload);
+                    co.set_payload(out);
+                }
+            }
+        }
+
+        let mut out = Vec::with_capacity(pos as usize);
+        for b in &self.boxes {
+            b.write(&mut out);
+        }
+        Ok(out)
+    }
+}
+
+/// Parsed `iloc`, written back with the same field sizes
+#[derive(Debug, Clone)]
+pub struct Iloc {
+    pub version: u8,
+    pub offset_size: u8,
+    pub length_size: u8,
+    pub base_offset_size: u8,
+    pub index_size: u8,
+    pub items: Vec<IlocEntry>,
+}
+
+#[derive(Debug, Clone)]
+pub struct IlocEntry {
+    pub id: u32,
+    /// 0 = file offset, 1 = `idat`, 2 = another item
+    pub construction_method: u8,
+    pub data_reference_index: u16,
+    pub base_offset: u64,
+    pub extents: Vec<IlocEntryExtent>,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct IlocEntryExtent {
+    pub index: u64,
+    pub offset: u64,
+    pub len: u64,
+}
+
+impl Iloc {
+    pub fn parse(payload: &[u8]) -> io::Result<Self> {
+        let mut r = Reader::new(payload);
+        let (version, _) = r.full_box()?;
+        if version > 2 {
+            return Err(unsupported("iloc version"));
+        }
+        let sizes = r.u8()?;
+        let sizes2 = r.u8()?;
+        let mut iloc = Self {
+            version,
+            offset_size: sizes >> 4,
+            length_size: sizes & 15,
+            base_offset_size: sizes2 >> 4,
+            index_size: if version > 0 { sizes2 & 15 } else { 0 },
+            items: Vec::new(),
+        };
+        let count = if version < 2 { r.u16()?.into() } else { r.u32()? };
+        for _ in 0..count {
+            let id = if version < 2 { r.u16()?.into() } else { r.u32()? };
+            let construction_method = if version > 0 { (r.u16()? & 15) as u8 } else { 0 };
+            let data_reference_index = r.u16()?;
+            let base_offset = r.uint(iloc.base_offset_size)?;
+            let extent_count = r.u16()?;
+            let extents = (0..extent_count).map(|_| Ok(IlocEntryExtent {
+                index: r.uint(iloc.index_size)?,
+                offset: r.uint(iloc.offset_size)?,
+                len: r.uint(iloc.length_size)?,
+            })).collect::<io::Result<_>>()?;
+            iloc.items.push(IlocEntry { id, construction_method, data_reference_index, base_offset, extents });
+        }
+        Ok(iloc)
+    }
+
+    pub fn to_vec(&self) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        put_full_box(&mut out, self.version, 0);
+        out.push(self.offset_size << 4 | self.length_size);
+        out.push(self.base_offset_size << 4 | self.index_size);
+        if self.version < 2 {
+            put_uint(&mut out, 2, self.items.len() as u64)?;
+        } else {
+            put_uint(&mut out, 4, self.items.len() as u64)?;
+        }
+        for item in &self.items {
+            put_uint(&mut out, if self.version < 2 { 2 } else { 4 }, item.id.into())?;
+            if self.version > 0 {
+                out.extend_from_slice(&u16::from(item.construction_method).to_be_bytes());
+            }
+            out.extend_from_slice(&item.data_reference_index.to_be_bytes());
+            put_uint(&mut out, self.base_offset_size, item.base_offset)?;
+            out.extend_from_slice(&(item.extents.len() as u16).to_be_bytes());
+            for ex in &item.extents {
+                put_uint(&mut out, self.index_size, ex.index)?;
+                put_uint(&mut out, self.offset_size, ex.offset)?;
+                put_uint(&mut out, self.length_size, ex.len)?;
+            }
+        }
+        Ok(out)
+    }
+}
+
+impl IlocEntry {
+    fn relocate(&mut self, relocations: &Relocations, has_base_offset: bool) -> io::Result<()> {
+        let new_offsets = self.extents.iter()
+            .map(|ex| relocations.map(self.base_offset.checked_add(ex.offset).ok_or_else(|| invalid("bad item offset"))?))
+            .collect::<io::Result<Vec<_>>>()?;
+        // Extents are relative to the base, which may need to move back
+        if let Some(&min) = new_offsets.iter().min() {
+            let base = if !has_base_offset { 0 } else if self.extents.iter().all(|ex| ex.offset == 0) { min } else { self.base_offset.min(min) };
+            for (ex, new) in self.extents.iter_mut().zip(new_offsets) {
+                ex.offset = new - base;
+            }
+            self.base_offset = base;
+        }
+        Ok(())
+    }
+}
+
+/// Parsed `ipma`
+#[derive(Debug, Clone)]
+pub struct Ipma {
+    pub version: u8,
+    pub flags: u32,
+    /// Item ID, and 1-based `ipco` indices with the essential flag
+    pub entries: Vec<(u32, Vec<(bool, u16)>)>,
+}
+
+impl Ipma {
+    pub fn parse(payload: &[u8]) -> io::Result<Self> {
+        let mut r = Reader::new(payload);
+        let (version, flags) = r.full_box()?;
+        let count = r.u32()?;
+        let entries = (0..count).map(|_| {
+            let id = if version < 1 { r.u16()?.into() } else { r.u32()? };
+            let assoc_count = r.u8()?;
+            let props = (0..assoc_count).map(|_| Ok(if flags & 1 != 0 {
+                let p = r.u16()?;
+                (p & 0x8000 != 0, p & 0x7FFF)
+            } else {
+                let p = r.u8()?;
+                (p & 0x80 != 0, u16::from(p & 0x7F))
+            })).collect::<io::Result<_>>()?;
+            Ok((id, props))
+        }).collect::<io::Result<_>>()?;
+        Ok(Self { version, flags, entries })
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let large = self.entries.iter().flat_map(|e| &e.1).any(|&(_, p)| p > 0x7F);
+        let flags = if large { self.flags | 1 } else { self.flags };
+        let version = if self.entries.iter().any(|e| e.0 > 0xFFFF) { 1 } else { self.version };
+        let mut out = Vec::new();
+        put_full_box(&mut out, version, flags);
+        out.extend_from_slice(&(self.entries.len() as u32).to_be_bytes());
+        for (id, props) in &self.entries {
+            if version < 1 {
+                out.extend_from_slice(&(*id as u16).to_be_bytes());
+            } else {
+                out.extend_from_slice(&id.to_be_bytes());
+            }
+            out.push(props.len() as u8);
+            for &(essential, p) in props {
+                if flags & 1 != 0 {
+                    out.extend_from_slice(&(u16::from(essential) << 15 | p).to_be_bytes());
+                } else {
+                    out.push(u8::from(essential) << 7 | p as u8);
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Parsed `iref`
+#[derive(Debug, Clone)]
+pub struct Iref {
+    pub version: u8,
+    /// Type, from ID, to IDs
+    pub refs: Vec<([u8; 4], u32, Vec<u32>)>,
+}
+
+impl Iref {
+    pub fn parse(payload: &[u8]) -> io::Result<Self> {
+        let mut r = Reader::new(payload);
+        let (version, _) = r.full_box()?;
+        let id = |r: &mut Reader<'_>| if version == 0 { r.u16().map(u32::from) } else { r.u32() };
+        let mut refs = Vec::new();
+        for child in parse_boxes(r.rest(), 0)? {
+            let payload = child.payload();
+            let mut r = Reader::new(&payload);
+            let from = id(&mut r)?;
+            let count = r.u16()?;
+            let to = (0..count).map(|_| id(&mut r)).collect::<io::Result<_>>()?;
+            refs.push((child.typ, from, to));
+        }
+        Ok(Self { version, refs })
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let version = if self.refs.iter().any(|(_, from, to)| *from > 0xFFFF || to.iter().any(|&t| t > 0xFFFF)) { 1 } else { self.version };
+        let put_id = |out: &mut Vec<u8>, id: u32| if version == 0 {
+            out.extend_from_slice(&(id as u16).to_be_bytes());
+        } else {
+            out.extend_from_slice(&id.to_be_bytes());
+        };
+        let id_size = if version == 0 { 2 } else { 4 };
+        let mut out = Vec::new();
+        put_full_box(&mut out, version, 0);
+        for (typ, from, to) in &self.refs {
+            out.extend_from_slice(&((8 + 2 + id_size * (1 + to.len())) as u32).to_be_bytes());
+            out.extend_from_slice(typ);
+            put_id(&mut out, *from);
+            out.extend_from_slice(&(to.len() as u16).to_be_bytes());
+            for &id in to {
+                put_id(&mut out, id);
+            }
+        }
+        out
+    }
+}
+
+/// ID and type of an `infe` (version 2 or 3)
+pub fn infe_id_type(payload: &[u8]) -> io::Result<(u32, [u8; 4])> {
+    let mut r = Reader::new(payload);
+    let (version, _) = r.full_box()?;
+    let id = match version {
+        2 => r.u16()?.into(),
+        3 => r.u32()?,
+        _ => return Err(unsupported("infe version")),
+    };
+    r.u16()?; // protection index
+    Ok((id, r.bytes(4)?.try_into().unwrap()))
+}
+
+/// `content_type` of a `mime` item's `infe` (version 2 or 3)
+pub fn infe_content_type(payload: &[u8]) -> io::Result<Option<&[u8]>> {
+    Ok(infe_mime(payload)?.map(|(content_type, _)| content_type))
+}
+
+/// `content_encoding` of a `mime` item's `infe`, e.g. `deflate`, or `None` if the item isn't compressed
+pub fn infe_content_encoding(payload: &[u8]) -> io::Result<Option<&[u8]>> {
+    Ok(infe_mime(payload)?.map(|(_, content_encoding)| content_encoding).filter(|e| !e.is_empty()))
+}
+
+/// `content_type` and `content_encoding` (empty if absent) of a `mime` item
+fn infe_mime(payload: &[u8]) -> io::Result<Option<(&[u8], &[u8])>> {
+    let (_, typ) = infe_id_type(payload)?;
+    if typ != *b"mime" {
+        return Ok(None);
+    }
+    let mut r = Reader::new(payload);
+    let (version, _) = r.full_box()?;
+    r.bytes(if version == 2 { 2 + 2 + 4 } else { 4 + 2 + 4 })?;
+    r.cstr()?; // item name
+    Ok(Some((r.cstr()?, r.cstr()?)))
+}
+
+/// File positions of all samples of a track, from `stsz`, `stsc` and `stco`/`co64`
+pub fn sample_ranges(stbl: &Node<'_>, file_len: u64) -> io::Result<Vec<Range<u64>>> {
+    SampleTable::parse(stbl, file_len)?.sample_ranges()
+}
+
+/// Sample table of a track, with `stsc` expanded to one entry per chunk
+#[derive(Debug, Clone)]
+pub struct SampleTable {
+    /// `stts` entries: sample count and duration
+    pub durations: Vec<(u32, u32)>,
+    pub sizes: Vec<u32>,
+    /// 1-based numbers of sync samples, or `None` if all samples are sync samples
+    pub sync: Option<Vec<u32>>,
+    /// File offset, sample count, and sample description index of every chunk
+    pub chunks: Vec<(u64, u32, u32)>,
+}
+
+impl SampleTable {
+    /// `file_len` bounds the number of samples, since `stsz` can declare billions of them in a few bytes
+    pub fn parse(stbl: &Node<'_>, file_len: u64) -> io::Result<Self> {
+        let durations = match stbl.child(*b"stts") {
+            Some(stts) => {
+                let payload = stts.payload();
+                let mut r = Reader::new(&payload);
+                r.full_box()?;
+                (0..r.u32()?).map(|_| Ok((r.u32()?, r.u32()?))).collect::<io::Result<_>>()?
+            },
+            None => Vec::new(),
+        };
+
+        let stsz = stbl.child(*b"stsz").ok_or_else(|| invalid("missing stsz"))?.payload();
+        let mut r = Reader::new(&stsz);
+        r.full_box()?;
+        let sample_size = r.u32()?;
+        let sample_count = r.u32()?;
+        if sample_size != 0 && u64::from(sample_size) * u64::from(sample_count) > file_len {
+            return Err(invalid("samples larger than the file"));
+        }
+        let sizes = (0..sample_count).map(|_| if sample_size != 0 { Ok(sample_size) } else { r.u32() }).collect::<io::Result<_>>()?;
+
+        let sync = match stbl.child(*b"stss") {
+            Some(stss) => {
+                let payload = stss.payload();
+                let mut r = Reader::new(&payload);
+                r.full_box()?;
+                Some((0..r.u32()?).map(|_| r.u32()).collect::<io::Result<_>>()?)
+            },
+            None => None,
+        };
+
+        let stsc = stbl.child(*b"stsc").ok_or_else(|| invalid("missing stsc"))?.payload();
+        let mut r = Reader::new(&stsc);
+        r.full_box()?;
+        let stsc = (0..r.u32()?).map(|_| Ok((r.u32()?, r.u32()?, r.u32()?))).collect::<io::Result<Vec<_>>>()?;
+
+        let (co, offset_size) = match (stbl.child(*b"stco"), stbl.child(*b"co64")) {
+            (Some(stco), _) => (stco.payload(), 4),
+            (_, Some(co64)) => (co64.payload(), 8),
+            _ => return Err(invalid("missing stco")),
+        };
+        let mut r = Reader::new(&co);
+        r.full_box()?;
+        let chunks = (1..=r.u32()?).map(|chunk| {
+            let (_, samples_per_chunk, description) = stsc.iter().rev().find(|e| e.0 <= chunk).copied().unwrap_or_default();
+            Ok((r.uint(offset_size)?, samples_per_chunk, description))
+        }).collect::<io::Result<_>>()?;
+
+        Ok(Self { durations, sizes, sync, chunks })
+    }
+
+    pub fn sample_ranges(&self) -> io::Result<Vec<Range<u64>>> {
+        let mut ranges = Vec::with_capacity(self.sizes.len());
+        let mut sizes = self.sizes.iter();
+        for &(offset, samples_per_chunk, _) in &self.chunks {
+            let mut pos = offset;
+            for &size in sizes.by_ref().take(samples_per_chunk as usize) {
+                let end = pos.checked_add(u64::from(size)).ok_or_else(|| invalid("bad sample offset"))?;
+                ranges.push(pos..end);
+                pos = end;
+            }
+        }
+        Ok(ranges)
+    }
+
+    /// Replaces the tables in `stbl` (other than `stsd`)
+    pub fn write(&self, stbl: &mut Node<'_>, wide_offsets: bool) -> io::Result<()> {
+        let count = |len: usize| u32::try_from(len).map_err(|_| invalid("too many samples"));
+        stbl.retain_children(|c| ![*b"stts", *b"stss", *b"stsc", *b"stsz", *b"stco", *b"co64"].contains(&c.typ));
+
+        let mut stts = Vec::with_capacity(8 + 8 * self.durations.len());
+        put_full_box(&mut stts, 0, 0);
+        stts.extend_from_slice(&count(self.durations.len())?.to_be_bytes());
+        for &(samples, duration) in &self.durations {
+            stts.extend_from_slice(&samples.to_be_bytes());
+            stts.extend_from_slice(&duration.to_be_bytes());
+        }
+        stbl.push_child(Node::new(*b"stts", stts));
+
+        let mut runs: Vec<(u32, u32, u32)> = Vec::new();
+        for (chunk, &(_, samples_per_chunk, description)) in self.chunks.iter().enumerate() {
+            if runs.last().map(|r| (r.1, r.2)) != Some((samples_per_chunk, description)) {
+                runs.push((count(chunk + 1)?, samples_per_chunk, description));
+            }
+        }
+        let mut stsc = Vec::with_capacity(8 + 12 * runs.len());
+        put_full_box(&mut stsc, 0, 0);
+        stsc.extend_from_slice(&count(runs.len())?.to_be_bytes());
+        for (first_chunk, samples_per_chunk, description) in runs {
+            stsc.extend_from_slice(&first_chunk.to_be_bytes());
+            stsc.extend_from_slice(&samples_per_chunk.to_be_bytes());
+            stsc.extend_from_slice(&description.to_be_bytes());
+        }
+        stbl.push_child(Node::new(*b"stsc", stsc));
+
+        let mut stsz = Vec::with_capacity(12 + 4 * self.sizes.len());
+        put_full_box(&mut stsz, 0, 0);
+        let same_size = self.sizes.first().copied().filter(|&size| self.sizes.iter().all(|&s| s == size));
+        stsz.extend_from_slice(&same_size.unwrap_or(0).to_be_bytes());
+        stsz.extend_from_slice(&count(self.sizes.len())?.to_be_bytes());
+        if same_size.is_none() {
+            self.sizes.iter().for_each(|s| stsz.extend_from_slice(&s.to_be_bytes()));
+        }
+        stbl.push_child(Node::new(*b"stsz", stsz));
+
+        let offset_size = if wide_offsets { 8 } else { 4 };
+        let mut co = Vec::with_capacity(8 + usize::from(offset_size) * self.chunks.len());
+        put_full_box(&mut co, 0, 0);
+        co.extend_from_slice(&count(self.chunks.len())?.to_be_bytes());
+        for &(offset, ..) in &self.chunks {
+            put_uint(&mut co, offset_size, offset)?;
+        }
+        stbl.push_child(Node::new(if wide_offsets { *b"co64" } else { *b"stco" }, co));
+
+        if let Some(sync) = &self.sync {
+            let mut stss = Vec::with_capacity(8 + 4 * sync.len());
+            put_full_box(&mut stss, 0, 0);
+            stss.extend_from_slice(&count(sync.len())?.to_be_bytes());
+            sync.iter().for_each(|s| stss.extend_from_slice(&s.to_be_bytes()));
+            stbl.push_child(Node::new(*b"stss", stss));
+        }
+        Ok(())
+    }
+}
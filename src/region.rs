@@ -0,0 +1,62 @@
+//! Region items (`rgan`), which mark areas of an image, e.g. faces. See [`Composition::add_regions`](crate::Composition::add_regions).
+
+/// Area of an image, in the coordinates of the region item's reference size
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Region {
+    /// A single pixel
+    Point { x: i32, y: i32 },
+    /// Top left corner and size
+    Rectangle { x: i32, y: i32, width: u32, height: u32 },
+    /// Center and radii
+    Ellipse { x: i32, y: i32, radius_x: u32, radius_y: u32 },
+}
+
+impl Region {
+    fn geometry_type(&self) -> u8 {
+        match self {
+            Self::Point { .. } => 0,
+            Self::Rectangle { .. } => 1,
+            Self::Ellipse { .. } => 2,
+        }
+    }
+
+    /// Signed and unsigned fields, widened so they can be written in either size
+    fn fields(&self) -> ([i64; 2], Option<[i64; 2]>) {
+        match *self {
+            Self::Point { x, y } => ([x.into(), y.into()], None),
+            Self::Rectangle { x, y, width, height } => ([x.into(), y.into()], Some([width.into(), height.into()])),
+            Self::Ellipse { x, y, radius_x, radius_y } => ([x.into(), y.into()], Some([radius_x.into(), radius_y.into()])),
+        }
+    }
+}
+
+/// Payload of a region item, or `None` if there are too many regions
+pub(crate) fn region_item(reference_width: u32, reference_height: u32, regions: &[Region]) -> Option<Vec<u8>> {
+    let region_count = u8::try_from(regions.len()).ok()?;
+    let large = reference_width > 0xFFFF || reference_height > 0xFFFF || regions.iter().any(|r| {
+        let (signed, unsigned) = r.fields();
+        signed.iter().any(|&v| i16::try_from(v).is_err()) || unsigned.iter().flatten().any(|&v| u16::try_from(v).is_err())
+    });
+    let mut out = vec![0, u8::from(large)]; // version, flags with field_size
+    push_field(&mut out, reference_width.into(), large);
+    push_field(&mut out, reference_height.into(), large);
+    out.push(region_count);
+    for region in regions {
+        out.push(region.geometry_type());
+        let (signed, unsigned) = region.fields();
+        for value in signed.into_iter().chain(unsigned.into_iter().flatten()) {
+            push_field(&mut out, value, large);
+        }
+    }
+    Some(out)
+}
+
+/// 16 or 32 bits, two's complement for signed fields
+fn push_field(out: &mut Vec<u8>, value: i64, large: bool) {
+    if large {
+        out.extend_from_slice(&(value as u32).to_be_bytes());
+    } else {
+        out.extend_from_slice(&(value as u16).to_be_bytes());
+    }
+}
@@ -0,0 +1,996 @@
+//! Reading and lossless editing of existing AVIF files. AV1 data is copied as-is, without re-encoding.
+
+use crate::constants::AlphaUrn;
+use crate::reader::*;
+use std::io;
+use std::ops::Range;
+
+/// New timing for [`retime`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Retiming {
+    /// Plays `num`/`den` times faster, e.g. 2/1 is double speed, 1/2 is half speed.
+    ///
+    /// Only the timescales change, so it may be rounded slightly.
+    Speed { num: u32, den: u32 },
+    /// Every frame lasts `frame_duration`/`timescale` seconds, e.g. 1/30 for 30 fps.
+    ConstantFrameRate { timescale: u32, frame_duration: u32 },
+}
+
+/// Changes speed or frame durations of an animated AVIF (`avis`), without touching the frames.
+///
+/// Fragmented animations aren't supported.
+pub fn retime(avif: &[u8], timing: Retiming) -> io::Result<Vec<u8>> {
+    let mut file = BoxFile::parse(avif)?;
+    let moov = file.get_mut(*b"moov").ok_or_else(|| invalid("not an animated AVIF"))?;
+    if moov.child(*b"mvex").is_some() {
+        return Err(unsupported("fragmented animation"));
+    }
+    let mvhd = moov.child_mut(*b"mvhd").ok_or_else(|| invalid("missing mvhd"))?;
+    let mut movie = TimeHeader::parse(&mvhd.payload(), false)?;
+    let old_movie_timescale = movie.field;
+
+    match timing {
+        Retiming::Speed { num, den } => {
+            if num == 0 || den == 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "zero speed"));
+            }
+            let scale = |timescale: u32| {
+                u64::from(timescale).checked_mul(u64::from(num)).map(|t| t / u64::from(den))
+                    .filter(|&t| t > 0)
+                    .and_then(|t| u32::try_from(t).ok())
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "timescale out of range"))
+            };
+            movie.field = scale(movie.field)?;
+            for trak in moov.children_mut().iter_mut().filter(|c| c.typ == *b"trak") {
+                let mdhd = trak.descendant_mut(&[*b"mdia", *b"mdhd"]).ok_or_else(|| invalid("missing mdhd"))?;
+                let mut media = TimeHeader::parse(&mdhd.payload(), false)?;
+                media.field = scale(media.field)?;
+                mdhd.set_payload(media.to_vec());
+            }
+        },
+        Retiming::ConstantFrameRate { timescale, frame_duration } => {
+            if timescale == 0 || frame_duration == 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "zero frame rate"));
+            }
+            // Both use the new timescale, so that movie and media durations are the same
+            movie.field = timescale;
+            movie.duration = 0;
+            for trak in moov.children_mut().iter_mut().filter(|c| c.typ == *b"trak") {
+                let duration = retime_track(trak, old_movie_timescale, timescale, frame_duration)?;
+                movie.duration = movie.duration.max(duration);
+            }
+        },
+    }
+    let mvhd = moov.child_mut(*b"mvhd").ok_or_else(|| invalid("missing mvhd"))?;
+    mvhd.set_payload(movie.to_vec());
+    file.into_vec()
+}
+
+/// Returns the new track duration in the movie timescale
+fn retime_track(trak: &mut Node<'_>, old_movie_timescale: u32, timescale: u32, frame_duration: u32) -> io::Result<u64> {
+    let stts = trak.descendant_mut(&[*b"mdia", *b"minf", *b"stbl", *b"stts"]).ok_or_else(|| invalid("missing stts"))?;
+    let old_stts = parse_stts(&stts.payload())?;
+    let sample_count = old_stts.iter().map(|&(count, _)| u64::from(count)).sum::<u64>();
+    let mut new_stts = Vec::new();
+    put_full_box(&mut new_stts, 0, 0);
+    new_stts.extend_from_slice(&1u32.to_be_bytes());
+    new_stts.extend_from_slice(&u32::try_from(sample_count).map_err(|_| invalid("too many samples"))?.to_be_bytes());
+    new_stts.extend_from_slice(&frame_duration.to_be_bytes());
+    stts.set_payload(new_stts);
+
+    let duration = sample_count * u64::from(frame_duration);
+    let mdhd = trak.descendant_mut(&[*b"mdia", *b"mdhd"]).ok_or_else(|| invalid("missing mdhd"))?;
+    let mut media = TimeHeader::parse(&mdhd.payload(), false)?;
+    let old_media_timescale = media.field;
+    media.field = timescale;
+    media.duration = duration;
+    mdhd.set_payload(media.to_vec());
+
+    // Maps time in the old media timescale to the new one, keeping positions within frames.
+    // Times come from the file, so it's computed in u128 to avoid overflow.
+    let map_time = |time: u64| {
+        let (time, frame_duration) = (u128::from(time), u128::from(frame_duration));
+        let mut start = 0;
+        let mut frame = 0;
+        for &(count, delta) in &old_stts {
+            let (count, delta) = (u128::from(count), u128::from(delta));
+            if time < start + count * delta {
+                let frames = (time - start) / delta.max(1);
+                let within = (time - start) % delta.max(1);
+                return to_time((frame + frames) * frame_duration + within * frame_duration / delta.max(1));
+            }
+            start += count * delta;
+            frame += count;
+        }
+        to_time(u128::from(duration) + (time - start))
+    };
+
+    let mut track_duration = duration;
+    if let Some(elst) = trak.descendant_mut(&[*b"edts", *b"elst"]) {
+        let payload = elst.payload();
+        let mut r = Reader::new(&payload);
+        let (version, flags) = r.full_box()?;
+        let count = r.u32()?;
+        let mut out = Vec::with_capacity(payload.len());
+        put_full_box(&mut out, 1, flags);
+        out.extend_from_slice(&count.to_be_bytes());
+        track_duration = 0;
+        for _ in 0..count {
+            let (segment_duration, media_time) = if version == 1 {
+                (r.u64()?, r.u64()? as i64)
+            } else {
+                let d = r.u32()?;
+                (if d == u32::MAX { u64::MAX } else { d.into() }, i64::from(r.u32()? as i32))
+            };
+            let rate = r.bytes(4)?;
+            let (segment_duration, media_time) = if media_time < 0 {
+                // Empty edit
+                (if segment_duration == u64::MAX { u64::MAX } else { rescale(segment_duration, timescale, old_movie_timescale)? }, media_time)
+            } else {
+                let new_start = map_time(media_time as u64)?;
+                let new_duration = if segment_duration == u64::MAX {
+                    u64::MAX
+                } else {
+                    let end = (media_time as u64).checked_add(rescale(segment_duration, old_media_timescale, old_movie_timescale)?)
+                        .ok_or_else(|| invalid("time out of range"))?;
+                    map_time(end)? - new_start
+                };
+                (new_duration, i64::try_from(new_start).map_err(|_| invalid("edit list time out of range"))?)
+            };
+            track_duration = track_duration.saturating_add(segment_duration);
+            out.extend_from_slice(&segment_duration.to_be_bytes());
+            out.extend_from_slice(&media_time.to_be_bytes());
+            out.extend_from_slice(rate);
+        }
+        drop(payload);
+        elst.set_payload(out);
+    }
+
+    let tkhd = trak.child_mut(*b"tkhd").ok_or_else(|| invalid("missing tkhd"))?;
+    let mut header = TimeHeader::parse(&tkhd.payload(), true)?;
+    header.duration = track_duration;
+    tkhd.set_payload(header.to_vec());
+    Ok(track_duration)
+}
+
+/// `value` * `num` / `den`, for times read from the file
+fn rescale(value: u64, num: u32, den: u32) -> io::Result<u64> {
+    to_time(u128::from(value) * u128::from(num) / u128::from(den.max(1)))
+}
+
+fn to_time(time: u128) -> io::Result<u64> {
+    u64::try_from(time).map_err(|_| invalid("time out of range"))
+}
+
+fn parse_stts(payload: &[u8]) -> io::Result<Vec<(u32, u32)>> {
+    let mut r = Reader::new(payload);
+    r.full_box()?;
+    let count = r.u32()?;
+    (0..count).map(|_| Ok((r.u32()?, r.u32()?))).collect()
+}
+
+/// Common beginning of `mvhd`, `tkhd` and `mdhd`: times, timescale (or `tkhd`'s track ID and a reserved field), and duration
+struct TimeHeader {
+    version: u8,
+    flags: u32,
+    creation_time: u64,
+    modification_time: u64,
+    field: u32,
+    reserved: Option<u32>,
+    /// All ones (indefinite) is `u64::MAX` regardless of version
+    duration: u64,
+    rest: Vec<u8>,
+}
+
+impl TimeHeader {
+    fn parse(payload: &[u8], is_tkhd: bool) -> io::Result<Self> {
+        let mut r = Reader::new(payload);
+        let (version, flags) = r.full_box()?;
+        let time = |r: &mut Reader<'_>| if version == 1 { r.u64() } else { r.u32().map(u64::from) };
+        let creation_time = time(&mut r)?;
+        let modification_time = time(&mut r)?;
+        let field = r.u32()?;
+        let reserved = if is_tkhd { Some(r.u32()?) } else { None };
+        let duration = match time(&mut r)? {
+            d if version != 1 && d == u32::MAX.into() => u64::MAX,
+            d => d,
+        };
+        Ok(Self { version, flags, creation_time, modification_time, field, reserved, duration, rest: r.rest().to_vec() })
+    }
+
+    /// Upgrades to version 1 if needed
+    fn to_vec(&self) -> Vec<u8> {
+        let fits = [self.creation_time, self.modification_time].iter().all(|&t| t <= u32::MAX.into())
+            && (self.duration < u32::MAX.into() || self.duration == u64::MAX);
+        let version = if self.version == 1 || !fits { 1 } else { 0 };
+        let mut out = Vec::with_capacity(36 + self.rest.len());
+        put_full_box(&mut out, version, self.flags);
+        let put_time = |out: &mut Vec<u8>, t: u64| if version == 1 {
+            out.extend_from_slice(&t.to_be_bytes());
+        } else {
+            out.extend_from_slice(&(t.min(u32::MAX.into()) as u32).to_be_bytes());
+        };
+        put_time(&mut out, self.creation_time);
+        put_time(&mut out, self.modification_time);
+        out.extend_from_slice(&self.field.to_be_bytes());
+        if let Some(reserved) = self.reserved {
+            out.extend_from_slice(&reserved.to_be_bytes());
+        }
+        put_time(&mut out, self.duration);
+        out.extend_from_slice(&self.rest);
+        out
+    }
+}
+
+/// Removes transparency: the alpha image item and the alpha track, with their properties, references and data.
+///
+/// Files without alpha are only re-serialized.
+pub fn strip_alpha(avif: &[u8]) -> io::Result<Vec<u8>> {
+    let mut file = BoxFile::parse(avif)?;
+    let mut removed_data = Vec::new();
+    if let Some(meta) = file.get_mut(*b"meta") {
+        let alpha_ids = alpha_item_ids(meta)?;
+        removed_data.extend(remove_items(meta, &alpha_ids)?);
+    }
+    if let Some(moov) = file.get_mut(*b"moov") {
+        removed_data.extend(remove_alpha_tracks(moov, avif.len() as u64)?);
+    }
+    file.remove_data(&removed_data);
+    file.into_vec()
+}
+
+fn is_alpha_urn(urn: &[u8]) -> bool {
+    [AlphaUrn::Cicp, AlphaUrn::Hevc].iter().any(|u| u.as_str().as_bytes() == urn)
+}
+
+/// Items that have an alpha `auxC` property
+fn alpha_item_ids(meta: &Node<'_>) -> io::Result<Vec<u32>> {
+    let Some(iprp) = meta.child(*b"iprp") else { return Ok(Vec::new()) };
+    let (Some(ipco), Some(ipma)) = (iprp.child(*b"ipco"), iprp.child(*b"ipma")) else { return Ok(Vec::new()) };
+    let mut ids = Vec::new();
+    for (id, props) in Ipma::parse(&ipma.payload())?.entries {
+        for (_, index) in props {
+            // indices are 1-based
+            let Some(prop) = ipco.children().get(usize::from(index).wrapping_sub(1)) else { continue };
+            if prop.typ == *b"auxC" {
+                let payload = prop.payload();
+                let mut r = Reader::new(&payload);
+                r.full_box()?;
+                if is_alpha_urn(r.cstr()?) {
+                    ids.push(id);
+                }
+            }
+        }
+    }
+    Ok(ids)
+}
+
+/// Removes items and everything that refers to them. Returns file ranges of their data.
+fn remove_items(meta: &mut Node<'_>, ids: &[u32]) -> io::Result<Vec<Range<u64>>> {
+    let mut removed_data = Vec::new();
+    if ids.is_empty() {
+        return Ok(removed_data);
+    }
+    if let Some(iloc) = meta.child_mut(*b"iloc") {
+        let mut parsed = Iloc::parse(&iloc.payload())?;
+        for item in parsed.items.iter().filter(|item| ids.contains(&item.id)) {
+            if item.construction_method == 0 && item.data_reference_index == 0 {
+                for ex in &item.extents {
+                    let start = item.base_offset.checked_add(ex.offset).ok_or_else(|| invalid("bad item offset"))?;
+                    removed_data.push(start..start.checked_add(ex.len).ok_or_else(|| invalid("bad item length"))?);
+                }
+            }
+        }
+        parsed.items.retain(|item| !ids.contains(&item.id));
+        iloc.set_payload(parsed.to_vec()?);
+    }
+    if let Some(iinf) = meta.child_mut(*b"iinf") {
+        iinf.retain_children(|infe| infe_id_type(&infe.payload()).map_or(true, |(id, _)| !ids.contains(&id)));
+        let version = iinf.header().first().copied().unwrap_or(0);
+        let count = iinf.children().len();
+        let mut header = Vec::new();
+        put_full_box(&mut header, version, 0);
+        put_uint(&mut header, if version == 0 { 2 } else { 4 }, count as u64)?;
+        iinf.set_header(header);
+    }
+    if let Some(iref_box) = meta.child_mut(*b"iref") {
+        let mut iref = Iref::parse(&iref_box.payload())?;
+        for (_, _, to) in &mut iref.refs {
+            to.retain(|id| !ids.contains(id));
+        }
+        iref.refs.retain(|(_, from, to)| !ids.contains(from) && !to.is_empty());
+        if iref.refs.is_empty() {
+            meta.retain_children(|c| c.typ != *b"iref");
+        } else {
+            iref_box.set_payload(iref.to_vec());
+        }
+    }
+    if let Some(iprp) = meta.child_mut(*b"iprp") {
+        if let Some(ipma_box) = iprp.child(*b"ipma") {
+            let mut ipma = Ipma::parse(&ipma_box.payload())?;
+            let mut candidates = Vec::new();
+            ipma.entries.retain(|(id, props)| {
+                if !ids.contains(id) {
+                    return true;
+                }
+                candidates.extend(props.iter().map(|&(_, index)| index));
+                false
+            });
+            set_ipma(iprp, ipma, candidates);
+        }
+    }
+    if let Some(grpl) = meta.child_mut(*b"grpl") {
+        for group in grpl.children_mut() {
+            let payload = group.payload();
+            let mut r = Reader::new(&payload);
+            let (version, flags) = r.full_box()?;
+            let group_id = r.u32()?;
+            let entities = (0..r.u32()?).map(|_| r.u32()).collect::<io::Result<Vec<_>>>()?;
+            let entities: Vec<_> = entities.into_iter().filter(|id| !ids.contains(id)).collect();
+            let mut out = Vec::with_capacity(payload.len());
+            put_full_box(&mut out, version, flags);
+            out.extend_from_slice(&group_id.to_be_bytes());
+            out.extend_from_slice(&(entities.len() as u32).to_be_bytes());
+            for id in entities {
+                out.extend_from_slice(&id.to_be_bytes());
+            }
+            drop(payload);
+            group.set_payload(out);
+        }
+    }
+    Ok(removed_data)
+}
+
+/// Writes the new `ipma`, and removes properties from `candidates` that no item uses anymore
+fn set_ipma(iprp: &mut Node<'_>, mut ipma: Ipma, mut candidates: Vec<u16>) {
+    candidates.retain(|&index| index > 0 && !ipma.entries.iter().flat_map(|(_, props)| props).any(|&(_, i)| i == index));
+    candidates.sort_unstable();
+    candidates.dedup();
+    for (_, props) in &mut ipma.entries {
+        for (_, index) in props.iter_mut() {
+            *index -= candidates.iter().filter(|&&u| u < *index).count() as u16;
+        }
+    }
+    if let Some(ipma_box) = iprp.child_mut(*b"ipma") {
+        ipma_box.set_payload(ipma.to_vec());
+    }
+    if let Some(ipco) = iprp.child_mut(*b"ipco") {
+        let mut index = 0;
+        ipco.retain_children(|_| {
+            index += 1;
+            !candidates.contains(&index)
+        });
+    }
+}
+
+/// Tracks with an `auxv` handler that are `auxl` of another track
+fn remove_alpha_tracks(moov: &mut Node<'_>, file_len: u64) -> io::Result<Vec<Range<u64>>> {
+    let mut removed_ids = Vec::new();
+    let mut removed_data = Vec::new();
+    for trak in moov.children().iter().filter(|t| t.typ == *b"trak") {
+        let is_auxv = trak.child(*b"mdia").and_then(|m| m.child(*b"hdlr")).is_some_and(|h| h.payload().get(8..12) == Some(b"auxv"));
+        let is_auxl = trak.child(*b"tref").map(|t| track_refs(&t.payload())).transpose()?
+            .is_some_and(|refs| refs.iter().any(|(typ, _)| typ == b"auxl"));
+        if !is_auxv || !is_auxl {
+            continue;
+        }
+        let tkhd = trak.child(*b"tkhd").ok_or_else(|| invalid("missing tkhd"))?;
+        removed_ids.push(TimeHeader::parse(&tkhd.payload(), true)?.field);
+        let stbl = trak.child(*b"mdia").and_then(|m| m.child(*b"minf")).and_then(|m| m.child(*b"stbl")).ok_or_else(|| invalid("missing stbl"))?;
+        removed_data.extend(sample_ranges(stbl, file_len)?);
+    }
+    if removed_ids.is_empty() {
+        return Ok(removed_data);
+    }
+    if moov.child(*b"mvex").is_some() {
+        return Err(unsupported("fragmented animation"));
+    }
+    moov.retain_children(|c| c.typ != *b"trak" || !c.child(*b"tkhd").and_then(|t| TimeHeader::parse(&t.payload(), true).ok()).is_some_and(|t| removed_ids.contains(&t.field)));
+
+    for trak in moov.children_mut().iter_mut().filter(|t| t.typ == *b"trak") {
+        let Some(tref) = trak.child_mut(*b"tref") else { continue };
+        let mut refs = track_refs(&tref.payload())?;
+        for (_, to) in &mut refs {
+            to.retain(|id| !removed_ids.contains(id));
+        }
+        refs.retain(|(_, to)| !to.is_empty());
+        if refs.is_empty() {
+            trak.retain_children(|c| c.typ != *b"tref");
+            continue;
+        }
+        let mut out = Vec::new();
+        for (typ, to) in refs {
+            out.extend_from_slice(&(8 + 4 * to.len() as u32).to_be_bytes());
+            out.extend_from_slice(&typ);
+            for id in to {
+                out.extend_from_slice(&id.to_be_bytes());
+            }
+        }
+        tref.set_payload(out);
+    }
+    Ok(removed_data)
+}
+
+/// Contents of `tref`
+fn track_refs(payload: &[u8]) -> io::Result<Vec<([u8; 4], Vec<u32>)>> {
+    parse_boxes(payload, 0)?.into_iter().map(|b| {
+        let payload = b.payload();
+        let mut r = Reader::new(&payload);
+        let ids = (0..payload.len() / 4).map(|_| r.u32()).collect::<io::Result<_>>()?;
+        Ok((b.typ, ids))
+    }).collect()
+}
+
+/// Rectangle within the image, in pixels. See [`crop`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Crop {
+    pub left: u32,
+    pub top: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Crops the image without re-encoding, by setting the `clap` (clean aperture) property
+/// of the primary image and its alpha. `None` removes the crop.
+///
+/// The crop is applied before rotation and mirroring. For subsampled images
+/// MIAF requires `left` and `top` to be aligned to the chroma subsampling.
+pub fn crop(avif: &[u8], crop: Option<Crop>) -> io::Result<Vec<u8>> {
+    let mut file = BoxFile::parse(avif)?;
+    let meta = file.get_mut(*b"meta").ok_or_else(|| invalid("missing meta"))?;
+    let primary = primary_item_id(meta)?;
+    let clap = match crop {
+        Some(c) => {
+            let ispe = item_property(meta, primary, *b"ispe")?.ok_or_else(|| invalid("missing ispe"))?;
+            let mut r = Reader::new(&ispe);
+            r.full_box()?;
+            let (width, height) = (r.u32()?, r.u32()?);
+            let fits = |start: u32, len: u32, max: u32| len > 0 && start.checked_add(len).is_some_and(|end| end <= max);
+            if !fits(c.left, c.width, width) || !fits(c.top, c.height, height) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "crop is outside of the image"));
+            }
+            // Offsets are of the center, relative to the center of the image
+            let offset = |start: u32, len: u32, max: u32| i32::try_from(2 * i64::from(start) + i64::from(len) - i64::from(max));
+            let fractions = [
+                (c.width, 1),
+                (c.height, 1),
+                (offset(c.left, c.width, width).map_err(|_| invalid("image too large"))? as u32, 2),
+                (offset(c.top, c.height, height).map_err(|_| invalid("image too large"))? as u32, 2),
+            ];
+            Some(fractions.iter().flat_map(|&(n, d): &(u32, u32)| n.to_be_bytes().into_iter().chain(d.to_be_bytes())).collect())
+        },
+        None => None,
+    };
+    let mut ids = vec![primary];
+    ids.extend(alpha_items_of(meta, primary)?);
+    set_item_property(meta, &ids, *b"clap", clap)?;
+    file.into_vec()
+}
+
+fn primary_item_id(meta: &Node<'_>) -> io::Result<u32> {
+    let pitm = meta.child(*b"pitm").ok_or_else(|| invalid("missing pitm"))?.payload();
+    let mut r = Reader::new(&pitm);
+    let (version, _) = r.full_box()?;
+    if version == 0 { r.u16().map(u32::from) } else { r.u32() }
+}
+
+/// Alpha items that are `auxl` of the given item
+fn alpha_items_of(meta: &Node<'_>, id: u32) -> io::Result<Vec<u32>> {
+    let Some(iref) = meta.child(*b"iref") else { return Ok(Vec::new()) };
+    let iref = Iref::parse(&iref.payload())?;
+    let mut alpha = alpha_item_ids(meta)?;
+    alpha.retain(|a| iref.refs.iter().any(|(typ, from, to)| typ == b"auxl" && from == a && to.contains(&id)));
+    Ok(alpha)
+}
+
+/// Payload of the item's property of the given type
+fn item_property(meta: &Node<'_>, id: u32, typ: [u8; 4]) -> io::Result<Option<Vec<u8>>> {
+    let Some(iprp) = meta.child(*b"iprp") else { return Ok(None) };
+    let (Some(ipco), Some(ipma)) = (iprp.child(*b"ipco"), iprp.child(*b"ipma")) else { return Ok(None) };
+    let ipma = Ipma::parse(&ipma.payload())?;
+    Ok(ipma.entries.iter().filter(|(item, _)| *item == id).flat_map(|(_, props)| props)
+        .filter_map(|&(_, index)| ipco.children().get(usize::from(index).wrapping_sub(1)))
+        .find(|prop| prop.typ == typ)
+        .map(|prop| prop.payload().into_owned()))
+}
+
+/// Transformative properties must be last, in this order
+const TRANSFORMS: [[u8; 4]; 3] = [*b"clap", *b"irot", *b"imir"];
+
+/// Replaces or removes (if `payload` is `None`) a property of the given type of the items
+fn set_item_property(meta: &mut Node<'_>, ids: &[u32], typ: [u8; 4], payload: Option<Vec<u8>>) -> io::Result<()> {
+    let iprp = meta.child_mut(*b"iprp").ok_or_else(|| invalid("missing iprp"))?;
+    let ipco = iprp.child_mut(*b"ipco").ok_or_else(|| invalid("missing ipco"))?;
+    let new_index = payload.map(|payload| {
+        // Reuses an identical property if there is one
+        match ipco.children().iter().position(|p| p.typ == typ && *p.payload() == payload[..]) {
+            Some(i) => i + 1,
+            None => {
+                ipco.push_child(Node::new(typ, payload));
+                ipco.children().len()
+            },
+        }
+    }).map(|index| u16::try_from(index).map_err(|_| invalid("too many properties"))).transpose()?;
+    let prop_types: Vec<_> = ipco.children().iter().map(|p| p.typ).collect();
+    let transform_order = |index: u16| prop_types.get(usize::from(index).wrapping_sub(1)).and_then(|t| TRANSFORMS.iter().position(|x| x == t));
+
+    let ipma = iprp.child(*b"ipma").ok_or_else(|| invalid("missing ipma"))?;
+    let mut ipma = Ipma::parse(&ipma.payload())?;
+    for &id in ids {
+        if !ipma.entries.iter().any(|(item, _)| *item == id) {
+            ipma.entries.push((id, Vec::new()));
+        }
+    }
+    let mut candidates = Vec::new();
+    for (_, props) in ipma.entries.iter_mut().filter(|(id, _)| ids.contains(id)) {
+        props.retain(|&(_, index)| {
+            let same_type = prop_types.get(usize::from(index).wrapping_sub(1)) == Some(&typ);
+            if same_type {
+                candidates.push(index);
+            }
+            !same_type
+        });
+        if let Some(new_index) = new_index {
+            let pos = match transform_order(new_index) {
+                Some(order) => props.iter().position(|&(_, i)| transform_order(i).is_some_and(|o| o > order)).unwrap_or(props.len()),
+                None => props.iter().position(|&(_, i)| transform_order(i).is_some()).unwrap_or(props.len()),
+            };
+            props.insert(pos, (true, new_index));
+        }
+    }
+    set_ipma(iprp, ipma, candidates);
+    Ok(())
+}
+
+/// Mirroring for [`set_orientation`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Mirror {
+    /// Swaps left and right (around the vertical axis)
+    LeftRight = 0,
+    /// Swaps top and bottom (around the horizontal axis)
+    TopBottom = 1,
+}
+
+/// Sets rotation and mirroring (`irot` and `imir`) of the primary image and its alpha, without re-encoding.
+///
+/// `quarter_turns` rotates anti-clockwise in 90° steps. Rotation is applied before mirroring.
+/// This replaces any previous orientation; see [`rotate`] for a relative change.
+pub fn set_orientation(avif: &[u8], quarter_turns: u8, mirror: Option<Mirror>) -> io::Result<Vec<u8>> {
+    let mut file = BoxFile::parse(avif)?;
+    let meta = file.get_mut(*b"meta").ok_or_else(|| invalid("missing meta"))?;
+    orient(meta, quarter_turns, mirror)?;
+    file.into_vec()
+}
+
+/// Rotates the image, as displayed, by `quarter_turns` × 90° anti-clockwise.
+/// Existing rotation and mirroring are taken into account.
+pub fn rotate(avif: &[u8], quarter_turns: u8) -> io::Result<Vec<u8>> {
+    let mut file = BoxFile::parse(avif)?;
+    let meta = file.get_mut(*b"meta").ok_or_else(|| invalid("missing meta"))?;
+    let primary = primary_item_id(meta)?;
+    let angle = item_property(meta, primary, *b"irot")?.and_then(|p| p.first().copied()).unwrap_or(0) & 3;
+    let mirror = item_property(meta, primary, *b"imir")?.and_then(|p| p.first().copied())
+        .map(|axis| if axis & 1 == 0 { Mirror::LeftRight } else { Mirror::TopBottom });
+    // Mirroring reverses the direction of the rotation that happens before it
+    let angle = match mirror {
+        Some(_) => angle + 4 - quarter_turns % 4,
+        None => angle + quarter_turns % 4,
+    };
+    orient(meta, angle, mirror)?;
+    file.into_vec()
+}
+
+fn orient(meta: &mut Node<'_>, quarter_turns: u8, mirror: Option<Mirror>) -> io::Result<()> {
+    let primary = primary_item_id(meta)?;
+    let mut ids = vec![primary];
+    ids.extend(alpha_items_of(meta, primary)?);
+    let irot = Some(vec![quarter_turns % 4]).filter(|_| quarter_turns % 4 != 0);
+    set_item_property(meta, &ids, *b"irot", irot)?;
+    set_item_property(meta, &ids, *b"imir", mirror.map(|m| vec![m as u8]))
+}
+
+/// Brands that matter to AVIF decoders
+const AVIF_BRANDS: [[u8; 4]; 11] = [*b"avif", *b"avis", *b"avio", *b"mif1", *b"mif2", *b"msf1", *b"miaf", *b"MA1A", *b"MA1B", *b"iso8", *b"tmap"];
+
+/// Rewrites headers of an AVIF file from any encoder more compactly, without changing the images.
+///
+/// Removes irrelevant brands, duplicate and unused properties, and `free` boxes, uses the smallest `iloc` field sizes,
+/// and puts `meta` and `moov` before the data.
+pub fn optimize(avif: &[u8]) -> io::Result<Vec<u8>> {
+    let mut file = BoxFile::parse(avif)?;
+    // The output can only get smaller
+    let small_offsets = u32::try_from(avif.len()).is_ok();
+
+    file.boxes.retain(|b| b.typ != *b"free" && b.typ != *b"skip");
+    file.boxes.sort_by_key(|b| match &b.typ {
+        b"ftyp" => 0,
+        b"meta" => 1,
+        b"moov" => 2,
+        _ => 3,
+    });
+    if let Some(ftyp) = file.get_mut(*b"ftyp") {
+        let payload = ftyp.payload();
+        let mut r = Reader::new(&payload);
+        let major: [u8; 4] = r.bytes(4)?.try_into().unwrap();
+        r.u32()?;
+        let mut brands = vec![major];
+        while let Ok(brand) = r.bytes(4) {
+            let brand = brand.try_into().unwrap();
+            if AVIF_BRANDS.contains(&brand) && !brands.contains(&brand) {
+                brands.push(brand);
+            }
+        }
+        let mut out = Vec::with_capacity(8 + 4 * brands.len());
+        out.extend_from_slice(&major);
+        out.extend_from_slice(&0u32.to_be_bytes());
+        brands.iter().for_each(|b| out.extend_from_slice(b));
+        drop(payload);
+        ftyp.set_payload(out);
+    }
+    if let Some(meta) = file.get_mut(*b"meta") {
+        dedup_properties(meta)?;
+        if let Some(iloc_box) = meta.child_mut(*b"iloc") {
+            let mut iloc = Iloc::parse(&iloc_box.payload())?;
+            for item in &mut iloc.items {
+                for ex in &mut item.extents {
+                    ex.offset = ex.offset.checked_add(item.base_offset).ok_or_else(|| invalid("bad item offset"))?;
+                }
+                item.base_offset = 0;
+            }
+            let extents = || iloc.items.iter().flat_map(|i| &i.extents);
+            let size = |fits: bool| if fits { 4 } else { 8 };
+            iloc.base_offset_size = 0;
+            iloc.offset_size = size(small_offsets && extents().all(|ex| ex.offset <= u32::MAX.into()));
+            iloc.length_size = size(extents().all(|ex| ex.len <= u32::MAX.into()));
+            iloc.index_size = if extents().all(|ex| ex.index == 0) { 0 } else { size(extents().all(|ex| ex.index <= u32::MAX.into())) };
+            iloc.version = if iloc.items.len() > 0xFFFF || iloc.items.iter().any(|i| i.id > 0xFFFF) {
+                2
+            } else if iloc.index_size > 0 || iloc.items.iter().any(|i| i.construction_method != 0) {
+                1
+            } else {
+                0
+            };
+            iloc_box.set_payload(iloc.to_vec()?);
+        }
+        if let Some(iinf) = meta.child_mut(*b"iinf") {
+            let count = iinf.children().len();
+            let version = if count > 0xFFFF { 1 } else { 0 };
+            let mut header = Vec::new();
+            put_full_box(&mut header, version, 0);
+            put_uint(&mut header, if version == 0 { 2 } else { 4 }, count as u64)?;
+            iinf.set_header(header);
+        }
+    }
+    if let (Some(moov), true) = (file.get_mut(*b"moov"), small_offsets) {
+        for trak in moov.children_mut().iter_mut().filter(|t| t.typ == *b"trak") {
+            let Some(co64) = trak.descendant_mut(&[*b"mdia", *b"minf", *b"stbl", *b"co64"]) else { continue };
+            let payload = co64.payload();
+            let mut r = Reader::new(&payload);
+            r.full_box()?;
+            let count = r.u32()?;
+            let mut out = Vec::with_capacity(8 + 4 * count as usize);
+            put_full_box(&mut out, 0, 0);
+            out.extend_from_slice(&count.to_be_bytes());
+            for _ in 0..count {
+                put_uint(&mut out, 4, r.u64()?)?;
+            }
+            drop(payload);
+            co64.set_payload(out);
+            co64.typ = *b"stco";
+        }
+    }
+    file.into_vec()
+}
+
+/// Merges identical properties, and removes unused ones
+fn dedup_properties(meta: &mut Node<'_>) -> io::Result<()> {
+    let Some(iprp) = meta.child_mut(*b"iprp") else { return Ok(()) };
+    let (Some(ipco), Some(ipma_box)) = (iprp.child(*b"ipco"), iprp.child(*b"ipma")) else { return Ok(()) };
+    let props = ipco.children();
+    // 1-based index of the first identical property
+    let first_of_kind: Vec<u16> = (0..props.len()).map(|i| {
+        let first = props.iter().position(|p| p.typ == props[i].typ && p.payload() == props[i].payload()).unwrap_or(i);
+        first as u16 + 1
+    }).collect();
+    let mut ipma = Ipma::parse(&ipma_box.payload())?;
+    ipma.version = 0;
+    ipma.flags = 0;
+    for (_, assoc) in &mut ipma.entries {
+        let mut deduped: Vec<(bool, u16)> = Vec::with_capacity(assoc.len());
+        for &(essential, index) in assoc.iter() {
+            let index = first_of_kind.get(usize::from(index).wrapping_sub(1)).copied().unwrap_or(index);
+            match deduped.iter_mut().find(|(_, i)| *i == index) {
+                Some(existing) => existing.0 |= essential,
+                None => deduped.push((essential, index)),
+            }
+        }
+        *assoc = deduped;
+    }
+    let all = (1..=props.len() as u16).collect();
+    set_ipma(iprp, ipma, all);
+    Ok(())
+}
+
+/// Joins animations (`avis`) with identical coding parameters into one longer animation, playing them one after another.
+///
+/// Tracks are matched by their order, and must have the same sample descriptions and timescales.
+/// The still image and other metadata are taken from the first file. Edit lists are only kept
+/// for dwelling on the last frame of the last animation (see [`Aviffy::hold_last_frame`](crate::Aviffy::hold_last_frame)).
+///
+/// Fragmented animations aren't supported.
+pub fn concat(animations: &[&[u8]]) -> io::Result<Vec<u8>> {
+    let (&first, rest) = animations.split_first().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no animations to join"))?;
+    let wide_offsets = animations.iter().map(|a| a.len() as u64).sum::<u64>() > u32::MAX.into();
+
+    let mut file = BoxFile::parse(first)?;
+    let moov = file.get_mut(*b"moov").ok_or_else(|| invalid("not an animated AVIF"))?;
+    let (movie_timescale, mut tracks) = joinable_tracks(moov, 0, first.len() as u64)?;
+
+    // Other files' positions follow the first one's, so that chunk offsets stay distinct until relocated
+    let mut file_pos = first.len() as u64;
+    let mut mdats = Vec::new();
+    for &data in rest {
+        let boxes = parse_boxes(data, file_pos)?;
+        let moov = boxes.iter().find(|b| b.typ == *b"moov").ok_or_else(|| invalid("not an animated AVIF"))?;
+        let (other_movie_timescale, other_tracks) = joinable_tracks(moov, file_pos, data.len() as u64)?;
+        file_pos += data.len() as u64;
+        if other_movie_timescale != movie_timescale || other_tracks.len() != tracks.len() {
+            return Err(unsupported("animations have different tracks or timescales"));
+        }
+        for (track, other) in tracks.iter_mut().zip(other_tracks) {
+            if other.sample_description != track.sample_description || other.timescale != track.timescale {
+                return Err(unsupported("animations have different coding parameters"));
+            }
+            track.append(other)?;
+        }
+        mdats.extend(boxes.into_iter().filter(|b| b.typ == *b"mdat"));
+    }
+
+    let moov = file.get_mut(*b"moov").ok_or_else(|| invalid("not an animated AVIF"))?;
+    let mut movie_duration = 0;
+    for (trak, track) in moov.children_mut().iter_mut().filter(|c| c.typ == *b"trak").zip(tracks) {
+        let stbl = trak.descendant_mut(&[*b"mdia", *b"minf", *b"stbl"]).ok_or_else(|| invalid("missing stbl"))?;
+        track.samples.write(stbl, wide_offsets)?;
+
+        let media_duration = media_duration(&track.samples.durations)?;
+        let mdhd = trak.descendant_mut(&[*b"mdia", *b"mdhd"]).ok_or_else(|| invalid("missing mdhd"))?;
+        let mut media = TimeHeader::parse(&mdhd.payload(), false)?;
+        media.duration = media_duration;
+        mdhd.set_payload(media.to_vec());
+
+        let played_duration = rescale(media_duration, movie_timescale, track.timescale)?;
+        let track_duration = match track.dwell_time {
+            Some(media_time) => {
+                let mut elst = Vec::with_capacity(8 + 2 * 20);
+                put_full_box(&mut elst, 1, 0);
+                elst.extend_from_slice(&2u32.to_be_bytes());
+                for (segment_duration, media_time, rate) in [(played_duration, 0, 1u16), (u64::MAX, media_time, 0)] {
+                    elst.extend_from_slice(&segment_duration.to_be_bytes());
+                    elst.extend_from_slice(&media_time.to_be_bytes());
+                    elst.extend_from_slice(&rate.to_be_bytes());
+                    elst.extend_from_slice(&0u16.to_be_bytes());
+                }
+                trak.retain_children(|c| c.typ != *b"edts");
+                let index = trak.children().iter().position(|c| c.typ != *b"tkhd" && c.typ != *b"tref").unwrap_or(trak.children().len());
+                trak.insert_child(index, Node::new_container(*b"edts", vec![Node::new(*b"elst", elst)]));
+                u64::MAX
+            },
+            None => {
+                trak.retain_children(|c| c.typ != *b"edts");
+                played_duration
+            },
+        };
+        let tkhd = trak.child_mut(*b"tkhd").ok_or_else(|| invalid("missing tkhd"))?;
+        let mut header = TimeHeader::parse(&tkhd.payload(), true)?;
+        header.duration = track_duration;
+        tkhd.set_payload(header.to_vec());
+        movie_duration = movie_duration.max(track_duration);
+    }
+    let mvhd = moov.child_mut(*b"mvhd").ok_or_else(|| invalid("missing mvhd"))?;
+    let mut movie = TimeHeader::parse(&mvhd.payload(), false)?;
+    movie.duration = movie_duration;
+    mvhd.set_payload(movie.to_vec());
+
+    file.boxes.extend(mdats);
+    file.into_vec()
+}
+
+/// Sum of `stts` entries
+fn media_duration(durations: &[(u32, u32)]) -> io::Result<u64> {
+    durations.iter().try_fold(0u64, |sum, &(count, delta)| sum.checked_add(u64::from(count) * u64::from(delta)))
+        .ok_or_else(|| invalid("duration out of range"))
+}
+
+struct JoinableTrack {
+    sample_description: Vec<u8>,
+    timescale: u32,
+    samples: SampleTable,
+    /// Media time of the frame shown indefinitely after the end
+    dwell_time: Option<i64>,
+}
+
+impl JoinableTrack {
+    fn append(&mut self, other: Self) -> io::Result<()> {
+        let too_many = || invalid("too many samples");
+        let media_duration = i64::try_from(media_duration(&self.samples.durations)?).map_err(|_| invalid("duration out of range"))?;
+        let sample_count = u32::try_from(self.samples.sizes.len()).map_err(|_| too_many())?;
+        let other_count = u32::try_from(other.samples.sizes.len()).ok().filter(|&n| n.checked_add(sample_count).is_some()).ok_or_else(too_many)?;
+        let shift = |sync: Vec<u32>| sync.into_iter().map(|s| s.checked_add(sample_count).ok_or_else(too_many)).collect::<io::Result<Vec<_>>>();
+        match (&mut self.samples.sync, other.samples.sync) {
+            (Some(sync), Some(other_sync)) => sync.extend(shift(other_sync)?),
+            (Some(sync), None) => sync.extend((1..=other_count).map(|s| s + sample_count)),
+            (None, Some(other_sync)) => {
+                self.samples.sync = Some((1..=sample_count).chain(shift(other_sync)?).collect());
+            },
+            (None, None) => {},
+        }
+        self.samples.durations.extend(other.samples.durations);
+        self.samples.sizes.extend(other.samples.sizes);
+        self.samples.chunks.extend(other.samples.chunks);
+        self.dwell_time = other.dwell_time.map(|t| t.checked_add(media_duration).ok_or_else(|| invalid("duration out of range"))).transpose()?;
+        Ok(())
+    }
+}
+
+/// Movie timescale, and sample tables of all tracks. Chunk offsets are moved by `file_pos`.
+fn joinable_tracks(moov: &Node<'_>, file_pos: u64, file_len: u64) -> io::Result<(u32, Vec<JoinableTrack>)> {
+    if moov.child(*b"mvex").is_some() {
+        return Err(unsupported("fragmented animation"));
+    }
+    let mvhd = moov.child(*b"mvhd").ok_or_else(|| invalid("missing mvhd"))?;
+    let movie_timescale = TimeHeader::parse(&mvhd.payload(), false)?.field;
+    let tracks = moov.children().iter().filter(|c| c.typ == *b"trak").map(|trak| {
+        let mdia = trak.child(*b"mdia").ok_or_else(|| invalid("missing mdia"))?;
+        let mdhd = mdia.child(*b"mdhd").ok_or_else(|| invalid("missing mdhd"))?;
+        let stbl = mdia.child(*b"minf").and_then(|m| m.child(*b"stbl")).ok_or_else(|| invalid("missing stbl"))?;
+        if stbl.children().iter().any(|c| ![*b"stsd", *b"stts", *b"stss", *b"stsc", *b"stsz", *b"stco", *b"co64"].contains(&c.typ)) {
+            return Err(unsupported("sample table has unsupported boxes"));
+        }
+        let mut samples = SampleTable::parse(stbl, file_len)?;
+        for chunk in &mut samples.chunks {
+            chunk.0 = chunk.0.checked_add(file_pos).ok_or_else(|| invalid("bad chunk offset"))?;
+        }
+        Ok(JoinableTrack {
+            sample_description: stbl.child(*b"stsd").ok_or_else(|| invalid("missing stsd"))?.to_vec(),
+            timescale: TimeHeader::parse(&mdhd.payload(), false)?.field,
+            samples,
+            dwell_time: trak.child(*b"edts").and_then(|e| e.child(*b"elst")).map(|elst| dwell_time(&elst.payload())).transpose()?.flatten(),
+        })
+    }).collect::<io::Result<_>>()?;
+    Ok((movie_timescale, tracks))
+}
+
+/// Accepts edit lists that play the whole track, optionally followed by dwelling on a frame
+fn dwell_time(elst: &[u8]) -> io::Result<Option<i64>> {
+    let mut r = Reader::new(elst);
+    let (version, _) = r.full_box()?;
+    let entries = (0..r.u32()?).map(|_| {
+        let media_time = if version == 1 { r.u64()?; r.u64()? as i64 } else { r.u32()?; i64::from(r.u32()? as i32) };
+        Ok((media_time, r.u32()?))
+    }).collect::<io::Result<Vec<_>>>()?;
+    match entries[..] {
+        [] | [(0, 0x0001_0000)] => Ok(None),
+        [(0, 0x0001_0000), (media_time, 0)] if media_time >= 0 => Ok(Some(media_time)),
+        _ => Err(unsupported("edit list")),
+    }
+}
+
+/// Metadata embedded in an AVIF file. See [`metadata`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Metadata {
+    /// Exif data starting at the TIFF header, without the offset that precedes it in AVIF.
+    ///
+    /// This is the same format as `exif_data` of [`Aviffy::to_vec`](crate::Aviffy::to_vec).
+    pub exif: Option<Vec<u8>>,
+    /// XMP packet (XML)
+    pub xmp: Option<Vec<u8>>,
+    /// IPTC-IIM records
+    pub iptc: Option<Vec<u8>>,
+    /// C2PA manifest store (JUMBF)
+    pub c2pa: Option<Vec<u8>>,
+    /// ICC profile of the primary image
+    pub icc: Option<Vec<u8>>,
+}
+
+/// Reads Exif, XMP, IPTC, C2PA manifest, and the ICC profile of an AVIF file, without looking at the image data.
+///
+/// If there are several Exif, XMP or IPTC items, the ones describing the primary image are preferred.
+/// Items with `deflate` content encoding are decompressed, which requires the `deflate` feature.
+pub fn metadata(avif: &[u8]) -> io::Result<Metadata> {
+    let file = BoxFile::parse(avif)?;
+    let Some(meta) = file.boxes.iter().find(|b| b.typ == *b"meta") else { return Ok(Metadata::default()) };
+    let primary = primary_item_id(meta)?;
+    let describes_primary = match meta.child(*b"iref") {
+        Some(iref) => Iref::parse(&iref.payload())?.refs.into_iter()
+            .filter(|(typ, _, to)| typ == b"cdsc" && to.contains(&primary))
+            .map(|(_, from, _)| from).collect(),
+        None => Vec::new(),
+    };
+    let mut exif_items = Vec::new();
+    let mut xmp_items = Vec::new();
+    let mut iptc_items = Vec::new();
+    let mut c2pa_items = Vec::new();
+    let mut encodings = Vec::new();
+    for infe in meta.child(*b"iinf").map(|i| i.children()).unwrap_or_default().iter().filter(|c| c.typ == *b"infe") {
+        let payload = infe.payload();
+        let (id, typ) = infe_id_type(&payload)?;
+        if typ == *b"Exif" {
+            exif_items.push(id);
+        } else {
+            if let Some(content_encoding) = infe_content_encoding(&payload)? {
+                encodings.push((id, content_encoding.to_vec()));
+            }
+            match infe_content_type(&payload)? {
+                Some(b"application/rdf+xml") => xmp_items.push(id),
+                Some(b"application/x-iptc") => iptc_items.push(id),
+                Some(b"application/c2pa") => c2pa_items.push(id),
+                _ => {},
+            }
+        }
+    }
+    let preferred = |ids: &[u32]| ids.iter().find(|id| describes_primary.contains(id)).or(ids.first()).copied();
+
+    let exif = preferred(&exif_items).map(|id| {
+        let data = item_data(avif, meta, id)?;
+        let tiff_header_offset = data.get(..4).map(|o| u32::from_be_bytes(o.try_into().unwrap())).ok_or_else(|| invalid("truncated Exif"))?;
+        data.get(4 + tiff_header_offset as usize..).map(|tiff| tiff.to_vec()).ok_or_else(|| invalid("truncated Exif"))
+    }).transpose()?;
+    let mime_data = |id| {
+        let data = item_data(avif, meta, id)?;
+        match encodings.iter().find(|(i, _)| *i == id).map(|(_, e)| &e[..]) {
+            None => Ok(data),
+            Some(b"deflate") => inflate(&data),
+            Some(_) => Err(unsupported("metadata content encoding")),
+        }
+    };
+    let xmp = preferred(&xmp_items).map(mime_data).transpose()?;
+    let iptc = preferred(&iptc_items).map(mime_data).transpose()?;
+    let c2pa = preferred(&c2pa_items).map(mime_data).transpose()?;
+
+    let mut icc = None;
+    if let Some(iprp) = meta.child(*b"iprp") {
+        if let (Some(ipco), Some(ipma)) = (iprp.child(*b"ipco"), iprp.child(*b"ipma")) {
+            icc = Ipma::parse(&ipma.payload())?.entries.iter().filter(|(item, _)| *item == primary).flat_map(|(_, props)| props)
+                .filter_map(|&(_, index)| ipco.children().get(usize::from(index).wrapping_sub(1)))
+                .filter(|prop| prop.typ == *b"colr")
+                .find_map(|prop| {
+                    let payload = prop.payload();
+                    matches!(payload.get(..4), Some(b"prof" | b"rICC")).then(|| payload[4..].to_vec())
+                });
+        }
+    }
+    Ok(Metadata { exif, xmp, iptc, c2pa, icc })
+}
+
+/// Decompresses `deflate` content encoding (zlib format, as in HTTP)
+#[cfg(feature = "deflate")]
+fn inflate(data: &[u8]) -> io::Result<Vec<u8>> {
+    miniz_oxide::inflate::decompress_to_vec_zlib(data).map_err(|_| invalid("bad deflate data"))
+}
+
+#[cfg(not(feature = "deflate"))]
+fn inflate(_: &[u8]) -> io::Result<Vec<u8>> {
+    Err(unsupported("deflate content encoding (enable the deflate feature)"))
+}
+
+/// All extents of an item, joined
+fn item_data(avif: &[u8], meta: &Node<'_>, id: u32) -> io::Result<Vec<u8>> {
+    let iloc = Iloc::parse(&meta.child(*b"iloc").ok_or_else(|| invalid("missing iloc"))?.payload())?;
+    let item = iloc.items.iter().find(|i| i.id == id).ok_or_else(|| invalid("item has no location"))?;
+    let source = match item.construction_method {
+        0 if item.data_reference_index == 0 => avif.into(),
+        1 => meta.child(*b"idat").ok_or_else(|| invalid("missing idat"))?.payload(),
+        _ => return Err(unsupported("item construction method")),
+    };
+    let mut data = Vec::new();
+    for ex in &item.extents {
+        let start = item.base_offset.checked_add(ex.offset).ok_or_else(|| invalid("bad item offset"))?;
+        // Zero length means till the end
+        let end = if ex.len == 0 { source.len() as u64 } else { start.saturating_add(ex.len) };
+        let extent = usize::try_from(start).ok().zip(usize::try_from(end).ok()).and_then(|(start, end)| source.get(start..end));
+        data.extend_from_slice(extent.ok_or_else(|| invalid("item data out of bounds"))?);
+    }
+    Ok(data)
+}
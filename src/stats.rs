@@ -0,0 +1,80 @@
+use crate::{Aviffy, ImageData};
+use std::io;
+
+/// Summary of a written animation, for logging. See [`Aviffy::write_with_stats`].
+///
+/// Frame statistics are of the color track. They're all zero for still images.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+#[non_exhaustive]
+pub struct MuxStats {
+    pub frame_count: usize,
+    /// Frame sizes in bytes
+    pub min_frame_size: usize,
+    pub avg_frame_size: f64,
+    pub max_frame_size: usize,
+    /// Number of sync frames
+    pub keyframe_count: usize,
+    /// Largest number of frames from a keyframe to the next one (or to the end)
+    pub max_keyframe_interval: usize,
+    /// Duration of the color track in seconds
+    pub duration: f64,
+    /// Size of the whole file in bytes
+    pub file_size: u64,
+    /// Bits per second of the whole file
+    pub avg_bitrate: f64,
+}
+
+struct CountingWriter<'a> {
+    inner: &'a mut dyn io::Write,
+    written: u64,
+}
+
+impl io::Write for CountingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Aviffy {
+    /// Same as [`write`](Self::write), but also returns statistics of the frames and the file,
+    /// so they don't need to be computed from the output.
+    pub fn write_with_stats(&self, into_output: &mut dyn io::Write, image: ImageData<'_>) -> io::Result<MuxStats> {
+        let mut out = CountingWriter { inner: into_output, written: 0 };
+        self.write_dyn(&mut out, image.color_av1_data, image.alpha_av1_data, image.width, image.height, image.depth_bits,
+            image.timescale, image.color_frames, image.alpha_frames, image.exif_data)?;
+        let timescale = image.timescale;
+
+        let mut stats = MuxStats { file_size: out.written, ..MuxStats::default() };
+        let frames = image.color_frames.unwrap_or_default();
+        if frames.is_empty() {
+            return Ok(stats);
+        }
+        stats.frame_count = frames.len();
+        stats.min_frame_size = frames.iter().map(|f| f.size).min().unwrap_or(0);
+        stats.max_frame_size = frames.iter().map(|f| f.size).max().unwrap_or(0);
+        stats.avg_frame_size = frames.iter().map(|f| f.size as f64).sum::<f64>() / frames.len() as f64;
+        stats.keyframe_count = frames.iter().filter(|f| f.sync).count();
+        let mut run = 0;
+        for frame in frames {
+            if frame.sync && run > 0 {
+                stats.max_keyframe_interval = stats.max_keyframe_interval.max(run);
+                run = 0;
+            }
+            run += 1;
+        }
+        stats.max_keyframe_interval = stats.max_keyframe_interval.max(run);
+        if timescale > 0 {
+            stats.duration = frames.iter().map(|f| f.duration_in_timescales as f64).sum::<f64>() / f64::from(timescale);
+        }
+        if stats.duration > 0. {
+            stats.avg_bitrate = stats.file_size as f64 * 8. / stats.duration;
+        }
+        Ok(stats)
+    }
+}
@@ -0,0 +1,117 @@
+//! Fast path for the most common kind of file: a still image without alpha or metadata.
+//!
+//! It writes the same bytes as the general serializer, but without building the box tree, so it doesn't allocate.
+
+use crate::boxes::*;
+use crate::writer::Writer;
+use crate::{Aviffy, FrameInfo};
+use arrayvec::{ArrayVec, CapacityError};
+use std::borrow::Cow;
+use std::io;
+
+/// The header is about 300 bytes
+const MAX_HEADER_LEN: usize = 512;
+
+impl Aviffy {
+    /// Only files made of the color image and the boxes that always describe it qualify.
+    /// See [`Aviffy::is_default_for_still`] for the settings.
+    pub(crate) fn is_simple_still(&self, alpha_av1_data: Option<&[u8]>, color_frames: Option<&[FrameInfo]>, exif_data: &[u8]) -> bool {
+        alpha_av1_data.is_none() && color_frames.is_none() && exif_data.is_empty() && self.is_default_for_still()
+    }
+
+    pub(crate) fn write_simple_still(&self, into_output: &mut dyn io::Write, color_av1_data: &[u8], width: u32, height: u32, depth_bits: u8) -> io::Result<()> {
+        let depth_bits = self.bit_depth.unwrap_or(depth_bits);
+        let color_config = match self.auto_av1c(color_av1_data, self.color_av1c, depth_bits)? {
+            Some(av1c) => av1c,
+            None => self.color_config(depth_bits, self.level(width, height, None)),
+        };
+        let hdlr = HdlrBox { handler_type: FourCC(*b"pict"), name: "avifser" };
+        let pitm = PitmBox(1);
+        let infe = InfeBox { id: 1, typ: FourCC(*b"av01"), name: Cow::Borrowed("Color"), content_type: None, content_encoding: None };
+        let ispe = IspeBox { width, height };
+        let pixi = PixiBox { channels: if color_config.monochrome { 1 } else { 3 }, depth: depth_bits };
+        let colr = if self.colr != Default::default() { Some(self.colr) } else { None };
+        let brands = [*b"avif", *b"mif1", *b"miaf", *b"MA1A"];
+
+        let ftyp_len = BASIC_BOX_SIZE + 4 + 4 + 4 * brands.len();
+        let iloc_len = FULL_BOX_SIZE + 4 + 14;
+        let iinf_len = FULL_BOX_SIZE + 2 + infe.len();
+        let ipco_len = BASIC_BOX_SIZE + ispe.len() + pixi.len() + color_config.len() + colr.map_or(0, |c| c.len());
+        let ipma_len = FULL_BOX_SIZE + 4 + 2 + 1 + 3 + usize::from(colr.is_some());
+        let iprp_len = BASIC_BOX_SIZE + ipco_len + ipma_len;
+        let meta_len = FULL_BOX_SIZE + hdlr.len() + pitm.len() + iloc_len + iinf_len + iprp_len;
+        let too_large = || io::Error::new(io::ErrorKind::InvalidInput, "file too large for 32-bit offsets");
+        let color_offset = u32::try_from(ftyp_len + meta_len + BASIC_BOX_SIZE).map_err(|_| too_large())?;
+        let color_len = u32::try_from(color_av1_data.len()).map_err(|_| too_large())?;
+        let mdat_len = u32::try_from(BASIC_BOX_SIZE + color_av1_data.len()).map_err(|_| too_large())?;
+
+        let mut header = ArrayVec::<u8, MAX_HEADER_LEN>::new();
+        let mut w = Writer::new(&mut header);
+        let written = (|| -> Result<(), CapacityError> {
+            let mut b = w.new_box(ftyp_len);
+            b.basic_box(*b"ftyp")?;
+            b.push(b"avif")?;
+            b.u32(0)?; // minor version
+            for brand in &brands {
+                b.push(brand)?;
+            }
+            drop(b);
+
+            let mut meta = w.new_box(meta_len);
+            meta.full_box(*b"meta", 0, 0)?;
+            hdlr.write(&mut meta)?;
+            pitm.write(&mut meta)?;
+
+            let mut b = meta.new_box(iloc_len);
+            b.full_box(*b"iloc", 0, 0)?;
+            b.push(&[4 << 4 | 4, 0])?; // offset and length are 4 bytes
+            b.u16(1)?; // num items
+            b.u16(1)?; // id
+            b.u16(0)?;
+            b.u16(1)?; // num extents
+            b.u32(color_offset)?;
+            b.u32(color_len)?;
+            drop(b);
+
+            let mut b = meta.new_box(iinf_len);
+            b.full_box(*b"iinf", 0, 0)?;
+            b.u16(1)?;
+            infe.write(&mut b)?;
+            drop(b);
+
+            let mut iprp = meta.new_box(iprp_len);
+            iprp.basic_box(*b"iprp")?;
+            let mut b = iprp.new_box(ipco_len);
+            b.basic_box(*b"ipco")?;
+            ispe.write(&mut b)?;
+            pixi.write(&mut b)?;
+            color_config.write(&mut b)?;
+            if let Some(colr) = &colr {
+                colr.write(&mut b)?;
+            }
+            drop(b);
+            let mut b = iprp.new_box(ipma_len);
+            b.full_box(*b"ipma", 0, 0)?;
+            b.u32(1)?; // entry count
+            b.u16(1)?; // item id
+            b.u8(3 + u8::from(colr.is_some()))?;
+            b.push(&[1, 2, 3 | 0x80])?; // ispe, pixi, essential av1C
+            if colr.is_some() {
+                b.u8(4)?;
+            }
+            Ok(())
+        })();
+        drop(w);
+        // Can't happen, all the boxes have a fixed size
+        written.map_err(|_| io::Error::other("header too large"))?;
+
+        let (ftyp, meta) = header.split_at(ftyp_len);
+        into_output.write_all(ftyp).map_err(|e| BoxWriteError::wrap(e, "ftyp".into()))?;
+        into_output.write_all(meta).map_err(|e| BoxWriteError::wrap(e, "meta".into()))?;
+        (|| {
+            into_output.write_all(&mdat_len.to_be_bytes())?;
+            into_output.write_all(b"mdat")?;
+            into_output.write_all(color_av1_data)
+        })().map_err(|e| BoxWriteError::wrap(e, "mdat".into()))
+    }
+}
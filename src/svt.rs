@@ -0,0 +1,69 @@
+//! Adapter for output of the [SVT-AV1](https://gitlab.com/AOMediaCodec/SVT-AV1) encoder.
+//!
+//! SVT-AV1 timestamps (`pts`) count in a timebase that is the inverse of the frame rate,
+//! and frame durations are implied by the next frame's timestamp.
+
+use crate::bitstream::IvfReader;
+use crate::obu;
+use crate::FrameInfo;
+use std::io;
+
+/// Frames collected from SVT-AV1, ready to be passed to [`Aviffy::write`](crate::Aviffy::write)
+/// as `color_av1_data`, `color_frames`, `timescale`, etc.
+pub struct SvtFrames {
+    /// All frames, concatenated
+    pub data: Vec<u8>,
+    pub frames: Vec<FrameInfo>,
+    pub timescale: u32,
+    pub width: u32,
+    pub height: u32,
+    timebase_num: u32,
+    last_pts: Option<u64>,
+}
+
+impl SvtFrames {
+    /// `pts` of packets are in units of `timebase_num`/`timebase_den` seconds.
+    /// In SVT-AV1's config that's `frame_rate_denominator`/`frame_rate_numerator`.
+    #[must_use]
+    pub fn new(width: u32, height: u32, timebase_num: u32, timebase_den: u32) -> Self {
+        Self {
+            data: Vec::new(),
+            frames: Vec::new(),
+            timescale: timebase_den,
+            width,
+            height,
+            timebase_num: timebase_num.max(1),
+            last_pts: None,
+        }
+    }
+
+    /// Add a packet from `svt_av1_enc_get_packet` (`p_buffer` up to `n_filled_len`, and `pts`).
+    ///
+    /// Keyframes are detected from the AV1 data, since `pic_type` isn't reliable for `show_existing_frame` packets.
+    /// The last frame lasts one `pts` tick, until another packet is pushed.
+    pub fn push_packet(&mut self, data: &[u8], pts: u64) -> io::Result<()> {
+        let sync = obu::is_keyframe(data)?;
+        if let (Some(last_pts), Some(last)) = (self.last_pts, self.frames.last_mut()) {
+            last.duration_in_timescales = pts.saturating_sub(last_pts).max(1).checked_mul(u64::from(self.timebase_num))
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "frame duration overflows"))?;
+        }
+        self.data.extend_from_slice(data);
+        self.frames.push(FrameInfo {
+            duration_in_timescales: self.timebase_num.into(),
+            sync,
+            size: data.len(),
+        });
+        self.last_pts = Some(pts);
+        Ok(())
+    }
+
+    /// Reads an IVF file, as written by `SvtAv1EncApp -b out.ivf`
+    pub fn from_ivf(ivf: &[u8]) -> io::Result<Self> {
+        let mut reader = IvfReader::new(ivf)?;
+        let mut frames = Self::new(reader.width, reader.height, reader.timebase_num, reader.timebase_den);
+        while let Some((data, pts)) = reader.next_packet()? {
+            frames.push_packet(&data, pts)?;
+        }
+        Ok(frames)
+    }
+}
@@ -0,0 +1,181 @@
+use crate::boxes::*;
+use crate::compose::NO_ITEMS;
+use crate::verify::sequence_header_av1c;
+use crate::{monochrome_av1c, obu, Aviffy, Mismatch};
+use std::io;
+use std::ptr;
+
+/// Pre-serialized header of still images that have the same settings and dimensions, and differ only in their payloads.
+///
+/// Writing with a template only patches `iloc` offsets and `mdat` sizes, instead of building all the boxes again.
+/// Create it with [`Aviffy::header_template`].
+#[derive(Debug, Clone)]
+pub struct HeaderTemplate {
+    has_alpha: bool,
+    has_exif: bool,
+    /// Everything before the first `mdat`
+    header: Vec<u8>,
+    /// Contents of all `mdat` boxes, in order
+    payloads: Vec<Payload>,
+    /// Number of payloads in each `mdat`
+    mdat_payload_counts: Vec<usize>,
+    /// Position of each `iloc` extent in the header, and index of its payload
+    iloc_extents: Vec<(usize, usize)>,
+    /// `av1C` of the color and alpha images that their sequence headers must match,
+    /// if writing would make it from them (see [`Aviffy::av1c_from_sequence_header`])
+    color_av1c: Option<Av1CBox>,
+    alpha_av1c: Option<Av1CBox>,
+}
+
+#[derive(Debug, Clone)]
+enum Payload {
+    Color,
+    Alpha,
+    Exif,
+    /// Renditions, which are part of the settings
+    Fixed(Vec<u8>),
+}
+
+impl Payload {
+    fn data<'a>(&'a self, color_av1_data: &'a [u8], alpha_av1_data: &'a [u8], exif_data: &'a [u8]) -> &'a [u8] {
+        match self {
+            Self::Color => color_av1_data,
+            Self::Alpha => alpha_av1_data,
+            Self::Exif => exif_data,
+            Self::Fixed(data) => data,
+        }
+    }
+}
+
+impl Aviffy {
+    /// Serializes the header once, for writing many still images with the same settings, dimensions and depth.
+    ///
+    /// `has_alpha` and `has_exif` must match the images written with the template.
+    /// Exif set with [`exif`](Self::exif) isn't used; pass it to [`HeaderTemplate::write`] instead.
+    ///
+    /// The header can't depend on the payloads, so this fails with `InvalidInput` if [`sequence_header_in_av1c`](Self::sequence_header_in_av1c),
+    /// [`verify_size`](Self::verify_size), [`verify_alpha`](Self::verify_alpha) or [`layer_sizes`](Self::layer_sizes) are set.
+    /// `av1C` is made from the settings, so use [`av1c_from_sequence_header`](Self::av1c_from_sequence_header) for AV1 data that differs from them.
+    pub fn header_template(&self, has_alpha: bool, width: u32, height: u32, depth_bits: u8, has_exif: bool) -> io::Result<HeaderTemplate> {
+        if self.sequence_header_in_av1c || self.verify_size || self.verify_alpha || !self.layer_sizes.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "settings that depend on the AV1 data can't be used with a template"));
+        }
+        // Payloads are told apart by their address
+        let probe = [0u8; 2];
+        let (color, alpha) = (&probe[..1], &probe[1..]);
+        let exif = if has_exif { &probe[..1] } else { &[][..] };
+        let mut file = self.make_boxes(color, has_alpha.then_some(alpha), width, height, depth_bits, 1, None, None, exif, &NO_ITEMS)?;
+
+        let mut payloads = Vec::new();
+        let mut mdat_payload_counts = Vec::new();
+        let mut relative_starts = Vec::new();
+        let mut relative_pos = 0;
+        for mdat in &file.mdat {
+            for chunk in &mdat.data_chunks {
+                relative_starts.push((relative_pos, chunk.len()));
+                relative_pos += chunk.len();
+                payloads.push(if ptr::eq(&**chunk, color) {
+                    Payload::Color
+                } else if ptr::eq(&**chunk, alpha) {
+                    Payload::Alpha
+                } else {
+                    Payload::Fixed(chunk.to_vec())
+                });
+            }
+            if let Some(exif) = &mdat.exif {
+                relative_starts.push((relative_pos, exif.len()));
+                relative_pos += exif.len();
+                payloads.push(Payload::Exif);
+            }
+            mdat_payload_counts.push(mdat.data_chunks.len() + usize::from(mdat.exif.is_some()));
+        }
+
+        let iloc_extents = iloc_extent_fields(&file, &relative_starts);
+
+        let mut header = Vec::new();
+        file.write(&mut header)?;
+        header.truncate(header.len() - file.mdat.iter().map(|m| m.len()).sum::<usize>());
+
+        // The probes have no sequence headers, so the header has `av1C` from the settings
+        let depth_bits = self.bit_depth.unwrap_or(depth_bits);
+        let level = self.level(width, height, None);
+        let auto_av1c = self.layout_version >= 3;
+        let color_av1c = (auto_av1c && self.color_av1c.is_none()).then(|| self.color_config(depth_bits, level));
+        let alpha_av1c = (auto_av1c && has_alpha && self.alpha_av1c.is_none()).then(|| monochrome_av1c(depth_bits, level));
+
+        Ok(HeaderTemplate { has_alpha, has_exif, header, payloads, mdat_payload_counts, iloc_extents, color_av1c, alpha_av1c })
+    }
+}
+
+/// Position of each `iloc` extent's offset and length in the serialized header, and the index of its payload,
+/// found by the payload's position in the `mdat`s' contents and its length.
+pub(crate) fn iloc_extent_fields(file: &AvifFile<'_>, relative_starts: &[(usize, usize)]) -> Vec<(usize, usize)> {
+    let iloc_start = file.ftyp.len() + file.meta.iloc_position();
+    file.meta.iloc.extent_positions().into_iter().filter_map(|(pos, extent)| {
+        let index = relative_starts.iter().position(|&(start, len)| extent.offset == IlocOffset::Relative(start) && extent.len == len)?;
+        Some((iloc_start + pos, index))
+    }).collect()
+}
+
+impl HeaderTemplate {
+    /// Same as [`Aviffy::write`] with the template's settings. Fails if the presence of alpha or Exif doesn't match the template,
+    /// if the file would be too large for 32-bit offsets, or if the `av1C` made from a sequence header would differ from the template's.
+    pub fn write<W: io::Write>(&self, mut into_output: W, color_av1_data: &[u8], alpha_av1_data: Option<&[u8]>, exif_data: &[u8]) -> io::Result<()> {
+        if self.has_alpha != alpha_av1_data.is_some() || self.has_exif == exif_data.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "alpha or Exif doesn't match the template"));
+        }
+        for (setting, data) in [(self.color_av1c, Some(color_av1_data)), (self.alpha_av1c, alpha_av1_data)] {
+            if let (Some(setting), Some(Ok(seq))) = (setting, data.map(obu::sequence_header)) {
+                let av1 = sequence_header_av1c(&seq);
+                if setting != av1 {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, Mismatch::Av1C { setting, av1 }.to_string()));
+                }
+            }
+        }
+        let alpha_av1_data = alpha_av1_data.unwrap_or_default();
+        let payload_len = |payload: &Payload| match payload {
+            Payload::Exif => 4 + exif_data.len(), // header offset
+            _ => payload.data(color_av1_data, alpha_av1_data, exif_data).len(),
+        };
+        let too_large = || io::Error::new(io::ErrorKind::InvalidInput, "file too large for the template");
+        let payload_start = |index: usize| {
+            let mut start = self.header.len();
+            let mut payloads = self.payloads.iter().enumerate();
+            for &count in &self.mdat_payload_counts {
+                start += BASIC_BOX_SIZE;
+                for (n, payload) in payloads.by_ref().take(count) {
+                    if n == index {
+                        return start;
+                    }
+                    start += payload_len(payload);
+                }
+            }
+            start
+        };
+
+        let mut written = 0;
+        for &(field, index) in &self.iloc_extents {
+            let offset = u32::try_from(payload_start(index)).map_err(|_| too_large())?;
+            let len = u32::try_from(self.payloads.get(index).map_or(0, payload_len)).map_err(|_| too_large())?;
+            into_output.write_all(self.header.get(written..field).unwrap_or_default())?;
+            into_output.write_all(&offset.to_be_bytes())?;
+            into_output.write_all(&len.to_be_bytes())?;
+            written = field + 8;
+        }
+        into_output.write_all(self.header.get(written..).unwrap_or_default())?;
+
+        let mut payloads = self.payloads.iter();
+        for &count in &self.mdat_payload_counts {
+            let mdat_len = BASIC_BOX_SIZE + payloads.clone().take(count).map(payload_len).sum::<usize>();
+            into_output.write_all(&u32::try_from(mdat_len).map_err(|_| too_large())?.to_be_bytes())?;
+            into_output.write_all(b"mdat")?;
+            for payload in payloads.by_ref().take(count) {
+                if matches!(payload, Payload::Exif) {
+                    into_output.write_all(&[0; 4])?;
+                }
+                into_output.write_all(payload.data(color_av1_data, alpha_av1_data, exif_data))?;
+            }
+        }
+        Ok(())
+    }
+}
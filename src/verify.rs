@@ -0,0 +1,258 @@
+//! Checks that the settings agree with what's in the AV1 data
+
+use crate::constants::{ChromaSamplePosition, ChromaSubsampling};
+use crate::obu;
+use crate::{Av1CBox, Aviffy, FrameInfo};
+use std::fmt;
+use std::io;
+
+/// Disagreement between the container and the AV1 bitstream.
+///
+/// Decoders differ in which one they trust, so images with mismatches may look different in every browser.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Mismatch {
+    /// CICP values of the `colr` box and the sequence header's color config
+    ColorPrimaries { colr: u16, av1: u8 },
+    TransferCharacteristics { colr: u16, av1: u8 },
+    MatrixCoefficients { colr: u16, av1: u8 },
+    FullColorRange { colr: bool, av1: bool },
+    /// Image size given to the serializer and the color sequence header's maximum frame size
+    ImageSize { width: u32, height: u32, av1_width: u32, av1_height: u32 },
+    /// The alpha image has chroma planes
+    AlphaNotMonochrome,
+    /// Image size given to the serializer and the alpha sequence header's maximum frame size
+    AlphaSize { width: u32, height: u32, av1_width: u32, av1_height: u32 },
+    /// [`bit_depth`](Aviffy::bit_depth) setting and the color sequence header's bit depth
+    BitDepth { setting: u8, av1: u8 },
+    /// [`color_av1c`](Aviffy::color_av1c) setting and the one made from the color sequence header
+    Av1C { setting: Av1CBox, av1: Av1CBox },
+    /// [`FrameInfo::sync`] of the frame at the index, and whether its AV1 data starts with a key frame
+    Sync { frame: usize, sync: bool, key_frame: bool },
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::ColorPrimaries { colr, av1 } => write!(f, "colr has color primaries {colr}, but AV1 has {av1}"),
+            Self::TransferCharacteristics { colr, av1 } => write!(f, "colr has transfer characteristics {colr}, but AV1 has {av1}"),
+            Self::MatrixCoefficients { colr, av1 } => write!(f, "colr has matrix coefficients {colr}, but AV1 has {av1}"),
+            Self::FullColorRange { colr, av1 } => write!(f, "colr has full range {colr}, but AV1 has {av1}"),
+            Self::ImageSize { width, height, av1_width, av1_height } => {
+                write!(f, "image is {width}x{height}, but AV1 data is {av1_width}x{av1_height}")?;
+                if (width, height) == (av1_height, av1_width) {
+                    f.write_str(" (width and height swapped?)")?;
+                }
+                Ok(())
+            },
+            Self::AlphaNotMonochrome => f.write_str("alpha AV1 data is not monochrome"),
+            Self::AlphaSize { width, height, av1_width, av1_height } => write!(f, "image is {width}x{height}, but alpha AV1 data is {av1_width}x{av1_height}"),
+            Self::BitDepth { setting, av1 } => write!(f, "bit depth is {setting}, but AV1 data is {av1}-bit"),
+            Self::Av1C { setting, av1 } => write!(f, "av1C is {setting:?}, but AV1 data has {av1:?}"),
+            Self::Sync { frame, sync, key_frame } => {
+                write!(f, "frame {frame} is {}a sync sample, but AV1 data is {}a key frame", if sync { "" } else { "not " }, if key_frame { "" } else { "not " })
+            },
+        }
+    }
+}
+
+impl Aviffy {
+    /// Compares the `colr` settings with the color config in the sequence header of `color_av1_data`.
+    ///
+    /// Returns an empty list if they agree, or if no CICP `colr` box would be written
+    /// (e.g. because of an [ICC profile](Self::icc_profile) without [`nclx_with_icc`](Self::nclx_with_icc)). Fails if the AV1 data has no sequence header.
+    pub fn check_colr(&self, color_av1_data: &[u8]) -> io::Result<Vec<Mismatch>> {
+        let mut mismatches = Vec::new();
+        let writes_nclx = match self.icc_profile {
+            Some(_) => self.nclx_with_icc,
+            None => self.colr != Default::default(),
+        };
+        if !writes_nclx {
+            return Ok(mismatches);
+        }
+        let seq = obu::sequence_header(color_av1_data)?;
+        let colr = self.colr;
+        if colr.color_primaries as u16 != u16::from(seq.color_primaries) {
+            mismatches.push(Mismatch::ColorPrimaries { colr: colr.color_primaries as u16, av1: seq.color_primaries });
+        }
+        if colr.transfer_characteristics as u16 != u16::from(seq.transfer_characteristics) {
+            mismatches.push(Mismatch::TransferCharacteristics { colr: colr.transfer_characteristics as u16, av1: seq.transfer_characteristics });
+        }
+        if colr.matrix_coefficients as u16 != u16::from(seq.matrix_coefficients) {
+            mismatches.push(Mismatch::MatrixCoefficients { colr: colr.matrix_coefficients as u16, av1: seq.matrix_coefficients });
+        }
+        if colr.full_range_flag != seq.full_color_range {
+            mismatches.push(Mismatch::FullColorRange { colr: colr.full_range_flag, av1: seq.full_color_range });
+        }
+        Ok(mismatches)
+    }
+
+    /// Checks that the sequence header of `alpha_av1_data` is of a monochrome image of the given size.
+    ///
+    /// Returns an empty list if it is. Fails if the AV1 data has no sequence header.
+    /// [`verify_alpha`](Self::verify_alpha) makes writing do this check.
+    pub fn check_alpha(&self, alpha_av1_data: &[u8], width: u32, height: u32) -> io::Result<Vec<Mismatch>> {
+        let mut mismatches = Vec::new();
+        let seq = obu::sequence_header(alpha_av1_data)?;
+        if !seq.monochrome {
+            mismatches.push(Mismatch::AlphaNotMonochrome);
+        }
+        if (seq.max_frame_width, seq.max_frame_height) != (width, height) {
+            mismatches.push(Mismatch::AlphaSize { width, height, av1_width: seq.max_frame_width, av1_height: seq.max_frame_height });
+        }
+        Ok(mismatches)
+    }
+
+    /// Checks that `width`/`height` (written in `ispe`) are the maximum frame size in the sequence header of `color_av1_data`.
+    ///
+    /// Returns an empty list if they are. Fails if the AV1 data has no sequence header.
+    /// [`verify_size`](Self::verify_size) makes writing do this check.
+    pub fn check_size(&self, color_av1_data: &[u8], width: u32, height: u32) -> io::Result<Vec<Mismatch>> {
+        let mut mismatches = Vec::new();
+        let seq = obu::sequence_header(color_av1_data)?;
+        if (seq.max_frame_width, seq.max_frame_height) != (width, height) {
+            mismatches.push(Mismatch::ImageSize { width, height, av1_width: seq.max_frame_width, av1_height: seq.max_frame_height });
+        }
+        Ok(mismatches)
+    }
+
+    /// Checks that the frames marked as [`sync`](FrameInfo::sync) are the ones that start with a key frame in `av1_data`.
+    /// A wrong `stss` breaks seeking.
+    ///
+    /// Returns an empty list if they are. Fails if the frame sizes add up to more than the data.
+    /// [`verify_sync`](Self::verify_sync) makes writing do this check. See also [`detect_key_frames`](crate::bitstream::detect_key_frames).
+    pub fn check_sync(&self, av1_data: &[u8], frames: &[FrameInfo]) -> io::Result<Vec<Mismatch>> {
+        let mut mismatches = Vec::new();
+        let mut rest = av1_data;
+        for (index, frame) in frames.iter().enumerate() {
+            let (data, next) = rest.split_at_checked(frame.size).ok_or_else(frames_too_large)?;
+            let key_frame = obu::is_keyframe(data)?;
+            if frame.sync != key_frame {
+                mismatches.push(Mismatch::Sync { frame: index, sync: frame.sync, key_frame });
+            }
+            rest = next;
+        }
+        Ok(mismatches)
+    }
+
+    /// Checks the frames of the color and alpha tracks if [`verify_sync`](Self::verify_sync) is enabled
+    pub(crate) fn verify_frames(&self, color_av1_data: &[u8], color_frames: Option<&[FrameInfo]>, alpha_av1_data: Option<&[u8]>, alpha_frames: Option<&[FrameInfo]>) -> io::Result<()> {
+        if !self.verify_sync {
+            return Ok(());
+        }
+        let tracks = [(Some(color_av1_data), color_frames), (alpha_av1_data, alpha_frames)];
+        for (data, frames) in tracks {
+            if let (Some(data), Some(frames)) = (data, frames) {
+                if let Some(mismatch) = self.check_sync(data, frames)?.first() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, mismatch.to_string()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the color image's `av1C` (profile, level, tier, bit depth, monochrome and chroma subsampling)
+    /// from the sequence header of `color_av1_data`, so that they don't have to be configured by hand.
+    ///
+    /// This also sets [`bit_depth`](Self::bit_depth), [`chroma_subsampling`](Self::chroma_subsampling) and [`chroma_sample_position`](Self::chroma_sample_position).
+    /// Fails if the AV1 data has no sequence header, or it disagrees with a `bit_depth` or [`color_av1c`](Self::color_av1c) that has been set.
+    ///
+    /// In [layout version](Self::layout_version) 3 and later, writing makes `av1C` of the color and alpha images from their sequence
+    /// headers anyway, if they have one. This is still needed for [templates](Self::header_template) and older layouts.
+    pub fn av1c_from_sequence_header(&mut self, color_av1_data: &[u8]) -> io::Result<&mut Self> {
+        let seq = obu::sequence_header(color_av1_data)?;
+        let av1c = sequence_header_av1c(&seq);
+        let mismatch = match (self.bit_depth, self.color_av1c) {
+            (Some(setting), _) if setting != seq.bit_depth => Some(Mismatch::BitDepth { setting, av1: seq.bit_depth }),
+            (_, Some(setting)) if setting != av1c => Some(Mismatch::Av1C { setting, av1: av1c }),
+            _ => None,
+        };
+        if let Some(mismatch) = mismatch {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, mismatch.to_string()));
+        }
+        self.chroma_subsampling = match (seq.monochrome, seq.subsampling_x, seq.subsampling_y) {
+            (true, ..) => ChromaSubsampling::Cs400,
+            (false, true, true) => ChromaSubsampling::Cs420,
+            (false, true, false) => ChromaSubsampling::Cs422,
+            _ => ChromaSubsampling::Cs444,
+        };
+        self.chroma_sample_position = match seq.chroma_sample_position {
+            1 => ChromaSamplePosition::Vertical,
+            2 => ChromaSamplePosition::Colocated,
+            _ => ChromaSamplePosition::Unknown,
+        };
+        self.bit_depth = Some(seq.bit_depth);
+        self.color_av1c = Some(av1c);
+        Ok(self)
+    }
+
+    /// In layout version 3 and later, `av1C` is made from the sequence header of the AV1 data if there's a readable one,
+    /// unless it's been set explicitly (`explicit`). Fails if the sequence header disagrees with the bit depth,
+    /// or with [`seq_profile`](Self::seq_profile) and [`seq_level_idx`](Self::seq_level_idx) if they've been set.
+    pub(crate) fn auto_av1c(&self, av1_data: &[u8], explicit: Option<Av1CBox>, depth_bits: u8) -> io::Result<Option<Av1CBox>> {
+        if self.layout_version < 3 || explicit.is_some() {
+            return Ok(None);
+        }
+        // Without a readable one (e.g. in test data), the settings are used.
+        // Looking for it first avoids allocating an error in the fast path.
+        if !obu::Obus::new(av1_data).map_while(Result::ok).any(|obu| obu.typ == obu::OBU_SEQUENCE_HEADER) {
+            return Ok(None);
+        }
+        let Ok(seq) = obu::sequence_header(av1_data) else { return Ok(None) };
+        let av1c = sequence_header_av1c(&seq);
+        let setting = Av1CBox {
+            seq_profile: self.seq_profile.unwrap_or(av1c.seq_profile),
+            seq_level_idx_0: self.seq_level_idx.unwrap_or(av1c.seq_level_idx_0),
+            ..av1c
+        };
+        let mismatch = if depth_bits != seq.bit_depth {
+            Some(Mismatch::BitDepth { setting: depth_bits, av1: seq.bit_depth })
+        } else if setting != av1c {
+            Some(Mismatch::Av1C { setting, av1: av1c })
+        } else {
+            None
+        };
+        match mismatch {
+            Some(mismatch) => Err(io::Error::new(io::ErrorKind::InvalidInput, mismatch.to_string())),
+            None => Ok(Some(av1c)),
+        }
+    }
+
+    /// Checks the [`layout_version`](Self::layout_version), and the ones enabled with
+    /// [`verify_size`](Self::verify_size) and [`verify_alpha`](Self::verify_alpha)
+    pub(crate) fn verify_input(&self, color_av1_data: &[u8], alpha_av1_data: Option<&[u8]>, width: u32, height: u32) -> io::Result<()> {
+        if !(1..=Self::LATEST_LAYOUT_VERSION).contains(&self.layout_version) {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "unknown layout version"));
+        }
+        let mut mismatches = Vec::new();
+        if self.verify_size {
+            mismatches.extend(self.check_size(color_av1_data, width, height)?);
+        }
+        if let Some(alpha) = alpha_av1_data.filter(|_| self.verify_alpha) {
+            mismatches.extend(self.check_alpha(alpha, width, height)?);
+        }
+        match mismatches.first() {
+            Some(mismatch) => Err(io::Error::new(io::ErrorKind::InvalidInput, mismatch.to_string())),
+            None => Ok(()),
+        }
+    }
+}
+
+/// `av1C` that matches the sequence header
+pub(crate) fn sequence_header_av1c(seq: &obu::SequenceHeader) -> Av1CBox {
+    Av1CBox {
+        seq_profile: seq.seq_profile,
+        seq_level_idx_0: seq.seq_level_idx_0,
+        seq_tier_0: seq.seq_tier_0,
+        high_bitdepth: seq.bit_depth >= 10,
+        twelve_bit: seq.bit_depth >= 12,
+        monochrome: seq.monochrome,
+        chroma_subsampling_x: seq.subsampling_x,
+        chroma_subsampling_y: seq.subsampling_y,
+        chroma_sample_position: seq.chroma_sample_position,
+    }
+}
+
+pub(crate) fn frames_too_large() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "frame sizes add up to more than the AV1 data")
+}
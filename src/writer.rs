@@ -1,3 +1,4 @@
+use arrayvec::{ArrayVec, CapacityError};
 use std::convert::Infallible;
 use std::convert::TryFrom;
 use std::io;
@@ -18,6 +19,15 @@ impl WriterBackend for Vec<u8> {
     }
 }
 
+/// Fixed-size buffer on the stack, for writing without heap allocations
+impl<const N: usize> WriterBackend for ArrayVec<u8, N> {
+    type Error = CapacityError;
+    #[inline(always)]
+    fn extend_from_slice(&mut self, data: &[u8]) -> Result<(), CapacityError> {
+        self.try_extend_from_slice(data)
+    }
+}
+
 pub struct IO<W>(pub W);
 
 impl<W: io::Write> WriterBackend for IO<W> {
@@ -30,7 +40,18 @@ impl<W: io::Write> WriterBackend for IO<W> {
 
 pub struct Writer<'p, 'w, B> {
     parent: Option<&'p mut usize>,
+    /// Set in the parent when this box failed, since its length is then incomplete too
+    #[cfg_attr(not(any(debug_assertions, feature = "check-box-len")), allow(dead_code))]
+    parent_failed: Option<&'p mut bool>,
     left: Option<usize>,
+    /// The backend returned an error, so the length check doesn't apply
+    #[cfg_attr(not(any(debug_assertions, feature = "check-box-len")), allow(dead_code))]
+    failed: bool,
+    /// Declared length and type of the box, for the length check
+    #[cfg_attr(not(any(debug_assertions, feature = "check-box-len")), allow(dead_code))]
+    len: usize,
+    #[cfg_attr(not(any(debug_assertions, feature = "check-box-len")), allow(dead_code))]
+    typ: [u8; 4],
     out: &'w mut B,
 }
 
@@ -39,7 +60,11 @@ impl<'w, B> Writer<'static, 'w, B> {
     pub fn new(out: &'w mut B) -> Self {
         Self {
             parent: None,
+            parent_failed: None,
             left: None,
+            failed: false,
+            len: 0,
+            typ: [0; 4],
             out,
         }
     }
@@ -49,11 +74,12 @@ impl<'p, 'w, B: WriterBackend> Writer<'p, 'w, B> {
     #[inline]
     pub fn new_box(&mut self, len: usize) -> Writer<'_, '_, B> {
         Writer {
-            parent: match &mut self.left {
-                Some(l) => Some(l),
-                None => None,
-            },
+            parent: self.left.as_mut(),
+            parent_failed: Some(&mut self.failed),
             left: Some(len),
+            failed: false,
+            len,
+            typ: [0; 4],
             out: self.out,
         }
     }
@@ -66,9 +92,10 @@ impl<'p, 'w, B: WriterBackend> Writer<'p, 'w, B> {
 
     #[inline]
     pub fn basic_box(&mut self, typ: [u8; 4]) -> Result<(), B::Error> {
-        let len = self.left.unwrap();
+        self.typ = typ;
+        let len = self.left.unwrap_or(0);
         if let Some(parent) = &mut self.parent {
-            **parent -= len;
+            **parent = parent.wrapping_sub(len);
         }
         match u32::try_from(len) {
             Ok(len) => self.u32(len)?,
@@ -82,8 +109,15 @@ impl<'p, 'w, B: WriterBackend> Writer<'p, 'w, B> {
 
     #[inline(always)]
     pub fn push(&mut self, data: &[u8]) -> Result<(), B::Error> {
-        *self.left.as_mut().unwrap() -= data.len();
-        self.out.extend_from_slice(data)
+        if let Some(left) = &mut self.left {
+            // a mismatch is caught by the assert in `drop`
+            *left = left.wrapping_sub(data.len());
+        }
+        let res = self.out.extend_from_slice(data);
+        if res.is_err() {
+            self.failed = true;
+        }
+        res
     }
 
     #[inline(always)]
@@ -107,11 +141,22 @@ impl<'p, 'w, B: WriterBackend> Writer<'p, 'w, B> {
     }
 }
 
-#[cfg(debug_assertions)]
+/// A box that writes a different number of bytes than its `len()` corrupts the file,
+/// so it's checked in debug builds, and in release builds with the `check-box-len` feature.
+#[cfg(any(debug_assertions, feature = "check-box-len"))]
 impl<B> Drop for Writer<'_, '_, B> {
     fn drop(&mut self) {
+        if self.failed {
+            if let Some(parent_failed) = &mut self.parent_failed {
+                **parent_failed = true;
+            }
+            return;
+        }
         if let Some(unwritten_bytes) = self.left {
-            assert_eq!(0, unwritten_bytes);
+            if unwritten_bytes != 0 && !std::thread::panicking() {
+                let written = self.len.wrapping_sub(unwritten_bytes);
+                panic!("box '{}' wrote {written} bytes, but its len() is {}", String::from_utf8_lossy(&self.typ), self.len);
+            }
         }
     }
 }
@@ -0,0 +1,45 @@
+//! Has its own test binary, because counting allocations needs a global allocator
+
+use avif_serialize::Aviffy;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::io;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+struct Counting;
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|a| a.set(a.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+#[test]
+fn simple_still_does_not_allocate() {
+    let test_img = [1,2,3,4,5,6];
+    let mut aviffy = Aviffy::new();
+    for colr in [false, true] {
+        aviffy.full_color_range(colr);
+        let expected = aviffy.to_vec(&test_img, None, 10, 20, 8, 1, None, None, &[]);
+
+        let mut out = [0u8; 1000];
+        let mut cursor = io::Cursor::new(&mut out[..]);
+        let before = ALLOCATIONS.with(|a| a.get());
+        aviffy.write(&mut cursor, &test_img, None, 10, 20, 8, 1, None, None, &[]).unwrap();
+        assert_eq!(before, ALLOCATIONS.with(|a| a.get()));
+
+        let len = cursor.position() as usize;
+        assert_eq!(expected, out[..len]);
+    }
+}